@@ -26,7 +26,10 @@ const RESULT_OFFSET: usize = 0x100;
 const GUEST_RESULT_ADDR: usize = GUEST_ADDR + RESULT_OFFSET;
 
 #[cfg(target_arch = "aarch64")]
-use hv::arm64::{Reg, VcpuExt};
+use hv::arm64::{
+    psci::{Psci, PsciEvent},
+    Reg,
+};
 
 #[cfg(target_arch = "aarch64")]
 fn main() -> Result<(), hv::Error> {
@@ -52,7 +55,7 @@ fn main() -> Result<(), hv::Error> {
     }
 
     // Init VM
-    let vm = Arc::new(hv::Vm::new(std::ptr::null_mut())?);
+    let vm = Arc::new(hv::Vm::new(hv::arm64::VmConfig::default())?);
 
     // Initialize guest memory
     vm.map(
@@ -63,20 +66,26 @@ fn main() -> Result<(), hv::Error> {
     )?;
 
     // Create VCPU
-    let cpu = vm.create_cpu()?;
-
-    // Set regs
-    cpu.set_reg(Reg::PC, GUEST_ADDR as _)
-        .expect("Failed to set PC reg");
-
-    cpu.set_reg(Reg::X1, GUEST_RESULT_ADDR as _)
-        .expect("Failed to set X1");
+    let cpu = hv::VcpuBuilder::new()
+        .entry(GUEST_ADDR as _)
+        .reg(Reg::X1, GUEST_RESULT_ADDR as _)
+        .build(vm)?;
 
+    let psci = Psci::new();
     loop {
-        cpu.run().expect("Failed to run CPU");
-
-        let info = cpu.exit_info();
-        println!("{:?}", info);
+        let exit = cpu.run().expect("Failed to run CPU");
+        println!("{:?}", exit);
+
+        if let Some(call) = exit.hypercall() {
+            match psci.handle(&cpu, call)? {
+                Some(PsciEvent::SystemReset) => {
+                    println!("Guest requested SYSTEM_RESET");
+                    break;
+                }
+                Some(event) => println!("Unexpected PSCI event {:?}", event),
+                None => continue,
+            }
+        }
 
         break;
     }
@@ -0,0 +1,365 @@
+//! Exception syndromes (`ESR_EL2`) of [Exit::Exception](super::Exit::Exception) exits.
+
+/// Exception class, `ESR_EL2.EC`, of exceptions taken from AArch64 guests.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum ExceptionClass {
+    /// Unknown reason, e.g. an undefined instruction.
+    Unknown,
+    /// `wfi` or `wfe`.
+    Wfx,
+    /// Access to SIMD or floating point registers trapped by `CPTR_EL2`.
+    SimdFp,
+    /// Illegal execution state.
+    IllegalState,
+    /// `svc`.
+    Svc,
+    /// `hvc`.
+    Hvc,
+    /// `smc`.
+    Smc,
+    /// Trapped `msr`, `mrs` or system instruction.
+    SysReg,
+    /// Access to SVE registers trapped by `CPTR_EL2`.
+    Sve,
+    /// Instruction abort from a lower exception level.
+    InstructionAbortLower,
+    /// Instruction abort without a change of exception level.
+    InstructionAbort,
+    /// Misaligned `PC`.
+    PcAlignment,
+    /// Data abort from a lower exception level.
+    DataAbortLower,
+    /// Data abort without a change of exception level.
+    DataAbort,
+    /// Misaligned `SP`.
+    SpAlignment,
+    /// Trapped floating point exception.
+    FpException,
+    /// SError interrupt.
+    SError,
+    /// Hardware breakpoint from a lower exception level.
+    BreakpointLower,
+    /// Hardware breakpoint without a change of exception level.
+    Breakpoint,
+    /// Software step from a lower exception level.
+    SoftwareStepLower,
+    /// Software step without a change of exception level.
+    SoftwareStep,
+    /// Watchpoint from a lower exception level.
+    WatchpointLower,
+    /// Watchpoint without a change of exception level.
+    Watchpoint,
+    /// `brk`.
+    Brk,
+    /// Any other class, e.g. of AArch32 guests.
+    Other(u8),
+}
+
+impl From<u8> for ExceptionClass {
+    fn from(ec: u8) -> Self {
+        match ec {
+            0x00 => ExceptionClass::Unknown,
+            0x01 => ExceptionClass::Wfx,
+            0x07 => ExceptionClass::SimdFp,
+            0x0e => ExceptionClass::IllegalState,
+            0x15 => ExceptionClass::Svc,
+            0x16 => ExceptionClass::Hvc,
+            0x17 => ExceptionClass::Smc,
+            0x18 => ExceptionClass::SysReg,
+            0x19 => ExceptionClass::Sve,
+            0x20 => ExceptionClass::InstructionAbortLower,
+            0x21 => ExceptionClass::InstructionAbort,
+            0x22 => ExceptionClass::PcAlignment,
+            0x24 => ExceptionClass::DataAbortLower,
+            0x25 => ExceptionClass::DataAbort,
+            0x26 => ExceptionClass::SpAlignment,
+            0x2c => ExceptionClass::FpException,
+            0x2f => ExceptionClass::SError,
+            0x30 => ExceptionClass::BreakpointLower,
+            0x31 => ExceptionClass::Breakpoint,
+            0x32 => ExceptionClass::SoftwareStepLower,
+            0x33 => ExceptionClass::SoftwareStep,
+            0x34 => ExceptionClass::WatchpointLower,
+            0x35 => ExceptionClass::Watchpoint,
+            0x3c => ExceptionClass::Brk,
+            ec => ExceptionClass::Other(ec),
+        }
+    }
+}
+
+/// Data access of a data abort with a valid syndrome, i.e. a single register load or store.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct DataAccess {
+    /// Access size in bytes.
+    pub size: u8,
+    /// Transfer register index (31 is `XZR`).
+    pub reg: u8,
+    /// Loads sign extend the value.
+    pub sign_extend: bool,
+    /// The register is 64 bits wide.
+    pub sixty_four: bool,
+}
+
+/// Instruction specific syndrome of data aborts.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct DataAbortIss {
+    /// The access, `None` if the syndrome isn't valid, e.g. for load pairs.
+    pub access: Option<DataAccess>,
+    pub write: bool,
+    /// The fault happened on a stage 2 translation of a stage 1 page table walk.
+    pub s1ptw: bool,
+    /// The fault was caused by a cache maintenance instruction.
+    pub cache_maintenance: bool,
+    /// The faulting virtual address is valid.
+    pub far_valid: bool,
+    /// Data fault status code.
+    pub fault_status: u8,
+}
+
+/// Instruction specific syndrome of instruction aborts.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct InstructionAbortIss {
+    /// The fault happened on a stage 2 translation of a stage 1 page table walk.
+    pub s1ptw: bool,
+    /// The faulting virtual address is valid.
+    pub far_valid: bool,
+    /// Instruction fault status code.
+    pub fault_status: u8,
+}
+
+/// Instruction specific syndrome of trapped system register accesses.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct SysRegIss {
+    /// Register encoding, same as [SysReg](super::SysReg) values.
+    pub reg: u16,
+    /// General purpose register index (31 is `XZR`).
+    pub rt: u8,
+    /// `true` for `mrs`, `false` for `msr`.
+    pub read: bool,
+}
+
+/// Returns the encoding of a system register in the layout of [SysReg](super::SysReg) values
+/// and [SysRegIss::reg].
+pub const fn sys_reg(op0: u8, op1: u8, crn: u8, crm: u8, op2: u8) -> u16 {
+    ((op0 as u16) << 14)
+        | ((op1 as u16) << 11)
+        | ((crn as u16) << 7)
+        | ((crm as u16) << 3)
+        | op2 as u16
+}
+
+impl SysRegIss {
+    /// Returns the fields of the register encoding: `(op0, op1, crn, crm, op2)`.
+    pub fn encoding(&self) -> (u8, u8, u8, u8, u8) {
+        let field = |shift: u16, len: u16| ((self.reg >> shift) & ((1 << len) - 1)) as u8;
+        (
+            field(14, 2),
+            field(11, 3),
+            field(7, 4),
+            field(3, 4),
+            field(0, 3),
+        )
+    }
+}
+
+/// Exception syndrome register value.
+///
+/// ```ignore
+/// if let Exit::Exception { syndrome, .. } = exit {
+///     let esr = Esr::from(syndrome);
+///     match esr.class() {
+///         ExceptionClass::DataAbortLower => handle_mmio(esr.data_abort().unwrap()),
+///         ExceptionClass::Hvc => handle_hvc(esr.imm16().unwrap()),
+///         _ => {}
+///     }
+/// }
+/// ```
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Esr(pub u64);
+
+impl From<u64> for Esr {
+    fn from(value: u64) -> Self {
+        Esr(value)
+    }
+}
+
+impl Esr {
+    /// Returns the exception class.
+    pub fn class(&self) -> ExceptionClass {
+        ExceptionClass::from(((self.0 >> 26) & 0x3f) as u8)
+    }
+
+    /// Returns the instruction specific syndrome.
+    pub fn iss(&self) -> u32 {
+        (self.0 & 0x1ff_ffff) as u32
+    }
+
+    /// Returns `true` if the trapped instruction is 32 bits long, `false` for 16 bits.
+    pub fn il(&self) -> bool {
+        self.0 & (1 << 25) != 0
+    }
+
+    /// Returns the length of the trapped instruction in bytes.
+    pub fn instruction_len(&self) -> u64 {
+        if self.il() {
+            4
+        } else {
+            2
+        }
+    }
+
+    /// Returns the immediate of `svc`, `hvc`, `smc` and `brk`, `None` for other classes.
+    pub fn imm16(&self) -> Option<u16> {
+        match self.class() {
+            ExceptionClass::Svc
+            | ExceptionClass::Hvc
+            | ExceptionClass::Smc
+            | ExceptionClass::Brk => Some(self.iss() as u16),
+            _ => None,
+        }
+    }
+
+    /// Returns `Some(true)` for `wfe` and `Some(false)` for `wfi`, `None` for other classes.
+    pub fn wfe(&self) -> Option<bool> {
+        match self.class() {
+            ExceptionClass::Wfx => Some(self.iss() & 1 != 0),
+            _ => None,
+        }
+    }
+
+    /// Decodes the syndrome of data aborts, `None` for other classes.
+    pub fn data_abort(&self) -> Option<DataAbortIss> {
+        match self.class() {
+            ExceptionClass::DataAbortLower | ExceptionClass::DataAbort => {}
+            _ => return None,
+        }
+        let access = if self.bits(24, 1) != 0 {
+            Some(DataAccess {
+                size: 1 << self.bits(22, 2),
+                reg: self.bits(16, 5) as u8,
+                sign_extend: self.bits(21, 1) != 0,
+                sixty_four: self.bits(15, 1) != 0,
+            })
+        } else {
+            None
+        };
+        Some(DataAbortIss {
+            access,
+            write: self.bits(6, 1) != 0,
+            s1ptw: self.bits(7, 1) != 0,
+            cache_maintenance: self.bits(8, 1) != 0,
+            far_valid: self.bits(10, 1) == 0,
+            fault_status: self.bits(0, 6) as u8,
+        })
+    }
+
+    /// Decodes the syndrome of instruction aborts, `None` for other classes.
+    pub fn instruction_abort(&self) -> Option<InstructionAbortIss> {
+        match self.class() {
+            ExceptionClass::InstructionAbortLower | ExceptionClass::InstructionAbort => {}
+            _ => return None,
+        }
+        Some(InstructionAbortIss {
+            s1ptw: self.bits(7, 1) != 0,
+            far_valid: self.bits(10, 1) == 0,
+            fault_status: self.bits(0, 6) as u8,
+        })
+    }
+
+    /// Decodes the syndrome of trapped system register accesses, `None` for other classes.
+    pub fn sys_reg(&self) -> Option<SysRegIss> {
+        if self.class() != ExceptionClass::SysReg {
+            return None;
+        }
+        let (op0, op2, op1) = (self.bits(20, 2), self.bits(17, 3), self.bits(14, 3));
+        let (crn, crm) = (self.bits(10, 4), self.bits(1, 4));
+        Some(SysRegIss {
+            reg: sys_reg(op0 as u8, op1 as u8, crn as u8, crm as u8, op2 as u8),
+            rt: self.bits(5, 5) as u8,
+            read: self.bits(0, 1) != 0,
+        })
+    }
+
+    fn bits(&self, shift: u32, len: u32) -> u32 {
+        (self.iss() >> shift) & ((1 << len) - 1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn data_abort() {
+        let cases = [
+            // str w1, [x0] from EL1, stage 2 translation fault
+            (
+                0x9381_0047,
+                Some(DataAbortIss {
+                    access: Some(DataAccess {
+                        size: 4,
+                        reg: 1,
+                        sign_extend: false,
+                        sixty_four: false,
+                    }),
+                    write: true,
+                    s1ptw: false,
+                    cache_maintenance: false,
+                    far_valid: true,
+                    fault_status: 0x07,
+                }),
+            ),
+            // ldrsh x3, [x2]
+            (
+                0x9363_8006,
+                Some(DataAbortIss {
+                    access: Some(DataAccess {
+                        size: 2,
+                        reg: 3,
+                        sign_extend: true,
+                        sixty_four: true,
+                    }),
+                    write: false,
+                    s1ptw: false,
+                    cache_maintenance: false,
+                    far_valid: true,
+                    fault_status: 0x06,
+                }),
+            ),
+            // No valid syndrome, on a stage 1 walk by a cache maintenance instruction.
+            (
+                0x9600_0584,
+                Some(DataAbortIss {
+                    access: None,
+                    write: false,
+                    s1ptw: true,
+                    cache_maintenance: true,
+                    far_valid: false,
+                    fault_status: 0x04,
+                }),
+            ),
+            // hvc #0
+            (0x5a00_0000, None),
+        ];
+        for (esr, expected) in cases.iter() {
+            assert_eq!(Esr::from(*esr).data_abort(), *expected, "{:#x}", esr);
+        }
+    }
+
+    #[test]
+    fn sys_reg_access() {
+        let cases = [
+            // mrs x5, cntv_ctl_el0
+            (0x6232_f8a7, (3, 3, 14, 3, 1), 5, true),
+            // msr sctlr_el1, xzr
+            (0x6230_07e0, (3, 0, 1, 0, 0), 31, false),
+        ];
+        for (esr, (op0, op1, crn, crm, op2), rt, read) in cases.iter() {
+            let iss = Esr::from(*esr).sys_reg().unwrap();
+            assert_eq!(iss.reg, sys_reg(*op0, *op1, *crn, *crm, *op2), "{:#x}", esr);
+            assert_eq!(iss.encoding(), (*op0, *op1, *crn, *crm, *op2), "{:#x}", esr);
+            assert_eq!((iss.rt, iss.read), (*rt, *read), "{:#x}", esr);
+        }
+        assert_eq!(Esr::from(0x9381_0047).sys_reg(), None);
+    }
+}
@@ -0,0 +1,131 @@
+use super::{Esr, ExceptionClass, ExitReason, HypercallExit, VcpuExit};
+use crate::GPAddr;
+
+/// Decoded vCPU exit, returned by [Vcpu::run](crate::Vcpu::run).
+///
+/// Exceptions without a dedicated variant are reported as [Exit::Exception] with the raw
+/// syndrome. The raw exit structure remains available with
+/// [VcpuExt::exit_info](super::VcpuExt::exit_info).
+#[non_exhaustive]
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Exit {
+    /// Asynchronous exit requested explicitly by `hv_vcpus_exit` call.
+    Canceled,
+    /// The VTimer fired, the VTimer is masked until unmasked by the VMM.
+    VTimerActivated,
+    /// The guest executed `wfi` or `wfe`.
+    Wfx { wfe: bool },
+    /// The guest executed `hvc`.
+    Hvc { imm: u16 },
+    /// The guest executed `smc`, `PC` is not advanced.
+    Smc { imm: u16 },
+    /// Trapped system register access.
+    SysReg {
+        /// Register encoding, same as [SysReg](super::SysReg) values.
+        reg: u16,
+        /// General purpose register index (31 is `XZR`).
+        rt: u8,
+        /// `true` for `mrs`, `false` for `msr`.
+        read: bool,
+    },
+    /// Data abort on unmapped or protected guest memory, e.g. MMIO.
+    DataAbort {
+        gpa: GPAddr,
+        va: u64,
+        write: bool,
+        /// Access size in bytes and the transfer register, if the syndrome is valid.
+        access: Option<(u8, u8)>,
+    },
+    /// Instruction abort on unmapped or non executable guest memory.
+    InstructionAbort { gpa: GPAddr, va: u64 },
+    /// The guest executed `brk`.
+    Brk { imm: u16 },
+    /// Software step completed, the guest executed a single instruction.
+    SoftwareStep,
+    /// A deadline of a [TimerWheel](crate::run::TimerWheel) passed, never returned by
+    /// [Vcpu::run](crate::Vcpu::run).
+    TimerExpired,
+    /// Any other exception.
+    Exception { syndrome: u64, va: u64, gpa: GPAddr },
+    /// The framework couldn't determine the exit reason, with the raw reason.
+    Unknown(u32),
+}
+
+impl Exit {
+    /// Returns the name of the exit kind, e.g. for statistics.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Exit::Canceled => "canceled",
+            Exit::VTimerActivated => "vtimer_activated",
+            Exit::Wfx { .. } => "wfx",
+            Exit::Hvc { .. } => "hvc",
+            Exit::Smc { .. } => "smc",
+            Exit::SysReg { .. } => "sys_reg",
+            Exit::DataAbort { .. } => "data_abort",
+            Exit::InstructionAbort { .. } => "instruction_abort",
+            Exit::Brk { .. } => "brk",
+            Exit::SoftwareStep => "software_step",
+            Exit::TimerExpired => "timer_expired",
+            Exit::Exception { .. } => "exception",
+            Exit::Unknown(_) => "unknown",
+        }
+    }
+
+    /// Returns the call of an `hvc` or `smc` exit, `None` for other exits.
+    pub fn hypercall(&self) -> Option<HypercallExit> {
+        HypercallExit::from_exit(self)
+    }
+}
+
+impl From<&VcpuExit> for Exit {
+    fn from(exit: &VcpuExit) -> Exit {
+        match ExitReason::from(exit.reason) {
+            ExitReason::Canceled => Exit::Canceled,
+            ExitReason::VTimerActivated => Exit::VTimerActivated,
+            ExitReason::Unknown => Exit::Unknown(exit.reason),
+            ExitReason::Exception => decode_exception(exit),
+        }
+    }
+}
+
+fn decode_exception(exit: &VcpuExit) -> Exit {
+    let syndrome = exit.exception.syndrome;
+    let va = exit.exception.virtual_address;
+    let gpa = exit.exception.physical_address;
+    let esr = Esr::from(syndrome);
+
+    match esr.class() {
+        ExceptionClass::Wfx => Exit::Wfx {
+            wfe: esr.iss() & 1 != 0,
+        },
+        ExceptionClass::Hvc => Exit::Hvc {
+            imm: esr.iss() as u16,
+        },
+        ExceptionClass::Smc => Exit::Smc {
+            imm: esr.iss() as u16,
+        },
+        ExceptionClass::SysReg => match esr.sys_reg() {
+            Some(iss) => Exit::SysReg {
+                reg: iss.reg,
+                rt: iss.rt,
+                read: iss.read,
+            },
+            None => Exit::Exception { syndrome, va, gpa },
+        },
+        ExceptionClass::DataAbortLower => match esr.data_abort() {
+            Some(iss) => Exit::DataAbort {
+                gpa,
+                va,
+                write: iss.write,
+                access: iss.access.map(|a| (a.size, a.reg)),
+            },
+            None => Exit::Exception { syndrome, va, gpa },
+        },
+        ExceptionClass::InstructionAbortLower => Exit::InstructionAbort { gpa, va },
+        ExceptionClass::Brk => Exit::Brk {
+            imm: esr.iss() as u16,
+        },
+        ExceptionClass::SoftwareStepLower => Exit::SoftwareStep,
+        _ => Exit::Exception { syndrome, va, gpa },
+    }
+}
@@ -0,0 +1,237 @@
+//! Typed views of the `ID_AA64*_EL1` feature registers.
+
+use super::{FeatureReg, SysReg, VcpuExt};
+use crate::{Error, Vcpu, VcpuConfig};
+
+/// Pointer authentication support, see [Features::pointer_auth].
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct PointerAuth {
+    /// Address authentication uses the architected QARMA algorithm, otherwise an
+    /// implementation defined one.
+    pub architected: bool,
+    /// Generic authentication (`pacga`) is supported.
+    pub generic: bool,
+}
+
+/// CPU features reported to the guest by its ID registers.
+///
+/// The raw registers are kept for fields not decoded here.
+///
+/// ```ignore
+/// let features = Features::from_config(&VcpuConfig::new()?)?;
+/// if features.has_sve() {
+///     cpu_node.property("sve", &[])?;
+/// }
+/// let bits = features.pa_range();
+/// ```
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Features {
+    pub pfr0: u64,
+    pub pfr1: u64,
+    pub dfr0: u64,
+    pub isar0: u64,
+    pub isar1: u64,
+    pub mmfr0: u64,
+    pub mmfr1: u64,
+    pub mmfr2: u64,
+}
+
+impl Features {
+    /// Reads the feature registers vCPUs created with `config` will report.
+    pub fn from_config(config: &VcpuConfig) -> Result<Features, Error> {
+        Ok(Features {
+            pfr0: config.feature_reg(FeatureReg::ID_AA64PFR0_EL1)?,
+            pfr1: config.feature_reg(FeatureReg::ID_AA64PFR1_EL1)?,
+            dfr0: config.feature_reg(FeatureReg::ID_AA64DFR0_EL1)?,
+            isar0: config.feature_reg(FeatureReg::ID_AA64ISAR0_EL1)?,
+            isar1: config.feature_reg(FeatureReg::ID_AA64ISAR1_EL1)?,
+            mmfr0: config.feature_reg(FeatureReg::ID_AA64MMFR0_EL1)?,
+            mmfr1: config.feature_reg(FeatureReg::ID_AA64MMFR1_EL1)?,
+            mmfr2: config.feature_reg(FeatureReg::ID_AA64MMFR2_EL1)?,
+        })
+    }
+
+    /// Reads the feature registers of an existing vCPU.
+    pub fn from_vcpu(vcpu: &Vcpu) -> Result<Features, Error> {
+        Ok(Features {
+            pfr0: vcpu.get_sys_reg(SysReg::ID_AA64PFR0_EL1)?,
+            pfr1: vcpu.get_sys_reg(SysReg::ID_AA64PFR1_EL1)?,
+            dfr0: vcpu.get_sys_reg(SysReg::ID_AA64DFR0_EL1)?,
+            isar0: vcpu.get_sys_reg(SysReg::ID_AA64ISAR0_EL1)?,
+            isar1: vcpu.get_sys_reg(SysReg::ID_AA64ISAR1_EL1)?,
+            mmfr0: vcpu.get_sys_reg(SysReg::ID_AA64MMFR0_EL1)?,
+            mmfr1: vcpu.get_sys_reg(SysReg::ID_AA64MMFR1_EL1)?,
+            mmfr2: vcpu.get_sys_reg(SysReg::ID_AA64MMFR2_EL1)?,
+        })
+    }
+
+    /// Returns `true` if EL2 is implemented, i.e. the guest can run a hypervisor.
+    pub fn has_el2(&self) -> bool {
+        field(self.pfr0, 8) != 0
+    }
+
+    /// Returns `true` if floating point is implemented.
+    pub fn has_fp(&self) -> bool {
+        field(self.pfr0, 16) != 0xf
+    }
+
+    /// Returns `true` if Advanced SIMD is implemented.
+    pub fn has_asimd(&self) -> bool {
+        field(self.pfr0, 20) != 0xf
+    }
+
+    /// Returns `true` if the GIC CPU interface system registers are implemented.
+    pub fn has_gic_sysregs(&self) -> bool {
+        field(self.pfr0, 24) != 0
+    }
+
+    /// Returns `true` if the Scalable Vector Extension is implemented.
+    pub fn has_sve(&self) -> bool {
+        field(self.pfr0, 32) != 0
+    }
+
+    /// Returns `true` if Branch Target Identification is implemented.
+    pub fn has_bti(&self) -> bool {
+        field(self.pfr1, 0) != 0
+    }
+
+    /// Returns `true` if the Memory Tagging Extension is implemented.
+    pub fn has_mte(&self) -> bool {
+        field(self.pfr1, 8) != 0
+    }
+
+    /// Returns `true` if the AES instructions are implemented.
+    pub fn has_aes(&self) -> bool {
+        field(self.isar0, 4) != 0
+    }
+
+    /// Returns `true` if the polynomial multiply long instructions are implemented.
+    pub fn has_pmull(&self) -> bool {
+        field(self.isar0, 4) >= 2
+    }
+
+    /// Returns `true` if the SHA1 instructions are implemented.
+    pub fn has_sha1(&self) -> bool {
+        field(self.isar0, 8) != 0
+    }
+
+    /// Returns `true` if the SHA256 instructions are implemented.
+    pub fn has_sha2(&self) -> bool {
+        field(self.isar0, 12) != 0
+    }
+
+    /// Returns `true` if the SHA512 instructions are implemented.
+    pub fn has_sha512(&self) -> bool {
+        field(self.isar0, 12) >= 2
+    }
+
+    /// Returns `true` if the CRC32 instructions are implemented.
+    pub fn has_crc32(&self) -> bool {
+        field(self.isar0, 16) != 0
+    }
+
+    /// Returns `true` if the Large System Extensions atomics are implemented.
+    pub fn has_atomics(&self) -> bool {
+        field(self.isar0, 20) >= 2
+    }
+
+    /// Returns `true` if the SHA3 instructions are implemented.
+    pub fn has_sha3(&self) -> bool {
+        field(self.isar0, 32) != 0
+    }
+
+    /// Returns `true` if the dot product instructions are implemented.
+    pub fn has_dot_product(&self) -> bool {
+        field(self.isar0, 44) != 0
+    }
+
+    /// Returns `true` if the `RNDR` and `RNDRRS` random number registers are implemented.
+    pub fn has_rng(&self) -> bool {
+        field(self.isar0, 60) != 0
+    }
+
+    /// Returns the pointer authentication support, `None` if not implemented.
+    pub fn pointer_auth(&self) -> Option<PointerAuth> {
+        let (apa, api) = (field(self.isar1, 4), field(self.isar1, 8));
+        let (gpa, gpi) = (field(self.isar1, 24), field(self.isar1, 28));
+        if apa == 0 && api == 0 {
+            return None;
+        }
+        Some(PointerAuth {
+            architected: apa != 0,
+            generic: gpa != 0 || gpi != 0,
+        })
+    }
+
+    /// Returns `true` if the `ldapr` release consistent loads are implemented.
+    pub fn has_rcpc(&self) -> bool {
+        field(self.isar1, 20) != 0
+    }
+
+    /// Returns the supported physical address size in bits.
+    pub fn pa_range(&self) -> u32 {
+        match field(self.mmfr0, 0) {
+            0 => 32,
+            1 => 36,
+            2 => 40,
+            3 => 42,
+            4 => 44,
+            5 => 48,
+            _ => 52,
+        }
+    }
+
+    /// Returns the number of ASID bits, 8 or 16.
+    pub fn asid_bits(&self) -> u32 {
+        if field(self.mmfr0, 4) == 2 {
+            16
+        } else {
+            8
+        }
+    }
+
+    /// Returns `true` if the 4KB translation granule is supported.
+    pub fn has_granule_4k(&self) -> bool {
+        field(self.mmfr0, 28) != 0xf
+    }
+
+    /// Returns `true` if the 16KB translation granule is supported.
+    pub fn has_granule_16k(&self) -> bool {
+        field(self.mmfr0, 20) != 0
+    }
+
+    /// Returns `true` if the 64KB translation granule is supported.
+    pub fn has_granule_64k(&self) -> bool {
+        field(self.mmfr0, 24) != 0xf
+    }
+
+    /// Returns `true` if the Virtualization Host Extensions are implemented.
+    pub fn has_vhe(&self) -> bool {
+        field(self.mmfr1, 8) != 0
+    }
+
+    /// Returns `true` if Privileged Access Never is implemented.
+    pub fn has_pan(&self) -> bool {
+        field(self.mmfr1, 20) != 0
+    }
+
+    /// Returns the number of hardware breakpoints.
+    pub fn breakpoints(&self) -> u32 {
+        field(self.dfr0, 12) as u32 + 1
+    }
+
+    /// Returns the number of hardware watchpoints.
+    pub fn watchpoints(&self) -> u32 {
+        field(self.dfr0, 20) as u32 + 1
+    }
+
+    /// Returns `true` if a PMUv3 is implemented.
+    pub fn has_pmu(&self) -> bool {
+        !matches!(field(self.dfr0, 8), 0 | 0xf)
+    }
+}
+
+/// Returns the 4-bit ID register field at `shift`.
+fn field(reg: u64, shift: u32) -> u8 {
+    ((reg >> shift) & 0xf) as u8
+}
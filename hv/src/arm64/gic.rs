@@ -0,0 +1,321 @@
+use std::collections::HashMap;
+use std::ops::Range;
+use std::sync::Arc;
+
+use crate::{call, sys, Error, GPAddr, Vcpu, Vm};
+
+extern "C" {
+    fn os_release(object: *mut std::ffi::c_void);
+}
+
+/// Configuration of the in-kernel GICv3, see [Gic].
+///
+/// ```ignore
+/// let gic = GicBuilder::new(0x0800_0000, 0x080a_0000)
+///     .msi(0x0808_0000, 64, 32)
+///     .build(vm.clone())?;
+/// let cpu = vm.create_cpu()?;
+/// let redistributor = gic.redistributor_base(&cpu)?;
+/// gic.assert_spi(32, true)?;
+/// ```
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub struct GicBuilder {
+    distributor_base: GPAddr,
+    redistributor_base: GPAddr,
+    msi: Option<(GPAddr, u32, u32)>,
+}
+
+impl GicBuilder {
+    /// Places the distributor and the redistributor region at the given guest physical
+    /// addresses, see [Gic::distributor_base_alignment] and
+    /// [Gic::redistributor_base_alignment].
+    pub fn new(distributor_base: GPAddr, redistributor_base: GPAddr) -> GicBuilder {
+        GicBuilder {
+            distributor_base,
+            redistributor_base,
+            msi: None,
+        }
+    }
+
+    /// Enables MSIs through a frame at `base`, for the `count` interrupt IDs from `intid_base`.
+    pub fn msi(mut self, base: GPAddr, intid_base: u32, count: u32) -> GicBuilder {
+        self.msi = Some((base, intid_base, count));
+        self
+    }
+
+    /// Creates the GIC of `vm`. Must be called before any vCPU is created.
+    pub fn build(self, vm: Arc<Vm>) -> Result<Gic, Error> {
+        let config = unsafe { sys::hv_gic_config_create() };
+        if config.is_null() {
+            return Err(Error::NoResources);
+        }
+
+        let result = self
+            .configure(config)
+            .and_then(|_| call!(sys::hv_gic_create(config)));
+        unsafe { os_release(config as *mut _) };
+        result?;
+
+        Ok(Gic {
+            config: self,
+            _vm: vm,
+        })
+    }
+
+    fn configure(&self, config: sys::hv_gic_config_t) -> Result<(), Error> {
+        call!(sys::hv_gic_config_set_distributor_base(
+            config,
+            self.distributor_base
+        ))?;
+        call!(sys::hv_gic_config_set_redistributor_base(
+            config,
+            self.redistributor_base
+        ))?;
+        if let Some((base, intid_base, count)) = self.msi {
+            call!(sys::hv_gic_config_set_msi_region_base(config, base))?;
+            call!(sys::hv_gic_config_set_msi_interrupt_range(
+                config, intid_base, count
+            ))?;
+        }
+        Ok(())
+    }
+}
+
+/// The in-kernel GICv3 of a VM (macOS 15).
+///
+/// The framework emulates the distributor, the redistributors and the CPU interfaces, and
+/// delivers interrupts to vCPUs itself, replacing
+/// [set_pending_interrupt](super::VcpuExt::set_pending_interrupt). The VMM asserts SPIs of its
+/// devices with [Gic::assert_spi], PPIs with [Gic::assert_ppi], and describes the layout to
+/// the guest, e.g. in the device tree.
+#[derive(Debug)]
+pub struct Gic {
+    config: GicBuilder,
+    /// The GIC lives as long as the VM.
+    _vm: Arc<Vm>,
+}
+
+impl Gic {
+    /// Returns the guest physical address of the distributor.
+    pub fn distributor_base(&self) -> GPAddr {
+        self.config.distributor_base
+    }
+
+    /// Returns the guest physical address of the redistributor region.
+    pub fn redistributor_base_region(&self) -> GPAddr {
+        self.config.redistributor_base
+    }
+
+    /// Returns the guest physical address of the redistributor of `vcpu` within the
+    /// redistributor region.
+    pub fn redistributor_base(&self, vcpu: &Vcpu) -> Result<GPAddr, Error> {
+        let mut out = 0;
+        call!(sys::hv_gic_get_redistributor_base(vcpu.id, &mut out))?;
+        Ok(out)
+    }
+
+    /// Sets the line level of the level-triggered shared peripheral interrupt `intid`, the
+    /// interrupt stays pending while the line is high.
+    pub fn assert_spi(&self, intid: u32, level: bool) -> Result<(), Error> {
+        call!(sys::hv_gic_set_spi(intid, level))
+    }
+
+    /// Signals an edge on the edge-triggered shared peripheral interrupt `intid`.
+    pub fn pulse_spi(&self, intid: u32) -> Result<(), Error> {
+        self.assert_spi(intid, true)?;
+        self.assert_spi(intid, false)
+    }
+
+    /// Sets the pending state of the private peripheral interrupt `intid` (16-31) of `vcpu`.
+    ///
+    /// Level-triggered PPIs are made pending while the line is high and are no longer pending
+    /// once it's lowered. Fails with [Error::BadArgument] for other interrupt IDs.
+    pub fn assert_ppi(&self, vcpu: &Vcpu, intid: u32, level: bool) -> Result<(), Error> {
+        let reg = if level {
+            sys::hv_gic_redistributor_reg_t_HV_GIC_REDISTRIBUTOR_REG_GICR_ISPENDR0
+        } else {
+            sys::hv_gic_redistributor_reg_t_HV_GIC_REDISTRIBUTOR_REG_GICR_ICPENDR0
+        };
+        let bit = ppi_bit(intid)?;
+        call!(sys::hv_gic_set_redistributor_reg(vcpu.id, reg, bit))
+    }
+
+    /// Makes the edge-triggered private peripheral interrupt `intid` (16-31) of `vcpu`
+    /// pending, it's consumed when the guest acknowledges it.
+    pub fn pulse_ppi(&self, vcpu: &Vcpu, intid: u32) -> Result<(), Error> {
+        let bit = ppi_bit(intid)?;
+        call!(sys::hv_gic_set_redistributor_reg(
+            vcpu.id,
+            sys::hv_gic_redistributor_reg_t_HV_GIC_REDISTRIBUTOR_REG_GICR_ISPENDR0,
+            bit
+        ))
+    }
+
+    /// Returns the guest physical address of the MSI frame and the interrupt IDs it can
+    /// signal, `None` unless enabled with [GicBuilder::msi].
+    pub fn msi_region(&self) -> Option<(GPAddr, Range<u32>)> {
+        self.config
+            .msi
+            .map(|(base, intid_base, count)| (base, intid_base..intid_base + count))
+    }
+
+    /// Sends a message signaled interrupt, as a device write of `data` (the interrupt ID) to
+    /// `address` in the MSI frame.
+    ///
+    /// Fails with [Error::Unsupported] unless MSIs were enabled with [GicBuilder::msi]. The
+    /// framework has no ITS, see [MsiRoutes] to route by device ID.
+    pub fn send_msi(&self, address: GPAddr, data: u32) -> Result<(), Error> {
+        if self.config.msi.is_none() {
+            return Err(Error::Unsupported);
+        }
+        call!(sys::hv_gic_send_msi(address, data))
+    }
+
+    /// Sends the MSI routed for `event` of `device`, as an ITS translating it would.
+    ///
+    /// Fails with [Error::BadArgument] without a route and [Error::Unsupported] unless MSIs
+    /// were enabled.
+    pub fn send_routed_msi(
+        &self,
+        routes: &MsiRoutes,
+        device: u32,
+        event: u32,
+    ) -> Result<(), Error> {
+        let (base, _) = self.msi_region().ok_or(Error::Unsupported)?;
+        let intid = routes.intid(device, event).ok_or(Error::BadArgument)?;
+        self.send_msi(base + MSI_SETSPI_NSR, intid)
+    }
+
+    /// Resets the GIC to its initial state.
+    pub fn reset(&self) -> Result<(), Error> {
+        call!(sys::hv_gic_reset())
+    }
+
+    /// Saves the state of the distributor, the redistributors and the CPU interfaces as an
+    /// opaque blob, e.g. for a VM snapshot. The vCPUs must not be running.
+    pub fn save_state(&self) -> Result<Vec<u8>, Error> {
+        let state = unsafe { sys::hv_gic_state_create() };
+        if state.is_null() {
+            return Err(Error::NoResources);
+        }
+
+        let result = read_state(state);
+        unsafe { os_release(state as *mut _) };
+        result
+    }
+
+    /// Restores a state returned by [Gic::save_state], after the vCPUs were created and their
+    /// state restored.
+    pub fn restore_state(&self, data: &[u8]) -> Result<(), Error> {
+        call!(sys::hv_gic_set_state(
+            data.len() as _,
+            data.as_ptr() as *const _
+        ))
+    }
+
+    /// Returns the size of the distributor in bytes.
+    pub fn distributor_size() -> Result<usize, Error> {
+        let mut out = 0;
+        call!(sys::hv_gic_get_distributor_size(&mut out))?;
+        Ok(out as usize)
+    }
+
+    /// Returns the required alignment of the distributor base.
+    pub fn distributor_base_alignment() -> Result<usize, Error> {
+        let mut out = 0;
+        call!(sys::hv_gic_get_distributor_base_alignment(&mut out))?;
+        Ok(out as usize)
+    }
+
+    /// Returns the size of the redistributor region in bytes, for the maximum number of vCPUs.
+    pub fn redistributor_region_size() -> Result<usize, Error> {
+        let mut out = 0;
+        call!(sys::hv_gic_get_redistributor_region_size(&mut out))?;
+        Ok(out as usize)
+    }
+
+    /// Returns the size of the redistributor of a single vCPU in bytes.
+    pub fn redistributor_size() -> Result<usize, Error> {
+        let mut out = 0;
+        call!(sys::hv_gic_get_redistributor_size(&mut out))?;
+        Ok(out as usize)
+    }
+
+    /// Returns the required alignment of the redistributor region base.
+    pub fn redistributor_base_alignment() -> Result<usize, Error> {
+        let mut out = 0;
+        call!(sys::hv_gic_get_redistributor_base_alignment(&mut out))?;
+        Ok(out as usize)
+    }
+
+    /// Returns the interrupt IDs available for SPIs.
+    pub fn spi_range() -> Result<Range<u32>, Error> {
+        let mut base = 0;
+        let mut count = 0;
+        call!(sys::hv_gic_get_spi_interrupt_range(&mut base, &mut count))?;
+        Ok(base..base + count)
+    }
+}
+
+/// Translation of device MSIs to interrupt IDs, as programmed into an ITS, see
+/// [Gic::send_routed_msi].
+///
+/// PCIe-style devices are identified by their requester ID and signal numbered events, the
+/// VMM routes each to an interrupt ID of the MSI range of the GIC.
+///
+/// ```ignore
+/// let routes = MsiRoutes::new().route(0x0008, 0, 64).route(0x0008, 1, 65);
+/// gic.send_routed_msi(&routes, 0x0008, 1)?;
+/// ```
+#[derive(Debug, Default, Clone, Eq, PartialEq)]
+pub struct MsiRoutes {
+    routes: HashMap<(u32, u32), u32>,
+}
+
+impl MsiRoutes {
+    pub fn new() -> MsiRoutes {
+        MsiRoutes::default()
+    }
+
+    /// Routes `event` of `device` to `intid`, replacing a previous route.
+    pub fn route(mut self, device: u32, event: u32, intid: u32) -> MsiRoutes {
+        self.routes.insert((device, event), intid);
+        self
+    }
+
+    /// Removes the routes of `device`, e.g. when it's unplugged.
+    pub fn remove_device(&mut self, device: u32) {
+        self.routes.retain(|(d, _), _| *d != device);
+    }
+
+    /// Returns the interrupt ID `event` of `device` is routed to.
+    pub fn intid(&self, device: u32, event: u32) -> Option<u32> {
+        self.routes.get(&(device, event)).copied()
+    }
+}
+
+/// Offset of the doorbell register in the MSI frame.
+const MSI_SETSPI_NSR: GPAddr = 0x40;
+
+/// Private peripheral interrupt IDs.
+const PPIS: Range<u32> = 16..32;
+
+/// Returns the bit of the PPI `intid` in the redistributor pending registers.
+fn ppi_bit(intid: u32) -> Result<u64, Error> {
+    if PPIS.contains(&intid) {
+        Ok(1 << intid)
+    } else {
+        Err(Error::BadArgument)
+    }
+}
+
+fn read_state(state: sys::hv_gic_state_t) -> Result<Vec<u8>, Error> {
+    let mut size = 0;
+    call!(sys::hv_gic_state_get_size(state, &mut size))?;
+    let mut data = vec![0_u8; size as usize];
+    call!(sys::hv_gic_state_get_data(
+        state,
+        data.as_mut_ptr() as *mut _
+    ))?;
+    Ok(data)
+}
@@ -0,0 +1,118 @@
+use super::{Exit, Reg, VcpuExt};
+use crate::{Error, Vcpu};
+
+/// SMCCC return value of unknown function identifiers.
+pub const SMCCC_NOT_SUPPORTED: u64 = -1_i64 as u64;
+
+/// Argument and result registers of the SMC calling convention.
+const REGS: [Reg; 8] = [
+    Reg::X0,
+    Reg::X1,
+    Reg::X2,
+    Reg::X3,
+    Reg::X4,
+    Reg::X5,
+    Reg::X6,
+    Reg::X7,
+];
+
+/// Instruction the guest used for a call.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Conduit {
+    Hvc,
+    Smc,
+}
+
+/// SMCCC function identifier, passed in `W0`.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct FunctionId(pub u32);
+
+impl FunctionId {
+    /// Returns `true` for fast calls, `false` for yielding calls.
+    pub fn fast(&self) -> bool {
+        self.0 & (1 << 31) != 0
+    }
+
+    /// Returns `true` for the SMC64/HVC64 convention, `false` for SMC32/HVC32.
+    pub fn sixty_four(&self) -> bool {
+        self.0 & (1 << 30) != 0
+    }
+
+    /// Returns the owning entity, e.g. 4 for standard secure services like PSCI.
+    pub fn owner(&self) -> u8 {
+        ((self.0 >> 24) & 0x3f) as u8
+    }
+
+    /// Returns the function number within the owner.
+    pub fn number(&self) -> u16 {
+        self.0 as u16
+    }
+}
+
+/// An `hvc` or `smc` exit, with helpers for the SMC calling convention.
+///
+/// ```ignore
+/// if let Some(call) = HypercallExit::from_exit(&exit) {
+///     let fid = call.function_id(&cpu)?;
+///     match fid.0 {
+///         PSCI_VERSION => call.complete(&cpu, &[0x1_0001])?,
+///         _ => call.complete(&cpu, &[SMCCC_NOT_SUPPORTED])?,
+///     }
+/// }
+/// ```
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct HypercallExit {
+    /// Immediate of the instruction, 0 for SMCCC calls.
+    pub imm: u16,
+    pub conduit: Conduit,
+}
+
+impl HypercallExit {
+    /// Returns the call of an [Exit::Hvc] or [Exit::Smc] exit, `None` for other exits.
+    pub fn from_exit(exit: &Exit) -> Option<HypercallExit> {
+        match *exit {
+            Exit::Hvc { imm } => Some(HypercallExit {
+                imm,
+                conduit: Conduit::Hvc,
+            }),
+            Exit::Smc { imm } => Some(HypercallExit {
+                imm,
+                conduit: Conduit::Smc,
+            }),
+            _ => None,
+        }
+    }
+
+    /// Returns the function identifier from `W0`.
+    pub fn function_id(&self, vcpu: &Vcpu) -> Result<FunctionId, Error> {
+        Ok(FunctionId(vcpu.get_reg(Reg::X0)? as u32))
+    }
+
+    /// Returns the arguments from `X1`-`X7`, 32-bit calls only use the low halves.
+    pub fn args(&self, vcpu: &Vcpu) -> Result<[u64; 7], Error> {
+        let values = vcpu.read_registers(&REGS[1..])?;
+        let mut args = [0; 7];
+        args.copy_from_slice(&values);
+        Ok(args)
+    }
+
+    /// Writes `results` to `X0` onwards, at most 8 values.
+    pub fn set_results(&self, vcpu: &Vcpu, results: &[u64]) -> Result<(), Error> {
+        if results.len() > REGS.len() {
+            return Err(Error::BadArgument);
+        }
+        let regs: Vec<_> = REGS.iter().copied().zip(results.iter().copied()).collect();
+        vcpu.write_registers(&regs)
+    }
+
+    /// Writes `results` and moves `PC` past an `smc`, `hvc` exits with `PC` already past the
+    /// instruction.
+    pub fn complete(&self, vcpu: &Vcpu, results: &[u64]) -> Result<(), Error> {
+        self.set_results(vcpu, results)?;
+        if self.conduit == Conduit::Smc {
+            let pc = vcpu.get_reg(Reg::PC)?;
+            vcpu.set_reg(Reg::PC, pc + 4)?;
+        }
+        Ok(())
+    }
+}
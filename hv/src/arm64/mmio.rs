@@ -0,0 +1,114 @@
+use super::{Esr, Reg, VcpuExit, VcpuExt, X_REGS};
+use crate::{Error, GPAddr, Vcpu};
+
+/// `XZR` in the transfer register field.
+const XZR: u8 = 31;
+
+/// A guest load or store to unmapped memory, decoded from a stage 2 data abort with a valid
+/// syndrome (`ISV` set).
+///
+/// ```ignore
+/// if let Some(access) = MmioAccess::decode(&cpu.exit_info()) {
+///     if access.write {
+///         device.write(access.gpa, access.value(&cpu)?);
+///         access.complete_write(&cpu)?;
+///     } else {
+///         access.complete_read(&cpu, device.read(access.gpa))?;
+///     }
+/// }
+/// ```
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct MmioAccess {
+    /// Faulting guest physical address, combined by the framework from `HPFAR_EL2` and
+    /// `FAR_EL2`.
+    pub gpa: GPAddr,
+    /// Access size in bytes.
+    pub len: u8,
+    pub write: bool,
+    /// Transfer register index (31 is `XZR`).
+    pub reg: u8,
+    /// Loads sign extend the value to the register width.
+    pub sign_extend: bool,
+    /// The transfer register is 64 bits wide (`Xt`), 32 bits otherwise (`Wt`).
+    pub sixty_four: bool,
+    /// Length of the trapped instruction in bytes.
+    instruction_len: u64,
+}
+
+impl MmioAccess {
+    /// Decodes the access of an exit, `None` unless it's a data abort with a valid syndrome.
+    pub fn decode(exit: &VcpuExit) -> Option<MmioAccess> {
+        MmioAccess::from_syndrome(
+            Esr::from(exit.exception.syndrome),
+            exit.exception.physical_address,
+        )
+    }
+
+    /// Decodes the access of a data abort with syndrome `esr` at `gpa`.
+    pub fn from_syndrome(esr: Esr, gpa: GPAddr) -> Option<MmioAccess> {
+        let abort = esr.data_abort()?;
+        let access = abort.access?;
+        Some(MmioAccess {
+            gpa,
+            len: access.size,
+            write: abort.write,
+            reg: access.reg,
+            sign_extend: access.sign_extend,
+            sixty_four: access.sixty_four,
+            instruction_len: esr.instruction_len(),
+        })
+    }
+
+    /// Returns the mask of the accessed bytes.
+    pub fn mask(&self) -> u64 {
+        if self.len >= 8 {
+            u64::MAX
+        } else {
+            (1_u64 << (self.len * 8)) - 1
+        }
+    }
+
+    /// Returns the value stored by the guest, truncated to the access size.
+    pub fn value(&self, vcpu: &Vcpu) -> Result<u64, Error> {
+        if self.reg == XZR {
+            return Ok(0);
+        }
+        Ok(vcpu.get_reg(X_REGS[self.reg as usize])? & self.mask())
+    }
+
+    /// Completes a load with `value`: writes it to the transfer register, sign extended if
+    /// required, and moves `PC` past the instruction.
+    pub fn complete_read(&self, vcpu: &Vcpu, value: u64) -> Result<(), Error> {
+        if self.reg != XZR {
+            vcpu.set_reg(X_REGS[self.reg as usize], self.extend(value))?;
+        }
+        self.skip(vcpu)
+    }
+
+    /// Completes a store by moving `PC` past the instruction.
+    pub fn complete_write(&self, vcpu: &Vcpu) -> Result<(), Error> {
+        self.skip(vcpu)
+    }
+
+    /// Returns the register value after loading `value`.
+    fn extend(&self, value: u64) -> u64 {
+        let value = value & self.mask();
+        let bits = u32::from(self.len) * 8;
+        let value = if self.sign_extend && bits < 64 {
+            let shift = 64 - bits;
+            (((value << shift) as i64) >> shift) as u64
+        } else {
+            value
+        };
+        if self.sixty_four {
+            value
+        } else {
+            value & u64::from(u32::MAX)
+        }
+    }
+
+    fn skip(&self, vcpu: &Vcpu) -> Result<(), Error> {
+        let pc = vcpu.get_reg(Reg::PC)?;
+        vcpu.set_reg(Reg::PC, pc + self.instruction_len)
+    }
+}
@@ -1,9 +1,30 @@
 //! Apple Silicon extensions support.
 
+use crate::vcpu::Field;
 use crate::{call, sys, Error, Vcpu};
 
+mod esr;
+mod exit;
+mod features;
+#[cfg(feature = "hv_15_0")]
+mod gic;
+mod hypercall;
+mod mmio;
+pub mod psci;
 mod regs;
+pub(crate) mod state;
+mod vm_config;
+pub use crate::vcpu::{CacheType, FeatureReg};
+pub use esr::*;
+pub use exit::Exit;
+pub use features::{Features, PointerAuth};
+#[cfg(feature = "hv_15_0")]
+pub use gic::{Gic, GicBuilder, MsiRoutes};
+pub use hypercall::*;
+pub use mmio::MmioAccess;
 pub use regs::*;
+pub use state::VcpuState;
+pub use vm_config::VmConfig;
 
 /// Injected interrupt type.
 #[repr(u32)]
@@ -62,6 +83,25 @@ pub trait VcpuExt {
     /// Sets the value of a vCPU register.
     fn set_reg(&self, reg: regs::Reg, value: u64) -> Result<(), Error>;
 
+    /// Returns the values of several vCPU registers, in order.
+    ///
+    /// A convenience loop over [VcpuExt::get_reg], it doesn't batch: every register that
+    /// isn't in the write cache costs one framework call.
+    fn read_registers(&self, regs: &[regs::Reg]) -> Result<Vec<u64>, Error>;
+
+    /// Sets the values of several vCPU registers, in order.
+    ///
+    /// A convenience loop over [VcpuExt::set_reg], it doesn't batch by itself: the writes
+    /// are only buffered until the next entry with the write cache enabled, see
+    /// [Vcpu::set_write_cache]. Stops at the first failing register.
+    fn write_registers(&self, regs: &[(regs::Reg, u64)]) -> Result<(), Error>;
+
+    /// Returns the general purpose registers of a vCPU.
+    fn get_regs(&self) -> Result<regs::GeneralRegs, Error>;
+
+    /// Sets the general purpose registers of a vCPU.
+    fn set_regs(&self, regs: &regs::GeneralRegs) -> Result<(), Error>;
+
     /// Returns the current value of a vCPU SIMD & FP register.
     fn get_simd_fp_reg(&self, reg: regs::SimdFpReg) -> Result<regs::SimdFpUchar16, Error>;
 
@@ -108,13 +148,20 @@ pub trait VcpuExt {
     /// Sets the VTimer offset.
     fn set_vtimer_offset(&self, vtimer_offset: u64) -> Result<(), Error>;
 
-    /// Returns the underlying `hv_vcpu_exit_t` structure.
+    /// Returns the underlying `hv_vcpu_exit_t` structure of the last exit, zeroed before the
+    /// first run.
+    ///
+    /// [Vcpu::run](crate::Vcpu::run) returns the decoded [Exit], this is an escape hatch for
+    /// fields it doesn't cover.
     fn exit_info(&self) -> VcpuExit;
 }
 
 impl VcpuExt for Vcpu {
     /// Returns the current value of a vCPU register.
     fn get_reg(&self, reg: regs::Reg) -> Result<u64, Error> {
+        if let Some(value) = self.cached(Field::Reg(reg)) {
+            return Ok(value);
+        }
         let mut out = 0_u64;
         call!(sys::hv_vcpu_get_reg(self.id, reg as _, &mut out))?;
         Ok(out)
@@ -122,9 +169,47 @@ impl VcpuExt for Vcpu {
 
     /// Sets the value of a vCPU register.
     fn set_reg(&self, reg: regs::Reg, value: u64) -> Result<(), Error> {
+        if self.cache_write(Field::Reg(reg), value) {
+            return Ok(());
+        }
         call!(sys::hv_vcpu_set_reg(self.id, reg as _, value))
     }
 
+    /// Returns the values of several vCPU registers, in order.
+    fn read_registers(&self, regs: &[regs::Reg]) -> Result<Vec<u64>, Error> {
+        regs.iter().map(|reg| self.get_reg(*reg)).collect()
+    }
+
+    /// Sets the values of several vCPU registers, in order.
+    fn write_registers(&self, regs: &[(regs::Reg, u64)]) -> Result<(), Error> {
+        regs.iter()
+            .try_for_each(|(reg, value)| self.set_reg(*reg, *value))
+    }
+
+    /// Returns the general purpose registers of a vCPU.
+    fn get_regs(&self) -> Result<regs::GeneralRegs, Error> {
+        let mut out = regs::GeneralRegs::default();
+        for (x, reg) in out.x.iter_mut().zip(regs::X_REGS.iter()) {
+            *x = self.get_reg(*reg)?;
+        }
+        out.sp = self.get_sys_reg(regs::SysReg::SP_EL0)?;
+        out.sp_el1 = self.get_sys_reg(regs::SysReg::SP_EL1)?;
+        out.pc = self.get_reg(regs::Reg::PC)?;
+        out.cpsr = self.get_reg(regs::Reg::CPSR)?;
+        Ok(out)
+    }
+
+    /// Sets the general purpose registers of a vCPU.
+    fn set_regs(&self, regs: &regs::GeneralRegs) -> Result<(), Error> {
+        for (x, reg) in regs.x.iter().zip(regs::X_REGS.iter()) {
+            self.set_reg(*reg, *x)?;
+        }
+        self.set_sys_reg(regs::SysReg::SP_EL0, regs.sp)?;
+        self.set_sys_reg(regs::SysReg::SP_EL1, regs.sp_el1)?;
+        self.set_reg(regs::Reg::PC, regs.pc)?;
+        self.set_reg(regs::Reg::CPSR, regs.cpsr)
+    }
+
     /// Returns the current value of a vCPU SIMD & FP register.
     fn get_simd_fp_reg(&self, reg: regs::SimdFpReg) -> Result<regs::SimdFpUchar16, Error> {
         let mut out = 0_u128;
@@ -144,6 +229,9 @@ impl VcpuExt for Vcpu {
 
     /// Returns the current value of a vCPU system register.
     fn get_sys_reg(&self, reg: regs::SysReg) -> Result<u64, Error> {
+        if let Some(value) = self.cached(Field::SysReg(reg)) {
+            return Ok(value);
+        }
         let mut out = 0_u64;
         call!(sys::hv_vcpu_get_sys_reg(self.id, reg as _, &mut out))?;
         Ok(out)
@@ -151,6 +239,9 @@ impl VcpuExt for Vcpu {
 
     /// Sets the value of a vCPU system register.
     fn set_sys_reg(&self, reg: regs::SysReg, value: u64) -> Result<(), Error> {
+        if self.cache_write(Field::SysReg(reg), value) {
+            return Ok(());
+        }
         call!(sys::hv_vcpu_set_sys_reg(self.id, reg as _, value))
     }
 
@@ -164,11 +255,9 @@ impl VcpuExt for Vcpu {
     }
 
     /// Sets pending interrupts for a vcpu.
-    fn set_pending_interrupt(&self, ty: InterruptType, mut pending: bool) -> Result<(), Error> {
-        call!(sys::hv_vcpu_get_pending_interrupt(
-            self.id,
-            ty as u32,
-            &mut pending
+    fn set_pending_interrupt(&self, ty: InterruptType, pending: bool) -> Result<(), Error> {
+        call!(sys::hv_vcpu_set_pending_interrupt(
+            self.id, ty as u32, pending
         ))
     }
 
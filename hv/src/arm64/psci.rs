@@ -0,0 +1,189 @@
+//! PSCI 1.1 emulation over `hvc` and `smc` exits.
+//!
+//! [Psci] answers the calls of the guest and reports the power requests the VMM must act on
+//! as [PsciEvent]s. Secondary vCPUs are started through a [VcpuGroup], see [Psci::with_group].
+//!
+//! ```ignore
+//! let psci = Psci::new().with_group(group.clone());
+//! loop {
+//!     let exit = cpu.run()?;
+//!     if let Some(call) = exit.hypercall() {
+//!         match psci.handle(&cpu, call)? {
+//!             Some(PsciEvent::CpuOff) => break,
+//!             Some(PsciEvent::SystemOff) | Some(PsciEvent::SystemReset) => return Ok(()),
+//!             Some(PsciEvent::CpuSuspend { .. }) => wait_for_interrupt(&cpu),
+//!             None => {}
+//!         }
+//!     }
+//! }
+//! ```
+
+use super::{HypercallExit, SysReg, VcpuExt, SMCCC_NOT_SUPPORTED};
+use crate::{Error, Vcpu, VcpuGroup};
+
+pub const PSCI_VERSION: u32 = 0x8400_0000;
+pub const CPU_SUSPEND: u32 = 0x8400_0001;
+pub const CPU_SUSPEND_64: u32 = 0xc400_0001;
+pub const CPU_OFF: u32 = 0x8400_0002;
+pub const CPU_ON: u32 = 0x8400_0003;
+pub const CPU_ON_64: u32 = 0xc400_0003;
+pub const AFFINITY_INFO: u32 = 0x8400_0004;
+pub const AFFINITY_INFO_64: u32 = 0xc400_0004;
+pub const MIGRATE_INFO_TYPE: u32 = 0x8400_0006;
+pub const SYSTEM_OFF: u32 = 0x8400_0008;
+pub const SYSTEM_RESET: u32 = 0x8400_0009;
+pub const PSCI_FEATURES: u32 = 0x8400_000a;
+
+/// Implemented version, 1.1.
+const VERSION: u64 = (1 << 16) | 1;
+
+const SUCCESS: u64 = 0;
+const NOT_SUPPORTED: u64 = SMCCC_NOT_SUPPORTED;
+const INVALID_PARAMETERS: u64 = -2_i64 as u64;
+const ALREADY_ON: u64 = -4_i64 as u64;
+
+/// `AFFINITY_INFO` results.
+const AFFINITY_ON: u64 = 0;
+const AFFINITY_OFF: u64 = 1;
+
+/// `MIGRATE_INFO_TYPE` result: no trusted OS that would need migrating.
+const MIGRATE_NOT_REQUIRED: u64 = 2;
+
+/// Affinity fields of `MPIDR_EL1`, Aff3 and Aff2-Aff0.
+const MPIDR_AFFINITY: u64 = 0xff_00ff_ffff;
+
+/// Power requests of the guest the VMM must act on.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum PsciEvent {
+    /// The calling vCPU is powered off, its thread should stop running it. It may be parked
+    /// again with [VcpuGroup::park] to be restarted by `CPU_ON`.
+    CpuOff,
+    /// The calling vCPU requests a low power state. The call already returned success, the VMM
+    /// may treat it as `wfi`.
+    CpuSuspend {
+        power_state: u32,
+        entry: u64,
+        context: u64,
+    },
+    /// The guest requests the system to be shut down.
+    SystemOff,
+    /// The guest requests the system to be reset.
+    SystemReset,
+}
+
+/// PSCI implementation shared by the vCPUs of a VM.
+///
+/// Secondary vCPUs are identified by the affinity fields of their `MPIDR_EL1`.
+#[derive(Debug, Clone, Default)]
+pub struct Psci {
+    group: Option<VcpuGroup>,
+}
+
+impl Psci {
+    /// Returns an implementation without secondary vCPUs, `CPU_ON` is not supported.
+    pub fn new() -> Psci {
+        Psci::default()
+    }
+
+    /// Starts and stops secondary vCPUs through `group`.
+    pub fn with_group(mut self, group: VcpuGroup) -> Psci {
+        self.group = Some(group);
+        self
+    }
+
+    /// Returns `true` if `function_id` is a PSCI function, i.e. owned by standard secure
+    /// services with a function number below 0x20.
+    pub fn is_psci(function_id: u32) -> bool {
+        function_id & 0x3f00_ffe0 == 0x0400_0000
+    }
+
+    /// Emulates the PSCI call of `vcpu` and completes it, see [HypercallExit::complete].
+    ///
+    /// Unknown functions return `NOT_SUPPORTED`. `CPU_OFF` doesn't return to the guest.
+    pub fn handle(&self, vcpu: &Vcpu, call: HypercallExit) -> Result<Option<PsciEvent>, Error> {
+        let fid = call.function_id(vcpu)?.0;
+        let args = call.args(vcpu)?;
+        // SMC32 arguments only use the low halves.
+        let arg = |i: usize| {
+            if fid & (1 << 30) != 0 {
+                args[i]
+            } else {
+                args[i] & 0xffff_ffff
+            }
+        };
+
+        let (result, event) = match fid {
+            PSCI_VERSION => (VERSION, None),
+            CPU_SUSPEND | CPU_SUSPEND_64 => {
+                let event = PsciEvent::CpuSuspend {
+                    power_state: arg(0) as u32,
+                    entry: arg(1),
+                    context: arg(2),
+                };
+                (SUCCESS, Some(event))
+            }
+            CPU_OFF => {
+                if let Some(group) = &self.group {
+                    group.stop_secondary(affinity(vcpu)?);
+                }
+                return Ok(Some(PsciEvent::CpuOff));
+            }
+            CPU_ON | CPU_ON_64 => (self.cpu_on(vcpu, arg(0), arg(1), arg(2))?, None),
+            AFFINITY_INFO | AFFINITY_INFO_64 => (self.affinity_info(vcpu, arg(0), arg(1))?, None),
+            MIGRATE_INFO_TYPE => (MIGRATE_NOT_REQUIRED, None),
+            SYSTEM_OFF => return Ok(Some(PsciEvent::SystemOff)),
+            SYSTEM_RESET => return Ok(Some(PsciEvent::SystemReset)),
+            PSCI_FEATURES => (self.features(arg(0) as u32), None),
+            _ => (NOT_SUPPORTED, None),
+        };
+        call.complete(vcpu, &[result])?;
+        Ok(event)
+    }
+
+    fn cpu_on(&self, vcpu: &Vcpu, target: u64, entry: u64, context: u64) -> Result<u64, Error> {
+        let group = match &self.group {
+            Some(group) => group,
+            None => return Ok(NOT_SUPPORTED),
+        };
+        let target = target & MPIDR_AFFINITY;
+        if target == affinity(vcpu)? {
+            return Ok(ALREADY_ON);
+        }
+        Ok(match group.start_secondary(target, entry, context) {
+            Ok(()) => SUCCESS,
+            Err(Error::Busy) => ALREADY_ON,
+            Err(_) => INVALID_PARAMETERS,
+        })
+    }
+
+    fn affinity_info(&self, vcpu: &Vcpu, target: u64, level: u64) -> Result<u64, Error> {
+        // Only affinity level 0, single vCPUs, is supported.
+        if level != 0 {
+            return Ok(INVALID_PARAMETERS);
+        }
+        let target = target & MPIDR_AFFINITY;
+        if target == affinity(vcpu)? {
+            return Ok(AFFINITY_ON);
+        }
+        Ok(match &self.group {
+            Some(group) if group.is_started(target) => AFFINITY_ON,
+            Some(group) if group.is_secondary(target) => AFFINITY_OFF,
+            _ => INVALID_PARAMETERS,
+        })
+    }
+
+    fn features(&self, function_id: u32) -> u64 {
+        match function_id {
+            CPU_ON | CPU_ON_64 if self.group.is_none() => NOT_SUPPORTED,
+            PSCI_VERSION | CPU_SUSPEND | CPU_SUSPEND_64 | CPU_OFF | CPU_ON | CPU_ON_64
+            | AFFINITY_INFO | AFFINITY_INFO_64 | MIGRATE_INFO_TYPE | SYSTEM_OFF | SYSTEM_RESET
+            | PSCI_FEATURES => SUCCESS,
+            _ => NOT_SUPPORTED,
+        }
+    }
+}
+
+/// Returns the affinity fields of the `MPIDR_EL1` of `vcpu`.
+fn affinity(vcpu: &Vcpu) -> Result<u64, Error> {
+    Ok(vcpu.get_sys_reg(SysReg::MPIDR_EL1)? & MPIDR_AFFINITY)
+}
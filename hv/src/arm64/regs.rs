@@ -45,6 +45,54 @@ pub enum Reg {
 pub const REG_FP: Reg = Reg::X29;
 pub const REG_LR: Reg = Reg::X30;
 
+/// X0 - X30 in order.
+pub(crate) const X_REGS: [Reg; 31] = [
+    Reg::X0,
+    Reg::X1,
+    Reg::X2,
+    Reg::X3,
+    Reg::X4,
+    Reg::X5,
+    Reg::X6,
+    Reg::X7,
+    Reg::X8,
+    Reg::X9,
+    Reg::X10,
+    Reg::X11,
+    Reg::X12,
+    Reg::X13,
+    Reg::X14,
+    Reg::X15,
+    Reg::X16,
+    Reg::X17,
+    Reg::X18,
+    Reg::X19,
+    Reg::X20,
+    Reg::X21,
+    Reg::X22,
+    Reg::X23,
+    Reg::X24,
+    Reg::X25,
+    Reg::X26,
+    Reg::X27,
+    Reg::X28,
+    Reg::X29,
+    Reg::X30,
+];
+
+/// General purpose registers of a vCPU, laid out like `kvm_regs` (without FP state).
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub struct GeneralRegs {
+    /// X0 - X30.
+    pub x: [u64; 31],
+    /// Stack pointer of EL0 (`SP_EL0`).
+    pub sp: u64,
+    /// Stack pointer of EL1 (`SP_EL1`).
+    pub sp_el1: u64,
+    pub pc: u64,
+    pub cpsr: u64,
+}
+
 pub type SimdFpUchar16 = sys::hv_simd_fp_uchar16_t;
 
 /// Type of an ARM SIMD & FP register.
@@ -203,4 +251,57 @@ pub enum SysReg {
     CNTV_CTL_EL0 = sys::hv_sys_reg_t_HV_SYS_REG_CNTV_CTL_EL0,
     CNTV_CVAL_EL0 = sys::hv_sys_reg_t_HV_SYS_REG_CNTV_CVAL_EL0,
     SP_EL1 = sys::hv_sys_reg_t_HV_SYS_REG_SP_EL1,
+    // EL2 registers, available to VMs with guest EL2 enabled (macOS 15).
+    #[cfg(feature = "hv_15_0")]
+    ACTLR_EL1 = sys::hv_sys_reg_t_HV_SYS_REG_ACTLR_EL1,
+    #[cfg(feature = "hv_15_0")]
+    CNTHCTL_EL2 = sys::hv_sys_reg_t_HV_SYS_REG_CNTHCTL_EL2,
+    #[cfg(feature = "hv_15_0")]
+    CNTHP_CTL_EL2 = sys::hv_sys_reg_t_HV_SYS_REG_CNTHP_CTL_EL2,
+    #[cfg(feature = "hv_15_0")]
+    CNTHP_CVAL_EL2 = sys::hv_sys_reg_t_HV_SYS_REG_CNTHP_CVAL_EL2,
+    #[cfg(feature = "hv_15_0")]
+    CNTHP_TVAL_EL2 = sys::hv_sys_reg_t_HV_SYS_REG_CNTHP_TVAL_EL2,
+    #[cfg(feature = "hv_15_0")]
+    CNTVOFF_EL2 = sys::hv_sys_reg_t_HV_SYS_REG_CNTVOFF_EL2,
+    #[cfg(feature = "hv_15_0")]
+    CPTR_EL2 = sys::hv_sys_reg_t_HV_SYS_REG_CPTR_EL2,
+    #[cfg(feature = "hv_15_0")]
+    ELR_EL2 = sys::hv_sys_reg_t_HV_SYS_REG_ELR_EL2,
+    #[cfg(feature = "hv_15_0")]
+    ESR_EL2 = sys::hv_sys_reg_t_HV_SYS_REG_ESR_EL2,
+    #[cfg(feature = "hv_15_0")]
+    FAR_EL2 = sys::hv_sys_reg_t_HV_SYS_REG_FAR_EL2,
+    #[cfg(feature = "hv_15_0")]
+    HCR_EL2 = sys::hv_sys_reg_t_HV_SYS_REG_HCR_EL2,
+    #[cfg(feature = "hv_15_0")]
+    HPFAR_EL2 = sys::hv_sys_reg_t_HV_SYS_REG_HPFAR_EL2,
+    #[cfg(feature = "hv_15_0")]
+    MAIR_EL2 = sys::hv_sys_reg_t_HV_SYS_REG_MAIR_EL2,
+    #[cfg(feature = "hv_15_0")]
+    MDCR_EL2 = sys::hv_sys_reg_t_HV_SYS_REG_MDCR_EL2,
+    #[cfg(feature = "hv_15_0")]
+    SCTLR_EL2 = sys::hv_sys_reg_t_HV_SYS_REG_SCTLR_EL2,
+    #[cfg(feature = "hv_15_0")]
+    SPSR_EL2 = sys::hv_sys_reg_t_HV_SYS_REG_SPSR_EL2,
+    #[cfg(feature = "hv_15_0")]
+    SP_EL2 = sys::hv_sys_reg_t_HV_SYS_REG_SP_EL2,
+    #[cfg(feature = "hv_15_0")]
+    TCR_EL2 = sys::hv_sys_reg_t_HV_SYS_REG_TCR_EL2,
+    #[cfg(feature = "hv_15_0")]
+    TPIDR_EL2 = sys::hv_sys_reg_t_HV_SYS_REG_TPIDR_EL2,
+    #[cfg(feature = "hv_15_0")]
+    TTBR0_EL2 = sys::hv_sys_reg_t_HV_SYS_REG_TTBR0_EL2,
+    #[cfg(feature = "hv_15_0")]
+    TTBR1_EL2 = sys::hv_sys_reg_t_HV_SYS_REG_TTBR1_EL2,
+    #[cfg(feature = "hv_15_0")]
+    VBAR_EL2 = sys::hv_sys_reg_t_HV_SYS_REG_VBAR_EL2,
+    #[cfg(feature = "hv_15_0")]
+    VMPIDR_EL2 = sys::hv_sys_reg_t_HV_SYS_REG_VMPIDR_EL2,
+    #[cfg(feature = "hv_15_0")]
+    VPIDR_EL2 = sys::hv_sys_reg_t_HV_SYS_REG_VPIDR_EL2,
+    #[cfg(feature = "hv_15_0")]
+    VTCR_EL2 = sys::hv_sys_reg_t_HV_SYS_REG_VTCR_EL2,
+    #[cfg(feature = "hv_15_0")]
+    VTTBR_EL2 = sys::hv_sys_reg_t_HV_SYS_REG_VTTBR_EL2,
 }
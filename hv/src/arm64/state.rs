@@ -0,0 +1,174 @@
+use crate::arm64::{GeneralRegs, InterruptType, Reg, SimdFpReg, SysReg, VcpuExt};
+use crate::{Error, Vcpu};
+
+/// FP/SIMD registers in order.
+const SIMD_FP_REGS: [SimdFpReg; 32] = [
+    SimdFpReg::Q0,
+    SimdFpReg::Q1,
+    SimdFpReg::Q2,
+    SimdFpReg::Q3,
+    SimdFpReg::Q4,
+    SimdFpReg::Q5,
+    SimdFpReg::Q6,
+    SimdFpReg::Q7,
+    SimdFpReg::Q8,
+    SimdFpReg::Q9,
+    SimdFpReg::Q10,
+    SimdFpReg::Q11,
+    SimdFpReg::Q12,
+    SimdFpReg::Q13,
+    SimdFpReg::Q14,
+    SimdFpReg::Q15,
+    SimdFpReg::Q16,
+    SimdFpReg::Q17,
+    SimdFpReg::Q18,
+    SimdFpReg::Q19,
+    SimdFpReg::Q20,
+    SimdFpReg::Q21,
+    SimdFpReg::Q22,
+    SimdFpReg::Q23,
+    SimdFpReg::Q24,
+    SimdFpReg::Q25,
+    SimdFpReg::Q26,
+    SimdFpReg::Q27,
+    SimdFpReg::Q28,
+    SimdFpReg::Q29,
+    SimdFpReg::Q30,
+    SimdFpReg::Q31,
+];
+
+/// System registers besides `SP_EL0` and `SP_EL1`, ID registers are not saved.
+const SYS_REGS: &[SysReg] = &[
+    SysReg::MPIDR_EL1,
+    SysReg::SCTLR_EL1,
+    SysReg::CPACR_EL1,
+    SysReg::TTBR0_EL1,
+    SysReg::TTBR1_EL1,
+    SysReg::TCR_EL1,
+    SysReg::SPSR_EL1,
+    SysReg::ELR_EL1,
+    SysReg::AFSR0_EL1,
+    SysReg::AFSR1_EL1,
+    SysReg::ESR_EL1,
+    SysReg::FAR_EL1,
+    SysReg::PAR_EL1,
+    SysReg::MAIR_EL1,
+    SysReg::AMAIR_EL1,
+    SysReg::VBAR_EL1,
+    SysReg::CONTEXTIDR_EL1,
+    SysReg::TPIDR_EL1,
+    SysReg::TPIDR_EL0,
+    SysReg::TPIDRRO_EL0,
+    SysReg::CNTKCTL_EL1,
+    SysReg::CSSELR_EL1,
+    SysReg::CNTV_CTL_EL0,
+    SysReg::CNTV_CVAL_EL0,
+    SysReg::APIAKEYLO_EL1,
+    SysReg::APIAKEYHI_EL1,
+    SysReg::APIBKEYLO_EL1,
+    SysReg::APIBKEYHI_EL1,
+    SysReg::APDAKEYLO_EL1,
+    SysReg::APDAKEYHI_EL1,
+    SysReg::APDBKEYLO_EL1,
+    SysReg::APDBKEYHI_EL1,
+    SysReg::APGAKEYLO_EL1,
+    SysReg::APGAKEYHI_EL1,
+    SysReg::MDSCR_EL1,
+    SysReg::MDCCINT_EL1,
+    SysReg::DBGBVR0_EL1,
+    SysReg::DBGBCR0_EL1,
+    SysReg::DBGWVR0_EL1,
+    SysReg::DBGWCR0_EL1,
+    SysReg::DBGBVR1_EL1,
+    SysReg::DBGBCR1_EL1,
+    SysReg::DBGWVR1_EL1,
+    SysReg::DBGWCR1_EL1,
+    SysReg::DBGBVR2_EL1,
+    SysReg::DBGBCR2_EL1,
+    SysReg::DBGWVR2_EL1,
+    SysReg::DBGWCR2_EL1,
+    SysReg::DBGBVR3_EL1,
+    SysReg::DBGBCR3_EL1,
+    SysReg::DBGWVR3_EL1,
+    SysReg::DBGWCR3_EL1,
+];
+
+/// Architectural state of a vCPU, see [Vcpu::save_state].
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct VcpuState {
+    pub regs: GeneralRegs,
+    pub fpcr: u64,
+    pub fpsr: u64,
+    /// Q0 - Q31.
+    pub simd_fp: [u128; 32],
+    /// EL0/EL1 system registers, including the virtual timer.
+    pub sys_regs: Vec<(SysReg, u64)>,
+    pub vtimer_offset: u64,
+    pub vtimer_mask: bool,
+    pub pending_irq: bool,
+    pub pending_fiq: bool,
+}
+
+impl VcpuState {
+    /// Drops the registers identifying the CPU, `MPIDR_EL1`, so restoring the state into
+    /// another vCPU keeps its identity, e.g. for CPU hotplug.
+    pub fn without_identity(mut self) -> VcpuState {
+        self.sys_regs.retain(|(reg, _)| *reg != SysReg::MPIDR_EL1);
+        self
+    }
+}
+
+/// Size of the FP/SIMD state returned by [Vcpu::fpstate]: Q0 - Q31 followed by `FPCR` and
+/// `FPSR`, all little endian.
+pub(crate) const FPSTATE_SIZE: usize = 32 * 16 + 2 * 8;
+
+pub(crate) fn fpstate(cpu: &Vcpu) -> Result<Vec<u8>, Error> {
+    let mut out = Vec::with_capacity(FPSTATE_SIZE);
+    for reg in SIMD_FP_REGS.iter() {
+        out.extend_from_slice(&cpu.get_simd_fp_reg(*reg)?.to_le_bytes());
+    }
+    out.extend_from_slice(&cpu.get_reg(Reg::FPCR)?.to_le_bytes());
+    out.extend_from_slice(&cpu.get_reg(Reg::FPSR)?.to_le_bytes());
+    Ok(out)
+}
+
+pub(crate) fn save(cpu: &Vcpu) -> Result<VcpuState, Error> {
+    let mut simd_fp = [0_u128; 32];
+    for (value, reg) in simd_fp.iter_mut().zip(SIMD_FP_REGS.iter()) {
+        *value = cpu.get_simd_fp_reg(*reg)?;
+    }
+
+    let sys_regs = SYS_REGS
+        .iter()
+        .map(|reg| Ok((*reg, cpu.get_sys_reg(*reg)?)))
+        .collect::<Result<_, Error>>()?;
+
+    Ok(VcpuState {
+        regs: cpu.get_regs()?,
+        fpcr: cpu.get_reg(Reg::FPCR)?,
+        fpsr: cpu.get_reg(Reg::FPSR)?,
+        simd_fp,
+        sys_regs,
+        vtimer_offset: cpu.vtimer_offset()?,
+        vtimer_mask: cpu.vtimer_mask()?,
+        pending_irq: cpu.pending_interrupt(InterruptType::IRQ)?,
+        pending_fiq: cpu.pending_interrupt(InterruptType::FIQ)?,
+    })
+}
+
+pub(crate) fn restore(cpu: &Vcpu, state: &VcpuState) -> Result<(), Error> {
+    for (reg, value) in SIMD_FP_REGS.iter().zip(state.simd_fp.iter()) {
+        cpu.set_simd_fp_reg(*reg, *value)?;
+    }
+    for (reg, value) in &state.sys_regs {
+        cpu.set_sys_reg(*reg, *value)?;
+    }
+
+    cpu.set_reg(Reg::FPCR, state.fpcr)?;
+    cpu.set_reg(Reg::FPSR, state.fpsr)?;
+    cpu.set_vtimer_offset(state.vtimer_offset)?;
+    cpu.set_vtimer_mask(state.vtimer_mask)?;
+    cpu.set_pending_interrupt(InterruptType::IRQ, state.pending_irq)?;
+    cpu.set_pending_interrupt(InterruptType::FIQ, state.pending_fiq)?;
+    cpu.set_regs(&state.regs)
+}
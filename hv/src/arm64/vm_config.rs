@@ -0,0 +1,124 @@
+use crate::sys;
+#[cfg(feature = "hv_13_0")]
+use crate::{call, Error};
+
+extern "C" {
+    fn os_release(object: *mut std::ffi::c_void);
+}
+
+/// VM creation options for [Vm::new](crate::Vm::new), wrapping `hv_vm_config_t`.
+///
+/// [VmConfig::default] uses the framework defaults without creating a configuration object.
+/// Configuring the VM requires macOS 13 and the `hv_13_0` feature.
+///
+/// ```ignore
+/// let mut config = VmConfig::new()?;
+/// config.set_ipa_size(VmConfig::max_ipa_size()?)?;
+/// if VmConfig::el2_supported()? {
+///     config.set_el2_enabled(true)?;
+/// }
+/// let vm = Arc::new(Vm::new(config)?);
+/// ```
+#[derive(Debug)]
+pub struct VmConfig(sys::hv_vm_config_t);
+
+impl Default for VmConfig {
+    fn default() -> Self {
+        VmConfig(std::ptr::null_mut())
+    }
+}
+
+impl VmConfig {
+    /// Creates a configuration with default settings.
+    #[cfg(feature = "hv_13_0")]
+    pub fn new() -> Result<VmConfig, Error> {
+        let config = unsafe { sys::hv_vm_config_create() };
+        if config.is_null() {
+            return Err(Error::NoResources);
+        }
+        Ok(VmConfig(config))
+    }
+
+    /// Sets the size of the guest physical address space in bits, see
+    /// [VmConfig::max_ipa_size].
+    #[cfg(feature = "hv_13_0")]
+    pub fn set_ipa_size(&mut self, bits: u32) -> Result<(), Error> {
+        call!(sys::hv_vm_config_set_ipa_size(self.object()?, bits))
+    }
+
+    /// Returns the configured size of the guest physical address space in bits.
+    #[cfg(feature = "hv_13_0")]
+    pub fn ipa_size(&self) -> Result<u32, Error> {
+        if self.0.is_null() {
+            return VmConfig::default_ipa_size();
+        }
+        let mut out = 0;
+        call!(sys::hv_vm_config_get_ipa_size(self.0, &mut out))?;
+        Ok(out)
+    }
+
+    /// Enables guest EL2, i.e. nested virtualization, see [VmConfig::el2_supported].
+    ///
+    /// vCPUs of the VM start at EL2 and have the EL2 system registers, e.g.
+    /// [SysReg::HCR_EL2](super::SysReg::HCR_EL2), so a hypervisor like KVM can run in the guest.
+    #[cfg(feature = "hv_15_0")]
+    pub fn set_el2_enabled(&mut self, enabled: bool) -> Result<(), Error> {
+        call!(sys::hv_vm_config_set_el2_enabled(self.object()?, enabled))
+    }
+
+    /// Returns `true` if guest EL2 is enabled.
+    #[cfg(feature = "hv_15_0")]
+    pub fn el2_enabled(&self) -> Result<bool, Error> {
+        if self.0.is_null() {
+            return Ok(false);
+        }
+        let mut out = false;
+        call!(sys::hv_vm_config_get_el2_enabled(self.0, &mut out))?;
+        Ok(out)
+    }
+
+    /// Returns the maximum guest physical address space size in bits the host supports.
+    #[cfg(feature = "hv_13_0")]
+    pub fn max_ipa_size() -> Result<u32, Error> {
+        let mut out = 0;
+        call!(sys::hv_vm_config_get_max_ipa_size(&mut out))?;
+        Ok(out)
+    }
+
+    /// Returns the default guest physical address space size in bits.
+    #[cfg(feature = "hv_13_0")]
+    pub fn default_ipa_size() -> Result<u32, Error> {
+        let mut out = 0;
+        call!(sys::hv_vm_config_get_default_ipa_size(&mut out))?;
+        Ok(out)
+    }
+
+    /// Returns `true` if the host supports guest EL2.
+    #[cfg(feature = "hv_15_0")]
+    pub fn el2_supported() -> Result<bool, Error> {
+        let mut out = false;
+        call!(sys::hv_vm_config_get_el2_supported(&mut out))?;
+        Ok(out)
+    }
+
+    /// Returns the configuration object, creating it for a default configuration.
+    #[cfg(feature = "hv_13_0")]
+    fn object(&mut self) -> Result<sys::hv_vm_config_t, Error> {
+        if self.0.is_null() {
+            *self = VmConfig::new()?;
+        }
+        Ok(self.0)
+    }
+
+    pub(crate) fn raw(&self) -> sys::hv_vm_config_t {
+        self.0
+    }
+}
+
+impl Drop for VmConfig {
+    fn drop(&mut self) {
+        if !self.0.is_null() {
+            unsafe { os_release(self.0 as *mut _) };
+        }
+    }
+}
@@ -0,0 +1,347 @@
+//! Host endpoints for guest consoles.
+//!
+//! Available with the `console` feature.
+//!
+//! Console device models (UART, virtio-console, [pv](crate::pv) devices) shouldn't hardcode
+//! stdout. They talk to a [ConsoleBackend] instead, so the host side can be swapped between:
+//! * [StdioBackend] - the terminal of the VMM process, in raw mode with a `Ctrl-]` escape.
+//! * [UnixSocketBackend] - a Unix domain socket a single client can attach to.
+//! * [PtyBackend] - a pseudo terminal, e.g. for `screen` or `minicom`.
+//!
+//! All backends are non-blocking, so they can be polled from the VMM event loop using
+//! [ConsoleBackend::as_raw_fd].
+
+use std::ffi::CStr;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+
+/// `Ctrl-]`, detaches from [StdioBackend].
+pub const ESCAPE: u8 = 0x1d;
+
+/// Result of reading host input.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Input {
+    /// Number of bytes read into the buffer.
+    Data(usize),
+    /// No input is pending.
+    Empty,
+    /// The user requested to leave the console.
+    Escape,
+    /// The host endpoint was closed.
+    Closed,
+}
+
+/// Host endpoint of a guest console.
+pub trait ConsoleBackend: Send {
+    /// Writes guest output to the host.
+    ///
+    /// Output may be dropped if nobody is attached.
+    fn write(&mut self, data: &[u8]) -> io::Result<()>;
+
+    /// Reads pending host input without blocking.
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<Input>;
+
+    /// Returns the descriptor to poll for input, if any.
+    fn as_raw_fd(&self) -> Option<RawFd>;
+}
+
+/// Makes `fd` non-blocking, returns the previous file status flags.
+fn set_nonblocking(fd: RawFd) -> io::Result<libc::c_int> {
+    unsafe {
+        let flags = libc::fcntl(fd, libc::F_GETFL);
+        if flags < 0 || libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK) < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(flags)
+    }
+}
+
+/// Writes all of `data` to `fd`, waiting for the descriptor to become writable if it's
+/// non-blocking.
+fn write_all_fd(fd: RawFd, mut data: &[u8]) -> io::Result<()> {
+    while !data.is_empty() {
+        let n = unsafe { libc::write(fd, data.as_ptr() as *const libc::c_void, data.len()) };
+        if n >= 0 {
+            data = &data[n as usize..];
+            continue;
+        }
+
+        let err = io::Error::last_os_error();
+        match err.kind() {
+            io::ErrorKind::Interrupted => {}
+            io::ErrorKind::WouldBlock => {
+                let mut pfd = libc::pollfd {
+                    fd,
+                    events: libc::POLLOUT,
+                    revents: 0,
+                };
+                if unsafe { libc::poll(&mut pfd, 1, -1) } < 0 {
+                    let err = io::Error::last_os_error();
+                    if err.kind() != io::ErrorKind::Interrupted {
+                        return Err(err);
+                    }
+                }
+            }
+            _ => return Err(err),
+        }
+    }
+    Ok(())
+}
+
+fn read_input(reader: &mut impl Read, buf: &mut [u8]) -> io::Result<Input> {
+    match reader.read(buf) {
+        Ok(0) => Ok(Input::Closed),
+        Ok(n) => Ok(Input::Data(n)),
+        Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(Input::Empty),
+        Err(e) => Err(e),
+    }
+}
+
+/// Console on the standard input and output of the VMM process.
+///
+/// When stdin is a terminal it's switched to raw mode, so keys like `Ctrl-C` reach the guest.
+/// Pressing `Ctrl-]` yields [Input::Escape]. The original terminal mode and file status flags
+/// are restored on drop.
+///
+/// Input following an escape is kept and returned by the next reads, which don't wait for
+/// stdin to become readable.
+pub struct StdioBackend {
+    termios: Option<libc::termios>,
+    /// File status flags of stdin before it was made non-blocking.
+    flags: libc::c_int,
+    /// Input read after an escape, starting with the escape byte.
+    pending: Vec<u8>,
+}
+
+impl StdioBackend {
+    pub fn new() -> io::Result<StdioBackend> {
+        let fd = libc::STDIN_FILENO;
+        let mut termios = None;
+
+        if unsafe { libc::isatty(fd) } == 1 {
+            let mut original: libc::termios = unsafe { std::mem::zeroed() };
+            if unsafe { libc::tcgetattr(fd, &mut original) } < 0 {
+                return Err(io::Error::last_os_error());
+            }
+
+            let mut raw = original;
+            unsafe { libc::cfmakeraw(&mut raw) };
+            if unsafe { libc::tcsetattr(fd, libc::TCSANOW, &raw) } < 0 {
+                return Err(io::Error::last_os_error());
+            }
+
+            termios = Some(original);
+        }
+
+        let flags = set_nonblocking(fd)?;
+        Ok(StdioBackend {
+            termios,
+            flags,
+            pending: Vec::new(),
+        })
+    }
+}
+
+impl ConsoleBackend for StdioBackend {
+    fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        // On a terminal stdout shares the now non-blocking file description of stdin.
+        io::stdout().flush()?;
+        write_all_fd(libc::STDOUT_FILENO, data)
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<Input> {
+        if self.pending.is_empty() {
+            let n = match read_input(&mut io::stdin(), buf)? {
+                Input::Data(n) => n,
+                other => return Ok(other),
+            };
+            let pos = match buf[..n].iter().position(|b| *b == ESCAPE) {
+                Some(pos) => pos,
+                None => return Ok(Input::Data(n)),
+            };
+            self.pending.extend_from_slice(&buf[pos..n]);
+            if pos > 0 {
+                // Deliver input preceding the escape first.
+                return Ok(Input::Data(pos));
+            }
+        }
+
+        if self.pending[0] == ESCAPE {
+            self.pending.remove(0);
+            return Ok(Input::Escape);
+        }
+
+        let len = self
+            .pending
+            .iter()
+            .position(|b| *b == ESCAPE)
+            .unwrap_or_else(|| self.pending.len())
+            .min(buf.len());
+        buf[..len].copy_from_slice(&self.pending[..len]);
+        self.pending.drain(..len);
+        Ok(Input::Data(len))
+    }
+
+    fn as_raw_fd(&self) -> Option<RawFd> {
+        Some(libc::STDIN_FILENO)
+    }
+}
+
+impl Drop for StdioBackend {
+    fn drop(&mut self) {
+        unsafe { libc::fcntl(libc::STDIN_FILENO, libc::F_SETFL, self.flags) };
+        if let Some(termios) = self.termios {
+            unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &termios) };
+        }
+    }
+}
+
+/// Console exposed on a Unix domain socket.
+///
+/// A single client is served at a time, guest output is dropped while nobody is attached.
+pub struct UnixSocketBackend {
+    listener: UnixListener,
+    path: PathBuf,
+    client: Option<UnixStream>,
+}
+
+impl UnixSocketBackend {
+    /// Listens on `path`, replacing a stale socket file.
+    pub fn bind(path: impl AsRef<Path>) -> io::Result<UnixSocketBackend> {
+        let path = path.as_ref().to_path_buf();
+        let _ = std::fs::remove_file(&path);
+
+        let listener = UnixListener::bind(&path)?;
+        listener.set_nonblocking(true)?;
+
+        Ok(UnixSocketBackend {
+            listener,
+            path,
+            client: None,
+        })
+    }
+
+    /// Returns the path of the socket.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Returns `true` if a client is attached.
+    pub fn is_attached(&self) -> bool {
+        self.client.is_some()
+    }
+
+    fn accept(&mut self) -> io::Result<()> {
+        if self.client.is_none() {
+            match self.listener.accept() {
+                Ok((stream, _)) => {
+                    stream.set_nonblocking(true)?;
+                    self.client = Some(stream);
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+}
+
+impl ConsoleBackend for UnixSocketBackend {
+    fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        self.accept()?;
+        if let Some(client) = &self.client {
+            // Waits for a slow client rather than truncating the stream, only real errors
+            // like a closed socket disconnect it.
+            if write_all_fd(client.as_raw_fd(), data).is_err() {
+                self.client = None;
+            }
+        }
+        Ok(())
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<Input> {
+        self.accept()?;
+        let client = match &mut self.client {
+            Some(client) => client,
+            None => return Ok(Input::Empty),
+        };
+
+        match read_input(client, buf)? {
+            // The client went away, wait for the next one.
+            Input::Closed => {
+                self.client = None;
+                Ok(Input::Empty)
+            }
+            other => Ok(other),
+        }
+    }
+
+    fn as_raw_fd(&self) -> Option<RawFd> {
+        match &self.client {
+            Some(client) => Some(client.as_raw_fd()),
+            None => Some(self.listener.as_raw_fd()),
+        }
+    }
+}
+
+impl Drop for UnixSocketBackend {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// Console on a pseudo terminal.
+pub struct PtyBackend {
+    master: File,
+    path: PathBuf,
+}
+
+impl PtyBackend {
+    /// Allocates a new pseudo terminal.
+    pub fn open() -> io::Result<PtyBackend> {
+        let fd = unsafe { libc::posix_openpt(libc::O_RDWR | libc::O_NOCTTY) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let master = unsafe { File::from_raw_fd(fd) };
+
+        if unsafe { libc::grantpt(fd) } < 0 || unsafe { libc::unlockpt(fd) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let name = unsafe { libc::ptsname(fd) };
+        if name.is_null() {
+            return Err(io::Error::last_os_error());
+        }
+        let path = PathBuf::from(unsafe { CStr::from_ptr(name) }.to_string_lossy().as_ref());
+
+        set_nonblocking(fd)?;
+        Ok(PtyBackend { master, path })
+    }
+
+    /// Returns the path of the terminal device to attach to (e.g. `/dev/ttys004`).
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl ConsoleBackend for PtyBackend {
+    fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        match self.master.write_all(data) {
+            // Nobody reads the terminal and its buffer is full, drop the output.
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(()),
+            other => other,
+        }
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<Input> {
+        read_input(&mut self.master, buf)
+    }
+
+    fn as_raw_fd(&self) -> Option<RawFd> {
+        Some(self.master.as_raw_fd())
+    }
+}
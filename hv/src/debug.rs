@@ -0,0 +1,356 @@
+//! VM-wide hardware breakpoints and watchpoints.
+//!
+//! Debug registers are per vCPU, while a debugger (e.g. a GDB stub) thinks in terms of the
+//! whole guest. [Breakpoints] keeps a single VM-wide set of hardware breakpoints and
+//! watchpoints on guest linear addresses, applies it to every vCPU that calls
+//! [Breakpoints::sync] (including vCPUs created after the breakpoint was added), and
+//! attributes debug exits to the vCPU and breakpoint that caused them.
+//!
+//! vCPU registers can only be modified from the owning thread, so every vCPU thread is expected
+//! to call [Breakpoints::sync] before `run`, which is cheap when nothing changed.
+//!
+//! Single-stepping a vCPU is provided by [Vcpu::step].
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use crate::{Error, Exit, Vcpu};
+
+/// The type of a vCPU ID.
+type Id = crate::vcpu::Id;
+
+/// Number of hardware breakpoint slots managed.
+pub const SLOTS: usize = 4;
+
+/// Memory accesses that trigger a watchpoint.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum WatchKind {
+    Write,
+    ReadWrite,
+}
+
+/// A hardware breakpoint on a guest linear (virtual) address.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Breakpoint {
+    /// Instruction breakpoint.
+    Exec(u64),
+    /// Data watchpoint of `len` (1, 2, 4 or 8) bytes, `addr` must be aligned to `len`.
+    Watch { addr: u64, len: u8, kind: WatchKind },
+}
+
+impl Breakpoint {
+    /// Returns `true` if `addr` is covered by the breakpoint.
+    pub fn contains(&self, addr: u64) -> bool {
+        match *self {
+            Breakpoint::Exec(a) => a == addr,
+            Breakpoint::Watch { addr: a, len, .. } => addr >= a && addr < a + len as u64,
+        }
+    }
+}
+
+/// A debug exit attributed to a breakpoint.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Hit {
+    /// The vCPU that hit the breakpoint.
+    pub vcpu: Id,
+    /// Slot of the breakpoint.
+    pub slot: usize,
+    pub breakpoint: Breakpoint,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    slots: [Option<Breakpoint>; SLOTS],
+    /// Bumped on every change.
+    generation: u64,
+    /// Generation applied to each vCPU.
+    applied: HashMap<Id, u64>,
+}
+
+/// VM-wide set of hardware breakpoints, shared between vCPU threads.
+#[derive(Debug, Default)]
+pub struct Breakpoints {
+    inner: Mutex<Inner>,
+}
+
+impl Breakpoints {
+    pub fn new() -> Breakpoints {
+        Breakpoints::default()
+    }
+
+    /// Adds a breakpoint and returns its slot.
+    ///
+    /// Returns [Error::NoResources] if all slots are in use.
+    pub fn insert(&self, breakpoint: Breakpoint) -> Result<usize, Error> {
+        if let Breakpoint::Watch { addr, len, .. } = breakpoint {
+            if !matches!(len, 1 | 2 | 4 | 8) || addr % len as u64 != 0 {
+                return Err(Error::BadArgument);
+            }
+        }
+
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(slot) = inner.slots.iter().position(|s| *s == Some(breakpoint)) {
+            return Ok(slot);
+        }
+
+        let slot = inner
+            .slots
+            .iter()
+            .position(Option::is_none)
+            .ok_or(Error::NoResources)?;
+
+        inner.slots[slot] = Some(breakpoint);
+        inner.generation += 1;
+        Ok(slot)
+    }
+
+    /// Removes a breakpoint, returns `false` if it wasn't set.
+    pub fn remove(&self, breakpoint: Breakpoint) -> bool {
+        let mut inner = self.inner.lock().unwrap();
+        match inner.slots.iter().position(|s| *s == Some(breakpoint)) {
+            Some(slot) => {
+                inner.slots[slot] = None;
+                inner.generation += 1;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Removes all breakpoints.
+    pub fn clear(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.slots = Default::default();
+        inner.generation += 1;
+    }
+
+    /// Returns the breakpoints by slot.
+    pub fn slots(&self) -> [Option<Breakpoint>; SLOTS] {
+        self.inner.lock().unwrap().slots
+    }
+
+    /// Programs the current breakpoints into the debug registers of `vcpu` if they changed
+    /// since the last sync of this vCPU.
+    ///
+    /// Must be called from the vCPU thread.
+    pub fn sync(&self, vcpu: &Vcpu) -> Result<(), Error> {
+        let (slots, generation) = {
+            let inner = self.inner.lock().unwrap();
+            if inner.applied.get(&vcpu.id()) == Some(&inner.generation) {
+                return Ok(());
+            }
+            (inner.slots, inner.generation)
+        };
+
+        arch::apply(vcpu, &slots)?;
+
+        let mut inner = self.inner.lock().unwrap();
+        inner.applied.insert(vcpu.id(), generation);
+        Ok(())
+    }
+
+    /// Forgets the state of a destroyed vCPU.
+    pub fn detach(&self, vcpu: &Vcpu) {
+        self.inner.lock().unwrap().applied.remove(&vcpu.id());
+    }
+
+    /// Attributes `exit` of `vcpu` to a breakpoint.
+    ///
+    /// Returns `None` if the exit is not a debug exit caused by one of the breakpoints.
+    pub fn hit(&self, vcpu: &Vcpu, exit: &Exit) -> Result<Option<Hit>, Error> {
+        let slots = self.slots();
+        let found = arch::hit(vcpu, exit, &slots)?;
+
+        Ok(found.and_then(|slot| {
+            slots[slot].map(|breakpoint| Hit {
+                vcpu: vcpu.id(),
+                slot,
+                breakpoint,
+            })
+        }))
+    }
+}
+
+/// Executes a single guest instruction, see [Vcpu::step].
+pub(crate) fn step(vcpu: &Vcpu) -> Result<Exit, Error> {
+    arch::step(vcpu)
+}
+
+#[cfg(target_arch = "x86_64")]
+mod arch {
+    use super::*;
+    use crate::x86::debug::{self, BreakKind, BreakLen, DebugStatus, HwBreakpoint};
+    use crate::x86::vmx::{VCpuVmxExt, Vmcs};
+    use crate::x86::{VcpuExt, CPU_BASED_MTF};
+
+    pub fn step(vcpu: &Vcpu) -> Result<Exit, Error> {
+        let enabled = vcpu.read_vmcs(Vmcs::CTRL_CPU_BASED)? & CPU_BASED_MTF != 0;
+        vcpu.enable_mtf(true)?;
+
+        // Host interrupts may exit before the instruction is executed.
+        let result = loop {
+            match vcpu.run() {
+                Ok(Exit::Irq) => continue,
+                other => break other,
+            }
+        };
+
+        if !enabled {
+            vcpu.enable_mtf(false)?;
+        }
+        result
+    }
+
+    pub fn apply(vcpu: &Vcpu, slots: &[Option<Breakpoint>; SLOTS]) -> Result<(), Error> {
+        let mut regs = debug::Breakpoints::new();
+        for (i, slot) in slots.iter().enumerate() {
+            let bp = match *slot {
+                Some(Breakpoint::Exec(addr)) => HwBreakpoint::exec(addr),
+                Some(Breakpoint::Watch { addr, len, kind }) => {
+                    let kind = match kind {
+                        WatchKind::Write => BreakKind::Write,
+                        WatchKind::ReadWrite => BreakKind::ReadWrite,
+                    };
+                    let len = BreakLen::from_bytes(len).ok_or(Error::BadArgument)?;
+                    HwBreakpoint::new(addr, kind, len)
+                }
+                None => continue,
+            };
+            regs.set(i, bp)?;
+        }
+        regs.write_registers(vcpu)?;
+
+        // Intercept #DB while any breakpoint is set.
+        let any = slots.iter().any(Option::is_some);
+        debug::trap_exception(vcpu, debug::VECTOR_DB, any)
+    }
+
+    pub fn hit(
+        vcpu: &Vcpu,
+        exit: &Exit,
+        _slots: &[Option<Breakpoint>; SLOTS],
+    ) -> Result<Option<usize>, Error> {
+        let status = DebugStatus::from_exit(vcpu, exit)?;
+        Ok(status.and_then(|status| status.slots().next()))
+    }
+}
+
+#[cfg(target_arch = "aarch64")]
+mod arch {
+    use super::*;
+    use crate::arm64::{Esr, ExceptionClass, Reg, SysReg, VcpuExt};
+
+    const BVR: [SysReg; SLOTS] = [
+        SysReg::DBGBVR0_EL1,
+        SysReg::DBGBVR1_EL1,
+        SysReg::DBGBVR2_EL1,
+        SysReg::DBGBVR3_EL1,
+    ];
+    const BCR: [SysReg; SLOTS] = [
+        SysReg::DBGBCR0_EL1,
+        SysReg::DBGBCR1_EL1,
+        SysReg::DBGBCR2_EL1,
+        SysReg::DBGBCR3_EL1,
+    ];
+    const WVR: [SysReg; SLOTS] = [
+        SysReg::DBGWVR0_EL1,
+        SysReg::DBGWVR1_EL1,
+        SysReg::DBGWVR2_EL1,
+        SysReg::DBGWVR3_EL1,
+    ];
+    const WCR: [SysReg; SLOTS] = [
+        SysReg::DBGWCR0_EL1,
+        SysReg::DBGWCR1_EL1,
+        SysReg::DBGWCR2_EL1,
+        SysReg::DBGWCR3_EL1,
+    ];
+
+    /// Enable, match at EL1 and EL0.
+    const CTRL_ENABLE: u64 = 1 | (0b11 << 1);
+
+    /// `MDSCR_EL1.MDE` and `MDSCR_EL1.KDE`.
+    const MDSCR_DEBUG: u64 = (1 << 15) | (1 << 13);
+
+    /// `MDSCR_EL1.SS`.
+    const MDSCR_SS: u64 = 1;
+
+    /// `PSTATE.SS`.
+    const CPSR_SS: u64 = 1 << 21;
+
+    pub fn step(vcpu: &Vcpu) -> Result<Exit, Error> {
+        let mdscr = vcpu.get_sys_reg(SysReg::MDSCR_EL1)?;
+        let trap = vcpu.trap_debug_exceptions()?;
+        let cpsr = vcpu.get_reg(Reg::CPSR)?;
+
+        vcpu.set_sys_reg(SysReg::MDSCR_EL1, mdscr | MDSCR_SS)?;
+        vcpu.set_trap_debug_exceptions(true)?;
+        vcpu.set_reg(Reg::CPSR, cpsr | CPSR_SS)?;
+
+        let result = vcpu.run();
+
+        vcpu.set_sys_reg(SysReg::MDSCR_EL1, mdscr)?;
+        vcpu.set_trap_debug_exceptions(trap)?;
+        let cpsr = vcpu.get_reg(Reg::CPSR)?;
+        vcpu.set_reg(Reg::CPSR, cpsr & !CPSR_SS)?;
+        result
+    }
+
+    pub fn apply(vcpu: &Vcpu, slots: &[Option<Breakpoint>; SLOTS]) -> Result<(), Error> {
+        for (i, slot) in slots.iter().enumerate() {
+            let (bvr, bcr, wvr, wcr) = match *slot {
+                Some(Breakpoint::Exec(addr)) => (addr, CTRL_ENABLE | (0xf << 5), 0, 0),
+                Some(Breakpoint::Watch { addr, len, kind }) => {
+                    let lsc = match kind {
+                        WatchKind::Write => 0b10,
+                        WatchKind::ReadWrite => 0b11,
+                    };
+                    let bas = ((1_u64 << len) - 1) << (addr & 7);
+                    (0, 0, addr & !7, CTRL_ENABLE | (lsc << 3) | (bas << 5))
+                }
+                None => (0, 0, 0, 0),
+            };
+
+            vcpu.set_sys_reg(BVR[i], bvr)?;
+            vcpu.set_sys_reg(BCR[i], bcr)?;
+            vcpu.set_sys_reg(WVR[i], wvr)?;
+            vcpu.set_sys_reg(WCR[i], wcr)?;
+        }
+
+        let active = slots.iter().any(Option::is_some);
+        let mdscr = vcpu.get_sys_reg(SysReg::MDSCR_EL1)?;
+        let mdscr = if active {
+            mdscr | MDSCR_DEBUG
+        } else {
+            mdscr & !MDSCR_DEBUG
+        };
+        vcpu.set_sys_reg(SysReg::MDSCR_EL1, mdscr)?;
+
+        vcpu.set_trap_debug_exceptions(active)?;
+        vcpu.set_trap_debug_reg_accesses(active)
+    }
+
+    pub fn hit(
+        vcpu: &Vcpu,
+        exit: &Exit,
+        slots: &[Option<Breakpoint>; SLOTS],
+    ) -> Result<Option<usize>, Error> {
+        let (syndrome, va) = match *exit {
+            Exit::Exception { syndrome, va, .. } => (syndrome, va),
+            _ => return Ok(None),
+        };
+
+        let find = |addr: u64, exec: bool| {
+            slots.iter().position(|s| match s {
+                Some(bp @ Breakpoint::Exec(_)) => exec && bp.contains(addr),
+                Some(bp @ Breakpoint::Watch { .. }) => !exec && bp.contains(addr),
+                None => false,
+            })
+        };
+
+        match Esr::from(syndrome).class() {
+            ExceptionClass::BreakpointLower => Ok(find(vcpu.get_reg(Reg::PC)?, true)),
+            ExceptionClass::WatchpointLower => Ok(find(va, false)),
+            _ => Ok(None),
+        }
+    }
+}
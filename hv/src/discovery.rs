@@ -0,0 +1,199 @@
+//! Guest visible handshake device.
+//!
+//! Guest drivers and agents written against this crate shouldn't need to be built per VMM
+//! configuration. [Discovery] is a tiny read-only register block at a well known MMIO or PIO
+//! location that reports the crate version, the paravirtual features enabled by the VMM and
+//! where the agent channel lives.
+//!
+//! # Register layout
+//!
+//! | Offset | Size | Description                                              |
+//! |--------|------|----------------------------------------------------------|
+//! | 0x00   | 4    | Signature ([SIGNATURE])                                  |
+//! | 0x04   | 4    | Layout version ([LAYOUT_VERSION])                        |
+//! | 0x08   | 2    | Crate major version                                      |
+//! | 0x0a   | 2    | Crate minor version                                      |
+//! | 0x0c   | 4    | Crate patch version                                      |
+//! | 0x10   | 4    | [Features] enabled by the VMM                            |
+//! | 0x14   | 4    | Embedder defined feature bits                            |
+//! | 0x18   | 8    | Agent channel address (guest physical address or port)   |
+//! | 0x20   | 4    | Agent channel kind, see [Channel]                        |
+//! | 0x24   | 4    | Agent channel size in bytes                              |
+//!
+//! Reads of any size up to 8 bytes are supported, writes are ignored. Reads outside the
+//! register block return zeros, so a missing device reads as a bad signature.
+
+use std::convert::TryFrom;
+
+use crate::pv::{self, MmioWindow};
+use crate::{Error, GPAddr};
+
+/// Value of the signature register ("hvdc" in little endian).
+pub const SIGNATURE: u32 = 0x6364_7668;
+
+/// Version of the register layout.
+pub const LAYOUT_VERSION: u32 = 1;
+
+/// Size in bytes of the register block.
+pub const SIZE: u64 = 0x28;
+
+/// Register offsets.
+pub mod regs {
+    pub const SIGNATURE: u64 = 0x00;
+    pub const LAYOUT_VERSION: u64 = 0x04;
+    pub const VERSION_MAJOR_MINOR: u64 = 0x08;
+    pub const VERSION_PATCH: u64 = 0x0c;
+    pub const FEATURES: u64 = 0x10;
+    pub const USER_FEATURES: u64 = 0x14;
+    pub const CHANNEL_ADDR: u64 = 0x18;
+    pub const CHANNEL_ADDR_HI: u64 = 0x1c;
+    pub const CHANNEL_KIND: u64 = 0x20;
+    pub const CHANNEL_SIZE: u64 = 0x24;
+}
+
+bitflags::bitflags! {
+    /// Paravirtual features reported to the guest.
+    pub struct Features: u32 {
+        /// Devices using the [pv](crate::pv) register interface are present.
+        const PV_DEVICES = 1 << 0;
+        /// A paravirtual console is present.
+        const CONSOLE = 1 << 1;
+        /// The VMM adjusts guest clocks after host sleep.
+        const SLEEP_AWARE_CLOCKS = 1 << 2;
+        /// The guest runs under the test [harness](crate::harness) hypercall protocol.
+        const TEST_HARNESS = 1 << 3;
+    }
+}
+
+/// Location of the agent channel.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Channel {
+    /// No agent channel, reported as kind 0.
+    None,
+    /// MMIO window, reported as kind 1.
+    Mmio(MmioWindow),
+    /// I/O port range, reported as kind 2.
+    Pio { port: u16, size: u16 },
+}
+
+impl Channel {
+    /// Returns the kind, address and size registers, `None` if the size doesn't fit.
+    fn registers(&self) -> Option<(u32, u64, u32)> {
+        match self {
+            Channel::None => Some((0, 0, 0)),
+            Channel::Mmio(window) => u32::try_from(window.size)
+                .ok()
+                .map(|size| (1, window.base, size)),
+            Channel::Pio { port, size } => Some((2, *port as u64, *size as u32)),
+        }
+    }
+}
+
+/// Where the guest finds the [Discovery] registers.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Location {
+    Mmio(GPAddr),
+    Pio(u16),
+}
+
+/// Handshake device state.
+///
+/// Call [Discovery::mmio_read] or [Discovery::pio_read] from the VMM exit handler.
+#[derive(Debug, Clone)]
+pub struct Discovery {
+    location: Location,
+    features: Features,
+    user_features: u32,
+    channel: Channel,
+}
+
+impl Discovery {
+    /// Creates a device without features nor agent channel.
+    pub fn new(location: Location) -> Discovery {
+        Discovery {
+            location,
+            features: Features::empty(),
+            user_features: 0,
+            channel: Channel::None,
+        }
+    }
+
+    /// Sets the reported paravirtual features.
+    pub fn features(mut self, features: Features) -> Discovery {
+        self.features = features;
+        self
+    }
+
+    /// Sets embedder defined feature bits.
+    pub fn user_features(mut self, bits: u32) -> Discovery {
+        self.user_features = bits;
+        self
+    }
+
+    /// Sets the reported agent channel.
+    ///
+    /// Fails with [Error::BadArgument] if an MMIO window is larger than the 32-bit size
+    /// register can report.
+    pub fn channel(mut self, channel: Channel) -> Result<Discovery, Error> {
+        if channel.registers().is_none() {
+            return Err(Error::BadArgument);
+        }
+        self.channel = channel;
+        Ok(self)
+    }
+
+    /// Returns where the registers are located.
+    pub fn location(&self) -> Location {
+        self.location
+    }
+
+    /// Handles a guest read of `data.len()` bytes at `gpa`.
+    ///
+    /// Returns `false` if the access doesn't target the device.
+    pub fn mmio_read(&self, gpa: GPAddr, data: &mut [u8]) -> bool {
+        match self.location {
+            Location::Mmio(base) if gpa >= base && gpa - base < SIZE => {
+                self.read(gpa - base, data);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Handles a guest `in` of `data.len()` bytes from `port`.
+    ///
+    /// Returns `false` if the access doesn't target the device.
+    pub fn pio_read(&self, port: u16, data: &mut [u8]) -> bool {
+        match self.location {
+            Location::Pio(base) if port >= base && ((port - base) as u64) < SIZE => {
+                self.read((port - base) as u64, data);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn read(&self, offset: u64, data: &mut [u8]) {
+        let (kind, addr, size) = self.channel.registers().unwrap_or((0, 0, 0));
+        pv::read_registers(offset, data, |reg| match reg {
+            regs::SIGNATURE => SIGNATURE,
+            regs::LAYOUT_VERSION => LAYOUT_VERSION,
+            regs::VERSION_MAJOR_MINOR => {
+                version(env!("CARGO_PKG_VERSION_MAJOR"))
+                    | version(env!("CARGO_PKG_VERSION_MINOR")) << 16
+            }
+            regs::VERSION_PATCH => version(env!("CARGO_PKG_VERSION_PATCH")),
+            regs::FEATURES => self.features.bits(),
+            regs::USER_FEATURES => self.user_features,
+            regs::CHANNEL_ADDR => addr as u32,
+            regs::CHANNEL_ADDR_HI => (addr >> 32) as u32,
+            regs::CHANNEL_KIND => kind,
+            regs::CHANNEL_SIZE => size,
+            _ => 0,
+        });
+    }
+}
+
+fn version(part: &str) -> u32 {
+    part.parse().unwrap_or(0)
+}
@@ -0,0 +1,114 @@
+//! Fault injection for the FFI layer.
+//!
+//! Available with the `fault_injection` feature. Makes selected `hv_*` calls fail with a chosen
+//! error at a chosen call count, so embedders can deterministically test their error paths
+//! (e.g. a failing `hv_vm_map` in the middle of boot or `hv_vcpu_create` failing on the Nth
+//! thread).
+//!
+//! Faulted calls are not forwarded to the framework. Calls are counted per function and
+//! process-wide, starting from the last [reset].
+//!
+//! ```ignore
+//! // Fail the third vCPU creation.
+//! hv::fault::inject("hv_vcpu_create", Fault::nth(3, Error::NoResources));
+//! ```
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use crate::{sys, Error};
+
+/// Describes when and how an injected fault triggers.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Fault {
+    /// 1-based call number to fail, `None` fails every call.
+    nth: Option<usize>,
+    error: Error,
+}
+
+impl Fault {
+    /// Fails the `n`-th call (1-based) of a function.
+    pub fn nth(n: usize, error: Error) -> Fault {
+        Fault {
+            nth: Some(n),
+            error,
+        }
+    }
+
+    /// Fails every call of a function.
+    pub fn always(error: Error) -> Fault {
+        Fault { nth: None, error }
+    }
+}
+
+#[derive(Default)]
+struct Entry {
+    calls: usize,
+    faults: Vec<Fault>,
+}
+
+lazy_static::lazy_static! {
+    static ref STATE: Mutex<HashMap<String, Entry>> = Mutex::new(HashMap::new());
+}
+
+/// Registers a fault for the given framework function (e.g. `"hv_vm_map"`).
+pub fn inject(function: &str, fault: Fault) {
+    let mut state = STATE.lock().unwrap();
+    state
+        .entry(function.to_string())
+        .or_default()
+        .faults
+        .push(fault);
+}
+
+/// Removes all registered faults and resets call counters.
+pub fn reset() {
+    STATE.lock().unwrap().clear();
+}
+
+/// Returns the number of calls of a function since the last [reset].
+pub fn calls(function: &str) -> usize {
+    STATE
+        .lock()
+        .unwrap()
+        .get(function)
+        .map_or(0, |entry| entry.calls)
+}
+
+/// Accounts a call and returns the error code to fail it with, if any.
+///
+/// `expr` is the stringified call expression as seen by the [crate::call] macro.
+#[doc(hidden)]
+pub fn check(expr: &str) -> Option<sys::hv_return_t> {
+    let name = expr
+        .split('(')
+        .next()
+        .and_then(|path| path.rsplit("::").next())
+        .map(str::trim)?;
+
+    let mut state = STATE.lock().unwrap();
+    let entry = state.entry(name.to_string()).or_default();
+    entry.calls += 1;
+
+    let calls = entry.calls;
+    entry
+        .faults
+        .iter()
+        .find(|fault| fault.nth.map_or(true, |n| n == calls))
+        .map(|fault| error_code(fault.error))
+}
+
+/// Maps an [Error] back to the framework error code.
+fn error_code(error: Error) -> sys::hv_return_t {
+    let code: u32 = match error {
+        Error::Unsuccessful => 0xfae94001,
+        Error::Busy | Error::VmExists => 0xfae94002,
+        Error::BadArgument | Error::InvalidMapping { .. } | Error::InvalidXcr0 { .. } => 0xfae94003,
+        Error::NoResources => 0xfae94005,
+        Error::NoDevice => 0xfae94006,
+        Error::Unsupported => 0xfae9400f,
+        Error::Unknown(code) => return code,
+    };
+
+    code as sys::hv_return_t
+}
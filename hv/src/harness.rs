@@ -0,0 +1,366 @@
+//! Runs bare-metal test payloads in throwaway VMs.
+//!
+//! Kernel and firmware code is hard to unit test on the host. [Harness] loads a small
+//! `#[no_std]` guest payload (a flat binary, typically embedded with `include_bytes!`), runs it
+//! in a fresh VM and reports the verdict the payload signals with a hypercall.
+//!
+//! # Environment
+//!
+//! The payload is loaded at [LOAD_GPA] and entered at its first byte, with the stack pointer
+//! at the end of guest memory. All of guest memory is mapped RWX.
+//! * x86: 64-bit long mode as set up by [setup_long_mode](crate::x86::boot::setup_long_mode),
+//!   with the boot structures below [LOAD_GPA], and SSE enabled. There is no IDT, any
+//!   exception ends the test.
+//! * arm64: EL1h with the MMU off and FP/SIMD enabled. Memory accesses are Device memory, so
+//!   payloads must be built with `+strict-align`.
+//!
+//! # Hypercalls
+//!
+//! | Number     | Arguments  | Description                                  |
+//! |------------|------------|----------------------------------------------|
+//! | [HC_PASS]  | -          | The test passed                              |
+//! | [HC_FAIL]  | code       | The test failed with a payload defined code  |
+//! | [HC_LOG]   | gpa, len   | Appends the UTF-8 string at `gpa` to the log |
+//!
+//! On x86 the number is passed in `rax` and arguments in `rdi` and `rsi`, then `vmcall` is
+//! executed. On arm64 `x0` holds the number, `x1` and `x2` the arguments, and `hvc #0` is
+//! executed.
+//!
+//! # cargo test
+//!
+//! Only one VM may exist per process, so tests running on parallel threads wait for each
+//! other. [guest_test](crate::guest_test) declares a `#[test]` for a payload:
+//!
+//! ```ignore
+//! hv::guest_test!(allocator_works, include_bytes!("payloads/allocator.bin"));
+//! ```
+
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::Duration;
+
+use crate::memory::GuestMemory;
+use crate::{Error, Exit, GPAddr, Size, Vcpu, Vm, PAGE_SIZE};
+
+/// Guest physical address the payload is loaded at.
+pub const LOAD_GPA: GPAddr = 0x10000;
+
+/// The test passed.
+pub const HC_PASS: u64 = 0;
+
+/// The test failed, the first argument is a payload defined code.
+pub const HC_FAIL: u64 = 1;
+
+/// Log a message, the arguments are the guest physical address and length of a UTF-8 string.
+pub const HC_LOG: u64 = 2;
+
+/// How long to wait for VMs of other tests to go away.
+const VM_WAIT: Duration = Duration::from_secs(60);
+
+/// Longest message accepted by [HC_LOG].
+const MAX_LOG: u64 = 4096;
+
+/// Verdict of a payload.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Outcome {
+    /// The payload issued [HC_PASS].
+    Passed,
+    /// The payload issued [HC_FAIL] with the given code.
+    Failed(u64),
+    /// The payload exited in an unexpected way, e.g. with an exception.
+    Crashed(Exit),
+    /// The payload did not finish in time.
+    TimedOut,
+}
+
+/// Result of a single payload run.
+#[derive(Debug, Clone)]
+pub struct Report {
+    pub name: String,
+    pub outcome: Outcome,
+    /// Messages logged with [HC_LOG].
+    pub log: Vec<String>,
+}
+
+impl Report {
+    /// Returns `true` if the payload passed.
+    pub fn passed(&self) -> bool {
+        self.outcome == Outcome::Passed
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.outcome {
+            Outcome::Passed => writeln!(f, "{}: ok", self.name)?,
+            Outcome::Failed(code) => writeln!(f, "{}: FAILED (code {:#x})", self.name, code)?,
+            Outcome::Crashed(exit) => writeln!(f, "{}: CRASHED ({:?})", self.name, exit)?,
+            Outcome::TimedOut => writeln!(f, "{}: TIMED OUT", self.name)?,
+        }
+        for line in &self.log {
+            writeln!(f, "  {}", line)?;
+        }
+        Ok(())
+    }
+}
+
+/// Runs test payloads, each in its own VM.
+#[derive(Debug, Copy, Clone)]
+pub struct Harness {
+    memory: Size,
+    timeout: Duration,
+}
+
+impl Default for Harness {
+    fn default() -> Self {
+        Harness {
+            memory: 4 * 1024 * 1024,
+            timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+impl Harness {
+    /// Harness with 4 MiB of guest memory and a 10 seconds timeout.
+    pub fn new() -> Harness {
+        Harness::default()
+    }
+
+    /// Sets the size of guest memory, a multiple of [PAGE_SIZE] of at most 1 GiB.
+    pub fn memory(mut self, size: Size) -> Harness {
+        self.memory = size;
+        self
+    }
+
+    /// Sets how long a payload may run.
+    pub fn timeout(mut self, timeout: Duration) -> Harness {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Runs `payload` on the current thread.
+    ///
+    /// Waits for the VMs of concurrently running tests, returns [Error::VmExists] if a VM
+    /// remains alive for too long.
+    pub fn run(&self, name: &str, payload: &[u8]) -> Result<Report, Error> {
+        if self.memory % PAGE_SIZE != 0
+            || self.memory > arch::MAX_MEMORY
+            || LOAD_GPA + payload.len() as u64 + PAGE_SIZE > self.memory
+        {
+            return Err(Error::BadArgument);
+        }
+
+        let vm = Arc::new(Vm::recreate(arch::options(), VM_WAIT)?);
+        let mut mem = GuestMemory::new(Arc::clone(&vm));
+        mem.add_code_region(0, self.memory)?;
+        mem.write(LOAD_GPA, payload)?;
+
+        let cpu = Arc::clone(&vm).create_cpu()?;
+        arch::setup(&cpu, &mem, self.memory)?;
+
+        let fired = Arc::new(AtomicBool::new(false));
+        let (done, wait) = mpsc::channel::<()>();
+        let watchdog = {
+            let fired = Arc::clone(&fired);
+            let handle = cpu.handle();
+            let timeout = self.timeout;
+            thread::spawn(move || {
+                if wait.recv_timeout(timeout) == Err(mpsc::RecvTimeoutError::Timeout) {
+                    fired.store(true, Ordering::SeqCst);
+                    let _ = handle.kick();
+                }
+            })
+        };
+
+        let result = run_payload(&cpu, &mem, self.memory, &fired);
+        drop(done);
+        let _ = watchdog.join();
+
+        let (outcome, log) = result?;
+        Ok(Report {
+            name: name.to_string(),
+            outcome,
+            log,
+        })
+    }
+
+    /// Runs `payload` and panics with the report unless it passed.
+    pub fn assert_passes(&self, name: &str, payload: &[u8]) {
+        match self.run(name, payload) {
+            Ok(report) if report.passed() => {}
+            Ok(report) => panic!("{}", report),
+            Err(e) => panic!("{}: unable to run the payload: {}", name, e),
+        }
+    }
+}
+
+fn run_payload(
+    cpu: &Vcpu,
+    mem: &GuestMemory,
+    memory: Size,
+    fired: &AtomicBool,
+) -> Result<(Outcome, Vec<String>), Error> {
+    let mut log = Vec::new();
+
+    loop {
+        let exit = cpu.run()?;
+        if fired.load(Ordering::SeqCst) {
+            return Ok((Outcome::TimedOut, log));
+        }
+
+        let (nr, arg0, arg1) = match arch::hypercall(cpu, &exit)? {
+            Some(call) => call,
+            None if arch::is_spurious(&exit, memory) => continue,
+            None => return Ok((Outcome::Crashed(exit), log)),
+        };
+
+        match nr {
+            HC_PASS => return Ok((Outcome::Passed, log)),
+            HC_FAIL => return Ok((Outcome::Failed(arg0), log)),
+            HC_LOG => {
+                let mut buf = vec![0_u8; arg1.min(MAX_LOG) as usize];
+                mem.read(arg0, &mut buf)?;
+                log.push(String::from_utf8_lossy(&buf).into_owned());
+                arch::resume_after_hypercall(cpu)?;
+            }
+            _ => return Ok((Outcome::Crashed(exit), log)),
+        }
+    }
+}
+
+/// Declares a `#[test]` running a bare-metal payload with the default
+/// [Harness](crate::harness::Harness).
+#[macro_export]
+macro_rules! guest_test {
+    ($name:ident, $payload:expr) => {
+        #[test]
+        fn $name() {
+            $crate::harness::Harness::new().assert_passes(stringify!($name), $payload);
+        }
+    };
+}
+
+#[cfg(target_arch = "x86_64")]
+mod arch {
+    use super::*;
+    use crate::x86::boot;
+    use crate::x86::vmx::{self, Capability, VCpuVmxExt, Vmcs};
+    use crate::x86::{Reg, VcpuExt, VmOptions};
+
+    /// Covered by the identity map.
+    pub const MAX_MEMORY: Size = 1 << 30;
+
+    /// Length of `vmcall`.
+    const VMCALL_LEN: u64 = 3;
+
+    const CPU_BASED_HLT: u64 = 1 << 7;
+
+    /// OSFXSR | OSXMMEXCPT, SSE is enabled on top of [boot::setup_long_mode].
+    const CR4_SSE: u64 = 0x600;
+
+    pub fn options() -> VmOptions {
+        VmOptions::default()
+    }
+
+    pub fn setup(cpu: &Vcpu, mem: &GuestMemory, size: Size) -> Result<(), Error> {
+        cpu.write_vmcs(
+            Vmcs::CTRL_PIN_BASED,
+            vmx::adjust_controls(Capability::PinBased, 0)?,
+        )?;
+        cpu.write_vmcs(
+            Vmcs::CTRL_CPU_BASED,
+            vmx::adjust_controls(Capability::ProcBased, CPU_BASED_HLT)?,
+        )?;
+        cpu.write_vmcs(
+            Vmcs::CTRL_CPU_BASED2,
+            vmx::adjust_controls(Capability::ProcBased2, 0)?,
+        )?;
+        cpu.write_vmcs(
+            Vmcs::CTRL_VMENTRY_CONTROLS,
+            vmx::adjust_controls(Capability::Entry, 0)?,
+        )?;
+        cpu.write_vmcs(Vmcs::CTRL_EXC_BITMAP, 0xffff_ffff)?;
+        cpu.write_vmcs(Vmcs::CTRL_CR0_MASK, 0)?;
+        cpu.write_vmcs(Vmcs::CTRL_CR0_SHADOW, 0)?;
+        cpu.write_vmcs(Vmcs::CTRL_CR4_MASK, 0)?;
+        cpu.write_vmcs(Vmcs::CTRL_CR4_SHADOW, 0)?;
+
+        // As if entered with `call`.
+        boot::setup_long_mode(cpu, mem, LOAD_GPA, size - 8)?;
+
+        let cr4 = cpu.read_vmcs(Vmcs::GUEST_CR4)?;
+        cpu.write_vmcs(Vmcs::GUEST_CR4, cr4 | CR4_SSE)
+    }
+
+    pub fn hypercall(cpu: &Vcpu, exit: &Exit) -> Result<Option<(u64, u64, u64)>, Error> {
+        match exit {
+            Exit::Vmcall => {
+                let regs = cpu.read_registers(&[Reg::RAX, Reg::RDI, Reg::RSI])?;
+                Ok(Some((regs[0], regs[1], regs[2])))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    pub fn resume_after_hypercall(cpu: &Vcpu) -> Result<(), Error> {
+        let rip = cpu.read_register(Reg::RIP)?;
+        cpu.write_register(Reg::RIP, rip + VMCALL_LEN)
+    }
+
+    /// Host interrupts and first touches of guest memory below `memory` exit as well, other
+    /// EPT violations are accesses outside guest memory.
+    pub fn is_spurious(exit: &Exit, memory: Size) -> bool {
+        match exit {
+            Exit::Irq => true,
+            Exit::EptViolation(ept) => ept.gpa < memory,
+            _ => false,
+        }
+    }
+}
+
+#[cfg(target_arch = "aarch64")]
+mod arch {
+    use super::*;
+    use crate::arm64::{Reg, SysReg, VcpuExt};
+    use crate::vm::Options;
+
+    pub const MAX_MEMORY: Size = 1 << 30;
+
+    /// EL1h with all interrupts masked.
+    const CPSR_EL1H_MASKED: u64 = 0x3c5;
+
+    /// FPEN, don't trap FP/SIMD at EL0 and EL1.
+    const CPACR_FPEN: u64 = 0b11 << 20;
+
+    pub fn options() -> Options {
+        Options::default()
+    }
+
+    pub fn setup(cpu: &Vcpu, _mem: &GuestMemory, size: Size) -> Result<(), Error> {
+        cpu.set_sys_reg(SysReg::CPACR_EL1, CPACR_FPEN)?;
+        cpu.set_sys_reg(SysReg::SP_EL1, size)?;
+        cpu.set_reg(Reg::CPSR, CPSR_EL1H_MASKED)?;
+        cpu.set_reg(Reg::PC, LOAD_GPA)
+    }
+
+    pub fn hypercall(cpu: &Vcpu, exit: &Exit) -> Result<Option<(u64, u64, u64)>, Error> {
+        match exit {
+            Exit::Hvc { imm: 0 } => {
+                let regs = cpu.read_registers(&[Reg::X0, Reg::X1, Reg::X2])?;
+                Ok(Some((regs[0], regs[1], regs[2])))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// `hvc` exits with the PC past the instruction.
+    pub fn resume_after_hypercall(_cpu: &Vcpu) -> Result<(), Error> {
+        Ok(())
+    }
+
+    pub fn is_spurious(exit: &Exit, _memory: Size) -> bool {
+        matches!(exit, Exit::Canceled)
+    }
+}
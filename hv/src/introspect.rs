@@ -0,0 +1,304 @@
+//! Guest introspection helpers.
+//!
+//! [PageTableWatch] tracks modifications of guest page tables: table pages found by walking
+//! from the root (`CR3` / `TTBRn_EL1`) are write protected, guest writes to them fault into the
+//! VMM, and [PageTableWatch::sync] reports the modified entries as [PageTableEvent]s.
+//!
+//! ```ignore
+//! let mut watch = PageTableWatch::new(Arc::clone(&vm), PagingFormat::X86_64);
+//! watch.watch_root(&mem, cr3)?;
+//!
+//! loop {
+//!     let exit = cpu.run()?;
+//!     if watch.handle_exit(&exit)? {
+//!         for event in watch.sync(&mem)? {
+//!             println!("{:?}", event);
+//!         }
+//!         continue;
+//!     }
+//!     // ...
+//! }
+//! ```
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::Arc;
+
+use crate::memory::GuestMemory;
+use crate::{Error, Exit, GPAddr, MappingError, Memory, Vm, PAGE_SIZE};
+
+/// Size of a guest page table page.
+const TABLE_SIZE: u64 = 0x1000;
+
+/// Number of entries in a page table page.
+const ENTRIES: usize = 512;
+
+/// Guest page table format.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum PagingFormat {
+    /// x86-64 4-level paging.
+    X86_64,
+    /// AArch64 4KB granule, 48-bit input addresses (4 levels, starting at level 0).
+    Aarch64_4K,
+}
+
+impl PagingFormat {
+    /// Returns the address of the next level table referenced by `entry`, if any.
+    ///
+    /// `level` is 0 for the root table.
+    fn next_table(self, entry: u64, level: usize) -> Option<GPAddr> {
+        const ADDR_MASK: u64 = 0x000f_ffff_ffff_f000;
+
+        match self {
+            PagingFormat::X86_64 => {
+                let present = entry & 1 != 0;
+                // PS bit maps 1GB / 2MB pages at the PDPT and PD levels.
+                let large = (level == 1 || level == 2) && entry & (1 << 7) != 0;
+                if present && !large && level < 3 {
+                    Some(entry & ADDR_MASK)
+                } else {
+                    None
+                }
+            }
+            PagingFormat::Aarch64_4K => {
+                let table = entry & 0b11 == 0b11;
+                if table && level < 3 {
+                    Some(entry & 0x0000_ffff_ffff_f000)
+                } else {
+                    None
+                }
+            }
+        }
+    }
+}
+
+/// A modified page table entry.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct PageTableEvent {
+    /// Guest physical address of the table page.
+    pub table: GPAddr,
+    /// Level of the table, 0 is the root.
+    pub level: usize,
+    /// Index of the entry within the table.
+    pub index: usize,
+    pub old: u64,
+    pub new: u64,
+}
+
+#[derive(Debug)]
+struct Table {
+    level: usize,
+    snapshot: Vec<u64>,
+}
+
+/// Watches guest page tables for modifications.
+///
+/// Only the default guest address space is supported, and guest memory must be mapped through
+/// [Vm::map] so the original permissions can be restored.
+#[derive(Debug)]
+pub struct PageTableWatch {
+    vm: Arc<Vm>,
+    format: PagingFormat,
+    tables: BTreeMap<GPAddr, Table>,
+    /// Write protected host pages and their original permissions.
+    protected: BTreeMap<GPAddr, Memory>,
+    /// Host pages made writable after a fault, pending [PageTableWatch::sync].
+    open: BTreeSet<GPAddr>,
+    /// Tables referenced by guest entries that aren't in mapped memory.
+    unreadable: BTreeSet<GPAddr>,
+}
+
+impl PageTableWatch {
+    pub fn new(vm: Arc<Vm>, format: PagingFormat) -> PageTableWatch {
+        PageTableWatch {
+            vm,
+            format,
+            tables: BTreeMap::new(),
+            protected: BTreeMap::new(),
+            open: BTreeSet::new(),
+            unreadable: BTreeSet::new(),
+        }
+    }
+
+    /// Walks the page tables starting at `root` and write protects every table page.
+    ///
+    /// Returns the number of table pages watched.
+    pub fn watch_root(&mut self, mem: &GuestMemory, root: GPAddr) -> Result<usize, Error> {
+        let before = self.tables.len();
+        self.add_table(mem, root & !(TABLE_SIZE - 1), 0)?;
+        Ok(self.tables.len() - before)
+    }
+
+    /// Returns the guest physical addresses of the watched table pages.
+    pub fn tables(&self) -> impl Iterator<Item = GPAddr> + '_ {
+        self.tables.keys().copied()
+    }
+
+    /// Returns the tables referenced by guest entries that can't be watched because they
+    /// aren't in memory mapped through [Vm::map]. Translations through them aren't tracked,
+    /// they're retried when an entry referencing them is written again.
+    pub fn unreadable(&self) -> impl Iterator<Item = GPAddr> + '_ {
+        self.unreadable.iter().copied()
+    }
+
+    /// Handles a write fault on a watched page by making it writable again.
+    ///
+    /// Returns `true` if the exit was caused by the watch and the vCPU can be resumed, the
+    /// write is reported by the next [PageTableWatch::sync].
+    pub fn handle_exit(&mut self, exit: &Exit) -> Result<bool, Error> {
+        let gpa = match write_fault(exit) {
+            Some(gpa) => gpa & !(PAGE_SIZE - 1),
+            None => return Ok(false),
+        };
+
+        let flags = match self.protected.get(&gpa) {
+            Some(flags) => *flags,
+            None => return Ok(false),
+        };
+
+        self.vm.protect(gpa, PAGE_SIZE, flags)?;
+        self.open.insert(gpa);
+        Ok(true)
+    }
+
+    /// Reports entries modified since the last call and write protects the tables again.
+    ///
+    /// Tables referenced by new entries are watched as well, those the guest placed outside
+    /// mapped memory are skipped and reported by [PageTableWatch::unreadable].
+    pub fn sync(&mut self, mem: &GuestMemory) -> Result<Vec<PageTableEvent>, Error> {
+        let mut events = Vec::new();
+        let mut new_tables = Vec::new();
+
+        for page in std::mem::take(&mut self.open) {
+            for (addr, table) in self.tables.range_mut(page..page + PAGE_SIZE) {
+                let current = read_table(mem, *addr)?;
+                for (index, (old, new)) in table.snapshot.iter().zip(&current).enumerate() {
+                    if old == new {
+                        continue;
+                    }
+
+                    events.push(PageTableEvent {
+                        table: *addr,
+                        level: table.level,
+                        index,
+                        old: *old,
+                        new: *new,
+                    });
+
+                    if let Some(next) = self.format.next_table(*new, table.level) {
+                        new_tables.push((next, table.level + 1));
+                    }
+                }
+                table.snapshot = current;
+            }
+
+            self.protect_page(page)?;
+        }
+
+        for (addr, level) in new_tables {
+            self.add_child(mem, addr, level)?;
+        }
+
+        Ok(events)
+    }
+
+    /// Stops watching and restores original permissions.
+    pub fn clear(&mut self) -> Result<(), Error> {
+        for (page, flags) in std::mem::take(&mut self.protected) {
+            if !self.open.contains(&page) {
+                self.vm.protect(page, PAGE_SIZE, flags)?;
+            }
+        }
+        self.open.clear();
+        self.tables.clear();
+        self.unreadable.clear();
+        Ok(())
+    }
+
+    fn add_table(&mut self, mem: &GuestMemory, addr: GPAddr, level: usize) -> Result<(), Error> {
+        if self.tables.contains_key(&addr) {
+            return Ok(());
+        }
+
+        let snapshot = read_table(mem, addr)?;
+        let children: Vec<GPAddr> = snapshot
+            .iter()
+            .filter_map(|e| self.format.next_table(*e, level))
+            .collect();
+
+        self.protect_page(addr & !(PAGE_SIZE - 1))?;
+        self.tables.insert(addr, Table { level, snapshot });
+        self.unreadable.remove(&addr);
+
+        for child in children {
+            self.add_child(mem, child, level + 1)?;
+        }
+
+        Ok(())
+    }
+
+    /// Watches a table referenced by a guest entry. The guest controls the entry, so a table
+    /// outside mapped memory is recorded as unreadable instead of failing, which would leave
+    /// the remaining tables unwatched.
+    fn add_child(&mut self, mem: &GuestMemory, addr: GPAddr, level: usize) -> Result<(), Error> {
+        match self.add_table(mem, addr, level) {
+            Err(Error::InvalidMapping { .. }) => {
+                self.unreadable.insert(addr);
+                Ok(())
+            }
+            result => result,
+        }
+    }
+
+    fn protect_page(&mut self, page: GPAddr) -> Result<(), Error> {
+        let flags = match self.protected.get(&page) {
+            Some(flags) => *flags,
+            None => self.vm.flags_at(page).ok_or(Error::InvalidMapping {
+                reason: MappingError::NotMapped(page),
+            })?,
+        };
+
+        self.vm.protect(page, PAGE_SIZE, flags - Memory::WRITE)?;
+        self.protected.insert(page, flags);
+        Ok(())
+    }
+}
+
+impl Drop for PageTableWatch {
+    fn drop(&mut self) {
+        let _ = self.clear();
+    }
+}
+
+fn read_table(mem: &GuestMemory, addr: GPAddr) -> Result<Vec<u64>, Error> {
+    let mut buf = [0_u8; TABLE_SIZE as usize];
+    mem.read(addr, &mut buf)?;
+
+    let mut entries = Vec::with_capacity(ENTRIES);
+    for chunk in buf.chunks_exact(8) {
+        let mut bytes = [0_u8; 8];
+        bytes.copy_from_slice(chunk);
+        entries.push(u64::from_le_bytes(bytes));
+    }
+
+    Ok(entries)
+}
+
+/// Returns the guest physical address of a write fault.
+#[cfg(target_arch = "x86_64")]
+fn write_fault(exit: &Exit) -> Option<GPAddr> {
+    match *exit {
+        Exit::EptViolation(ept) if ept.write => Some(ept.gpa),
+        _ => None,
+    }
+}
+
+/// Returns the guest physical address of a write fault.
+#[cfg(target_arch = "aarch64")]
+fn write_fault(exit: &Exit) -> Option<GPAddr> {
+    match *exit {
+        Exit::DataAbort {
+            gpa, write: true, ..
+        } => Some(gpa),
+        _ => None,
+    }
+}
@@ -5,9 +5,28 @@ use std::fmt;
 
 /// Low level access to generated bindings.
 pub use hv_sys as sys;
-pub use vcpu::Vcpu;
+pub use vcpu::{
+    ExitStats, Interrupt, IrqQueue, QosClass, Vcpu, VcpuBuilder, VcpuConfig, VcpuController,
+    VcpuGroup, VcpuHandle, VcpuStats,
+};
 pub use vm::Vm;
 
+#[cfg(feature = "console")]
+pub mod console;
+pub mod debug;
+pub mod discovery;
+#[cfg(feature = "fault_injection")]
+pub mod fault;
+pub mod harness;
+pub mod introspect;
+pub mod memory;
+#[cfg(feature = "power_notifications")]
+pub mod power;
+pub mod pv;
+pub mod requirements;
+pub mod run;
+pub mod selftest;
+mod time;
 mod vcpu;
 pub mod vm;
 
@@ -16,6 +35,11 @@ pub mod arm64;
 #[cfg(target_arch = "x86_64")]
 pub mod x86;
 
+#[cfg(target_arch = "aarch64")]
+pub use arm64::{Exit, VcpuState};
+#[cfg(target_arch = "x86_64")]
+pub use x86::{Exit, VcpuState};
+
 pub type Size = u64;
 
 /// Type of a user virtual address.
@@ -24,6 +48,14 @@ pub type Addr = *const u8;
 /// Type of a guest physical address.
 pub type GPAddr = u64;
 
+/// Granularity of guest memory mappings.
+#[cfg(target_arch = "x86_64")]
+pub const PAGE_SIZE: Size = 0x1000;
+
+/// Granularity of guest memory mappings (Apple Silicon uses 16KB host pages).
+#[cfg(target_arch = "aarch64")]
+pub const PAGE_SIZE: Size = 0x4000;
+
 bitflags::bitflags! {
     /// Guest physical memory region permissions.
     pub struct Memory: u32 {
@@ -34,6 +66,7 @@ bitflags::bitflags! {
 }
 
 /// Helper macro to call unsafe Hypervisor functions and map returned error codes to [Error] type.
+#[cfg(not(feature = "fault_injection"))]
 #[macro_export]
 macro_rules! call {
     ($f:expr) => {{
@@ -45,6 +78,24 @@ macro_rules! call {
     }};
 }
 
+/// Helper macro to call unsafe Hypervisor functions and map returned error codes to [Error] type.
+///
+/// Consults [fault] before calling the framework.
+#[cfg(feature = "fault_injection")]
+#[macro_export]
+macro_rules! call {
+    ($f:expr) => {{
+        let code = match $crate::fault::check(stringify!($f)) {
+            Some(code) => code,
+            None => unsafe { $f },
+        };
+        match code {
+            0 => Ok(()),
+            _ => Err(Error::from(code)),
+        }
+    }};
+}
+
 /// The return type of framework functions.
 /// Wraps the underlying `hv_return_t` type.
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
@@ -55,6 +106,16 @@ pub enum Error {
     NoResources,
     NoDevice,
     Unsupported,
+    /// A VM already exists in the current process.
+    VmExists,
+    /// Memory mapping arguments were rejected before calling the framework.
+    InvalidMapping {
+        reason: MappingError,
+    },
+    /// An XCR0 value was rejected before setting it, see `x86::VcpuExt::set_xcr0`.
+    InvalidXcr0 {
+        reason: Xcr0Error,
+    },
     /// Not mapped error code.
     Unknown(sys::hv_return_t),
 }
@@ -70,11 +131,165 @@ impl fmt::Display for Error {
             Error::NoResources => write!(f, "The operation was unsuccessful because the host had no resources available to complete the request"),
             Error::NoDevice => write!(f, "The operation was unsuccessful because no VM or vCPU was available"),
             Error::Unsupported => write!(f, "The operation requested isn’t supported by the hypervisor"),
+            Error::VmExists => write!(f, "Only one VM can exist per process and one already exists"),
+            Error::InvalidMapping { reason } => write!(f, "Invalid memory mapping: {}", reason),
+            Error::InvalidXcr0 { reason } => write!(f, "Invalid XCR0: {}", reason),
             Error::Unknown(code) => write!(f, "Error code: {}", *code as i32),
         }
     }
 }
 
+/// Describes why memory mapping arguments are invalid.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum MappingError {
+    /// Host address is null.
+    NullAddress,
+    /// Host address is not page aligned.
+    UnalignedAddress(usize),
+    /// Guest physical address is not page aligned.
+    UnalignedGuestAddress(GPAddr),
+    /// Size is not a multiple of the page size.
+    UnalignedSize(Size),
+    /// Size of the region is zero.
+    ZeroSize,
+    /// The region wraps around the end of the address space.
+    Overflow,
+    /// No access permissions were requested.
+    NoAccess,
+    /// Write permission requested without read permission.
+    WriteWithoutRead,
+    /// The region overlaps an existing mapping at the given guest address.
+    Overlap(GPAddr),
+    /// Guest physical address is not backed by a mapping.
+    NotMapped(GPAddr),
+    /// Guest physical range was not released, see [memory::GuestMemory::reclaim_range].
+    NotReleased(GPAddr),
+    /// `MAP_JIT` memory was denied, see [memory::HostMemory::new_executable].
+    JitNotAllowed,
+    /// The region ends beyond the guest physical address space of the given size in bits.
+    OutOfRange(u32),
+}
+
+impl fmt::Display for MappingError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MappingError::NullAddress => write!(f, "host address is null"),
+            MappingError::UnalignedAddress(addr) => write!(
+                f,
+                "host address {:#x} is not aligned to {:#x}",
+                addr, PAGE_SIZE
+            ),
+            MappingError::UnalignedGuestAddress(gpa) => write!(
+                f,
+                "guest address {:#x} is not aligned to {:#x}",
+                gpa, PAGE_SIZE
+            ),
+            MappingError::UnalignedSize(size) => {
+                write!(f, "size {:#x} is not a multiple of {:#x}", size, PAGE_SIZE)
+            }
+            MappingError::ZeroSize => write!(f, "size is zero"),
+            MappingError::Overflow => write!(f, "region wraps around the end of the address space"),
+            MappingError::NoAccess => write!(f, "no access permissions requested"),
+            MappingError::WriteWithoutRead => {
+                write!(f, "write permission requires read permission")
+            }
+            MappingError::Overlap(gpa) => {
+                write!(f, "region at {:#x} overlaps an existing mapping", gpa)
+            }
+            MappingError::NotMapped(gpa) => write!(f, "guest address {:#x} is not mapped", gpa),
+            MappingError::NotReleased(gpa) => {
+                write!(f, "guest address {:#x} is not in a released range", gpa)
+            }
+            MappingError::JitNotAllowed => write!(
+                f,
+                "MAP_JIT memory denied, the hardened runtime requires the com.apple.security.cs.allow-jit entitlement"
+            ),
+            MappingError::OutOfRange(bits) => write!(
+                f,
+                "region ends beyond the {}-bit guest physical address space",
+                bits
+            ),
+        }
+    }
+}
+
+/// Describes why an XCR0 value is invalid.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Xcr0Error {
+    /// The x87 state (bit 0) is always enabled.
+    MissingX87,
+    /// The given features aren't supported by the host processor or enabled by the host.
+    Unsupported(u64),
+    /// The given features require other features that aren't enabled.
+    MissingDependency { features: u64, requires: u64 },
+}
+
+impl fmt::Display for Xcr0Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Xcr0Error::MissingX87 => write!(f, "x87 state (bit 0) must be enabled"),
+            Xcr0Error::Unsupported(features) => {
+                write!(f, "features {:#x} are not supported by the host", features)
+            }
+            Xcr0Error::MissingDependency { features, requires } => write!(
+                f,
+                "features {:#x} require features {:#x}",
+                features, requires
+            ),
+        }
+    }
+}
+
+/// Validates guest memory mapping arguments.
+///
+/// `uva` and `flags` are optional as not every call takes them (e.g. unmap).
+/// New mappings (`uva` is set) additionally require at least one access permission,
+/// while `protect` may legitimately remove all of them.
+pub(crate) fn validate_mapping(
+    uva: Option<Addr>,
+    gpa: GPAddr,
+    size: Size,
+    flags: Option<Memory>,
+) -> Result<(), Error> {
+    let fail = |reason| Err(Error::InvalidMapping { reason });
+
+    if let Some(uva) = uva {
+        if uva.is_null() {
+            return fail(MappingError::NullAddress);
+        }
+        if uva as u64 % PAGE_SIZE != 0 {
+            return fail(MappingError::UnalignedAddress(uva as usize));
+        }
+    }
+
+    if gpa % PAGE_SIZE != 0 {
+        return fail(MappingError::UnalignedGuestAddress(gpa));
+    }
+
+    if size == 0 {
+        return fail(MappingError::ZeroSize);
+    }
+
+    if size % PAGE_SIZE != 0 {
+        return fail(MappingError::UnalignedSize(size));
+    }
+
+    if gpa.checked_add(size).is_none() {
+        return fail(MappingError::Overflow);
+    }
+
+    if let Some(flags) = flags {
+        if uva.is_some() && flags.is_empty() {
+            return fail(MappingError::NoAccess);
+        }
+        if flags.contains(Memory::WRITE) && !flags.contains(Memory::READ) {
+            return fail(MappingError::WriteWithoutRead);
+        }
+    }
+
+    Ok(())
+}
+
 impl From<sys::hv_return_t> for Error {
     fn from(value: sys::hv_return_t) -> Self {
         // Looks like bindgen gets confused sometimes and produces different code for these
@@ -0,0 +1,604 @@
+//! Guest memory management.
+//!
+//! [Vm::map] takes a raw host pointer, so nothing prevents the host allocation from being freed
+//! while the guest still uses it. The types in this module tie the lifetime of host memory to
+//! the lifetime of its mapping:
+//! * [HostMemory] is an owned, page aligned host allocation.
+//! * [Mapping] owns a [HostMemory] while it's mapped into the guest and unmaps it on drop,
+//!   before the host memory is released.
+//! * [GuestMemory] is a collection of mappings forming the guest physical memory layout.
+//! * [LazyMemory] is a guest physical range populated on demand.
+//! * [GuestIoVec] is a scatter-gather list pointing directly at guest memory.
+//! * [BouncePool] provides contiguous buffers when guest buffers can't be used in place.
+//!
+//! Executable guest code should be placed with [GuestMemory::add_code_region]: on Apple Silicon
+//! W+X host memory must be allocated with `MAP_JIT` and can only be written by a thread that
+//! lifted JIT write protection, which [HostMemory::write] takes care of.
+
+use std::ptr;
+use std::sync::Arc;
+
+use crate::{Error, GPAddr, MappingError, Memory, Size, Vm, PAGE_SIZE};
+
+mod bounce;
+mod iovec;
+mod lazy;
+pub use bounce::{BouncePool, BounceStats, IoBuffer};
+pub use iovec::{Descriptor, GuestIoVec};
+pub use lazy::LazyMemory;
+
+/// Owned, page aligned and zero initialized host memory allocation.
+#[derive(Debug)]
+pub struct HostMemory {
+    ptr: *mut u8,
+    size: Size,
+    /// Allocated with `MAP_JIT`, writes need to toggle JIT write protection.
+    jit: bool,
+}
+
+// Host memory is a plain allocation, synchronization of its contents is up to the user.
+unsafe impl Send for HostMemory {}
+unsafe impl Sync for HostMemory {}
+
+impl HostMemory {
+    /// Allocates `size` bytes of anonymous host memory.
+    ///
+    /// `size` must be a non-zero multiple of [PAGE_SIZE].
+    pub fn new(size: Size) -> Result<HostMemory, Error> {
+        HostMemory::alloc(
+            size,
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_ANONYMOUS | libc::MAP_PRIVATE | libc::MAP_NORESERVE,
+            false,
+        )
+    }
+
+    /// Allocates `size` bytes of host memory suitable for W+X guest mappings.
+    ///
+    /// On Apple Silicon the memory is allocated with `MAP_JIT`, on x86 this is the same as
+    /// [HostMemory::new].
+    ///
+    /// Under the hardened runtime `MAP_JIT` requires the `com.apple.security.cs.allow-jit`
+    /// entitlement, otherwise [MappingError::JitNotAllowed] is returned.
+    #[cfg(target_arch = "aarch64")]
+    pub fn new_executable(size: Size) -> Result<HostMemory, Error> {
+        HostMemory::alloc(
+            size,
+            libc::PROT_READ | libc::PROT_WRITE | libc::PROT_EXEC,
+            libc::MAP_ANONYMOUS | libc::MAP_PRIVATE | libc::MAP_JIT,
+            true,
+        )
+    }
+
+    /// Allocates `size` bytes of host memory suitable for W+X guest mappings.
+    ///
+    /// On Apple Silicon the memory is allocated with `MAP_JIT`, on x86 this is the same as
+    /// [HostMemory::new].
+    #[cfg(target_arch = "x86_64")]
+    pub fn new_executable(size: Size) -> Result<HostMemory, Error> {
+        HostMemory::new(size)
+    }
+
+    fn alloc(
+        size: Size,
+        prot: libc::c_int,
+        flags: libc::c_int,
+        jit: bool,
+    ) -> Result<HostMemory, Error> {
+        if size == 0 {
+            return Err(Error::InvalidMapping {
+                reason: MappingError::ZeroSize,
+            });
+        }
+
+        if size % PAGE_SIZE != 0 {
+            return Err(Error::InvalidMapping {
+                reason: MappingError::UnalignedSize(size),
+            });
+        }
+
+        let ptr = unsafe { libc::mmap(ptr::null_mut(), size as usize, prot, flags, -1, 0) };
+
+        if ptr == libc::MAP_FAILED {
+            let errno = std::io::Error::last_os_error().raw_os_error();
+            if jit && errno == Some(libc::EPERM) {
+                return Err(Error::InvalidMapping {
+                    reason: MappingError::JitNotAllowed,
+                });
+            }
+            return Err(Error::NoResources);
+        }
+
+        Ok(HostMemory {
+            ptr: ptr as *mut u8,
+            size,
+            jit,
+        })
+    }
+
+    /// Returns the host address of the allocation.
+    #[inline]
+    pub fn as_ptr(&self) -> *mut u8 {
+        self.ptr
+    }
+
+    /// Returns the size of the allocation in bytes.
+    #[inline]
+    pub fn size(&self) -> Size {
+        self.size
+    }
+
+    /// Copies `buf.len()` bytes at `offset` into `buf`.
+    pub fn read(&self, offset: Size, buf: &mut [u8]) -> Result<(), Error> {
+        let src = self.range(offset, buf.len())?;
+        unsafe { ptr::copy_nonoverlapping(src, buf.as_mut_ptr(), buf.len()) };
+        Ok(())
+    }
+
+    /// Returns `true` if the memory was allocated with [HostMemory::new_executable] and
+    /// is subject to JIT write protection.
+    #[inline]
+    pub fn is_executable(&self) -> bool {
+        self.jit
+    }
+
+    /// Copies `buf` into the allocation at `offset`.
+    ///
+    /// For executable memory JIT write protection is lifted for the calling thread during the
+    /// copy and the instruction cache is invalidated afterwards.
+    pub fn write(&self, offset: Size, buf: &[u8]) -> Result<(), Error> {
+        let dst = self.range(offset, buf.len())?;
+        if self.jit {
+            jit::write(dst, buf);
+        } else {
+            unsafe { ptr::copy_nonoverlapping(buf.as_ptr(), dst, buf.len()) };
+        }
+        Ok(())
+    }
+
+    /// Calls `f` with the allocation writable, e.g. to assemble or patch guest code in place.
+    ///
+    /// For executable memory JIT write protection is lifted for the calling thread until `f`
+    /// returns (or panics), then the instruction cache is invalidated. Calls for different
+    /// allocations may be nested, protection is restored when the outermost one returns.
+    pub fn write_with<R>(&mut self, f: impl FnOnce(&mut [u8]) -> R) -> R {
+        let _guard = if self.jit {
+            Some(jit::WriteGuard::new(self.ptr, self.size as usize))
+        } else {
+            None
+        };
+        let buf = unsafe { std::slice::from_raw_parts_mut(self.ptr, self.size as usize) };
+        f(buf)
+    }
+
+    /// Returns the host address of `len` bytes at `offset`, checking bounds.
+    fn range(&self, offset: Size, len: usize) -> Result<*mut u8, Error> {
+        match offset.checked_add(len as Size) {
+            Some(end) if end <= self.size => Ok(unsafe { self.ptr.add(offset as usize) }),
+            _ => Err(Error::BadArgument),
+        }
+    }
+}
+
+#[cfg(target_arch = "aarch64")]
+mod jit {
+    use std::cell::Cell;
+    use std::ptr;
+
+    extern "C" {
+        fn pthread_jit_write_protect_np(enabled: libc::c_int);
+        fn sys_icache_invalidate(start: *mut libc::c_void, len: libc::size_t);
+    }
+
+    /// Copies `buf` to `MAP_JIT` memory at `dst`.
+    pub(super) fn write(dst: *mut u8, buf: &[u8]) {
+        let _guard = WriteGuard::new(dst, buf.len());
+        unsafe { ptr::copy_nonoverlapping(buf.as_ptr(), dst, buf.len()) };
+    }
+
+    thread_local! {
+        /// Number of live guards of the thread, the framework has no getter for the current
+        /// protection state.
+        static WRITERS: Cell<usize> = Cell::new(0);
+    }
+
+    /// Makes `MAP_JIT` memory writable for the current thread while alive.
+    ///
+    /// Guards nest: write protection is only restored when the outermost guard is dropped.
+    pub(super) struct WriteGuard {
+        start: *mut u8,
+        len: usize,
+    }
+
+    impl WriteGuard {
+        pub(super) fn new(start: *mut u8, len: usize) -> WriteGuard {
+            WRITERS.with(|writers| {
+                if writers.get() == 0 {
+                    unsafe { pthread_jit_write_protect_np(0) };
+                }
+                writers.set(writers.get() + 1);
+            });
+            WriteGuard { start, len }
+        }
+    }
+
+    impl Drop for WriteGuard {
+        fn drop(&mut self) {
+            WRITERS.with(|writers| {
+                writers.set(writers.get() - 1);
+                if writers.get() == 0 {
+                    unsafe { pthread_jit_write_protect_np(1) };
+                }
+            });
+            unsafe { sys_icache_invalidate(self.start as *mut libc::c_void, self.len) };
+        }
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+mod jit {
+    use std::ptr;
+
+    /// x86 has no JIT write protection and coherent instruction caches.
+    pub(super) fn write(dst: *mut u8, buf: &[u8]) {
+        unsafe { ptr::copy_nonoverlapping(buf.as_ptr(), dst, buf.len()) };
+    }
+
+    pub(super) struct WriteGuard;
+
+    impl WriteGuard {
+        pub(super) fn new(_start: *mut u8, _len: usize) -> WriteGuard {
+            WriteGuard
+        }
+    }
+}
+
+impl Drop for HostMemory {
+    fn drop(&mut self) {
+        unsafe { libc::munmap(self.ptr as *mut libc::c_void, self.size as usize) };
+    }
+}
+
+/// Host memory mapped into the guest physical address space.
+///
+/// The region is unmapped from the guest when the mapping is dropped, and only then the host
+/// memory is released.
+#[derive(Debug)]
+pub struct Mapping {
+    vm: Arc<Vm>,
+    /// Always `Some` until dropped.
+    mem: Option<HostMemory>,
+    gpa: GPAddr,
+    flags: Memory,
+    /// Ranges unmapped with [GuestMemory::release_range], sorted by guest physical address.
+    released: Vec<(GPAddr, Size)>,
+}
+
+impl Mapping {
+    pub(crate) fn new(
+        vm: Arc<Vm>,
+        mem: HostMemory,
+        gpa: GPAddr,
+        flags: Memory,
+    ) -> Result<Mapping, Error> {
+        vm.map(mem.as_ptr(), gpa, mem.size(), flags)?;
+
+        Ok(Mapping {
+            vm,
+            mem: Some(mem),
+            gpa,
+            flags,
+            released: Vec::new(),
+        })
+    }
+
+    /// Returns the guest physical address of the mapping.
+    #[inline]
+    pub fn gpa(&self) -> GPAddr {
+        self.gpa
+    }
+
+    /// Returns the size of the mapping in bytes.
+    #[inline]
+    pub fn size(&self) -> Size {
+        self.memory().size()
+    }
+
+    /// Returns current access permissions of the mapping.
+    #[inline]
+    pub fn flags(&self) -> Memory {
+        self.flags
+    }
+
+    /// Returns the host memory backing the mapping.
+    #[inline]
+    pub fn memory(&self) -> &HostMemory {
+        self.mem.as_ref().unwrap()
+    }
+
+    /// Returns `true` if `len` bytes at `gpa` are within the mapping.
+    pub fn contains(&self, gpa: GPAddr, len: Size) -> bool {
+        gpa >= self.gpa
+            && gpa
+                .checked_add(len)
+                .map_or(false, |end| end <= self.gpa + self.size())
+    }
+
+    /// Modifies the access permissions of the whole mapping.
+    ///
+    /// Released ranges keep being unmapped and get the new permissions once reclaimed.
+    pub fn protect(&mut self, flags: Memory) -> Result<(), Error> {
+        for (gpa, size) in self.mapped_ranges() {
+            self.vm.protect(gpa, size, flags)?;
+        }
+        self.flags = flags;
+        Ok(())
+    }
+
+    /// Returns ranges released from the guest with [GuestMemory::release_range].
+    pub fn released(&self) -> &[(GPAddr, Size)] {
+        &self.released
+    }
+
+    /// Returns `true` if any of `len` bytes at `gpa` were released from the guest.
+    pub fn is_released(&self, gpa: GPAddr, len: Size) -> bool {
+        let end = gpa.saturating_add(len);
+        self.released.iter().any(|&(g, s)| g < end && gpa < g + s)
+    }
+
+    /// Returns the ranges of the mapping currently mapped into the guest.
+    fn mapped_ranges(&self) -> Vec<(GPAddr, Size)> {
+        let mut ranges = Vec::new();
+        let mut start = self.gpa;
+
+        for &(gpa, size) in &self.released {
+            if gpa > start {
+                ranges.push((start, gpa - start));
+            }
+            start = gpa + size;
+        }
+
+        let end = self.gpa + self.size();
+        if end > start {
+            ranges.push((start, end - start));
+        }
+
+        ranges
+    }
+
+    fn release(&mut self, gpa: GPAddr, size: Size) -> Result<(), Error> {
+        let index = self.released.partition_point(|&(g, _)| g < gpa);
+        let overlaps_prev = index > 0 && {
+            let (g, s) = self.released[index - 1];
+            g + s > gpa
+        };
+        let overlaps_next = index < self.released.len() && self.released[index].0 < gpa + size;
+
+        if overlaps_prev || overlaps_next {
+            return Err(Error::InvalidMapping {
+                reason: MappingError::NotMapped(gpa),
+            });
+        }
+
+        self.vm.unmap(gpa, size)?;
+        self.released.insert(index, (gpa, size));
+
+        let offset = (gpa - self.gpa) as usize;
+        unsafe {
+            libc::madvise(
+                self.memory().as_ptr().add(offset) as *mut libc::c_void,
+                size as usize,
+                libc::MADV_FREE,
+            )
+        };
+
+        Ok(())
+    }
+
+    fn reclaim(&mut self, gpa: GPAddr, size: Size) -> Result<(), Error> {
+        let index = self
+            .released
+            .iter()
+            .position(|&(g, s)| gpa >= g && gpa + size <= g + s)
+            .ok_or(Error::InvalidMapping {
+                reason: MappingError::NotReleased(gpa),
+            })?;
+
+        let uva = unsafe { self.memory().as_ptr().add((gpa - self.gpa) as usize) };
+        self.vm.map(uva, gpa, size, self.flags)?;
+
+        // Keep whatever is left of the released range on both sides.
+        let (g, s) = self.released.remove(index);
+        if gpa + size < g + s {
+            self.released
+                .insert(index, (gpa + size, g + s - (gpa + size)));
+        }
+        if gpa > g {
+            self.released.insert(index, (g, gpa - g));
+        }
+
+        Ok(())
+    }
+}
+
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        let ranges = self.mapped_ranges();
+        if let Some(mem) = self.mem.take() {
+            let failed = ranges
+                .into_iter()
+                .any(|(gpa, size)| self.vm.unmap(gpa, size).is_err());
+            if failed {
+                // The guest may still access this memory, leaking is the only safe option.
+                std::mem::forget(mem);
+            }
+        }
+    }
+}
+
+/// Guest physical memory layout made of non-overlapping mappings.
+#[derive(Debug)]
+pub struct GuestMemory {
+    vm: Arc<Vm>,
+    /// Sorted by guest physical address.
+    regions: Vec<Mapping>,
+}
+
+impl GuestMemory {
+    pub fn new(vm: Arc<Vm>) -> GuestMemory {
+        GuestMemory {
+            vm,
+            regions: Vec::new(),
+        }
+    }
+
+    /// Allocates host memory and maps it into the guest at `gpa`.
+    pub fn add_region(
+        &mut self,
+        gpa: GPAddr,
+        size: Size,
+        flags: Memory,
+    ) -> Result<&Mapping, Error> {
+        let mem = HostMemory::new(size)?;
+        self.insert(mem, gpa, flags)
+    }
+
+    /// Allocates host memory for guest code and maps it into the guest at `gpa` with
+    /// read, write and execute permissions.
+    ///
+    /// Use [GuestMemory::write] to load code into the region, it handles JIT write protection
+    /// and instruction cache maintenance on Apple Silicon.
+    pub fn add_code_region(&mut self, gpa: GPAddr, size: Size) -> Result<&Mapping, Error> {
+        let mem = HostMemory::new_executable(size)?;
+        self.insert(mem, gpa, Memory::READ | Memory::WRITE | Memory::EXEC)
+    }
+
+    /// Maps the given host memory into the guest at `gpa`.
+    pub fn insert(
+        &mut self,
+        mem: HostMemory,
+        gpa: GPAddr,
+        flags: Memory,
+    ) -> Result<&Mapping, Error> {
+        let end = gpa.checked_add(mem.size()).ok_or(Error::InvalidMapping {
+            reason: MappingError::Overflow,
+        })?;
+
+        let index = self.regions.partition_point(|r| r.gpa() < gpa);
+        let overlaps_prev = index > 0 && self.regions[index - 1].contains(gpa, 1);
+        let overlaps_next = index < self.regions.len() && self.regions[index].gpa() < end;
+
+        if overlaps_prev || overlaps_next {
+            return Err(Error::InvalidMapping {
+                reason: MappingError::Overlap(gpa),
+            });
+        }
+
+        let mapping = Mapping::new(Arc::clone(&self.vm), mem, gpa, flags)?;
+        self.regions.insert(index, mapping);
+
+        Ok(&self.regions[index])
+    }
+
+    /// Removes the region starting at `gpa`.
+    ///
+    /// The region is unmapped once the returned mapping is dropped.
+    pub fn remove_region(&mut self, gpa: GPAddr) -> Option<Mapping> {
+        let index = self.regions.iter().position(|r| r.gpa() == gpa)?;
+        Some(self.regions.remove(index))
+    }
+
+    /// Unmaps `size` bytes at `gpa` from the guest and lets the host reclaim the backing pages,
+    /// e.g. when a balloon driver hands memory back to the host.
+    ///
+    /// The range must be page aligned, within a single region and not released already.
+    /// Guest accesses to the range fault until it's reclaimed with
+    /// [GuestMemory::reclaim_range].
+    pub fn release_range(&mut self, gpa: GPAddr, size: Size) -> Result<(), Error> {
+        crate::validate_mapping(None, gpa, size, None)?;
+        self.region_for_mut(gpa, size)?.release(gpa, size)
+    }
+
+    /// Maps a previously released range back into the guest.
+    ///
+    /// The range must be within a released range, its contents are undefined.
+    pub fn reclaim_range(&mut self, gpa: GPAddr, size: Size) -> Result<(), Error> {
+        crate::validate_mapping(None, gpa, size, None)?;
+        self.region_for_mut(gpa, size)?.reclaim(gpa, size)
+    }
+
+    /// Returns an iterator over the regions sorted by guest physical address.
+    pub fn regions(&self) -> impl Iterator<Item = &Mapping> {
+        self.regions.iter()
+    }
+
+    /// Returns the region containing `gpa`.
+    pub fn find_region(&self, gpa: GPAddr) -> Option<&Mapping> {
+        let index = self.regions.partition_point(|r| r.gpa() <= gpa);
+        index
+            .checked_sub(1)
+            .map(|i| &self.regions[i])
+            .filter(|r| r.contains(gpa, 1))
+    }
+
+    /// Returns the host address backing `len` bytes at `gpa`.
+    ///
+    /// The range must be entirely within a single region and not released.
+    pub fn translate(&self, gpa: GPAddr, len: Size) -> Option<*mut u8> {
+        let region = self
+            .find_region(gpa)
+            .filter(|r| r.contains(gpa, len) && !r.is_released(gpa, len))?;
+        Some(unsafe { region.memory().as_ptr().add((gpa - region.gpa()) as usize) })
+    }
+
+    /// Reads guest memory at `gpa` into `buf`.
+    pub fn read(&self, gpa: GPAddr, buf: &mut [u8]) -> Result<(), Error> {
+        let region = self.region_for(gpa, buf.len())?;
+        region.memory().read(gpa - region.gpa(), buf)
+    }
+
+    /// Writes `buf` into guest memory at `gpa`.
+    pub fn write(&self, gpa: GPAddr, buf: &[u8]) -> Result<(), Error> {
+        let region = self.region_for(gpa, buf.len())?;
+        region.memory().write(gpa - region.gpa(), buf)
+    }
+
+    /// Translates a descriptor chain into host scatter-gather lists for zero-copy I/O.
+    ///
+    /// Fails if any buffer is not backed by guest memory or was released.
+    pub fn iovec(
+        &self,
+        chain: impl IntoIterator<Item = Descriptor>,
+    ) -> Result<GuestIoVec<'_>, Error> {
+        GuestIoVec::new(self, chain)
+    }
+
+    fn region_for_mut(&mut self, gpa: GPAddr, len: Size) -> Result<&mut Mapping, Error> {
+        let index = self.regions.partition_point(|r| r.gpa() <= gpa);
+        index
+            .checked_sub(1)
+            .map(move |i| &mut self.regions[i])
+            .filter(|r| r.contains(gpa, len))
+            .ok_or(Error::InvalidMapping {
+                reason: MappingError::NotMapped(gpa),
+            })
+    }
+
+    /// Returns the region backing `len` bytes at `gpa`, which must not be released.
+    fn region_for(&self, gpa: GPAddr, len: usize) -> Result<&Mapping, Error> {
+        let len = len as Size;
+        self.find_region(gpa)
+            .filter(|r| r.contains(gpa, len) && !r.is_released(gpa, len))
+            .ok_or(Error::InvalidMapping {
+                reason: MappingError::NotMapped(gpa),
+            })
+    }
+}
+
+// Regions can only be removed through `&mut self`, so translated ranges stay mapped while a
+// device owns the memory.
+unsafe impl crate::pv::Translate for GuestMemory {
+    fn translate(&self, gpa: GPAddr, size: Size) -> Option<*mut u8> {
+        GuestMemory::translate(self, gpa, size)
+    }
+}
@@ -0,0 +1,237 @@
+use std::iter;
+use std::ops::{Deref, DerefMut};
+use std::slice;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+use super::{Descriptor, GuestMemory};
+use crate::Error;
+
+/// Pool of reusable host buffers for guest I/O that can't be done in place.
+///
+/// [GuestIoVec](super::GuestIoVec) covers the fast path, but some backends need a single
+/// contiguous buffer (e.g. when a descriptor crosses a region boundary) or a specific alignment
+/// (e.g. `O_DIRECT` style I/O). [BouncePool::buffer] hands out guest memory directly when
+/// possible and falls back to a pooled bounce buffer otherwise.
+#[derive(Debug)]
+pub struct BouncePool {
+    buffer_size: usize,
+    max_buffers: usize,
+    free: Mutex<Vec<Vec<u8>>>,
+    direct: AtomicU64,
+    split: AtomicU64,
+    unaligned: AtomicU64,
+    allocations: AtomicU64,
+}
+
+/// Counters of [BouncePool] usage.
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub struct BounceStats {
+    /// Buffers served directly from guest memory.
+    pub direct: u64,
+    /// Bounced because the descriptor crosses a region boundary.
+    pub split: u64,
+    /// Bounced because the guest buffer is not suitably aligned.
+    pub unaligned: u64,
+    /// Bounce buffers allocated because the pool was empty or the request too large.
+    pub allocations: u64,
+}
+
+impl BounceStats {
+    /// Returns the number of times the slow path was taken.
+    pub fn bounced(&self) -> u64 {
+        self.split + self.unaligned
+    }
+}
+
+impl BouncePool {
+    /// Creates a pool keeping up to `max_buffers` buffers of `buffer_size` bytes for reuse.
+    pub fn new(buffer_size: usize, max_buffers: usize) -> BouncePool {
+        BouncePool {
+            buffer_size,
+            max_buffers,
+            free: Mutex::new(Vec::new()),
+            direct: AtomicU64::new(0),
+            split: AtomicU64::new(0),
+            unaligned: AtomicU64::new(0),
+            allocations: AtomicU64::new(0),
+        }
+    }
+
+    /// Returns a contiguous host buffer for the guest buffer described by `desc`.
+    ///
+    /// Guest memory is used in place if the buffer is within a single region and its host
+    /// address is aligned to `align` bytes. Otherwise the data is copied into a bounce buffer
+    /// (for buffers read by the device), and must be copied back with [IoBuffer::complete]
+    /// (for buffers written by the device).
+    ///
+    /// The buffer borrows `mem` mutably, as direct buffers hand out mutable views of guest
+    /// memory that must not alias.
+    pub fn buffer<'a>(
+        &'a self,
+        mem: &'a mut GuestMemory,
+        desc: Descriptor,
+        align: usize,
+    ) -> Result<IoBuffer<'a>, Error> {
+        let iov = mem.iovec(iter::once(desc))?;
+        let list = if desc.write {
+            iov.writable()
+        } else {
+            iov.readable()
+        };
+
+        let single = list.len() <= 1;
+        let aligned = list
+            .first()
+            .map_or(true, |v| v.iov_base as usize % align.max(1) == 0);
+
+        if single && aligned {
+            self.direct.fetch_add(1, Ordering::Relaxed);
+            let ptr = list.first().map_or(std::ptr::null_mut(), |v| v.iov_base);
+            return Ok(IoBuffer {
+                kind: Kind::Direct(ptr as *mut u8),
+                mem,
+                desc,
+            });
+        }
+
+        let counter = if single { &self.unaligned } else { &self.split };
+        counter.fetch_add(1, Ordering::Relaxed);
+
+        let mut buf = self.take(desc.len as usize);
+        if !desc.write {
+            let mut offset = 0;
+            for v in list {
+                let src = unsafe { slice::from_raw_parts(v.iov_base as *const u8, v.iov_len) };
+                buf[offset..offset + v.iov_len].copy_from_slice(src);
+                offset += v.iov_len;
+            }
+        }
+
+        Ok(IoBuffer {
+            kind: Kind::Bounced(BounceBuffer {
+                pool: self,
+                buf: Some(buf),
+            }),
+            mem,
+            desc,
+        })
+    }
+
+    /// Returns usage counters.
+    pub fn stats(&self) -> BounceStats {
+        BounceStats {
+            direct: self.direct.load(Ordering::Relaxed),
+            split: self.split.load(Ordering::Relaxed),
+            unaligned: self.unaligned.load(Ordering::Relaxed),
+            allocations: self.allocations.load(Ordering::Relaxed),
+        }
+    }
+
+    fn take(&self, len: usize) -> Vec<u8> {
+        let pooled = if len <= self.buffer_size {
+            self.free.lock().unwrap().pop()
+        } else {
+            None
+        };
+
+        let mut buf = pooled.unwrap_or_else(|| {
+            self.allocations.fetch_add(1, Ordering::Relaxed);
+            Vec::with_capacity(len.max(self.buffer_size))
+        });
+
+        buf.clear();
+        buf.resize(len, 0);
+        buf
+    }
+
+    fn give(&self, buf: Vec<u8>) {
+        if buf.capacity() != self.buffer_size {
+            return;
+        }
+
+        let mut free = self.free.lock().unwrap();
+        if free.len() < self.max_buffers {
+            free.push(buf);
+        }
+    }
+}
+
+/// Bounce buffer borrowed from a [BouncePool], returned to the pool on drop.
+#[derive(Debug)]
+struct BounceBuffer<'a> {
+    pool: &'a BouncePool,
+    /// Always `Some` until dropped.
+    buf: Option<Vec<u8>>,
+}
+
+impl Drop for BounceBuffer<'_> {
+    fn drop(&mut self) {
+        if let Some(buf) = self.buf.take() {
+            self.pool.give(buf);
+        }
+    }
+}
+
+#[derive(Debug)]
+enum Kind<'a> {
+    Direct(*mut u8),
+    Bounced(BounceBuffer<'a>),
+}
+
+/// Contiguous host view of a guest buffer, see [BouncePool::buffer].
+#[derive(Debug)]
+pub struct IoBuffer<'a> {
+    kind: Kind<'a>,
+    mem: &'a mut GuestMemory,
+    desc: Descriptor,
+}
+
+impl IoBuffer<'_> {
+    /// Returns `true` if the buffer points directly at guest memory.
+    pub fn is_direct(&self) -> bool {
+        matches!(self.kind, Kind::Direct(_))
+    }
+
+    /// Copies bounced data back into guest memory.
+    ///
+    /// Required for buffers written by the device, a no-op for direct buffers.
+    pub fn complete(self) -> Result<(), Error> {
+        let buf = match &self.kind {
+            Kind::Bounced(b) if self.desc.write => b.buf.as_ref().unwrap(),
+            _ => return Ok(()),
+        };
+
+        let iov = self.mem.iovec(iter::once(self.desc))?;
+        let mut offset = 0;
+        for v in iov.writable() {
+            let dst = unsafe { slice::from_raw_parts_mut(v.iov_base as *mut u8, v.iov_len) };
+            dst.copy_from_slice(&buf[offset..offset + v.iov_len]);
+            offset += v.iov_len;
+        }
+
+        Ok(())
+    }
+}
+
+impl Deref for IoBuffer<'_> {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match &self.kind {
+            Kind::Direct(ptr) if ptr.is_null() => &[],
+            Kind::Direct(ptr) => unsafe { slice::from_raw_parts(*ptr, self.desc.len as usize) },
+            Kind::Bounced(b) => b.buf.as_ref().unwrap(),
+        }
+    }
+}
+
+impl DerefMut for IoBuffer<'_> {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        match &mut self.kind {
+            Kind::Direct(ptr) if ptr.is_null() => &mut [],
+            Kind::Direct(ptr) => unsafe { slice::from_raw_parts_mut(*ptr, self.desc.len as usize) },
+            Kind::Bounced(b) => b.buf.as_mut().unwrap(),
+        }
+    }
+}
@@ -0,0 +1,124 @@
+use std::io;
+use std::marker::PhantomData;
+use std::os::unix::io::RawFd;
+
+use super::GuestMemory;
+use crate::{Error, GPAddr, MappingError, Size};
+
+/// A guest buffer referenced by a descriptor chain (e.g. a virtio descriptor).
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Descriptor {
+    /// Guest physical address of the buffer.
+    pub addr: GPAddr,
+    /// Length of the buffer in bytes.
+    pub len: u32,
+    /// The buffer is written by the device (`VIRTQ_DESC_F_WRITE`), otherwise it's read.
+    pub write: bool,
+}
+
+/// Scatter-gather list pointing directly at guest memory.
+///
+/// Built with [GuestMemory::iovec], the list borrows the guest memory, so regions can't be
+/// removed or released while the host addresses are in use.
+pub struct GuestIoVec<'a> {
+    readable: Vec<libc::iovec>,
+    writable: Vec<libc::iovec>,
+    _mem: PhantomData<&'a GuestMemory>,
+}
+
+impl<'a> GuestIoVec<'a> {
+    pub(super) fn new(
+        mem: &'a GuestMemory,
+        chain: impl IntoIterator<Item = Descriptor>,
+    ) -> Result<GuestIoVec<'a>, Error> {
+        let mut readable = Vec::new();
+        let mut writable = Vec::new();
+
+        for desc in chain {
+            let list = if desc.write {
+                &mut writable
+            } else {
+                &mut readable
+            };
+
+            let mut addr = desc.addr;
+            let mut remaining = desc.len as Size;
+
+            // A buffer may span adjacent regions, split it into one entry per region.
+            while remaining > 0 {
+                let region = mem.find_region(addr).ok_or(Error::InvalidMapping {
+                    reason: MappingError::NotMapped(addr),
+                })?;
+
+                let len = remaining.min(region.gpa() + region.size() - addr);
+                if region.is_released(addr, len) {
+                    return Err(Error::InvalidMapping {
+                        reason: MappingError::NotMapped(addr),
+                    });
+                }
+                let base = unsafe { region.memory().as_ptr().add((addr - region.gpa()) as usize) };
+
+                list.push(libc::iovec {
+                    iov_base: base as *mut libc::c_void,
+                    iov_len: len as usize,
+                });
+
+                addr += len;
+                remaining -= len;
+            }
+        }
+
+        Ok(GuestIoVec {
+            readable,
+            writable,
+            _mem: PhantomData,
+        })
+    }
+
+    /// Returns buffers the device reads from (driver to device data).
+    #[inline]
+    pub fn readable(&self) -> &[libc::iovec] {
+        &self.readable
+    }
+
+    /// Returns buffers the device writes to (device to driver data).
+    #[inline]
+    pub fn writable(&self) -> &[libc::iovec] {
+        &self.writable
+    }
+
+    /// Returns the total length of readable buffers in bytes.
+    pub fn readable_len(&self) -> usize {
+        self.readable.iter().map(|v| v.iov_len).sum()
+    }
+
+    /// Returns the total length of writable buffers in bytes.
+    pub fn writable_len(&self) -> usize {
+        self.writable.iter().map(|v| v.iov_len).sum()
+    }
+
+    /// Writes readable buffers to `fd` with a single `writev` call.
+    pub fn write_to(&self, fd: RawFd) -> io::Result<usize> {
+        let ret = unsafe { libc::writev(fd, self.readable.as_ptr(), iov_count(&self.readable)) };
+        io_result(ret)
+    }
+
+    /// Fills writable buffers from `fd` with a single `readv` call.
+    pub fn read_from(&mut self, fd: RawFd) -> io::Result<usize> {
+        let ret = unsafe { libc::readv(fd, self.writable.as_ptr(), iov_count(&self.writable)) };
+        io_result(ret)
+    }
+}
+
+fn iov_count(list: &[libc::iovec]) -> libc::c_int {
+    // Callers exceeding `IOV_MAX` get `EINVAL` from the kernel.
+    list.len().min(libc::c_int::MAX as usize) as libc::c_int
+}
+
+fn io_result(ret: libc::ssize_t) -> io::Result<usize> {
+    if ret < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(ret as usize)
+    }
+}
@@ -0,0 +1,175 @@
+use std::sync::{Arc, Mutex};
+
+use super::HostMemory;
+use crate::{Error, Exit, GPAddr, MappingError, Memory, Size, Vm, PAGE_SIZE};
+
+/// Guest physical range populated on demand.
+///
+/// The whole range is reserved in the host address space upfront, but host pages are only
+/// allocated and mapped into the guest when a vCPU faults on them. This keeps the footprint of
+/// large, mostly empty guest physical address spaces small.
+///
+/// Pass exits returned by `Vcpu::run` to [LazyMemory::handle_exit] and resume the vCPU if the
+/// fault was resolved.
+#[derive(Debug)]
+pub struct LazyMemory {
+    vm: Arc<Vm>,
+    /// Always `Some` until dropped.
+    mem: Option<HostMemory>,
+    gpa: GPAddr,
+    flags: Memory,
+    chunk: Size,
+    /// Populated chunks, shared between vCPU threads.
+    populated: Mutex<Vec<bool>>,
+}
+
+impl LazyMemory {
+    /// Declares `size` bytes of guest memory at `gpa` populated on demand in `chunk` sized
+    /// pieces.
+    ///
+    /// `size` and `chunk` must be multiples of [PAGE_SIZE], and `size` a multiple of `chunk`.
+    pub fn new(
+        vm: Arc<Vm>,
+        gpa: GPAddr,
+        size: Size,
+        chunk: Size,
+        flags: Memory,
+    ) -> Result<LazyMemory, Error> {
+        crate::validate_mapping(None, gpa, size, Some(flags))?;
+
+        if chunk == 0 || chunk % PAGE_SIZE != 0 || size % chunk != 0 {
+            return Err(Error::InvalidMapping {
+                reason: MappingError::UnalignedSize(chunk),
+            });
+        }
+
+        let mem = HostMemory::new(size)?;
+
+        Ok(LazyMemory {
+            vm,
+            mem: Some(mem),
+            gpa,
+            flags,
+            chunk,
+            populated: Mutex::new(vec![false; (size / chunk) as usize]),
+        })
+    }
+
+    /// Returns the guest physical address of the range.
+    #[inline]
+    pub fn gpa(&self) -> GPAddr {
+        self.gpa
+    }
+
+    /// Returns the size of the range in bytes.
+    #[inline]
+    pub fn size(&self) -> Size {
+        self.memory().size()
+    }
+
+    /// Returns the host memory reserved for the range.
+    ///
+    /// Reading unpopulated parts yields zeroes and doesn't populate them in the guest.
+    #[inline]
+    pub fn memory(&self) -> &HostMemory {
+        self.mem.as_ref().unwrap()
+    }
+
+    /// Returns `true` if `gpa` is within the range.
+    pub fn contains(&self, gpa: GPAddr) -> bool {
+        gpa >= self.gpa && gpa - self.gpa < self.size()
+    }
+
+    /// Returns `true` if the chunk containing `gpa` is mapped into the guest.
+    pub fn is_populated(&self, gpa: GPAddr) -> bool {
+        self.contains(gpa) && self.populated.lock().unwrap()[self.chunk_index(gpa)]
+    }
+
+    /// Returns the number of bytes currently mapped into the guest.
+    pub fn populated_size(&self) -> Size {
+        let populated = self.populated.lock().unwrap();
+        populated.iter().filter(|p| **p).count() as Size * self.chunk
+    }
+
+    /// Populates the chunk containing `gpa`.
+    ///
+    /// Returns `false` if `gpa` is outside of the range or its chunk is already populated, so
+    /// the fault isn't caused by the missing mapping (e.g. a write to a read-only range) and
+    /// must be handled elsewhere.
+    pub fn handle_fault(&self, gpa: GPAddr) -> Result<bool, Error> {
+        if !self.contains(gpa) {
+            return Ok(false);
+        }
+
+        let index = self.chunk_index(gpa);
+        let mut populated = self.populated.lock().unwrap();
+        if populated[index] {
+            return Ok(false);
+        }
+
+        let offset = index as Size * self.chunk;
+        let uva = unsafe { self.memory().as_ptr().add(offset as usize) };
+        self.vm
+            .map(uva, self.gpa + offset, self.chunk, self.flags)?;
+        populated[index] = true;
+        Ok(true)
+    }
+
+    /// Resolves `exit` if it's a guest memory fault within the range.
+    ///
+    /// Returns `true` if the vCPU can be resumed. Faults on chunks populated meanwhile by
+    /// another vCPU are resolved if the access is permitted by the mapping flags.
+    pub fn handle_exit(&self, exit: &Exit) -> Result<bool, Error> {
+        let (gpa, access) = match fault(exit) {
+            Some(fault) => fault,
+            None => return Ok(false),
+        };
+        if self.is_populated(gpa) {
+            return Ok(self.flags.contains(access));
+        }
+        self.handle_fault(gpa)
+    }
+
+    fn chunk_index(&self, gpa: GPAddr) -> usize {
+        ((gpa - self.gpa) / self.chunk) as usize
+    }
+}
+
+impl Drop for LazyMemory {
+    fn drop(&mut self) {
+        let populated = self.populated.get_mut().unwrap();
+        let mut leak = false;
+
+        for (index, _) in populated.iter().enumerate().filter(|(_, p)| **p) {
+            let gpa = self.gpa + index as Size * self.chunk;
+            leak |= self.vm.unmap(gpa, self.chunk).is_err();
+        }
+
+        if leak {
+            // The guest may still access this memory, leaking is the only safe option.
+            std::mem::forget(self.mem.take());
+        }
+    }
+}
+
+/// Returns the faulting guest physical address and access of a guest memory fault exit.
+#[cfg(target_arch = "x86_64")]
+fn fault(exit: &Exit) -> Option<(GPAddr, Memory)> {
+    match *exit {
+        Exit::EptViolation(ept) => Some((ept.gpa, ept.access())),
+        _ => None,
+    }
+}
+
+/// Returns the faulting guest physical address and access of a guest memory fault exit.
+#[cfg(target_arch = "aarch64")]
+fn fault(exit: &Exit) -> Option<(GPAddr, Memory)> {
+    match *exit {
+        Exit::DataAbort { gpa, write, .. } => {
+            let access = if write { Memory::WRITE } else { Memory::READ };
+            Some((gpa, access))
+        }
+        Exit::InstructionAbort { gpa, .. } => Some((gpa, Memory::EXEC)),
+        _ => None,
+    }
+}
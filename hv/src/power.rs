@@ -0,0 +1,297 @@
+//! Host sleep/wake awareness.
+//!
+//! Available with the `power_notifications` feature.
+//!
+//! While the host sleeps, `mach_absolute_time` (which drives the guest TSC and the ARM VTimer)
+//! stops, so guests wake up with clocks lagging behind the wall clock by the duration of the
+//! sleep. [PowerMonitor] subscribes to IOKit system power notifications and invokes
+//! [PowerHandler] hooks.
+//!
+//! [SleepCoordinator] is a handler pausing the registered vCPUs before sleep and making their
+//! guest clocks (the TSC on Intel, the VTimer on Apple Silicon) catch up on wake, through
+//! [VcpuController]. vCPU state can only be modified from the owning thread, so the clocks are
+//! fixed up by `Vcpu::run_loop` before re-entering the guest.
+//!
+//! ```ignore
+//! let sleep = SleepCoordinator::new();
+//! let _monitor = PowerMonitor::start(sleep.clone())?;
+//! // On each vCPU thread:
+//! sleep.register(cpu.controller());
+//! cpu.run_loop(&mut handler)?;
+//! ```
+
+use std::ffi::c_void;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use crate::{Error, VcpuController};
+
+/// How long [SleepCoordinator] waits for each vCPU to park before letting the host sleep.
+const PAUSE_TIMEOUT: Duration = Duration::from_secs(1);
+
+type IoObject = u32;
+type IoConnect = u32;
+type IoReturn = i32;
+type IoNotificationPortRef = *mut c_void;
+type CfRunLoopRef = *mut c_void;
+type CfRunLoopSourceRef = *mut c_void;
+type CfStringRef = *const c_void;
+type IoServiceInterestCallback =
+    extern "C" fn(refcon: *mut c_void, service: IoObject, message_type: u32, argument: *mut c_void);
+
+const IO_MESSAGE_CAN_SYSTEM_SLEEP: u32 = 0xe000_0270;
+const IO_MESSAGE_SYSTEM_WILL_SLEEP: u32 = 0xe000_0280;
+const IO_MESSAGE_SYSTEM_HAS_POWERED_ON: u32 = 0xe000_0300;
+
+#[link(name = "IOKit", kind = "framework")]
+extern "C" {
+    fn IORegisterForSystemPower(
+        refcon: *mut c_void,
+        port: *mut IoNotificationPortRef,
+        callback: IoServiceInterestCallback,
+        notifier: *mut IoObject,
+    ) -> IoConnect;
+    fn IODeregisterForSystemPower(notifier: *mut IoObject) -> IoReturn;
+    fn IOAllowPowerChange(kernel_port: IoConnect, notification_id: isize) -> IoReturn;
+    fn IONotificationPortGetRunLoopSource(port: IoNotificationPortRef) -> CfRunLoopSourceRef;
+    fn IONotificationPortDestroy(port: IoNotificationPortRef);
+    fn IOServiceClose(connect: IoConnect) -> IoReturn;
+}
+
+#[link(name = "CoreFoundation", kind = "framework")]
+extern "C" {
+    static kCFRunLoopDefaultMode: CfStringRef;
+    fn CFRunLoopGetCurrent() -> CfRunLoopRef;
+    fn CFRunLoopAddSource(rl: CfRunLoopRef, source: CfRunLoopSourceRef, mode: CfStringRef);
+    fn CFRunLoopRunInMode(mode: CfStringRef, seconds: f64, return_after_source: u8) -> i32;
+    fn CFRunLoopStop(rl: CfRunLoopRef);
+}
+
+extern "C" {
+    fn mach_continuous_time() -> u64;
+}
+
+/// Information about a completed host sleep.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Wake {
+    /// Time the host spent asleep, i.e. how far guest clocks lag behind.
+    pub slept: Duration,
+    /// Same as `slept`, in mach absolute time units.
+    pub slept_ticks: u64,
+}
+
+/// Hooks invoked on host power events.
+///
+/// Hooks are called from the monitor thread.
+pub trait PowerHandler: Send + 'static {
+    /// The host is about to sleep, sleep proceeds once this returns.
+    fn will_sleep(&mut self) {}
+
+    /// The host has woken up.
+    fn did_wake(&mut self, _wake: Wake) {}
+}
+
+/// [PowerHandler] pausing vCPUs before host sleep and catching up their clocks on wake.
+///
+/// Clones share the registered vCPUs, so one can be passed to [PowerMonitor::start] while
+/// vCPU threads register with another. vCPUs paused by the embedder stay paused on wake.
+#[derive(Clone, Default)]
+pub struct SleepCoordinator {
+    inner: Arc<Mutex<Coordinated>>,
+}
+
+#[derive(Default)]
+struct Coordinated {
+    vcpus: Vec<VcpuController>,
+    /// vCPUs paused for the current sleep, by index.
+    paused: Vec<usize>,
+    handler: Option<Box<dyn PowerHandler>>,
+}
+
+impl SleepCoordinator {
+    pub fn new() -> SleepCoordinator {
+        SleepCoordinator::default()
+    }
+
+    /// Also calls `handler`, after the vCPUs are paused and before they resume.
+    pub fn with_handler(self, handler: impl PowerHandler) -> SleepCoordinator {
+        self.inner.lock().unwrap().handler = Some(Box::new(handler));
+        self
+    }
+
+    /// Adds a vCPU, it must be run with `Vcpu::run_loop`.
+    pub fn register(&self, controller: VcpuController) {
+        self.inner.lock().unwrap().vcpus.push(controller);
+    }
+}
+
+impl PowerHandler for SleepCoordinator {
+    fn will_sleep(&mut self) {
+        let mut inner = self.inner.lock().unwrap();
+        let inner = &mut *inner;
+
+        inner.paused.clear();
+        for (index, vcpu) in inner.vcpus.iter().enumerate() {
+            // Stopped vCPUs and vCPUs destroyed meanwhile don't need pausing.
+            if !vcpu.is_paused() && !vcpu.is_stopped() && vcpu.pause().is_ok() {
+                inner.paused.push(index);
+            }
+        }
+        for index in &inner.paused {
+            inner.vcpus[*index].wait_paused(PAUSE_TIMEOUT);
+        }
+
+        if let Some(handler) = &mut inner.handler {
+            handler.will_sleep();
+        }
+    }
+
+    fn did_wake(&mut self, wake: Wake) {
+        let mut inner = self.inner.lock().unwrap();
+        let inner = &mut *inner;
+
+        if let Some(handler) = &mut inner.handler {
+            handler.did_wake(wake);
+        }
+
+        for vcpu in &inner.vcpus {
+            vcpu.catch_up(wake.slept_ticks);
+        }
+        for index in inner.paused.drain(..) {
+            inner.vcpus[index].resume();
+        }
+    }
+}
+
+struct State {
+    handler: Box<dyn PowerHandler>,
+    root_port: IoConnect,
+    /// `mach_continuous_time - mach_absolute_time` when sleep started.
+    sleep_offset: Option<u64>,
+}
+
+/// Run loop reference that can be stopped from another thread.
+struct RunLoop(CfRunLoopRef);
+
+unsafe impl Send for RunLoop {}
+
+/// Subscription to host power notifications.
+///
+/// Notifications are delivered on a dedicated thread until the monitor is dropped.
+pub struct PowerMonitor {
+    run_loop: RunLoop,
+    stop: Arc<AtomicBool>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl PowerMonitor {
+    /// Registers for system power notifications.
+    pub fn start(handler: impl PowerHandler) -> Result<PowerMonitor, Error> {
+        let (tx, rx) = mpsc::channel();
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread_stop = Arc::clone(&stop);
+
+        let thread = thread::Builder::new()
+            .name("hv-power".to_string())
+            .spawn(move || {
+                let mut state = Box::new(State {
+                    handler: Box::new(handler),
+                    root_port: 0,
+                    sleep_offset: None,
+                });
+
+                let mut port: IoNotificationPortRef = std::ptr::null_mut();
+                let mut notifier: IoObject = 0;
+
+                unsafe {
+                    state.root_port = IORegisterForSystemPower(
+                        &mut *state as *mut State as *mut c_void,
+                        &mut port,
+                        callback,
+                        &mut notifier,
+                    );
+
+                    if state.root_port == 0 {
+                        let _ = tx.send(Err(Error::Unsuccessful));
+                        return;
+                    }
+
+                    let run_loop = CFRunLoopGetCurrent();
+                    CFRunLoopAddSource(
+                        run_loop,
+                        IONotificationPortGetRunLoopSource(port),
+                        kCFRunLoopDefaultMode,
+                    );
+
+                    let _ = tx.send(Ok(RunLoop(run_loop)));
+
+                    // Re-check the stop flag periodically in case `CFRunLoopStop` was called
+                    // before the run loop started.
+                    while !thread_stop.load(Ordering::SeqCst) {
+                        CFRunLoopRunInMode(kCFRunLoopDefaultMode, 1.0, 0);
+                    }
+
+                    IODeregisterForSystemPower(&mut notifier);
+                    IOServiceClose(state.root_port);
+                    IONotificationPortDestroy(port);
+                }
+            })
+            .map_err(|_| Error::NoResources)?;
+
+        let run_loop = rx.recv().map_err(|_| Error::Unsuccessful)??;
+
+        Ok(PowerMonitor {
+            run_loop,
+            stop,
+            thread: Some(thread),
+        })
+    }
+}
+
+impl Drop for PowerMonitor {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        unsafe { CFRunLoopStop(self.run_loop.0) };
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Difference between the continuous clock (includes sleep) and the absolute clock.
+fn sleep_offset() -> u64 {
+    unsafe { mach_continuous_time() }.wrapping_sub(crate::time::now())
+}
+
+extern "C" fn callback(
+    refcon: *mut c_void,
+    _service: IoObject,
+    message: u32,
+    argument: *mut c_void,
+) {
+    let state = unsafe { &mut *(refcon as *mut State) };
+
+    match message {
+        IO_MESSAGE_CAN_SYSTEM_SLEEP => unsafe {
+            IOAllowPowerChange(state.root_port, argument as isize);
+        },
+        IO_MESSAGE_SYSTEM_WILL_SLEEP => {
+            state.handler.will_sleep();
+            state.sleep_offset = Some(sleep_offset());
+            unsafe { IOAllowPowerChange(state.root_port, argument as isize) };
+        }
+        IO_MESSAGE_SYSTEM_HAS_POWERED_ON => {
+            if let Some(before) = state.sleep_offset.take() {
+                let slept_ticks = sleep_offset().saturating_sub(before);
+
+                state.handler.did_wake(Wake {
+                    slept: crate::time::duration_from_ticks(slept_ticks),
+                    slept_ticks,
+                });
+            }
+        }
+        _ => {}
+    }
+}
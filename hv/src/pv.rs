@@ -0,0 +1,362 @@
+//! Scaffold for simple paravirtual host-guest devices.
+//!
+//! Not every experiment needs a full virtio implementation. This module provides the common
+//! plumbing of a minimal paravirtual device: an MMIO window with a fixed register block,
+//! a doorbell register, a guest-allocated shared ring and an interrupt line.
+//!
+//! A custom device only needs to implement the [Device] trait, while [PvDevice] handles
+//! register decoding, ring setup and interrupt status bookkeeping.
+//!
+//! # Register layout
+//!
+//! | Offset | Size | Access | Description                                        |
+//! |--------|------|--------|----------------------------------------------------|
+//! | 0x00   | 4    | R      | Magic value ([MAGIC])                              |
+//! | 0x04   | 4    | R      | Interface version ([VERSION])                      |
+//! | 0x08   | 4    | R      | Device ID ([Device::id])                           |
+//! | 0x0c   | 4    | R      | Device features ([Device::features])               |
+//! | 0x10   | 8    | RW     | Guest physical address of the shared ring          |
+//! | 0x18   | 4    | RW     | Number of ring entries (power of two), 0 disables  |
+//! | 0x1c   | 4    | W      | Doorbell                                           |
+//! | 0x20   | 4    | R      | Interrupt status                                   |
+//! | 0x24   | 4    | W      | Interrupt acknowledge                              |
+//! | 0x100  | -    | RW     | Device specific configuration space                |
+//!
+//! # Shared ring layout
+//!
+//! The ring consists of a 16 bytes header (`producer: u32`, `consumer: u32`, 8 bytes reserved)
+//! followed by `size` entries of type `u64`. The guest publishes entries by incrementing
+//! `producer`, the device consumes them and advances `consumer`.
+//!
+//! Writing the ring size register (re)configures the ring at the currently programmed address,
+//! so the guest must program the address first. The address must be 8 bytes aligned, otherwise
+//! the ring stays disabled.
+
+use std::mem;
+use std::sync::atomic::{fence, Ordering};
+
+use crate::{GPAddr, Size};
+
+/// Magic value reported by the `MAGIC` register ("hvpv" in little endian).
+pub const MAGIC: u32 = 0x7670_7668;
+
+/// Version of the register interface.
+pub const VERSION: u32 = 1;
+
+/// Offset of the device specific configuration space within the MMIO window.
+pub const CONFIG_OFFSET: u64 = 0x100;
+
+/// Common register offsets.
+pub mod regs {
+    pub const MAGIC: u64 = 0x00;
+    pub const VERSION: u64 = 0x04;
+    pub const DEVICE_ID: u64 = 0x08;
+    pub const FEATURES: u64 = 0x0c;
+    pub const RING_ADDR: u64 = 0x10;
+    pub const RING_ADDR_HI: u64 = 0x14;
+    pub const RING_SIZE: u64 = 0x18;
+    pub const DOORBELL: u64 = 0x1c;
+    pub const IRQ_STATUS: u64 = 0x20;
+    pub const IRQ_ACK: u64 = 0x24;
+}
+
+/// Required alignment of the shared ring address.
+const RING_ALIGN: GPAddr = 8;
+
+/// Size in bytes of the shared ring header.
+const RING_HEADER_SIZE: u64 = 16;
+
+/// Guest physical address range occupied by a device.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct MmioWindow {
+    pub base: GPAddr,
+    pub size: Size,
+}
+
+impl MmioWindow {
+    pub fn new(base: GPAddr, size: Size) -> MmioWindow {
+        MmioWindow { base, size }
+    }
+
+    /// Returns `true` if the access of `len` bytes at `gpa` falls into the window.
+    pub fn contains(&self, gpa: GPAddr, len: usize) -> bool {
+        gpa >= self.base
+            && gpa
+                .checked_add(len as u64)
+                .zip(self.base.checked_add(self.size))
+                .map_or(false, |(end, limit)| end <= limit)
+    }
+
+    /// Translates a guest physical address into an offset within the window.
+    pub fn offset(&self, gpa: GPAddr) -> Option<u64> {
+        if self.contains(gpa, 1) {
+            Some(gpa - self.base)
+        } else {
+            None
+        }
+    }
+}
+
+/// Reads `data.len()` bytes at `offset` of a block of 32-bit registers, bytes past the first
+/// 8 read as zeros.
+///
+/// `register` returns the value of the register at an aligned offset. Reads spanning several
+/// registers, e.g. of 8 bytes, are assembled from consecutive registers.
+pub(crate) fn read_registers<F>(offset: u64, data: &mut [u8], register: F)
+where
+    F: Fn(u64) -> u32,
+{
+    let aligned = offset & !0x3;
+    let mut bytes = [0_u8; 12];
+    for (i, chunk) in bytes.chunks_mut(4).enumerate() {
+        chunk.copy_from_slice(&register(aligned + 4 * i as u64).to_le_bytes());
+    }
+
+    let start = (offset & 0x3) as usize;
+    let len = data.len().min(8);
+    data[..len].copy_from_slice(&bytes[start..start + len]);
+    data[len..].iter_mut().for_each(|b| *b = 0);
+}
+
+/// Translation of guest physical addresses into host virtual addresses.
+///
+/// Used by [PvDevice] to locate the shared ring allocated by the guest.
+///
+/// # Safety
+/// A range returned by [Translate::translate] must stay valid for reads and writes for as long
+/// as the implementation is alive, e.g. it must not be unmapped while a [PvDevice] owns it.
+pub unsafe trait Translate {
+    /// Returns the host address backing `size` bytes of guest memory at `gpa`,
+    /// or `None` if the range is not entirely backed by host memory.
+    fn translate(&self, gpa: GPAddr, size: Size) -> Option<*mut u8>;
+}
+
+/// Interrupt line of a device.
+pub trait InterruptLine {
+    /// Asserts (`true`) or deasserts (`false`) the interrupt line.
+    fn set_level(&self, level: bool);
+}
+
+/// Device specific part of a paravirtual device.
+pub trait Device {
+    /// Returns the device ID reported to the guest.
+    fn id(&self) -> u32;
+
+    /// Returns the feature bits reported to the guest.
+    fn features(&self) -> u32 {
+        0
+    }
+
+    /// Called when the guest writes the doorbell register.
+    ///
+    /// The shared ring is provided if the guest has configured one.
+    /// Returns interrupt status bits to raise, 0 means no interrupt.
+    fn doorbell(&mut self, value: u32, ring: Option<&mut Ring>) -> u32;
+
+    /// Reads from the device specific configuration space.
+    fn read_config(&mut self, _offset: u64, data: &mut [u8]) {
+        data.iter_mut().for_each(|b| *b = 0);
+    }
+
+    /// Writes to the device specific configuration space.
+    fn write_config(&mut self, _offset: u64, _data: &[u8]) {}
+}
+
+/// Shared ring of `u64` entries residing in guest memory.
+#[derive(Debug)]
+pub struct Ring {
+    ptr: *mut u8,
+    size: u32,
+}
+
+impl Ring {
+    /// Creates a ring over host memory.
+    ///
+    /// # Safety
+    /// `ptr` must point to `16 + size * 8` bytes of memory that remain valid for the lifetime
+    /// of the ring, `size` must be a power of two.
+    pub unsafe fn from_raw(ptr: *mut u8, size: u32) -> Ring {
+        debug_assert!(size.is_power_of_two());
+        Ring { ptr, size }
+    }
+
+    /// Returns the size in bytes of a ring with `entries` entries.
+    pub fn byte_size(entries: u32) -> Size {
+        RING_HEADER_SIZE + entries as u64 * mem::size_of::<u64>() as u64
+    }
+
+    /// Number of entries in the ring.
+    pub fn size(&self) -> u32 {
+        self.size
+    }
+
+    fn producer(&self) -> u32 {
+        unsafe { (self.ptr as *const u32).read_volatile() }
+    }
+
+    fn consumer(&self) -> u32 {
+        unsafe { (self.ptr.add(4) as *const u32).read_volatile() }
+    }
+
+    fn set_consumer(&mut self, value: u32) {
+        unsafe { (self.ptr.add(4) as *mut u32).write_volatile(value) }
+    }
+
+    /// Returns the number of entries published by the guest and not yet consumed.
+    pub fn pending(&self) -> u32 {
+        self.producer().wrapping_sub(self.consumer())
+    }
+
+    /// Consumes the next entry published by the guest.
+    pub fn pop(&mut self) -> Option<u64> {
+        let consumer = self.consumer();
+        if self.producer() == consumer {
+            return None;
+        }
+
+        // Make sure the entry is read after the producer index.
+        fence(Ordering::Acquire);
+
+        let index = (consumer & (self.size - 1)) as usize;
+        let value = unsafe {
+            (self.ptr.add(RING_HEADER_SIZE as usize) as *const u64)
+                .add(index)
+                .read_volatile()
+        };
+
+        fence(Ordering::Release);
+        self.set_consumer(consumer.wrapping_add(1));
+
+        Some(value)
+    }
+}
+
+/// Generic paravirtual device implementing the common register block.
+///
+/// Call [PvDevice::mmio_read] and [PvDevice::mmio_write] from the VMM exit handler
+/// for accesses within [PvDevice::window].
+pub struct PvDevice<D, I, T> {
+    window: MmioWindow,
+    device: D,
+    irq: I,
+    mem: T,
+    ring_addr: GPAddr,
+    ring: Option<Ring>,
+    irq_status: u32,
+}
+
+impl<D: Device, I: InterruptLine, T: Translate> PvDevice<D, I, T> {
+    pub fn new(window: MmioWindow, device: D, irq: I, mem: T) -> PvDevice<D, I, T> {
+        PvDevice {
+            window,
+            device,
+            irq,
+            mem,
+            ring_addr: 0,
+            ring: None,
+            irq_status: 0,
+        }
+    }
+
+    /// Returns the MMIO window occupied by the device.
+    pub fn window(&self) -> MmioWindow {
+        self.window
+    }
+
+    /// Returns a reference to the device implementation.
+    pub fn device(&self) -> &D {
+        &self.device
+    }
+
+    /// Returns a mutable reference to the device implementation.
+    pub fn device_mut(&mut self) -> &mut D {
+        &mut self.device
+    }
+
+    /// Raises interrupt status bits outside of a doorbell write (e.g. from a worker thread
+    /// completing requests).
+    pub fn raise(&mut self, status: u32) {
+        if status != 0 {
+            self.irq_status |= status;
+            self.irq.set_level(true);
+        }
+    }
+
+    /// Handles a guest read of `data.len()` bytes at `gpa`.
+    pub fn mmio_read(&mut self, gpa: GPAddr, data: &mut [u8]) {
+        let offset = match self.window.offset(gpa) {
+            Some(offset) => offset,
+            None => return,
+        };
+
+        if offset >= CONFIG_OFFSET {
+            self.device.read_config(offset - CONFIG_OFFSET, data);
+            return;
+        }
+
+        read_registers(offset, data, |reg| match reg {
+            regs::MAGIC => MAGIC,
+            regs::VERSION => VERSION,
+            regs::DEVICE_ID => self.device.id(),
+            regs::FEATURES => self.device.features(),
+            regs::RING_ADDR => self.ring_addr as u32,
+            regs::RING_ADDR_HI => (self.ring_addr >> 32) as u32,
+            regs::RING_SIZE => self.ring.as_ref().map_or(0, |r| r.size()),
+            regs::IRQ_STATUS => self.irq_status,
+            _ => 0,
+        });
+    }
+
+    /// Handles a guest write of `data.len()` bytes at `gpa`.
+    pub fn mmio_write(&mut self, gpa: GPAddr, data: &[u8]) {
+        let offset = match self.window.offset(gpa) {
+            Some(offset) => offset,
+            None => return,
+        };
+
+        if offset >= CONFIG_OFFSET {
+            self.device.write_config(offset - CONFIG_OFFSET, data);
+            return;
+        }
+
+        let mut bytes = [0_u8; 8];
+        let len = data.len().min(bytes.len());
+        bytes[..len].copy_from_slice(&data[..len]);
+        let value = u64::from_le_bytes(bytes);
+
+        match offset {
+            regs::RING_ADDR => {
+                if len == 8 {
+                    self.ring_addr = value;
+                } else {
+                    self.ring_addr = (self.ring_addr & !0xffff_ffff) | (value & 0xffff_ffff);
+                }
+            }
+            regs::RING_ADDR_HI => self.ring_addr = (self.ring_addr & 0xffff_ffff) | (value << 32),
+            regs::RING_SIZE => self.setup_ring(value as u32),
+            regs::DOORBELL => {
+                let status = self.device.doorbell(value as u32, self.ring.as_mut());
+                self.raise(status);
+            }
+            regs::IRQ_ACK => {
+                self.irq_status &= !(value as u32);
+                if self.irq_status == 0 {
+                    self.irq.set_level(false);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn setup_ring(&mut self, size: u32) {
+        self.ring = None;
+
+        if size == 0 || !size.is_power_of_two() || self.ring_addr % RING_ALIGN != 0 {
+            return;
+        }
+
+        if let Some(ptr) = self.mem.translate(self.ring_addr, Ring::byte_size(size)) {
+            self.ring = Some(unsafe { Ring::from_raw(ptr, size) });
+        }
+    }
+}
@@ -0,0 +1,266 @@
+//! Host capability checks.
+//!
+//! Hypervisor Framework features vary between macOS releases and CPU generations.
+//! Instead of failing deep inside VM setup, declare the features a VMM relies on upfront
+//! and get a structured report of what's missing on this host and which fallbacks apply:
+//!
+//! ```ignore
+//! let report = hv::requirements!(Feature::Gic, Feature::El2).check();
+//! if !report.is_satisfied() {
+//!     eprintln!("{}", report);
+//! }
+//! ```
+//!
+//! There is no feature for an in-kernel local APIC, Hypervisor Framework doesn't provide one
+//! on Intel. VMMs always emulate it, e.g. with `x86::apic::LocalApic` (`apic` feature).
+
+use std::ffi::CStr;
+use std::fmt;
+use std::os::raw::c_char;
+
+use crate::sys;
+
+/// Optional host features.
+#[non_exhaustive]
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub enum Feature {
+    /// Hypervisor Framework support (`kern.hv_support`).
+    Hypervisor,
+    /// Additional guest address spaces (x86, macOS 10.15).
+    Spaces,
+    /// Guest EL2 / nested virtualization (Apple Silicon, macOS 15).
+    El2,
+    /// In-kernel GICv3 interrupt controller (Apple Silicon, macOS 15).
+    Gic,
+    /// Managed MSRs (x86, macOS 12).
+    ManagedMsr,
+}
+
+impl Feature {
+    /// Returns a short name of the feature.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Feature::Hypervisor => "hypervisor",
+            Feature::Spaces => "address spaces",
+            Feature::El2 => "EL2",
+            Feature::Gic => "GIC",
+            Feature::ManagedMsr => "managed MSRs",
+        }
+    }
+
+    /// Returns a description of the fallback a VMM can use if the feature is missing.
+    pub fn fallback(&self) -> Option<&'static str> {
+        match self {
+            Feature::Hypervisor | Feature::El2 => None,
+            Feature::Spaces => Some("use a single address space and remap on context switch"),
+            Feature::Gic => {
+                Some("emulate the interrupt controller and use `set_pending_interrupt`")
+            }
+            Feature::ManagedMsr => Some("use `enable_native_msr` or trap MSR accesses"),
+        }
+    }
+
+    /// Checks whether the feature is available on this host.
+    pub fn status(&self) -> Status {
+        let missing = |reason| Status::Missing {
+            reason,
+            fallback: self.fallback(),
+        };
+
+        if !hv_support() {
+            return missing("Hypervisor Framework is not supported on this host");
+        }
+
+        match self {
+            Feature::Hypervisor => Status::Available,
+            Feature::Spaces => {
+                if !cfg!(target_arch = "x86_64") {
+                    missing("not supported on this architecture")
+                } else if has_symbol(b"hv_vm_space_create\0") {
+                    Status::Available
+                } else {
+                    missing("requires macOS 10.15")
+                }
+            }
+            Feature::El2 => {
+                if !cfg!(target_arch = "aarch64") {
+                    missing("not supported on this architecture")
+                } else if !has_symbol(b"hv_vm_config_get_el2_supported\0") {
+                    missing("requires macOS 15")
+                } else if el2_supported() {
+                    Status::Available
+                } else {
+                    missing("not supported by this CPU")
+                }
+            }
+            Feature::Gic => {
+                if !cfg!(target_arch = "aarch64") {
+                    missing("not supported on this architecture")
+                } else if has_symbol(b"hv_gic_create\0") {
+                    Status::Available
+                } else {
+                    missing("requires macOS 15")
+                }
+            }
+            Feature::ManagedMsr => {
+                if !cfg!(target_arch = "x86_64") {
+                    missing("not supported on this architecture")
+                } else if has_symbol(b"hv_vcpu_enable_managed_msr\0") {
+                    Status::Available
+                } else {
+                    missing("requires macOS 12")
+                }
+            }
+        }
+    }
+}
+
+impl fmt::Display for Feature {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// Availability of a feature on the current host.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Status {
+    Available,
+    Missing {
+        /// Why the feature is not available.
+        reason: &'static str,
+        /// What a VMM can do instead, if anything.
+        fallback: Option<&'static str>,
+    },
+}
+
+impl Status {
+    pub fn is_available(&self) -> bool {
+        matches!(self, Status::Available)
+    }
+}
+
+/// Set of features required by a VMM.
+#[derive(Debug, Default, Clone)]
+pub struct Requirements {
+    features: Vec<Feature>,
+}
+
+impl Requirements {
+    pub fn new() -> Requirements {
+        Requirements::default()
+    }
+
+    /// Adds a required feature.
+    pub fn require(mut self, feature: Feature) -> Requirements {
+        if !self.features.contains(&feature) {
+            self.features.push(feature);
+        }
+        self
+    }
+
+    /// Checks all required features against the current host.
+    pub fn check(&self) -> Report {
+        Report {
+            entries: self.features.iter().map(|f| (*f, f.status())).collect(),
+        }
+    }
+}
+
+/// Declares a set of required [Feature]s.
+///
+/// Expands to a [Requirements] value.
+#[macro_export]
+macro_rules! requirements {
+    ($($feature:expr),* $(,)?) => {
+        $crate::requirements::Requirements::new()$(.require($feature))*
+    };
+}
+
+/// Result of a [Requirements] check.
+#[derive(Debug, Clone)]
+pub struct Report {
+    entries: Vec<(Feature, Status)>,
+}
+
+impl Report {
+    /// Returns `true` if all required features are available.
+    pub fn is_satisfied(&self) -> bool {
+        self.entries.iter().all(|(_, status)| status.is_available())
+    }
+
+    /// Returns the status of every required feature.
+    pub fn entries(&self) -> &[(Feature, Status)] {
+        &self.entries
+    }
+
+    /// Returns the features that are not available on this host.
+    pub fn missing(&self) -> impl Iterator<Item = &(Feature, Status)> {
+        self.entries.iter().filter(|(_, s)| !s.is_available())
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (feature, status) in &self.entries {
+            match status {
+                Status::Available => writeln!(f, "{}: available", feature)?,
+                Status::Missing { reason, fallback } => {
+                    write!(f, "{}: missing ({})", feature, reason)?;
+                    if let Some(fallback) = fallback {
+                        write!(f, ", fallback: {}", fallback)?;
+                    }
+                    writeln!(f)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Queries `kern.hv_support` sysctl.
+fn hv_support() -> bool {
+    let mut value: libc::c_int = 0;
+    let mut size = std::mem::size_of::<libc::c_int>();
+    let name = b"kern.hv_support\0";
+
+    let ret = unsafe {
+        libc::sysctlbyname(
+            name.as_ptr() as *const c_char,
+            &mut value as *mut _ as *mut libc::c_void,
+            &mut size,
+            std::ptr::null_mut(),
+            0,
+        )
+    };
+
+    ret == 0 && value != 0
+}
+
+/// Checks whether the framework exports a symbol (`name` must be nul terminated).
+fn has_symbol(name: &[u8]) -> bool {
+    symbol(name).is_some()
+}
+
+fn symbol(name: &[u8]) -> Option<*mut libc::c_void> {
+    let name = CStr::from_bytes_with_nul(name).ok()?;
+    let ptr = unsafe { libc::dlsym(libc::RTLD_DEFAULT, name.as_ptr()) };
+    if ptr.is_null() {
+        None
+    } else {
+        Some(ptr)
+    }
+}
+
+/// Calls `hv_vm_config_get_el2_supported` if the framework provides it.
+fn el2_supported() -> bool {
+    type GetEl2Supported = unsafe extern "C" fn(*mut bool) -> sys::hv_return_t;
+
+    match symbol(b"hv_vm_config_get_el2_supported\0") {
+        Some(ptr) => {
+            let f: GetEl2Supported = unsafe { std::mem::transmute(ptr) };
+            let mut supported = false;
+            unsafe { f(&mut supported) == 0 && supported }
+        }
+        None => false,
+    }
+}
@@ -0,0 +1,113 @@
+//! Helpers to drive vCPUs.
+
+use std::time::{Duration, Instant};
+
+use crate::Exit;
+
+#[cfg(target_arch = "x86_64")]
+mod cpuid;
+mod handler;
+#[cfg(target_arch = "x86_64")]
+mod msr;
+mod replay;
+mod sched;
+#[cfg(target_arch = "aarch64")]
+mod sysreg;
+mod timer;
+#[cfg(target_arch = "x86_64")]
+pub use cpuid::{CpuidPolicy, CpuidRegs};
+pub(crate) use handler::dispatch;
+pub use handler::ExitHandler;
+#[cfg(target_arch = "aarch64")]
+pub use handler::MmioRequest;
+#[cfg(target_arch = "x86_64")]
+pub use msr::{MsrPolicy, MsrRouter};
+pub use replay::{Divergence, Entry, Event, Log, Recorder, Replayer};
+pub use sched::{Fairness, Scheduler, SchedulerStats};
+#[cfg(target_arch = "aarch64")]
+pub use sysreg::{SysRegPolicy, SysRegRouter};
+pub use timer::TimerWheel;
+
+/// What to do after an exit was handled.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Action {
+    /// Re-enter the guest immediately.
+    Resume,
+    /// Return the exit to the caller.
+    Return,
+}
+
+/// Bounds the work done by a single [Vcpu::run_slice](crate::Vcpu::run_slice) call.
+///
+/// Pathological guests (e.g. MMIO storms) can exit at a very high rate, each exit being
+/// handled and the guest re-entered immediately. The budget makes sure control returns to the
+/// embedder's loop regularly regardless.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Budget {
+    /// Maximum number of exits handled with [Action::Resume] in a row.
+    pub exits: usize,
+    /// Maximum wall clock time spent in the slice, checked after every exit.
+    pub time: Option<Duration>,
+}
+
+impl Budget {
+    /// Budget of at most `exits` consecutive re-entries.
+    pub fn exits(exits: usize) -> Budget {
+        Budget { exits, time: None }
+    }
+
+    /// Additionally limits the wall clock time of a slice.
+    pub fn with_time(mut self, time: Duration) -> Budget {
+        self.time = Some(time);
+        self
+    }
+}
+
+impl Default for Budget {
+    fn default() -> Self {
+        Budget::exits(1024)
+    }
+}
+
+/// Outcome of [Vcpu::run_slice](crate::Vcpu::run_slice).
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Slice {
+    /// The handler returned [Action::Return] for this exit.
+    Exit(Exit),
+    /// The budget was exhausted, the vCPU can be resumed with another slice.
+    Yielded {
+        /// Number of exits handled during the slice.
+        exits: usize,
+    },
+}
+
+/// Tracks budget consumption of a slice.
+pub(crate) struct Meter {
+    budget: Budget,
+    started: Instant,
+    exits: usize,
+}
+
+impl Meter {
+    pub fn new(budget: Budget) -> Meter {
+        Meter {
+            budget,
+            started: Instant::now(),
+            exits: 0,
+        }
+    }
+
+    /// Accounts an exit, returns `false` once the budget is exhausted.
+    pub fn consume(&mut self) -> bool {
+        self.exits += 1;
+        self.exits < self.budget.exits
+            && self
+                .budget
+                .time
+                .map_or(true, |limit| self.started.elapsed() < limit)
+    }
+
+    pub fn exits(&self) -> usize {
+        self.exits
+    }
+}
@@ -0,0 +1,195 @@
+use std::arch::x86_64::__cpuid_count;
+
+use super::handler::skip;
+use super::Action;
+use crate::x86::{Reg, VcpuExt};
+use crate::{Error, Vcpu};
+
+/// `CPUID.01H:ECX.VMX`.
+const ECX_VMX: u32 = 1 << 5;
+
+/// `CPUID.01H:ECX`, reserved for hypervisors to report their presence.
+const ECX_HYPERVISOR: u32 = 1 << 31;
+
+/// First leaf of the hypervisor range.
+const HYPERVISOR_LEAF: u32 = 0x4000_0000;
+
+/// Output registers of `cpuid`.
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub struct CpuidRegs {
+    pub eax: u32,
+    pub ebx: u32,
+    pub ecx: u32,
+    pub edx: u32,
+}
+
+impl CpuidRegs {
+    /// Executes `cpuid` on the host.
+    pub fn host(leaf: u32, subleaf: u32) -> CpuidRegs {
+        let r = unsafe { __cpuid_count(leaf, subleaf) };
+        CpuidRegs {
+            eax: r.eax,
+            ebx: r.ebx,
+            ecx: r.ecx,
+            edx: r.edx,
+        }
+    }
+
+    /// Splits a 12 byte string in three little endian words.
+    fn signature(s: &[u8; 12]) -> (u32, u32, u32) {
+        let word = |i: usize| u32::from_le_bytes([s[i], s[i + 1], s[i + 2], s[i + 3]]);
+        (word(0), word(4), word(8))
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+enum Op {
+    Replace(CpuidRegs),
+    Mask { clear: CpuidRegs, set: CpuidRegs },
+}
+
+impl Op {
+    fn apply(&self, regs: &mut CpuidRegs) {
+        match self {
+            Op::Replace(replacement) => *regs = *replacement,
+            Op::Mask { clear, set } => {
+                regs.eax = (regs.eax & !clear.eax) | set.eax;
+                regs.ebx = (regs.ebx & !clear.ebx) | set.ebx;
+                regs.ecx = (regs.ecx & !clear.ecx) | set.ecx;
+                regs.edx = (regs.edx & !clear.edx) | set.edx;
+            }
+        }
+    }
+}
+
+/// Emulates `cpuid` for [Vcpu::run_loop], see [ExitHandler::cpuid_policy].
+///
+/// Leaves are executed on the host, then the registered overrides of the leaf are applied
+/// in order. The loop writes the result to the guest registers and advances `RIP`.
+///
+/// ```ignore
+/// let policy = CpuidPolicy::new()
+///     .hide_vmx()
+///     .hypervisor_bit()
+///     .hypervisor_leaf(b"MyHypervisor", 0x4000_0001);
+/// ```
+///
+/// [ExitHandler::cpuid_policy]: super::ExitHandler::cpuid_policy
+#[derive(Debug, Default, Clone)]
+pub struct CpuidPolicy {
+    /// Leaf, sub-leaf (`None` for all) and the operation.
+    ops: Vec<(u32, Option<u32>, Op)>,
+}
+
+impl CpuidPolicy {
+    /// Creates a policy passing all leaves through from the host.
+    pub fn new() -> CpuidPolicy {
+        CpuidPolicy::default()
+    }
+
+    /// Reports `regs` for `leaf`, for the given sub-leaf or all of them.
+    pub fn set(mut self, leaf: u32, subleaf: Option<u32>, regs: CpuidRegs) -> CpuidPolicy {
+        self.ops.push((leaf, subleaf, Op::Replace(regs)));
+        self
+    }
+
+    /// Clears the bits in `clear`, then sets the bits in `set` of `leaf`, for the given
+    /// sub-leaf or all of them.
+    pub fn mask(
+        mut self,
+        leaf: u32,
+        subleaf: Option<u32>,
+        clear: CpuidRegs,
+        set: CpuidRegs,
+    ) -> CpuidPolicy {
+        self.ops.push((leaf, subleaf, Op::Mask { clear, set }));
+        self
+    }
+
+    /// Hides VMX support (`CPUID.01H:ECX.VMX`), nested virtualization isn't available.
+    pub fn hide_vmx(self) -> CpuidPolicy {
+        let clear = CpuidRegs {
+            ecx: ECX_VMX,
+            ..CpuidRegs::default()
+        };
+        self.mask(1, None, clear, CpuidRegs::default())
+    }
+
+    /// Sets the hypervisor present bit (`CPUID.01H:ECX[31]`).
+    pub fn hypervisor_bit(self) -> CpuidPolicy {
+        let set = CpuidRegs {
+            ecx: ECX_HYPERVISOR,
+            ..CpuidRegs::default()
+        };
+        self.mask(1, None, CpuidRegs::default(), set)
+    }
+
+    /// Reports a custom CPU vendor string in leaf 0, keeping the maximum leaf.
+    pub fn vendor(self, vendor: &[u8; 12]) -> CpuidPolicy {
+        let (ebx, edx, ecx) = CpuidRegs::signature(vendor);
+        let all = CpuidRegs {
+            eax: 0,
+            ebx: !0,
+            ecx: !0,
+            edx: !0,
+        };
+        let set = CpuidRegs {
+            eax: 0,
+            ebx,
+            ecx,
+            edx,
+        };
+        self.mask(0, None, all, set)
+    }
+
+    /// Reports a hypervisor signature and the maximum hypervisor leaf in leaf `4000_0000h`.
+    pub fn hypervisor_leaf(self, signature: &[u8; 12], max_leaf: u32) -> CpuidPolicy {
+        let (ebx, ecx, edx) = CpuidRegs::signature(signature);
+        let regs = CpuidRegs {
+            eax: max_leaf,
+            ebx,
+            ecx,
+            edx,
+        };
+        self.set(HYPERVISOR_LEAF, None, regs)
+    }
+
+    /// Returns what the guest sees for `leaf` and `subleaf`.
+    pub fn query(&self, leaf: u32, subleaf: u32) -> CpuidRegs {
+        let mut regs = CpuidRegs::host(leaf, subleaf);
+        for (_, _, op) in self
+            .ops
+            .iter()
+            .filter(|(l, s, _)| *l == leaf && s.map_or(true, |s| s == subleaf))
+        {
+            op.apply(&mut regs);
+        }
+        regs
+    }
+
+    /// Emulates the `cpuid` that caused the last exit.
+    pub(crate) fn emulate(&self, vcpu: &Vcpu) -> Result<Action, Error> {
+        let (leaf, subleaf) = guest_leaf(vcpu)?;
+        complete(vcpu, self.query(leaf, subleaf))
+    }
+}
+
+/// Returns the leaf and sub-leaf of the `cpuid` that caused the last exit.
+pub(crate) fn guest_leaf(vcpu: &Vcpu) -> Result<(u32, u32), Error> {
+    let leaf = vcpu.read_register(Reg::RAX)? as u32;
+    let subleaf = vcpu.read_register(Reg::RCX)? as u32;
+    Ok((leaf, subleaf))
+}
+
+/// Completes `cpuid` with `regs`.
+pub(crate) fn complete(vcpu: &Vcpu, regs: CpuidRegs) -> Result<Action, Error> {
+    // 32-bit results zero extend into the 64-bit registers.
+    vcpu.write_registers(&[
+        (Reg::RAX, regs.eax as u64),
+        (Reg::RBX, regs.ebx as u64),
+        (Reg::RCX, regs.ecx as u64),
+        (Reg::RDX, regs.edx as u64),
+    ])?;
+    skip(vcpu)?;
+    Ok(Action::Resume)
+}
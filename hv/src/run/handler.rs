@@ -0,0 +1,313 @@
+use super::Action;
+use crate::{Error, Exit, Vcpu};
+
+#[cfg(target_arch = "aarch64")]
+use super::SysRegRouter;
+#[cfg(target_arch = "x86_64")]
+use super::{CpuidPolicy, MsrRouter};
+#[cfg(target_arch = "x86_64")]
+use crate::x86::{EptViolation, IoExit};
+#[cfg(target_arch = "aarch64")]
+use crate::GPAddr;
+
+/// Guest memory access to be emulated by [ExitHandler::on_mmio].
+#[cfg(target_arch = "aarch64")]
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct MmioRequest {
+    pub gpa: GPAddr,
+    /// Access size in bytes.
+    pub size: u8,
+    pub write: bool,
+    /// Value written by the guest, or to be filled in by the handler for reads.
+    pub data: u64,
+    /// The data abort, for [ExitHandler::on_exit].
+    exit: Exit,
+}
+
+/// Typed callbacks for [Vcpu::run_loop].
+///
+/// Every callback returns whether the loop resumes the guest or returns. The loop takes care
+/// of the architectural bookkeeping around the callbacks, like moving the instruction pointer
+/// past emulated instructions and transferring MMIO or port I/O data to guest registers.
+///
+/// Exits without a dedicated callback are passed to [ExitHandler::on_exit], which stops the
+/// loop unless overridden.
+pub trait ExitHandler {
+    /// Emulates an MMIO access decoded from a data abort.
+    ///
+    /// For reads the handler fills in [MmioRequest::data].
+    #[cfg(target_arch = "aarch64")]
+    fn on_mmio(&mut self, vcpu: &Vcpu, access: &mut MmioRequest) -> Result<Action, Error> {
+        self.on_exit(vcpu, &access.exit)
+    }
+
+    /// Handles an access to unmapped or protected guest memory.
+    ///
+    /// The faulting instruction is not decoded, the handler is responsible for emulating it
+    /// and advancing `RIP`.
+    #[cfg(target_arch = "x86_64")]
+    fn on_mmio(&mut self, vcpu: &Vcpu, ept: EptViolation) -> Result<Action, Error> {
+        self.on_exit(vcpu, &Exit::EptViolation(ept))
+    }
+
+    /// Handles a port I/O instruction.
+    ///
+    /// For `out`, `data` holds the value from `RAX`, for `in` the handler fills it in and it's
+    /// stored in `RAX` with [IoExit::complete]. String instructions must be emulated
+    /// completely by the handler, `data` is unused for them.
+    #[cfg(target_arch = "x86_64")]
+    fn on_io(&mut self, vcpu: &Vcpu, io: IoExit, data: &mut u64) -> Result<Action, Error> {
+        let _ = data;
+        self.on_exit(vcpu, &Exit::Io(io))
+    }
+
+    /// Returns the router emulating `rdmsr` and `wrmsr`, the loop handles these exits
+    /// completely with it. Without a router they're passed to [ExitHandler::on_exit].
+    #[cfg(target_arch = "x86_64")]
+    fn msr_router(&mut self) -> Option<&mut MsrRouter> {
+        None
+    }
+
+    /// Returns the router emulating trapped `mrs` and `msr`, the loop handles these exits with
+    /// it. Without a router, or for registers the router passes on, they're passed to
+    /// [ExitHandler::on_exit].
+    #[cfg(target_arch = "aarch64")]
+    fn sys_reg_router(&mut self) -> Option<&mut SysRegRouter> {
+        None
+    }
+
+    /// Returns the policy emulating `cpuid`, the loop handles these exits completely with it.
+    /// Without a policy they're passed to [ExitHandler::on_exit].
+    #[cfg(target_arch = "x86_64")]
+    fn cpuid_policy(&self) -> Option<&CpuidPolicy> {
+        None
+    }
+
+    /// Handles a hypercall (`vmcall` or `hvc`), arguments are in guest registers.
+    ///
+    /// `imm` is the immediate of `hvc`, always 0 on x86.
+    fn on_hypercall(&mut self, vcpu: &Vcpu, imm: u16) -> Result<Action, Error> {
+        self.on_exit(vcpu, &arch::hypercall_exit(imm))
+    }
+
+    /// Handles `hlt` or `wfi`, i.e. the guest waits for an interrupt.
+    ///
+    /// On x86, resuming leaves the vCPU in `ActivityState::Hlt` until an interrupt is
+    /// injected, e.g. with [IrqQueue::raise](crate::IrqQueue::raise).
+    fn on_halt(&mut self, vcpu: &Vcpu) -> Result<Action, Error> {
+        self.on_exit(vcpu, &arch::HALT_EXIT)
+    }
+
+    /// Handles [Exit::Pause](crate::x86::Exit::Pause), the guest spins waiting for another
+    /// vCPU. Yields the host thread and resumes the guest by default.
+    #[cfg(target_arch = "x86_64")]
+    fn on_pause(&mut self, _vcpu: &Vcpu) -> Result<Action, Error> {
+        std::thread::yield_now();
+        Ok(Action::Resume)
+    }
+
+    /// Handles a single-step exit, after the guest executed one instruction with the monitor
+    /// trap flag (`VcpuExt::enable_mtf`) on x86 or software step on arm64.
+    fn on_step(&mut self, vcpu: &Vcpu) -> Result<Action, Error> {
+        self.on_exit(vcpu, &arch::STEP_EXIT)
+    }
+
+    /// Handles exits caused by the host, e.g. host interrupts, [VcpuHandle::kick] or
+    /// interrupt windows opened for an [IrqQueue](crate::IrqQueue).
+    ///
+    /// Resumes the guest by default.
+    ///
+    /// [VcpuHandle::kick]: crate::VcpuHandle::kick
+    fn on_interrupted(&mut self, _vcpu: &Vcpu) -> Result<Action, Error> {
+        Ok(Action::Resume)
+    }
+
+    /// Handles any other exit, stops the loop by default.
+    fn on_exit(&mut self, _vcpu: &Vcpu, _exit: &Exit) -> Result<Action, Error> {
+        Ok(Action::Return)
+    }
+}
+
+pub(super) use arch::skip;
+pub(super) use arch::STEP_EXIT;
+
+/// Dispatches `exit` to `handler`.
+pub(crate) fn dispatch<H: ExitHandler + ?Sized>(
+    vcpu: &Vcpu,
+    exit: &Exit,
+    handler: &mut H,
+) -> Result<Action, Error> {
+    arch::dispatch(vcpu, exit, handler)
+}
+
+#[cfg(target_arch = "x86_64")]
+mod arch {
+    use super::*;
+    use crate::x86::vmx::{VCpuVmxExt, Vmcs};
+    use crate::x86::{ActivityState, IoDirection, Reg, VcpuExt};
+
+    pub const HALT_EXIT: Exit = Exit::Hlt;
+    pub const STEP_EXIT: Exit = Exit::MonitorTrap;
+
+    pub fn hypercall_exit(_imm: u16) -> Exit {
+        Exit::Vmcall
+    }
+
+    /// Moves `RIP` past the instruction that caused the exit.
+    pub fn skip(vcpu: &Vcpu) -> Result<(), Error> {
+        let len = vcpu.read_vmcs(Vmcs::RO_VMEXIT_INSTR_LEN)?;
+        let rip = vcpu.read_register(Reg::RIP)?;
+        vcpu.write_register(Reg::RIP, rip + len)
+    }
+
+    /// Runs `f` and skips the instruction if the guest is resumed.
+    fn emulate<F>(vcpu: &Vcpu, f: F) -> Result<Action, Error>
+    where
+        F: FnOnce() -> Result<Action, Error>,
+    {
+        let action = f()?;
+        if action == Action::Resume {
+            skip(vcpu)?;
+        }
+        Ok(action)
+    }
+
+    pub fn dispatch<H: ExitHandler + ?Sized>(
+        vcpu: &Vcpu,
+        exit: &Exit,
+        handler: &mut H,
+    ) -> Result<Action, Error> {
+        match *exit {
+            Exit::Irq | Exit::IrqWindow | Exit::NmiWindow => handler.on_interrupted(vcpu),
+            Exit::Hlt => {
+                let action = emulate(vcpu, || handler.on_halt(vcpu))?;
+                // Wait in the processor until an interrupt is injected.
+                if action == Action::Resume && !vcpu.is_event_pending()? {
+                    vcpu.set_activity_state(ActivityState::Hlt)?;
+                }
+                Ok(action)
+            }
+            Exit::MonitorTrap => handler.on_step(vcpu),
+            Exit::Pause => emulate(vcpu, || handler.on_pause(vcpu)),
+            Exit::Init => {
+                vcpu.deliver_init()?;
+                Ok(Action::Resume)
+            }
+            Exit::Sipi { vector } => {
+                vcpu.deliver_sipi(vector)?;
+                Ok(Action::Resume)
+            }
+            Exit::Vmcall => emulate(vcpu, || handler.on_hypercall(vcpu, 0)),
+            Exit::EptViolation(ept) => handler.on_mmio(vcpu, ept),
+            Exit::Cpuid => match handler.cpuid_policy() {
+                Some(policy) => policy.emulate(vcpu),
+                None => handler.on_exit(vcpu, exit),
+            },
+            Exit::Rdmsr { msr } => match handler.msr_router() {
+                Some(router) => router.rdmsr(vcpu, msr),
+                None => handler.on_exit(vcpu, exit),
+            },
+            Exit::Wrmsr { msr, value } => match handler.msr_router() {
+                Some(router) => router.wrmsr(vcpu, msr, value),
+                None => handler.on_exit(vcpu, exit),
+            },
+            Exit::Io(io) if io.string => emulate(vcpu, || handler.on_io(vcpu, io, &mut 0)),
+            Exit::Io(io) => {
+                let mut data = match io.direction {
+                    IoDirection::In => 0,
+                    IoDirection::Out => io.out_data(vcpu)?,
+                };
+
+                let action = handler.on_io(vcpu, io, &mut data)?;
+                if action == Action::Resume {
+                    io.complete(vcpu, data)?;
+                }
+                Ok(action)
+            }
+            _ => handler.on_exit(vcpu, exit),
+        }
+    }
+}
+
+#[cfg(target_arch = "aarch64")]
+mod arch {
+    use super::*;
+    use crate::arm64::{self, Esr, Reg, VcpuExt};
+
+    pub const HALT_EXIT: Exit = Exit::Wfx { wfe: false };
+    pub const STEP_EXIT: Exit = Exit::SoftwareStep;
+
+    pub fn hypercall_exit(imm: u16) -> Exit {
+        Exit::Hvc { imm }
+    }
+
+    /// Moves `PC` past the trapped instruction.
+    pub fn skip(vcpu: &Vcpu) -> Result<(), Error> {
+        let pc = vcpu.get_reg(Reg::PC)?;
+        vcpu.set_reg(Reg::PC, pc + 4)
+    }
+
+    pub fn dispatch<H: ExitHandler + ?Sized>(
+        vcpu: &Vcpu,
+        exit: &Exit,
+        handler: &mut H,
+    ) -> Result<Action, Error> {
+        match *exit {
+            Exit::Canceled => handler.on_interrupted(vcpu),
+            Exit::SoftwareStep => handler.on_step(vcpu),
+            // `hvc` exits with PC already past the instruction.
+            Exit::Hvc { imm } => handler.on_hypercall(vcpu, imm),
+            Exit::Wfx { wfe: false } => {
+                let action = handler.on_halt(vcpu)?;
+                if action == Action::Resume {
+                    skip(vcpu)?;
+                }
+                Ok(action)
+            }
+            Exit::SysReg { reg, rt, read } => {
+                let action = match handler.sys_reg_router() {
+                    Some(router) => router.emulate(vcpu, reg, rt, read)?,
+                    None => None,
+                };
+                match action {
+                    Some(action) => Ok(action),
+                    None => handler.on_exit(vcpu, exit),
+                }
+            }
+            Exit::DataAbort {
+                gpa,
+                access: Some(_),
+                ..
+            } => {
+                let syndrome = vcpu.exit_info().exception.syndrome;
+                let decoded = match arm64::MmioAccess::from_syndrome(Esr::from(syndrome), gpa) {
+                    Some(decoded) => decoded,
+                    None => return handler.on_exit(vcpu, exit),
+                };
+                let data = if decoded.write {
+                    decoded.value(vcpu)?
+                } else {
+                    0
+                };
+                let mut access = MmioRequest {
+                    gpa,
+                    size: decoded.len,
+                    write: decoded.write,
+                    data,
+                    exit: *exit,
+                };
+
+                let action = handler.on_mmio(vcpu, &mut access)?;
+                if action == Action::Resume {
+                    if decoded.write {
+                        decoded.complete_write(vcpu)?;
+                    } else {
+                        decoded.complete_read(vcpu, access.data)?;
+                    }
+                }
+                Ok(action)
+            }
+            _ => handler.on_exit(vcpu, exit),
+        }
+    }
+}
@@ -0,0 +1,180 @@
+use std::ops::RangeInclusive;
+
+use super::handler::skip;
+use super::Action;
+use crate::x86::{Event, Reg, VcpuExt};
+use crate::{Error, Vcpu};
+
+/// Vector of the general protection fault.
+const GP_VECTOR: u8 = 13;
+
+/// What to do with MSR accesses without a handler.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum MsrPolicy {
+    /// Inject a general protection fault, as for MSRs the CPU doesn't implement.
+    InjectGp,
+    /// Reads return 0, writes are dropped.
+    Ignore,
+    /// Access the guest value of the MSR kept by the framework.
+    Passthrough,
+}
+
+/// Read handler, returns `None` to inject a general protection fault.
+type ReadFn = Box<dyn FnMut(&Vcpu, u32) -> Result<Option<u64>, Error>>;
+/// Write handler, returns `false` to inject a general protection fault.
+type WriteFn = Box<dyn FnMut(&Vcpu, u32, u64) -> Result<bool, Error>>;
+
+enum Route {
+    Handler { read: ReadFn, write: WriteFn },
+    Policy(MsrPolicy),
+}
+
+/// Emulates `rdmsr` and `wrmsr` for [Vcpu::run_loop], see [ExitHandler::msr_router].
+///
+/// Handlers are registered per MSR range, the first matching route handles an access. The
+/// loop takes care of `RCX`, `RAX` and `RDX`, advances `RIP` and injects general protection
+/// faults.
+///
+/// ```ignore
+/// let router = MsrRouter::new(MsrPolicy::InjectGp)
+///     .route(
+///         0x4b56_4d00..=0x4b56_4d04,
+///         |cpu, msr| Ok(Some(kvm.read(msr))),
+///         |cpu, msr, value| Ok(kvm.write(msr, value)),
+///     )
+///     .policy(0x10..=0x10, MsrPolicy::Passthrough);
+/// ```
+///
+/// [ExitHandler::msr_router]: super::ExitHandler::msr_router
+pub struct MsrRouter {
+    routes: Vec<(RangeInclusive<u32>, Route)>,
+    default: MsrPolicy,
+}
+
+impl MsrRouter {
+    /// Creates a router applying `default` to MSRs without a route.
+    pub fn new(default: MsrPolicy) -> MsrRouter {
+        MsrRouter {
+            routes: Vec::new(),
+            default,
+        }
+    }
+
+    /// Routes accesses to `msrs` to the given handlers.
+    pub fn route<R, W>(mut self, msrs: RangeInclusive<u32>, read: R, write: W) -> MsrRouter
+    where
+        R: FnMut(&Vcpu, u32) -> Result<Option<u64>, Error> + 'static,
+        W: FnMut(&Vcpu, u32, u64) -> Result<bool, Error> + 'static,
+    {
+        let route = Route::Handler {
+            read: Box::new(read),
+            write: Box::new(write),
+        };
+        self.routes.push((msrs, route));
+        self
+    }
+
+    /// Applies `policy` to accesses to `msrs`.
+    pub fn policy(mut self, msrs: RangeInclusive<u32>, policy: MsrPolicy) -> MsrRouter {
+        self.routes.push((msrs, Route::Policy(policy)));
+        self
+    }
+
+    fn find(&mut self, msr: u32) -> Option<&mut Route> {
+        self.routes
+            .iter_mut()
+            .find(|(msrs, _)| msrs.contains(&msr))
+            .map(|(_, route)| route)
+    }
+
+    /// Emulates `rdmsr` of `msr`.
+    pub(crate) fn rdmsr(&mut self, vcpu: &Vcpu, msr: u32) -> Result<Action, Error> {
+        let value = self.read(vcpu, msr)?;
+        complete_rdmsr(vcpu, value)
+    }
+
+    /// Emulates `wrmsr` of `value` to `msr`.
+    pub(crate) fn wrmsr(&mut self, vcpu: &Vcpu, msr: u32, value: u64) -> Result<Action, Error> {
+        let handled = self.write(vcpu, msr, value)?;
+        complete_wrmsr(vcpu, handled)
+    }
+
+    /// Returns the value `rdmsr` of `msr` reads, `None` for a general protection fault.
+    pub(crate) fn read(&mut self, vcpu: &Vcpu, msr: u32) -> Result<Option<u64>, Error> {
+        let default = self.default;
+        match self.find(msr) {
+            Some(Route::Handler { read, .. }) => read(vcpu, msr),
+            Some(Route::Policy(policy)) => read_policy(vcpu, msr, *policy),
+            None => read_policy(vcpu, msr, default),
+        }
+    }
+
+    /// Writes `value` to `msr`, returns `false` for a general protection fault.
+    pub(crate) fn write(&mut self, vcpu: &Vcpu, msr: u32, value: u64) -> Result<bool, Error> {
+        let default = self.default;
+        match self.find(msr) {
+            Some(Route::Handler { write, .. }) => write(vcpu, msr, value),
+            Some(Route::Policy(policy)) => write_policy(vcpu, msr, value, *policy),
+            None => write_policy(vcpu, msr, value, default),
+        }
+    }
+}
+
+/// Completes `rdmsr` with the value read, or a general protection fault for `None`.
+pub(crate) fn complete_rdmsr(vcpu: &Vcpu, value: Option<u64>) -> Result<Action, Error> {
+    match value {
+        Some(value) => {
+            vcpu.write_register(Reg::RAX, value & 0xffff_ffff)?;
+            vcpu.write_register(Reg::RDX, value >> 32)?;
+            skip(vcpu)?;
+        }
+        None => inject_gp(vcpu)?,
+    }
+    Ok(Action::Resume)
+}
+
+/// Completes `wrmsr`, with a general protection fault if it wasn't `handled`.
+pub(crate) fn complete_wrmsr(vcpu: &Vcpu, handled: bool) -> Result<Action, Error> {
+    if handled {
+        skip(vcpu)?;
+    } else {
+        inject_gp(vcpu)?;
+    }
+    Ok(Action::Resume)
+}
+
+impl std::fmt::Debug for MsrRouter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("MsrRouter")
+            .field("routes", &self.routes.len())
+            .field("default", &self.default)
+            .finish()
+    }
+}
+
+fn read_policy(vcpu: &Vcpu, msr: u32, policy: MsrPolicy) -> Result<Option<u64>, Error> {
+    Ok(match policy {
+        MsrPolicy::InjectGp => None,
+        MsrPolicy::Ignore => Some(0),
+        MsrPolicy::Passthrough => Some(vcpu.read_msr(msr)?),
+    })
+}
+
+fn write_policy(vcpu: &Vcpu, msr: u32, value: u64, policy: MsrPolicy) -> Result<bool, Error> {
+    Ok(match policy {
+        MsrPolicy::InjectGp => false,
+        MsrPolicy::Ignore => true,
+        MsrPolicy::Passthrough => {
+            vcpu.write_msr(msr, value)?;
+            true
+        }
+    })
+}
+
+/// Injects a general protection fault with error code 0 at the next entry.
+fn inject_gp(vcpu: &Vcpu) -> Result<(), Error> {
+    vcpu.inject_event(Event::Exception {
+        vector: GP_VECTOR,
+        error_code: Some(0),
+    })
+}
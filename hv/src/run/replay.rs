@@ -0,0 +1,997 @@
+use std::io::{self, Read, Write};
+
+use super::handler::STEP_EXIT;
+use super::{Action, ExitHandler};
+use crate::{Error, Exit, GPAddr, Interrupt, IrqQueue, Vcpu};
+
+#[cfg(target_arch = "x86_64")]
+use super::handler::skip;
+#[cfg(target_arch = "aarch64")]
+use super::sysreg;
+#[cfg(target_arch = "aarch64")]
+use super::MmioRequest;
+#[cfg(target_arch = "x86_64")]
+use super::{cpuid, msr, CpuidRegs};
+#[cfg(target_arch = "aarch64")]
+use crate::arm64::Reg;
+#[cfg(target_arch = "x86_64")]
+use crate::x86::{
+    self, state::TSC_AUX, vmx::Reason, EptViolation, GeneralRegs, IoDirection, IoExit, Reg, VcpuExt,
+};
+
+/// Registers an x86 MMIO handler may change while emulating the faulting instruction.
+#[cfg(target_arch = "x86_64")]
+const MMIO_REGS: [Reg; 18] = GeneralRegs::REGS;
+
+/// On arm64 the loop transfers MMIO data itself.
+#[cfg(target_arch = "aarch64")]
+const MMIO_REGS: [Reg; 0] = [];
+
+/// An exit handled by a [Recorder], with the inputs the handler provided to the guest.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Event {
+    /// An MMIO access, `data` is the value read or written.
+    ///
+    /// On x86 the handler emulates the instruction: `data` is always 0 and `regs` holds the
+    /// general purpose registers, `RIP` and `RFLAGS` it changed. `regs` is empty on arm64.
+    Mmio {
+        gpa: GPAddr,
+        write: bool,
+        data: u64,
+        regs: Vec<(Reg, u64)>,
+    },
+    /// Port I/O, `data` is the value read or written.
+    Io {
+        port: u16,
+        input: bool,
+        data: u64,
+    },
+    Hypercall {
+        imm: u16,
+    },
+    Halt,
+    /// `rdmsr` emulated by the [MsrRouter](super::MsrRouter), `value` is `None` if it
+    /// raised a general protection fault.
+    Rdmsr {
+        msr: u32,
+        value: Option<u64>,
+    },
+    /// `wrmsr` emulated by the [MsrRouter](super::MsrRouter), `handled` is `false` if it
+    /// raised a general protection fault.
+    Wrmsr {
+        msr: u32,
+        value: u64,
+        handled: bool,
+    },
+    /// `cpuid` emulated by the [CpuidPolicy](super::CpuidPolicy), `regs` is the result in
+    /// `EAX`, `EBX`, `ECX` and `EDX`.
+    Cpuid {
+        leaf: u32,
+        subleaf: u32,
+        regs: [u32; 4],
+    },
+    /// A system register access emulated by the [SysRegRouter](super::SysRegRouter), `data`
+    /// is the value read or written.
+    SysReg {
+        reg: u16,
+        read: bool,
+        data: u64,
+    },
+    /// `rdtsc` or `rdtscp`, `value` is the TSC read by the guest.
+    Time {
+        value: u64,
+    },
+    /// Any other exit, by [Exit::name].
+    Other {
+        name: String,
+    },
+}
+
+/// An entry of a recording.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Entry {
+    /// An exit and what the handler decided.
+    Exit { event: Event, action: Action },
+    /// An interrupt raised with [Recorder::raise] after the preceding exit.
+    Irq(Interrupt),
+}
+
+/// A recorded vCPU execution, see [Recorder].
+#[derive(Debug, Default, Clone, Eq, PartialEq)]
+pub struct Log {
+    pub entries: Vec<Entry>,
+}
+
+/// Records exits and handler results of [Vcpu::run_loop] for later replay.
+///
+/// Wraps the embedder's [ExitHandler]. Everything the guest observes through exits is
+/// logged: MMIO and port I/O values, the registers changed by x86 MMIO emulation,
+/// hypercalls, halts, the results of the wrapped handler's MSR router, CPUID policy and
+/// system register router, and the decisions of the handler. Interrupts must be raised with
+/// [Recorder::raise] on the vCPU thread, e.g. from a callback, to be recorded at a
+/// deterministic point.
+///
+/// Exits caused by the host ([ExitHandler::on_interrupted]) are not recorded, they don't
+/// affect the guest. Guest visible time is recorded where it exits: on x86 with `rdtsc`
+/// exiting enabled (`VcpuExt::enable_rdtsc_exiting`), `rdtsc` and `rdtscp` are emulated
+/// and logged, and timer registers trapped into a system register router on arm64 are
+/// logged with the router results. The arm64 virtual counter doesn't trap and can't be
+/// recorded.
+pub struct Recorder<H> {
+    inner: H,
+    log: Log,
+}
+
+impl<H> Recorder<H> {
+    pub fn new(inner: H) -> Recorder<H> {
+        Recorder {
+            inner,
+            log: Log::default(),
+        }
+    }
+
+    /// Returns the recording so far.
+    pub fn log(&self) -> &Log {
+        &self.log
+    }
+
+    pub fn inner_mut(&mut self) -> &mut H {
+        &mut self.inner
+    }
+
+    /// Returns the wrapped handler and the recording.
+    pub fn into_parts(self) -> (H, Log) {
+        (self.inner, self.log)
+    }
+
+    /// Raises `irq` on `queue` and records it.
+    pub fn raise(&mut self, queue: &IrqQueue, irq: Interrupt) -> Result<(), Error> {
+        queue.raise(irq)?;
+        self.log.entries.push(Entry::Irq(irq));
+        Ok(())
+    }
+
+    fn record(&mut self, event: Event, action: Result<Action, Error>) -> Result<Action, Error> {
+        let action = action?;
+        self.log.entries.push(Entry::Exit { event, action });
+        Ok(action)
+    }
+}
+
+impl<H: ExitHandler> ExitHandler for Recorder<H> {
+    #[cfg(target_arch = "aarch64")]
+    fn on_mmio(&mut self, vcpu: &Vcpu, access: &mut MmioRequest) -> Result<Action, Error> {
+        let action = self.inner.on_mmio(vcpu, access);
+        let event = Event::Mmio {
+            gpa: access.gpa,
+            write: access.write,
+            data: access.data,
+            regs: Vec::new(),
+        };
+        self.record(event, action)
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    fn on_mmio(&mut self, vcpu: &Vcpu, ept: EptViolation) -> Result<Action, Error> {
+        let before = vcpu.read_registers(&MMIO_REGS)?;
+        let action = self.inner.on_mmio(vcpu, ept);
+        let after = vcpu.read_registers(&MMIO_REGS)?;
+        let regs = MMIO_REGS
+            .iter()
+            .zip(before.iter().zip(&after))
+            .filter(|(_, (before, after))| before != after)
+            .map(|(reg, (_, after))| (*reg, *after))
+            .collect();
+        let event = Event::Mmio {
+            gpa: ept.gpa,
+            write: ept.write,
+            data: 0,
+            regs,
+        };
+        self.record(event, action)
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    fn on_io(&mut self, vcpu: &Vcpu, io: IoExit, data: &mut u64) -> Result<Action, Error> {
+        let action = self.inner.on_io(vcpu, io, data);
+        let event = Event::Io {
+            port: io.port,
+            input: io.direction == IoDirection::In,
+            data: *data,
+        };
+        self.record(event, action)
+    }
+
+    fn on_hypercall(&mut self, vcpu: &Vcpu, imm: u16) -> Result<Action, Error> {
+        let action = self.inner.on_hypercall(vcpu, imm);
+        self.record(Event::Hypercall { imm }, action)
+    }
+
+    fn on_halt(&mut self, vcpu: &Vcpu) -> Result<Action, Error> {
+        let action = self.inner.on_halt(vcpu);
+        self.record(Event::Halt, action)
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    fn on_pause(&mut self, vcpu: &Vcpu) -> Result<Action, Error> {
+        let action = self.inner.on_pause(vcpu);
+        let event = Event::Other {
+            name: Exit::Pause.name().into(),
+        };
+        self.record(event, action)
+    }
+
+    fn on_step(&mut self, vcpu: &Vcpu) -> Result<Action, Error> {
+        let action = self.inner.on_step(vcpu);
+        let event = Event::Other {
+            name: STEP_EXIT.name().into(),
+        };
+        self.record(event, action)
+    }
+
+    fn on_interrupted(&mut self, vcpu: &Vcpu) -> Result<Action, Error> {
+        self.inner.on_interrupted(vcpu)
+    }
+
+    // The routers of the wrapped handler aren't exposed, the loop passes the exits they
+    // emulate to `on_exit` where they're emulated and recorded.
+    fn on_exit(&mut self, vcpu: &Vcpu, exit: &Exit) -> Result<Action, Error> {
+        if let Some(action) = self.record_emulated(vcpu, exit)? {
+            return Ok(action);
+        }
+
+        let action = self.inner.on_exit(vcpu, exit);
+        let event = Event::Other {
+            name: exit.name().into(),
+        };
+        self.record(event, action)
+    }
+}
+
+impl<H: ExitHandler> Recorder<H> {
+    /// Emulates and records an exit handled by the routers of the wrapped handler, or a
+    /// time read. Returns `None` for other exits.
+    #[cfg(target_arch = "x86_64")]
+    fn record_emulated(&mut self, vcpu: &Vcpu, exit: &Exit) -> Result<Option<Action>, Error> {
+        let (event, action) = match *exit {
+            Exit::Cpuid => {
+                let policy = match self.inner.cpuid_policy() {
+                    Some(policy) => policy,
+                    None => return Ok(None),
+                };
+                let (leaf, subleaf) = cpuid::guest_leaf(vcpu)?;
+                let regs = policy.query(leaf, subleaf);
+                let event = Event::Cpuid {
+                    leaf,
+                    subleaf,
+                    regs: [regs.eax, regs.ebx, regs.ecx, regs.edx],
+                };
+                (event, cpuid::complete(vcpu, regs)?)
+            }
+            Exit::Rdmsr { msr } => {
+                let value = match self.inner.msr_router() {
+                    Some(router) => router.read(vcpu, msr)?,
+                    None => return Ok(None),
+                };
+                (
+                    Event::Rdmsr { msr, value },
+                    msr::complete_rdmsr(vcpu, value)?,
+                )
+            }
+            Exit::Wrmsr { msr, value } => {
+                let handled = match self.inner.msr_router() {
+                    Some(router) => router.write(vcpu, msr, value)?,
+                    None => return Ok(None),
+                };
+                let event = Event::Wrmsr {
+                    msr,
+                    value,
+                    handled,
+                };
+                (event, msr::complete_wrmsr(vcpu, handled)?)
+            }
+            _ => match tsc_read(exit) {
+                Some(rdtscp) => {
+                    let value = x86::guest_tsc(vcpu)?;
+                    (Event::Time { value }, complete_tsc(vcpu, rdtscp, value)?)
+                }
+                None => return Ok(None),
+            },
+        };
+        self.log.entries.push(Entry::Exit { event, action });
+        Ok(Some(action))
+    }
+
+    /// Emulates and records an exit handled by the system register router of the wrapped
+    /// handler. Returns `None` for other exits.
+    #[cfg(target_arch = "aarch64")]
+    fn record_emulated(&mut self, vcpu: &Vcpu, exit: &Exit) -> Result<Option<Action>, Error> {
+        let (reg, rt, read) = match *exit {
+            Exit::SysReg { reg, rt, read } => (reg, rt, read),
+            _ => return Ok(None),
+        };
+        let data = match self.inner.sys_reg_router() {
+            Some(router) => match router.access(vcpu, reg, rt, read)? {
+                Some(data) => data,
+                None => return Ok(None),
+            },
+            None => return Ok(None),
+        };
+
+        let action = sysreg::complete(vcpu, rt, read, data)?;
+        let event = Event::SysReg { reg, read, data };
+        self.log.entries.push(Entry::Exit { event, action });
+        Ok(Some(action))
+    }
+}
+
+/// The first difference between a replay and its recording.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Divergence {
+    /// Index of the entry in the [Log].
+    pub index: usize,
+    /// The recorded entry, `None` if the recording ended.
+    pub expected: Option<Entry>,
+    pub actual: Event,
+}
+
+/// Replays a [Log] captured with a [Recorder].
+///
+/// The wrapped handler and its routers still run so device models stay in sync, but values
+/// read by the guest, including MSR, CPUID, system register and TSC reads, and the decisions
+/// returned to the loop come from the recording, recorded interrupts are raised on the given
+/// queue at the same points. Once the guest exits
+/// differently than recorded, or the recording ends, the loop is stopped with
+/// [Action::Return], see [Replayer::divergence].
+///
+/// MMIO accesses on x86 are emulated by the handler: the registers it changed are restored
+/// from the recording, guest memory it wrote isn't.
+pub struct Replayer<H> {
+    inner: H,
+    log: Log,
+    next: usize,
+    queue: Option<IrqQueue>,
+    divergence: Option<Divergence>,
+}
+
+impl<H> Replayer<H> {
+    /// Creates a replayer raising recorded interrupts on `queue`.
+    pub fn new(inner: H, log: Log, queue: Option<IrqQueue>) -> Result<Replayer<H>, Error> {
+        let mut replayer = Replayer {
+            inner,
+            log,
+            next: 0,
+            queue,
+            divergence: None,
+        };
+        replayer.raise_irqs()?;
+        Ok(replayer)
+    }
+
+    /// Returns where the replay diverged from the recording, if it did.
+    pub fn divergence(&self) -> Option<&Divergence> {
+        self.divergence.as_ref()
+    }
+
+    /// Returns `true` if all entries were replayed.
+    pub fn is_finished(&self) -> bool {
+        self.next >= self.log.entries.len()
+    }
+
+    pub fn inner_mut(&mut self) -> &mut H {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> H {
+        self.inner
+    }
+
+    /// Raises the interrupts recorded at the current position.
+    fn raise_irqs(&mut self) -> Result<(), Error> {
+        while let Some(Entry::Irq(irq)) = self.log.entries.get(self.next) {
+            if let Some(queue) = &self.queue {
+                queue.raise(*irq)?;
+            }
+            self.next += 1;
+        }
+        Ok(())
+    }
+
+    /// Consumes the entry for `actual`, returns the recorded event and action.
+    ///
+    /// Returns `None` and stops the replay on divergence.
+    fn expect(&mut self, actual: Event) -> Option<(Event, Action)> {
+        if self.divergence.is_some() {
+            return None;
+        }
+
+        let index = self.next;
+        match self.log.entries.get(index) {
+            Some(Entry::Exit { event, action }) if same_exit(event, &actual) => {
+                self.next += 1;
+                Some((event.clone(), *action))
+            }
+            expected => {
+                self.divergence = Some(Divergence {
+                    index,
+                    expected: expected.cloned(),
+                    actual,
+                });
+                None
+            }
+        }
+    }
+
+    fn replayed(&mut self, action: Action) -> Result<Action, Error> {
+        self.raise_irqs()?;
+        Ok(action)
+    }
+}
+
+impl<H: ExitHandler> Replayer<H> {
+    /// Replays an exit handled by the routers of the wrapped handler, or a time read, with
+    /// the recorded result. The routers still run for their side effects. Returns `None` for
+    /// other exits.
+    #[cfg(target_arch = "x86_64")]
+    fn replay_emulated(&mut self, vcpu: &Vcpu, exit: &Exit) -> Result<Option<Action>, Error> {
+        let actual = match *exit {
+            Exit::Cpuid if self.inner.cpuid_policy().is_some() => {
+                let (leaf, subleaf) = cpuid::guest_leaf(vcpu)?;
+                Event::Cpuid {
+                    leaf,
+                    subleaf,
+                    regs: [0; 4],
+                }
+            }
+            Exit::Rdmsr { msr } if self.inner.msr_router().is_some() => {
+                Event::Rdmsr { msr, value: None }
+            }
+            Exit::Wrmsr { msr, value } if self.inner.msr_router().is_some() => Event::Wrmsr {
+                msr,
+                value,
+                handled: false,
+            },
+            _ if tsc_read(exit).is_some() => Event::Time { value: 0 },
+            _ => return Ok(None),
+        };
+        let (event, action) = match self.expect(actual) {
+            Some(expected) => expected,
+            None => return Ok(Some(Action::Return)),
+        };
+
+        match (*exit, event) {
+            (_, Event::Cpuid { regs, .. }) => {
+                let [eax, ebx, ecx, edx] = regs;
+                cpuid::complete(vcpu, CpuidRegs { eax, ebx, ecx, edx })?;
+            }
+            (Exit::Rdmsr { msr }, Event::Rdmsr { value, .. }) => {
+                if let Some(router) = self.inner.msr_router() {
+                    router.read(vcpu, msr)?;
+                }
+                msr::complete_rdmsr(vcpu, value)?;
+            }
+            (Exit::Wrmsr { msr, value }, Event::Wrmsr { handled, .. }) => {
+                if let Some(router) = self.inner.msr_router() {
+                    router.write(vcpu, msr, value)?;
+                }
+                msr::complete_wrmsr(vcpu, handled)?;
+            }
+            (_, Event::Time { value }) => {
+                let rdtscp = tsc_read(exit) == Some(true);
+                complete_tsc(vcpu, rdtscp, value)?;
+            }
+            _ => {}
+        }
+        self.replayed(action).map(Some)
+    }
+
+    /// Replays an exit handled by the system register router of the wrapped handler with
+    /// the recorded value. The router still runs for its side effects. Returns `None` for
+    /// other exits.
+    #[cfg(target_arch = "aarch64")]
+    fn replay_emulated(&mut self, vcpu: &Vcpu, exit: &Exit) -> Result<Option<Action>, Error> {
+        let (reg, rt, read) = match *exit {
+            Exit::SysReg { reg, rt, read } => (reg, rt, read),
+            _ => return Ok(None),
+        };
+        match self.inner.sys_reg_router() {
+            Some(router) if router.handles(reg) => {}
+            _ => return Ok(None),
+        }
+
+        let actual = Event::SysReg { reg, read, data: 0 };
+        let (event, action) = match self.expect(actual) {
+            Some(expected) => expected,
+            None => return Ok(Some(Action::Return)),
+        };
+        if let Some(router) = self.inner.sys_reg_router() {
+            router.access(vcpu, reg, rt, read)?;
+        }
+        if let Event::SysReg { data, .. } = event {
+            sysreg::complete(vcpu, rt, read, data)?;
+        }
+        self.replayed(action).map(Some)
+    }
+}
+
+/// Returns `Some(true)` for `rdtscp` and `Some(false)` for `rdtsc` exits.
+#[cfg(target_arch = "x86_64")]
+fn tsc_read(exit: &Exit) -> Option<bool> {
+    match *exit {
+        Exit::Other { reason, .. } if reason == Reason::RDTSC as u32 => Some(false),
+        Exit::Other { reason, .. } if reason == Reason::RDTSCP as u32 => Some(true),
+        _ => None,
+    }
+}
+
+/// Completes `rdtsc` or `rdtscp` with the guest TSC `value`.
+#[cfg(target_arch = "x86_64")]
+fn complete_tsc(vcpu: &Vcpu, rdtscp: bool, value: u64) -> Result<Action, Error> {
+    vcpu.write_register(Reg::RAX, value & 0xffff_ffff)?;
+    vcpu.write_register(Reg::RDX, value >> 32)?;
+    if rdtscp {
+        let aux = vcpu.read_msr(TSC_AUX)?;
+        vcpu.write_register(Reg::RCX, aux & 0xffff_ffff)?;
+    }
+    skip(vcpu)?;
+    Ok(Action::Resume)
+}
+
+/// Compares exits ignoring the data provided by the handler.
+fn same_exit(recorded: &Event, actual: &Event) -> bool {
+    match (recorded, actual) {
+        (
+            Event::Mmio { gpa, write, .. },
+            Event::Mmio {
+                gpa: actual_gpa,
+                write: actual_write,
+                ..
+            },
+        ) => gpa == actual_gpa && write == actual_write,
+        (
+            Event::Io { port, input, .. },
+            Event::Io {
+                port: actual_port,
+                input: actual_input,
+                ..
+            },
+        ) => port == actual_port && input == actual_input,
+        (
+            Event::Rdmsr { msr, .. },
+            Event::Rdmsr {
+                msr: actual_msr, ..
+            },
+        )
+        | (
+            Event::Wrmsr { msr, .. },
+            Event::Wrmsr {
+                msr: actual_msr, ..
+            },
+        ) => msr == actual_msr,
+        (
+            Event::Cpuid { leaf, subleaf, .. },
+            Event::Cpuid {
+                leaf: actual_leaf,
+                subleaf: actual_subleaf,
+                ..
+            },
+        ) => leaf == actual_leaf && subleaf == actual_subleaf,
+        (
+            Event::SysReg { reg, read, .. },
+            Event::SysReg {
+                reg: actual_reg,
+                read: actual_read,
+                ..
+            },
+        ) => reg == actual_reg && read == actual_read,
+        (Event::Time { .. }, Event::Time { .. }) => true,
+        _ => recorded == actual,
+    }
+}
+
+impl<H: ExitHandler> ExitHandler for Replayer<H> {
+    #[cfg(target_arch = "aarch64")]
+    fn on_mmio(&mut self, vcpu: &Vcpu, access: &mut MmioRequest) -> Result<Action, Error> {
+        let actual = Event::Mmio {
+            gpa: access.gpa,
+            write: access.write,
+            data: access.data,
+            regs: Vec::new(),
+        };
+        let (event, action) = match self.expect(actual) {
+            Some(expected) => expected,
+            None => return Ok(Action::Return),
+        };
+
+        self.inner.on_mmio(vcpu, access)?;
+        if let Event::Mmio { data, .. } = event {
+            access.data = data;
+        }
+        self.replayed(action)
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    fn on_mmio(&mut self, vcpu: &Vcpu, ept: EptViolation) -> Result<Action, Error> {
+        let actual = Event::Mmio {
+            gpa: ept.gpa,
+            write: ept.write,
+            data: 0,
+            regs: Vec::new(),
+        };
+        let (event, action) = match self.expect(actual) {
+            Some(expected) => expected,
+            None => return Ok(Action::Return),
+        };
+
+        // The registers end up as recorded, whatever the handler does this time.
+        let before = vcpu.read_registers(&MMIO_REGS)?;
+        self.inner.on_mmio(vcpu, ept)?;
+        if let Event::Mmio { regs, .. } = event {
+            let writes: Vec<(Reg, u64)> = MMIO_REGS
+                .iter()
+                .zip(before)
+                .map(|(reg, value)| match regs.iter().find(|(r, _)| r == reg) {
+                    Some(recorded) => *recorded,
+                    None => (*reg, value),
+                })
+                .collect();
+            vcpu.write_registers(&writes)?;
+        }
+        self.replayed(action)
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    fn on_io(&mut self, vcpu: &Vcpu, io: IoExit, data: &mut u64) -> Result<Action, Error> {
+        let actual = Event::Io {
+            port: io.port,
+            input: io.direction == IoDirection::In,
+            data: *data,
+        };
+        let (event, action) = match self.expect(actual) {
+            Some(expected) => expected,
+            None => return Ok(Action::Return),
+        };
+
+        self.inner.on_io(vcpu, io, data)?;
+        if let Event::Io { data: recorded, .. } = event {
+            *data = recorded;
+        }
+        self.replayed(action)
+    }
+
+    fn on_hypercall(&mut self, vcpu: &Vcpu, imm: u16) -> Result<Action, Error> {
+        let (_, action) = match self.expect(Event::Hypercall { imm }) {
+            Some(expected) => expected,
+            None => return Ok(Action::Return),
+        };
+        self.inner.on_hypercall(vcpu, imm)?;
+        self.replayed(action)
+    }
+
+    fn on_halt(&mut self, vcpu: &Vcpu) -> Result<Action, Error> {
+        let (_, action) = match self.expect(Event::Halt) {
+            Some(expected) => expected,
+            None => return Ok(Action::Return),
+        };
+        self.inner.on_halt(vcpu)?;
+        self.replayed(action)
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    fn on_pause(&mut self, vcpu: &Vcpu) -> Result<Action, Error> {
+        let actual = Event::Other {
+            name: Exit::Pause.name().into(),
+        };
+        let (_, action) = match self.expect(actual) {
+            Some(expected) => expected,
+            None => return Ok(Action::Return),
+        };
+        self.inner.on_pause(vcpu)?;
+        self.replayed(action)
+    }
+
+    fn on_step(&mut self, vcpu: &Vcpu) -> Result<Action, Error> {
+        let actual = Event::Other {
+            name: STEP_EXIT.name().into(),
+        };
+        let (_, action) = match self.expect(actual) {
+            Some(expected) => expected,
+            None => return Ok(Action::Return),
+        };
+        self.inner.on_step(vcpu)?;
+        self.replayed(action)
+    }
+
+    fn on_interrupted(&mut self, vcpu: &Vcpu) -> Result<Action, Error> {
+        self.inner.on_interrupted(vcpu)
+    }
+
+    fn on_exit(&mut self, vcpu: &Vcpu, exit: &Exit) -> Result<Action, Error> {
+        if let Some(action) = self.replay_emulated(vcpu, exit)? {
+            return Ok(action);
+        }
+
+        let actual = Event::Other {
+            name: exit.name().into(),
+        };
+        let (_, action) = match self.expect(actual) {
+            Some(expected) => expected,
+            None => return Ok(Action::Return),
+        };
+        self.inner.on_exit(vcpu, exit)?;
+        self.replayed(action)
+    }
+}
+
+mod tag {
+    pub const MMIO: u8 = 0;
+    pub const IO: u8 = 1;
+    pub const HYPERCALL: u8 = 2;
+    pub const HALT: u8 = 3;
+    pub const OTHER: u8 = 4;
+    pub const IRQ: u8 = 5;
+    pub const RDMSR: u8 = 6;
+    pub const WRMSR: u8 = 7;
+    pub const CPUID: u8 = 8;
+    pub const SYS_REG: u8 = 9;
+    pub const TIME: u8 = 10;
+}
+
+impl Log {
+    /// Serializes the recording in a compact little endian format.
+    pub fn write_to<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        w.write_all(&(self.entries.len() as u64).to_le_bytes())?;
+        for entry in &self.entries {
+            match entry {
+                Entry::Exit { event, action } => {
+                    write_event(w, event)?;
+                    let action = match action {
+                        Action::Resume => 0_u8,
+                        Action::Return => 1,
+                    };
+                    w.write_all(&[action])?;
+                }
+                Entry::Irq(irq) => {
+                    w.write_all(&[tag::IRQ])?;
+                    w.write_all(&encode_irq(*irq))?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Deserializes a recording written with [Log::write_to].
+    pub fn read_from<R: Read>(r: &mut R) -> io::Result<Log> {
+        let len = read_u64(r)?;
+        let mut entries = Vec::new();
+        for _ in 0..len {
+            let tag = read_u8(r)?;
+            if tag == tag::IRQ {
+                let mut raw = [0_u8; 2];
+                r.read_exact(&mut raw)?;
+                entries.push(Entry::Irq(decode_irq(raw)?));
+                continue;
+            }
+
+            let event = read_event(r, tag)?;
+            let action = match read_u8(r)? {
+                0 => Action::Resume,
+                1 => Action::Return,
+                _ => return Err(invalid("bad action")),
+            };
+            entries.push(Entry::Exit { event, action });
+        }
+        Ok(Log { entries })
+    }
+}
+
+fn write_event<W: Write>(w: &mut W, event: &Event) -> io::Result<()> {
+    match event {
+        Event::Mmio {
+            gpa,
+            write,
+            data,
+            regs,
+        } => {
+            w.write_all(&[tag::MMIO, *write as u8])?;
+            w.write_all(&gpa.to_le_bytes())?;
+            w.write_all(&data.to_le_bytes())?;
+            w.write_all(&[regs.len() as u8])?;
+            for (reg, value) in regs {
+                w.write_all(&[encode_reg(*reg)?])?;
+                w.write_all(&value.to_le_bytes())?;
+            }
+            Ok(())
+        }
+        Event::Io { port, input, data } => {
+            w.write_all(&[tag::IO, *input as u8])?;
+            w.write_all(&port.to_le_bytes())?;
+            w.write_all(&data.to_le_bytes())
+        }
+        Event::Hypercall { imm } => {
+            w.write_all(&[tag::HYPERCALL])?;
+            w.write_all(&imm.to_le_bytes())
+        }
+        Event::Halt => w.write_all(&[tag::HALT]),
+        Event::Rdmsr { msr, value } => {
+            w.write_all(&[tag::RDMSR, value.is_some() as u8])?;
+            w.write_all(&msr.to_le_bytes())?;
+            w.write_all(&value.unwrap_or(0).to_le_bytes())
+        }
+        Event::Wrmsr {
+            msr,
+            value,
+            handled,
+        } => {
+            w.write_all(&[tag::WRMSR, *handled as u8])?;
+            w.write_all(&msr.to_le_bytes())?;
+            w.write_all(&value.to_le_bytes())
+        }
+        Event::Cpuid {
+            leaf,
+            subleaf,
+            regs,
+        } => {
+            w.write_all(&[tag::CPUID])?;
+            for word in [*leaf, *subleaf].iter().chain(regs) {
+                w.write_all(&word.to_le_bytes())?;
+            }
+            Ok(())
+        }
+        Event::SysReg { reg, read, data } => {
+            w.write_all(&[tag::SYS_REG, *read as u8])?;
+            w.write_all(&reg.to_le_bytes())?;
+            w.write_all(&data.to_le_bytes())
+        }
+        Event::Time { value } => {
+            w.write_all(&[tag::TIME])?;
+            w.write_all(&value.to_le_bytes())
+        }
+        Event::Other { name } => {
+            w.write_all(&[tag::OTHER])?;
+            w.write_all(&(name.len() as u16).to_le_bytes())?;
+            w.write_all(name.as_bytes())
+        }
+    }
+}
+
+fn read_event<R: Read>(r: &mut R, tag: u8) -> io::Result<Event> {
+    Ok(match tag {
+        tag::MMIO => {
+            let write = read_u8(r)? != 0;
+            let gpa = read_u64(r)?;
+            let data = read_u64(r)?;
+            let mut regs = Vec::new();
+            for _ in 0..read_u8(r)? {
+                let reg = decode_reg(read_u8(r)?)?;
+                regs.push((reg, read_u64(r)?));
+            }
+            Event::Mmio {
+                gpa,
+                write,
+                data,
+                regs,
+            }
+        }
+        tag::IO => Event::Io {
+            input: read_u8(r)? != 0,
+            port: read_u16(r)?,
+            data: read_u64(r)?,
+        },
+        tag::HYPERCALL => Event::Hypercall { imm: read_u16(r)? },
+        tag::HALT => Event::Halt,
+        tag::RDMSR => {
+            let valid = read_u8(r)? != 0;
+            let msr = read_u32(r)?;
+            let value = read_u64(r)?;
+            Event::Rdmsr {
+                msr,
+                value: if valid { Some(value) } else { None },
+            }
+        }
+        tag::WRMSR => Event::Wrmsr {
+            handled: read_u8(r)? != 0,
+            msr: read_u32(r)?,
+            value: read_u64(r)?,
+        },
+        tag::CPUID => Event::Cpuid {
+            leaf: read_u32(r)?,
+            subleaf: read_u32(r)?,
+            regs: [read_u32(r)?, read_u32(r)?, read_u32(r)?, read_u32(r)?],
+        },
+        tag::SYS_REG => Event::SysReg {
+            read: read_u8(r)? != 0,
+            reg: read_u16(r)?,
+            data: read_u64(r)?,
+        },
+        tag::TIME => Event::Time {
+            value: read_u64(r)?,
+        },
+        tag::OTHER => {
+            let mut name = vec![0_u8; read_u16(r)? as usize];
+            r.read_exact(&mut name)?;
+            Event::Other {
+                name: String::from_utf8(name).map_err(|_| invalid("bad exit name"))?,
+            }
+        }
+        _ => return Err(invalid("bad entry tag")),
+    })
+}
+
+#[cfg(target_arch = "x86_64")]
+fn encode_irq(irq: Interrupt) -> [u8; 2] {
+    match irq {
+        Interrupt::Nmi => [0, 0],
+        Interrupt::Vector(vector) => [1, vector],
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+fn decode_irq(raw: [u8; 2]) -> io::Result<Interrupt> {
+    match raw {
+        [0, _] => Ok(Interrupt::Nmi),
+        [1, vector] => Ok(Interrupt::Vector(vector)),
+        _ => Err(invalid("bad interrupt")),
+    }
+}
+
+#[cfg(target_arch = "aarch64")]
+fn encode_irq(irq: Interrupt) -> [u8; 2] {
+    match irq {
+        Interrupt::Irq => [0, 0],
+        Interrupt::Fiq => [1, 0],
+    }
+}
+
+#[cfg(target_arch = "aarch64")]
+fn decode_irq(raw: [u8; 2]) -> io::Result<Interrupt> {
+    match raw[0] {
+        0 => Ok(Interrupt::Irq),
+        1 => Ok(Interrupt::Fiq),
+        _ => Err(invalid("bad interrupt")),
+    }
+}
+
+/// Encodes a register of an MMIO event as its index in [MMIO_REGS].
+fn encode_reg(reg: Reg) -> io::Result<u8> {
+    MMIO_REGS
+        .iter()
+        .position(|r| *r == reg)
+        .map(|index| index as u8)
+        .ok_or_else(|| invalid("bad register"))
+}
+
+fn decode_reg(index: u8) -> io::Result<Reg> {
+    MMIO_REGS
+        .get(index as usize)
+        .copied()
+        .ok_or_else(|| invalid("bad register"))
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn read_u8<R: Read>(r: &mut R) -> io::Result<u8> {
+    let mut buf = [0_u8; 1];
+    r.read_exact(&mut buf)?;
+    Ok(buf[0])
+}
+
+fn read_u16<R: Read>(r: &mut R) -> io::Result<u16> {
+    let mut buf = [0_u8; 2];
+    r.read_exact(&mut buf)?;
+    Ok(u16::from_le_bytes(buf))
+}
+
+fn read_u32<R: Read>(r: &mut R) -> io::Result<u32> {
+    let mut buf = [0_u8; 4];
+    r.read_exact(&mut buf)?;
+    Ok(u32::from_le_bytes(buf))
+}
+
+fn read_u64<R: Read>(r: &mut R) -> io::Result<u64> {
+    let mut buf = [0_u8; 8];
+    r.read_exact(&mut buf)?;
+    Ok(u64::from_le_bytes(buf))
+}
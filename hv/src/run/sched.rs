@@ -0,0 +1,223 @@
+use std::cmp::{Ordering, Reverse};
+use std::collections::BinaryHeap;
+use std::time::{Duration, Instant};
+
+use super::timer::{interrupted, DeadlineRunner};
+use super::{Action, Budget, Meter, Slice};
+use crate::{Error, Exit, Vcpu};
+
+/// Time budgets of the two phases of a [Scheduler] iteration.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Fairness {
+    /// Longest time the vCPU runs before timers are checked again.
+    pub vcpu: Duration,
+    /// Longest time spent processing expired timers before the vCPU is re-entered.
+    pub timers: Duration,
+}
+
+impl Default for Fairness {
+    fn default() -> Self {
+        Fairness {
+            vcpu: Duration::from_millis(10),
+            timers: Duration::from_millis(1),
+        }
+    }
+}
+
+/// Counters describing how well a [Scheduler] keeps to its [Fairness] budgets.
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub struct SchedulerStats {
+    pub iterations: u64,
+    /// vCPU slices that ran past their budget, e.g. because of slow exit handlers.
+    pub vcpu_overruns: u64,
+    /// Timer phases that ran past their budget.
+    pub timer_overruns: u64,
+    /// Expired timers left for the next iteration because the timer budget was used up.
+    pub timers_deferred: u64,
+    pub timers_fired: u64,
+    /// Longest vCPU slice.
+    pub max_vcpu: Duration,
+    /// Longest timer phase.
+    pub max_timers: Duration,
+    /// Largest delay between a timer deadline and its callback.
+    pub max_lateness: Duration,
+}
+
+pub(super) struct Timer<T> {
+    pub deadline: Instant,
+    /// Keeps timers with equal deadlines in insertion order.
+    pub seq: u64,
+    pub token: T,
+}
+
+impl<T> PartialEq for Timer<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl<T> Eq for Timer<T> {}
+
+impl<T> PartialOrd for Timer<T> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<T> Ord for Timer<T> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self.deadline, self.seq).cmp(&(other.deadline, other.seq))
+    }
+}
+
+/// Shares a single thread between a vCPU and device timers.
+///
+/// Each [Scheduler::run_iteration] runs the vCPU for at most [Fairness::vcpu] (or until the
+/// next timer is due), then fires expired timers for at most [Fairness::timers]. Both phases
+/// run in every iteration, so neither side can starve the other no matter how many timers
+/// are pending or how often the guest exits.
+///
+/// The vCPU is forced out of the guest at the end of its slice like by a
+/// [TimerWheel](super::TimerWheel), so a guest that doesn't exit can't hold up timers.
+/// [SchedulerStats::vcpu_overruns] counts slices that still ran late, e.g. because an exit
+/// handler was slow.
+pub struct Scheduler<T> {
+    policy: Fairness,
+    timers: BinaryHeap<Reverse<Timer<T>>>,
+    seq: u64,
+    stats: SchedulerStats,
+    runner: DeadlineRunner,
+}
+
+impl<T> Scheduler<T> {
+    pub fn new(policy: Fairness) -> Scheduler<T> {
+        Scheduler {
+            policy,
+            timers: BinaryHeap::new(),
+            seq: 0,
+            stats: SchedulerStats::default(),
+            runner: DeadlineRunner::default(),
+        }
+    }
+
+    /// Arms a timer identified by `token` to fire at `deadline`.
+    pub fn add_timer(&mut self, deadline: Instant, token: T) {
+        self.seq += 1;
+        self.timers.push(Reverse(Timer {
+            deadline,
+            seq: self.seq,
+            token,
+        }));
+    }
+
+    /// Returns the deadline of the next timer, if any.
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.timers.peek().map(|Reverse(t)| t.deadline)
+    }
+
+    /// Returns the number of armed timers.
+    pub fn pending_timers(&self) -> usize {
+        self.timers.len()
+    }
+
+    /// Returns the counters collected so far.
+    pub fn stats(&self) -> &SchedulerStats {
+        &self.stats
+    }
+
+    /// Runs one vCPU phase followed by one timer phase.
+    ///
+    /// Exits are handled with `handler` like in [Vcpu::run_slice], the result of the vCPU
+    /// phase is returned. The exit forcing the vCPU out at the end of its slice isn't passed
+    /// to `handler`. Expired timers are passed to `on_timer`, returning a new deadline
+    /// re-arms the timer.
+    pub fn run_iteration<F, G>(
+        &mut self,
+        vcpu: &Vcpu,
+        mut handler: F,
+        mut on_timer: G,
+    ) -> Result<Slice, Error>
+    where
+        F: FnMut(&Exit) -> Result<Action, Error>,
+        G: FnMut(&mut T) -> Option<Instant>,
+    {
+        self.stats.iterations += 1;
+
+        let started = Instant::now();
+        let limit = match self.next_deadline() {
+            Some(deadline) => self
+                .policy
+                .vcpu
+                .min(deadline.saturating_duration_since(started)),
+            None => self.policy.vcpu,
+        };
+        let end = started + limit;
+        let mut meter = Meter::new(Budget::default().with_time(limit));
+        let slice = loop {
+            let exit = self.runner.run(vcpu, Some(end))?;
+            if interrupted(&exit) && Instant::now() >= end {
+                break Slice::Yielded {
+                    exits: meter.exits(),
+                };
+            }
+            if handler(&exit)? == Action::Return {
+                break Slice::Exit(exit);
+            }
+            if !meter.consume() {
+                break Slice::Yielded {
+                    exits: meter.exits(),
+                };
+            }
+        };
+
+        let elapsed = started.elapsed();
+        self.stats.max_vcpu = self.stats.max_vcpu.max(elapsed);
+        if elapsed > self.policy.vcpu {
+            self.stats.vcpu_overruns += 1;
+        }
+
+        self.fire_timers(&mut on_timer);
+        Ok(slice)
+    }
+
+    /// Fires expired timers until the timer budget is used up, at least one per call.
+    fn fire_timers<G>(&mut self, on_timer: &mut G)
+    where
+        G: FnMut(&mut T) -> Option<Instant>,
+    {
+        let started = Instant::now();
+        let mut fired = 0;
+
+        while let Some(Reverse(next)) = self.timers.peek() {
+            let now = Instant::now();
+            if next.deadline > now {
+                break;
+            }
+
+            if fired > 0 && now.duration_since(started) >= self.policy.timers {
+                self.stats.timers_deferred += self
+                    .timers
+                    .iter()
+                    .filter(|Reverse(t)| t.deadline <= now)
+                    .count() as u64;
+                break;
+            }
+
+            let Reverse(mut timer) = self.timers.pop().unwrap();
+            let lateness = now.duration_since(timer.deadline);
+            self.stats.max_lateness = self.stats.max_lateness.max(lateness);
+
+            if let Some(deadline) = on_timer(&mut timer.token) {
+                self.add_timer(deadline, timer.token);
+            }
+            fired += 1;
+        }
+
+        let elapsed = started.elapsed();
+        self.stats.timers_fired += fired;
+        self.stats.max_timers = self.stats.max_timers.max(elapsed);
+        if elapsed > self.policy.timers {
+            self.stats.timer_overruns += 1;
+        }
+    }
+}
@@ -0,0 +1,159 @@
+use std::ops::RangeInclusive;
+
+use super::handler::skip;
+use super::Action;
+use crate::arm64::{VcpuExt, X_REGS};
+use crate::{Error, Vcpu};
+
+/// `XZR` in the transfer register field.
+const XZR: u8 = 31;
+
+/// What to do with system register accesses without a handler.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum SysRegPolicy {
+    /// Pass the exit to [ExitHandler::on_exit](super::ExitHandler::on_exit).
+    Exit,
+    /// Reads return 0, writes are dropped.
+    Ignore,
+}
+
+/// Read handler, gets the register encoding.
+type ReadFn = Box<dyn FnMut(&Vcpu, u16) -> Result<u64, Error>>;
+/// Write handler, gets the register encoding and the value.
+type WriteFn = Box<dyn FnMut(&Vcpu, u16, u64) -> Result<(), Error>>;
+
+enum Route {
+    Handler { read: ReadFn, write: WriteFn },
+    Policy(SysRegPolicy),
+}
+
+/// Emulates trapped `mrs` and `msr` for [Vcpu::run_loop], see [ExitHandler::sys_reg_router].
+///
+/// Handlers are registered per range of register encodings, in the layout of
+/// [SysReg](crate::arm64::SysReg) values, see [sys_reg](crate::arm64::sys_reg). The first
+/// matching route handles an access. The loop takes care of the transfer register and
+/// advances `PC`.
+///
+/// ```ignore
+/// let cntp_ctl = sys_reg(3, 3, 14, 2, 1);
+/// let router = SysRegRouter::new(SysRegPolicy::Exit)
+///     .route(
+///         cntp_ctl..=cntp_ctl,
+///         |cpu, _| Ok(timer.ctl()),
+///         |cpu, _, value| Ok(timer.set_ctl(value)),
+///     )
+///     .policy(sys_reg(3, 0, 12, 8, 0)..=sys_reg(3, 0, 12, 15, 7), SysRegPolicy::Ignore);
+/// ```
+///
+/// [ExitHandler::sys_reg_router]: super::ExitHandler::sys_reg_router
+pub struct SysRegRouter {
+    routes: Vec<(RangeInclusive<u16>, Route)>,
+    default: SysRegPolicy,
+}
+
+impl SysRegRouter {
+    /// Creates a router applying `default` to registers without a route.
+    pub fn new(default: SysRegPolicy) -> SysRegRouter {
+        SysRegRouter {
+            routes: Vec::new(),
+            default,
+        }
+    }
+
+    /// Routes accesses to `regs` to the given handlers.
+    pub fn route<R, W>(mut self, regs: RangeInclusive<u16>, read: R, write: W) -> SysRegRouter
+    where
+        R: FnMut(&Vcpu, u16) -> Result<u64, Error> + 'static,
+        W: FnMut(&Vcpu, u16, u64) -> Result<(), Error> + 'static,
+    {
+        let route = Route::Handler {
+            read: Box::new(read),
+            write: Box::new(write),
+        };
+        self.routes.push((regs, route));
+        self
+    }
+
+    /// Applies `policy` to accesses to `regs`.
+    pub fn policy(mut self, regs: RangeInclusive<u16>, policy: SysRegPolicy) -> SysRegRouter {
+        self.routes.push((regs, Route::Policy(policy)));
+        self
+    }
+
+    /// Emulates an access to `reg` with transfer register `rt`, returns `None` if the exit is
+    /// passed on by [SysRegPolicy::Exit].
+    pub(crate) fn emulate(
+        &mut self,
+        vcpu: &Vcpu,
+        reg: u16,
+        rt: u8,
+        read: bool,
+    ) -> Result<Option<Action>, Error> {
+        match self.access(vcpu, reg, rt, read)? {
+            Some(value) => complete(vcpu, rt, read, value).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Returns `true` if accesses to `reg` are emulated rather than passed on.
+    pub(crate) fn handles(&self, reg: u16) -> bool {
+        match self.routes.iter().find(|(regs, _)| regs.contains(&reg)) {
+            Some((_, Route::Policy(policy))) => *policy != SysRegPolicy::Exit,
+            Some(_) => true,
+            None => self.default != SysRegPolicy::Exit,
+        }
+    }
+
+    /// Performs an access to `reg` without completing it, returns the value transferred, i.e.
+    /// the value read for `mrs` or written for `msr`, 0 if ignored. Returns `None` if the exit
+    /// is passed on by [SysRegPolicy::Exit].
+    pub(crate) fn access(
+        &mut self,
+        vcpu: &Vcpu,
+        reg: u16,
+        rt: u8,
+        read: bool,
+    ) -> Result<Option<u64>, Error> {
+        let default = self.default;
+        let route = self
+            .routes
+            .iter_mut()
+            .find(|(regs, _)| regs.contains(&reg))
+            .map(|(_, route)| route);
+
+        let value = match route {
+            Some(Route::Handler { read: on_read, .. }) if read => on_read(vcpu, reg)?,
+            Some(Route::Handler { write, .. }) => {
+                let value = if rt == XZR {
+                    0
+                } else {
+                    vcpu.get_reg(X_REGS[rt as usize])?
+                };
+                write(vcpu, reg, value)?;
+                value
+            }
+            Some(Route::Policy(SysRegPolicy::Exit)) => return Ok(None),
+            None if default == SysRegPolicy::Exit => return Ok(None),
+            Some(Route::Policy(SysRegPolicy::Ignore)) | None => 0,
+        };
+        Ok(Some(value))
+    }
+}
+
+/// Completes an access with transfer register `rt`, `value` is stored in it for `mrs`.
+pub(crate) fn complete(vcpu: &Vcpu, rt: u8, read: bool, value: u64) -> Result<Action, Error> {
+    if read && rt != XZR {
+        vcpu.set_reg(X_REGS[rt as usize], value)?;
+    }
+    skip(vcpu)?;
+    Ok(Action::Resume)
+}
+
+impl std::fmt::Debug for SysRegRouter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SysRegRouter")
+            .field("routes", &self.routes.len())
+            .field("default", &self.default)
+            .finish()
+    }
+}
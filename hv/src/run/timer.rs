@@ -0,0 +1,262 @@
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::time::Instant;
+
+use super::sched::Timer;
+use crate::{Error, Exit, Vcpu};
+
+/// Runs a vCPU until the next emulated timer is due.
+///
+/// Device models arm timers (e.g. an emulated local APIC timer or a periodic tick) with
+/// [TimerWheel::arm], [TimerWheel::run] enters the guest with the earliest deadline and
+/// returns [Exit::TimerExpired] once it passed, expired timers are then taken with
+/// [TimerWheel::pop_expired]:
+///
+/// ```ignore
+/// wheel.arm(Instant::now() + period, Tick);
+/// loop {
+///     match wheel.run(&vcpu)? {
+///         Exit::TimerExpired => {
+///             while let Some(Tick) = wheel.pop_expired() {
+///                 irqs.raise(TIMER_IRQ)?;
+///                 wheel.arm(Instant::now() + period, Tick);
+///             }
+///         }
+///         exit => handle(exit)?,
+///     }
+/// }
+/// ```
+///
+/// # Intel
+/// Uses `hv_vcpu_run_until` with the `hv_10_15` feature. Without it, and on Apple Silicon,
+/// a helper thread kicks the vCPU at the deadline.
+pub struct TimerWheel<T> {
+    timers: BinaryHeap<Reverse<Timer<T>>>,
+    seq: u64,
+    runner: DeadlineRunner,
+}
+
+impl<T> Default for TimerWheel<T> {
+    fn default() -> Self {
+        TimerWheel {
+            timers: BinaryHeap::new(),
+            seq: 0,
+            runner: DeadlineRunner::default(),
+        }
+    }
+}
+
+impl<T> TimerWheel<T> {
+    pub fn new() -> TimerWheel<T> {
+        TimerWheel::default()
+    }
+
+    /// Arms a timer identified by `token` to expire at `deadline`.
+    pub fn arm(&mut self, deadline: Instant, token: T) {
+        self.seq += 1;
+        self.timers.push(Reverse(Timer {
+            deadline,
+            seq: self.seq,
+            token,
+        }));
+    }
+
+    /// Returns the deadline of the next timer, if any.
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.timers.peek().map(|Reverse(t)| t.deadline)
+    }
+
+    /// Returns the number of armed timers.
+    pub fn pending(&self) -> usize {
+        self.timers.len()
+    }
+
+    /// Removes and returns the earliest expired timer.
+    pub fn pop_expired(&mut self) -> Option<T> {
+        match self.timers.peek() {
+            Some(Reverse(t)) if t.deadline <= Instant::now() => {
+                self.timers.pop().map(|Reverse(t)| t.token)
+            }
+            _ => None,
+        }
+    }
+
+    /// Runs the vCPU until it exits or the next timer is due.
+    ///
+    /// Returns [Exit::TimerExpired] without entering the guest if a timer already expired.
+    /// Host interrupts and kicks before the deadline are returned as usual.
+    pub fn run(&mut self, vcpu: &Vcpu) -> Result<Exit, Error> {
+        let deadline = match self.next_deadline() {
+            Some(deadline) if deadline <= Instant::now() => return Ok(Exit::TimerExpired),
+            deadline => deadline,
+        };
+
+        let exit = self.runner.run(vcpu, deadline)?;
+        match deadline {
+            Some(deadline) if interrupted(&exit) && Instant::now() >= deadline => {
+                Ok(Exit::TimerExpired)
+            }
+            _ => Ok(exit),
+        }
+    }
+
+    /// Returns when the guest VTimer fires, if it's enabled and not masked.
+    ///
+    /// The VTimer causes [Exit::VTimerActivated] by itself, this is useful to sleep until the
+    /// deadline when the guest waits with `wfi`.
+    #[cfg(target_arch = "aarch64")]
+    pub fn vtimer_deadline(vcpu: &Vcpu) -> Result<Option<Instant>, Error> {
+        use crate::arm64::{SysReg, VcpuExt};
+
+        /// `CNTV_CTL_EL0.ENABLE`.
+        const CTL_ENABLE: u64 = 1 << 0;
+        /// `CNTV_CTL_EL0.IMASK`.
+        const CTL_IMASK: u64 = 1 << 1;
+
+        let ctl = vcpu.get_sys_reg(SysReg::CNTV_CTL_EL0)?;
+        if ctl & (CTL_ENABLE | CTL_IMASK) != CTL_ENABLE || vcpu.vtimer_mask()? {
+            return Ok(None);
+        }
+
+        // The virtual counter is the host counter minus the VTimer offset.
+        let cval = vcpu.get_sys_reg(SysReg::CNTV_CVAL_EL0)?;
+        let deadline = cval.saturating_add(vcpu.vtimer_offset()?);
+        let remaining = deadline.saturating_sub(crate::time::now());
+        Ok(Some(
+            Instant::now() + crate::time::duration_from_ticks(remaining),
+        ))
+    }
+}
+
+/// Enters a vCPU until it exits or a deadline passes, which forces an exit.
+#[derive(Default)]
+pub(super) struct DeadlineRunner {
+    #[cfg(not(all(target_arch = "x86_64", feature = "hv_10_15")))]
+    kicker: Option<kicker::Kicker>,
+}
+
+impl DeadlineRunner {
+    #[cfg(all(target_arch = "x86_64", feature = "hv_10_15"))]
+    pub fn run(&mut self, vcpu: &Vcpu, deadline: Option<Instant>) -> Result<Exit, Error> {
+        use crate::x86::VcpuExt;
+
+        match deadline {
+            Some(deadline) => vcpu.run_until_instant(deadline),
+            None => vcpu.run(),
+        }
+    }
+
+    #[cfg(not(all(target_arch = "x86_64", feature = "hv_10_15")))]
+    pub fn run(&mut self, vcpu: &Vcpu, deadline: Option<Instant>) -> Result<Exit, Error> {
+        let deadline = match deadline {
+            Some(deadline) => deadline,
+            None => return vcpu.run(),
+        };
+
+        if self.kicker.as_ref().map_or(true, |k| k.id() != vcpu.id()) {
+            self.kicker = Some(kicker::Kicker::new(vcpu.handle()));
+        }
+        let kicker = self.kicker.as_ref().unwrap();
+
+        kicker.set(Some(deadline));
+        let exit = vcpu.run();
+        kicker.set(None);
+        exit
+    }
+}
+
+/// Returns `true` if the exit was forced by the host rather than caused by the guest.
+pub(super) fn interrupted(exit: &Exit) -> bool {
+    #[cfg(target_arch = "x86_64")]
+    {
+        matches!(exit, Exit::Irq | Exit::PreemptionTimer)
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    {
+        matches!(exit, Exit::Canceled)
+    }
+}
+
+#[cfg(not(all(target_arch = "x86_64", feature = "hv_10_15")))]
+mod kicker {
+    use std::sync::{Arc, Condvar, Mutex};
+    use std::thread::{self, JoinHandle};
+    use std::time::Instant;
+
+    use crate::vcpu::Id;
+    use crate::VcpuHandle;
+
+    #[derive(Default)]
+    struct State {
+        deadline: Option<Instant>,
+        stop: bool,
+    }
+
+    /// Kicks a vCPU out of `run` once a deadline passes.
+    pub struct Kicker {
+        shared: Arc<(Mutex<State>, Condvar)>,
+        id: Id,
+        thread: Option<JoinHandle<()>>,
+    }
+
+    impl Kicker {
+        pub fn new(handle: VcpuHandle) -> Kicker {
+            let shared = Arc::new((Mutex::new(State::default()), Condvar::new()));
+            let id = handle.id();
+
+            let thread = {
+                let shared = Arc::clone(&shared);
+                thread::spawn(move || {
+                    let (lock, cvar) = &*shared;
+                    let mut state = lock.lock().unwrap();
+                    loop {
+                        if state.stop {
+                            return;
+                        }
+                        state = match state.deadline {
+                            None => cvar.wait(state).unwrap(),
+                            Some(deadline) => {
+                                let now = Instant::now();
+                                if now >= deadline {
+                                    state.deadline = None;
+                                    // The vCPU may be gone, the wheel is dropped soon then.
+                                    let _ = handle.kick();
+                                    continue;
+                                }
+                                cvar.wait_timeout(state, deadline - now).unwrap().0
+                            }
+                        };
+                    }
+                })
+            };
+
+            Kicker {
+                shared,
+                id,
+                thread: Some(thread),
+            }
+        }
+
+        pub fn id(&self) -> Id {
+            self.id
+        }
+
+        pub fn set(&self, deadline: Option<Instant>) {
+            let (lock, cvar) = &*self.shared;
+            lock.lock().unwrap().deadline = deadline;
+            cvar.notify_one();
+        }
+    }
+
+    impl Drop for Kicker {
+        fn drop(&mut self) {
+            let (lock, cvar) = &*self.shared;
+            lock.lock().unwrap().stop = true;
+            cvar.notify_one();
+            if let Some(thread) = self.thread.take() {
+                let _ = thread.join();
+            }
+        }
+    }
+}
@@ -0,0 +1,526 @@
+//! Runtime self test of the crate against the current host.
+//!
+//! [selftest] creates a VM and exercises the main subsystems on the current machine with small
+//! guest stubs, skipping those the host doesn't support: the guest halts, takes an injected
+//! interrupt and exits on a timer. Useful to validate new macOS releases or hardware:
+//!
+//! ```ignore
+//! let report = hv::selftest::selftest()?;
+//! println!("{}", report);
+//! ```
+//!
+//! Only one VM may exist per process, so no other VM can be alive while the test runs.
+
+use std::fmt;
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use crate::memory::GuestMemory;
+use crate::requirements::{Feature, Status};
+use crate::{Error, GPAddr, Memory, Vcpu, Vm, PAGE_SIZE};
+
+/// Guest physical address of the test code page.
+const CODE_GPA: GPAddr = 0x10000;
+
+/// Guest physical address of the test data page.
+const DATA_GPA: GPAddr = 0x20000;
+
+/// Time after which a guest stub that didn't exit is interrupted.
+const WATCHDOG: Duration = Duration::from_secs(1);
+
+/// Subsystems covered by [selftest].
+#[non_exhaustive]
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub enum Subsystem {
+    /// Guest memory map, protect and unmap.
+    Mapping,
+    /// vCPU creation and a trip through the guest.
+    VcpuRun,
+    /// Interrupt injection, taken by the guest.
+    Interrupts,
+    /// Additional guest address spaces.
+    Spaces,
+    /// Guest timer exits, the VMX preemption timer or the VTimer.
+    Timers,
+}
+
+impl fmt::Display for Subsystem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Subsystem::Mapping => "mapping",
+            Subsystem::VcpuRun => "vcpu run",
+            Subsystem::Interrupts => "interrupts",
+            Subsystem::Spaces => "spaces",
+            Subsystem::Timers => "timers",
+        };
+        f.write_str(name)
+    }
+}
+
+/// Result of a single subsystem test.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Outcome {
+    Passed,
+    Failed(String),
+    /// The subsystem is not supported on this host.
+    Skipped(&'static str),
+}
+
+/// Results of [selftest].
+#[derive(Debug, Clone)]
+pub struct SelfTestReport {
+    results: Vec<(Subsystem, Outcome)>,
+}
+
+impl SelfTestReport {
+    /// Returns the outcome of every subsystem.
+    pub fn results(&self) -> &[(Subsystem, Outcome)] {
+        &self.results
+    }
+
+    /// Returns the outcome of the given subsystem.
+    pub fn outcome(&self, subsystem: Subsystem) -> Option<&Outcome> {
+        self.results
+            .iter()
+            .find(|(s, _)| *s == subsystem)
+            .map(|(_, o)| o)
+    }
+
+    /// Returns `true` if no subsystem failed.
+    pub fn passed(&self) -> bool {
+        self.results
+            .iter()
+            .all(|(_, o)| !matches!(o, Outcome::Failed(_)))
+    }
+}
+
+impl fmt::Display for SelfTestReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (subsystem, outcome) in &self.results {
+            match outcome {
+                Outcome::Passed => writeln!(f, "{}: ok", subsystem)?,
+                Outcome::Failed(reason) => writeln!(f, "{}: FAILED ({})", subsystem, reason)?,
+                Outcome::Skipped(reason) => writeln!(f, "{}: skipped ({})", subsystem, reason)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+type TestResult = Result<(), String>;
+
+fn outcome(result: TestResult) -> Outcome {
+    match result {
+        Ok(()) => Outcome::Passed,
+        Err(reason) => Outcome::Failed(reason),
+    }
+}
+
+fn check(cond: bool, what: &str) -> TestResult {
+    if cond {
+        Ok(())
+    } else {
+        Err(what.to_string())
+    }
+}
+
+fn err(e: Error) -> String {
+    e.to_string()
+}
+
+fn unexpected<E: fmt::Debug>(exit: E) -> String {
+    format!("unexpected exit {:?}", exit)
+}
+
+/// Runs `f`, interrupting `cpu` if it doesn't return within [WATCHDOG], so a guest stub stuck
+/// in a loop fails its test instead of hanging.
+fn with_watchdog<T>(cpu: &Vcpu, f: impl FnOnce() -> T) -> T {
+    let handle = cpu.handle();
+    let (done, wait) = mpsc::channel::<()>();
+    let watchdog = thread::spawn(move || {
+        if let Err(RecvTimeoutError::Timeout) = wait.recv_timeout(WATCHDOG) {
+            let _ = handle.interrupt();
+        }
+    });
+
+    let result = f();
+    drop(done);
+    let _ = watchdog.join();
+    result
+}
+
+/// Runs the self test on the current thread.
+///
+/// Returns [Error::VmExists] if a VM already exists in this process.
+pub fn selftest() -> Result<SelfTestReport, Error> {
+    let all = [
+        Subsystem::Mapping,
+        Subsystem::VcpuRun,
+        Subsystem::Interrupts,
+        Subsystem::Spaces,
+        Subsystem::Timers,
+    ];
+
+    if let Status::Missing { reason, .. } = Feature::Hypervisor.status() {
+        return Ok(SelfTestReport {
+            results: all.iter().map(|s| (*s, Outcome::Skipped(reason))).collect(),
+        });
+    }
+
+    let vm = Arc::new(Vm::new(arch::options())?);
+    let mut results = Vec::new();
+
+    let mut mem = GuestMemory::new(Arc::clone(&vm));
+    let mapping = test_mapping(&vm, &mut mem).and_then(|()| arch::map_boot(&mut mem).map_err(err));
+    let mapped = mapping.is_ok();
+    results.push((Subsystem::Mapping, outcome(mapping)));
+
+    match Arc::clone(&vm).create_cpu() {
+        Ok(cpu) => {
+            // Guest stubs need the code and data pages.
+            let in_guest = |test: &dyn Fn() -> Outcome| {
+                if mapped {
+                    test()
+                } else {
+                    Outcome::Failed("guest memory is not available".to_string())
+                }
+            };
+            let run = in_guest(&|| outcome(arch::test_run(&cpu, &mem)));
+            results.push((Subsystem::VcpuRun, run));
+            let interrupts = in_guest(&|| outcome(arch::test_interrupts(&cpu, &mem)));
+            results.push((Subsystem::Interrupts, interrupts));
+            results.push((Subsystem::Spaces, arch::test_spaces(&vm)));
+            let timers = in_guest(&|| arch::test_timers(&cpu, &mem));
+            results.push((Subsystem::Timers, timers));
+        }
+        Err(e) => {
+            for s in &all[1..] {
+                results.push((*s, Outcome::Failed(format!("vCPU creation: {}", e))));
+            }
+        }
+    }
+
+    Ok(SelfTestReport { results })
+}
+
+fn test_mapping(vm: &Vm, mem: &mut GuestMemory) -> TestResult {
+    mem.add_code_region(CODE_GPA, PAGE_SIZE).map_err(err)?;
+    mem.add_region(DATA_GPA, PAGE_SIZE, Memory::READ | Memory::WRITE)
+        .map_err(err)?;
+
+    let pattern = [0xa5_u8; 64];
+    let mut readback = [0_u8; 64];
+    mem.write(DATA_GPA, &pattern).map_err(err)?;
+    mem.read(DATA_GPA, &mut readback).map_err(err)?;
+    check(pattern == readback, "guest memory readback mismatch")?;
+
+    vm.protect(DATA_GPA, PAGE_SIZE, Memory::READ).map_err(err)?;
+    vm.protect(DATA_GPA, PAGE_SIZE, Memory::READ | Memory::WRITE)
+        .map_err(err)?;
+
+    let layout = vm.dump_layout();
+    check(
+        layout.regions.iter().any(|r| r.gpa == DATA_GPA),
+        "mapping is missing from the layout",
+    )
+}
+
+#[cfg(target_arch = "x86_64")]
+mod arch {
+    use std::time::Instant;
+
+    use super::*;
+    use crate::x86::boot::{self, CODE_SELECTOR, TABLES_ADDR, TABLES_SIZE};
+    use crate::x86::vmx::{self, Capability, VCpuVmxExt, Vmcs};
+    use crate::x86::{Event, Exit, Reg, VcpuExt, VmOptions};
+
+    /// `hlt`
+    const HLT: u8 = 0xf4;
+    /// `jmp $`
+    const SPIN: &[u8] = &[0xeb, 0xfe];
+
+    /// Offset of the interrupt handler, a `hlt`, in the code page.
+    const HANDLER: GPAddr = 0x10;
+    /// Vector injected by [test_interrupts].
+    const VECTOR: u8 = 0x20;
+    /// Present 64-bit interrupt gate with DPL 0.
+    const INTERRUPT_GATE: u64 = 0x8e;
+    /// Reserved bit 1 and IF, VM entry requires IF to inject an external interrupt.
+    const RFLAGS_IF: u64 = 0x202;
+
+    /// Duration of the preemption timer armed by [test_timers].
+    const TIMER: Duration = Duration::from_millis(1);
+
+    const CPU_BASED_HLT: u64 = 1 << 7;
+    const CPU_BASED_CR8_LOAD: u64 = 1 << 19;
+    const CPU_BASED_CR8_STORE: u64 = 1 << 20;
+
+    pub fn options() -> VmOptions {
+        VmOptions::default()
+    }
+
+    /// Maps the page tables and GDT written by [boot::setup_long_mode].
+    pub fn map_boot(mem: &mut GuestMemory) -> Result<(), Error> {
+        mem.add_region(TABLES_ADDR, TABLES_SIZE, Memory::READ | Memory::WRITE)
+            .map(|_| ())
+    }
+
+    /// Resets the VM-execution controls and puts the vCPU into long mode at `entry`, with the
+    /// stack at the end of the data page.
+    fn enter(cpu: &Vcpu, mem: &GuestMemory, entry: GPAddr) -> Result<(), Error> {
+        cpu.write_vmcs(
+            Vmcs::CTRL_PIN_BASED,
+            vmx::adjust_controls(Capability::PinBased, 0)?,
+        )?;
+        cpu.write_vmcs(
+            Vmcs::CTRL_CPU_BASED,
+            vmx::adjust_controls(
+                Capability::ProcBased,
+                CPU_BASED_HLT | CPU_BASED_CR8_LOAD | CPU_BASED_CR8_STORE,
+            )?,
+        )?;
+        cpu.write_vmcs(
+            Vmcs::CTRL_CPU_BASED2,
+            vmx::adjust_controls(Capability::ProcBased2, 0)?,
+        )?;
+        cpu.write_vmcs(
+            Vmcs::CTRL_VMENTRY_CONTROLS,
+            vmx::adjust_controls(Capability::Entry, 0)?,
+        )?;
+        cpu.write_vmcs(Vmcs::CTRL_EXC_BITMAP, 0xffff_ffff)?;
+
+        boot::setup_long_mode(cpu, mem, entry, DATA_GPA + PAGE_SIZE)
+    }
+
+    pub fn test_run(cpu: &Vcpu, mem: &GuestMemory) -> TestResult {
+        mem.write(CODE_GPA, &[HLT]).map_err(err)?;
+        enter(cpu, mem, CODE_GPA).map_err(err)?;
+
+        match with_watchdog(cpu, || cpu.run()).map_err(err)? {
+            Exit::Hlt => Ok(()),
+            exit => Err(unexpected(exit)),
+        }
+    }
+
+    /// Returns the IDT gate of `handler`.
+    fn gate(handler: GPAddr) -> [u8; 16] {
+        let low = (handler & 0xffff)
+            | (u64::from(CODE_SELECTOR) << 16)
+            | (INTERRUPT_GATE << 40)
+            | ((handler >> 16 & 0xffff) << 48);
+        let mut gate = [0; 16];
+        gate[..8].copy_from_slice(&low.to_le_bytes());
+        gate[8..].copy_from_slice(&(handler >> 32).to_le_bytes());
+        gate
+    }
+
+    /// Injects an external interrupt, the guest halts in its handler.
+    pub fn test_interrupts(cpu: &Vcpu, mem: &GuestMemory) -> TestResult {
+        let handler = CODE_GPA + HANDLER;
+        let mut code = vec![HLT; HANDLER as usize + 1];
+        code[..SPIN.len()].copy_from_slice(SPIN);
+        mem.write(CODE_GPA, &code).map_err(err)?;
+
+        // The IDT at the start of the data page only has the gate of the vector.
+        let mut idt = vec![0; (VECTOR as usize + 1) * 16];
+        idt[VECTOR as usize * 16..].copy_from_slice(&gate(handler));
+        mem.write(DATA_GPA, &idt).map_err(err)?;
+
+        enter(cpu, mem, CODE_GPA).map_err(err)?;
+        cpu.write_vmcs(Vmcs::GUEST_IDTR_BASE, DATA_GPA)
+            .map_err(err)?;
+        cpu.write_vmcs(Vmcs::GUEST_IDTR_LIMIT, idt.len() as u64 - 1)
+            .map_err(err)?;
+        cpu.write_register(Reg::RFLAGS, RFLAGS_IF).map_err(err)?;
+        cpu.inject_event(Event::ExternalInterrupt(VECTOR))
+            .map_err(err)?;
+
+        match with_watchdog(cpu, || cpu.run()).map_err(err)? {
+            Exit::Hlt => {}
+            exit => return Err(unexpected(exit)),
+        }
+        let rip = cpu.read_register(Reg::RIP).map_err(err)?;
+        check(rip == handler, "injected interrupt was not delivered")?;
+
+        // The interrupt frame starts with the interrupted RIP.
+        let rsp = cpu.read_register(Reg::RSP).map_err(err)?;
+        let mut frame = [0; 8];
+        mem.read(rsp, &mut frame).map_err(err)?;
+        check(
+            u64::from_le_bytes(frame) == CODE_GPA,
+            "interrupt frame mismatch",
+        )
+    }
+
+    #[cfg(feature = "hv_10_15")]
+    pub fn test_spaces(vm: &Arc<Vm>) -> Outcome {
+        use crate::memory::HostMemory;
+        use crate::x86::VmExt;
+
+        if let Status::Missing { reason, .. } = Feature::Spaces.status() {
+            return Outcome::Skipped(reason);
+        }
+
+        let result = (|| {
+            let space = Arc::clone(vm).create_space().map_err(err)?;
+            let mem = HostMemory::new(PAGE_SIZE).map_err(err)?;
+            space
+                .map(mem.as_ptr(), DATA_GPA, PAGE_SIZE, Memory::READ)
+                .map_err(err)?;
+            space.unmap(DATA_GPA, PAGE_SIZE).map_err(err)
+        })();
+
+        outcome(result)
+    }
+
+    #[cfg(not(feature = "hv_10_15"))]
+    pub fn test_spaces(_vm: &Arc<Vm>) -> Outcome {
+        Outcome::Skipped("requires the `hv_10_15` feature")
+    }
+
+    /// Arms the VMX preemption timer, the spinning guest must exit with it.
+    pub fn test_timers(cpu: &Vcpu, mem: &GuestMemory) -> Outcome {
+        let result = (|| {
+            mem.write(CODE_GPA, SPIN).map_err(err)?;
+            enter(cpu, mem, CODE_GPA).map_err(err)?;
+            match cpu.arm_preemption_timer(TIMER) {
+                Err(Error::Unsupported) => return Ok(false),
+                result => result.map_err(err)?,
+            }
+
+            // Before the watchdog starts, so its interrupt ends the loop.
+            let deadline = Instant::now() + WATCHDOG;
+            let exit = with_watchdog(cpu, || loop {
+                match cpu.run() {
+                    // Host interrupts.
+                    Ok(Exit::Irq) if Instant::now() < deadline => {}
+                    exit => break exit,
+                }
+            });
+            cpu.disarm_preemption_timer().map_err(err)?;
+            match exit.map_err(err)? {
+                Exit::PreemptionTimer => Ok(true),
+                exit => Err(unexpected(exit)),
+            }
+        })();
+
+        match result {
+            Ok(true) => Outcome::Passed,
+            Ok(false) => Outcome::Skipped("VMX preemption timer not supported"),
+            Err(reason) => Outcome::Failed(reason),
+        }
+    }
+}
+
+#[cfg(target_arch = "aarch64")]
+mod arch {
+    use super::*;
+    use crate::arm64::{Exit, InterruptType, Reg, SysReg, VcpuExt};
+    use crate::vm::Options;
+
+    /// `hvc #0`
+    const HVC_0: [u8; 4] = [0x02, 0x00, 0x00, 0xd4];
+    /// `hvc #1`
+    const HVC_1: [u8; 4] = [0x22, 0x00, 0x00, 0xd4];
+    /// `msr daifclr, #2`, unmasks IRQs.
+    const UNMASK_IRQ: [u8; 4] = [0xff, 0x42, 0x03, 0xd5];
+    /// `b .`
+    const SPIN: [u8; 4] = [0x00, 0x00, 0x00, 0x14];
+
+    /// Offset of the IRQ vector for the current EL with `SP_EL1` in the vector table at the
+    /// start of the code page.
+    const IRQ_VECTOR: usize = 0x280;
+    /// Offset of the code after the vector table.
+    const MAIN: usize = 0x800;
+
+    /// EL1h with all interrupts masked.
+    const CPSR_EL1H_MASKED: u64 = 0x3c5;
+
+    /// `CNTV_CTL_EL0.ENABLE`
+    const CNTV_CTL_ENABLE: u64 = 1;
+
+    pub fn options() -> Options {
+        Options::default()
+    }
+
+    /// Nothing besides the code and data pages is needed.
+    pub fn map_boot(_mem: &mut GuestMemory) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn enter(cpu: &Vcpu, entry: GPAddr) -> Result<(), Error> {
+        cpu.set_reg(Reg::PC, entry)?;
+        cpu.set_reg(Reg::CPSR, CPSR_EL1H_MASKED)
+    }
+
+    pub fn test_run(cpu: &Vcpu, mem: &GuestMemory) -> TestResult {
+        mem.write(CODE_GPA, &HVC_0).map_err(err)?;
+        enter(cpu, CODE_GPA).map_err(err)?;
+
+        match with_watchdog(cpu, || cpu.run()).map_err(err)? {
+            Exit::Hvc { imm: 0 } => Ok(()),
+            exit => Err(unexpected(exit)),
+        }
+    }
+
+    /// Raises an IRQ, the guest unmasks it and calls `hvc #1` from its vector.
+    pub fn test_interrupts(cpu: &Vcpu, mem: &GuestMemory) -> TestResult {
+        let mut code = vec![0; MAIN + 8];
+        code[IRQ_VECTOR..IRQ_VECTOR + 4].copy_from_slice(&HVC_1);
+        code[MAIN..MAIN + 4].copy_from_slice(&UNMASK_IRQ);
+        code[MAIN + 4..].copy_from_slice(&HVC_0);
+        mem.write(CODE_GPA, &code).map_err(err)?;
+
+        cpu.set_sys_reg(SysReg::VBAR_EL1, CODE_GPA).map_err(err)?;
+        enter(cpu, CODE_GPA + MAIN as GPAddr).map_err(err)?;
+        cpu.set_pending_interrupt(InterruptType::IRQ, true)
+            .map_err(err)?;
+        let exit = with_watchdog(cpu, || cpu.run());
+        cpu.set_pending_interrupt(InterruptType::IRQ, false)
+            .map_err(err)?;
+
+        match exit.map_err(err)? {
+            Exit::Hvc { imm: 1 } => {}
+            Exit::Hvc { imm: 0 } => return Err("pending IRQ was not taken".to_string()),
+            exit => return Err(unexpected(exit)),
+        }
+        let elr = cpu.get_sys_reg(SysReg::ELR_EL1).map_err(err)?;
+        check(
+            elr == CODE_GPA + MAIN as GPAddr + 4,
+            "IRQ return address mismatch",
+        )
+    }
+
+    pub fn test_spaces(_vm: &Arc<Vm>) -> Outcome {
+        Outcome::Skipped("not supported on this architecture")
+    }
+
+    /// Programs the VTimer to fire immediately, the spinning guest must exit with it.
+    pub fn test_timers(cpu: &Vcpu, mem: &GuestMemory) -> Outcome {
+        let result = (|| {
+            mem.write(CODE_GPA, &SPIN).map_err(err)?;
+            enter(cpu, CODE_GPA).map_err(err)?;
+
+            cpu.set_vtimer_offset(0x1000).map_err(err)?;
+            cpu.set_vtimer_mask(false).map_err(err)?;
+            // The guest counter is always past 0.
+            cpu.set_sys_reg(SysReg::CNTV_CVAL_EL0, 0).map_err(err)?;
+            cpu.set_sys_reg(SysReg::CNTV_CTL_EL0, CNTV_CTL_ENABLE)
+                .map_err(err)?;
+
+            let exit = with_watchdog(cpu, || cpu.run());
+            cpu.set_sys_reg(SysReg::CNTV_CTL_EL0, 0).map_err(err)?;
+            cpu.set_vtimer_mask(false).map_err(err)?;
+
+            match exit.map_err(err)? {
+                Exit::VTimerActivated => Ok(()),
+                Exit::Canceled => Err("VTimer did not fire".to_string()),
+                exit => Err(unexpected(exit)),
+            }
+        })();
+
+        outcome(result)
+    }
+}
@@ -0,0 +1,28 @@
+//! Conversions between `std::time` and mach absolute time used by the framework.
+
+use std::time::Duration;
+
+fn timebase() -> (u128, u128) {
+    let mut info = libc::mach_timebase_info { numer: 0, denom: 0 };
+    unsafe { libc::mach_timebase_info(&mut info) };
+    (info.numer.max(1) as u128, info.denom.max(1) as u128)
+}
+
+/// Returns the current mach absolute time.
+pub(crate) fn now() -> u64 {
+    unsafe { libc::mach_absolute_time() }
+}
+
+/// Converts a duration to mach absolute time units.
+pub(crate) fn ticks_from_duration(duration: Duration) -> u64 {
+    let (numer, denom) = timebase();
+    let ticks = duration.as_nanos() * denom / numer;
+    ticks.min(u64::MAX as u128) as u64
+}
+
+/// Converts mach absolute time units to a duration.
+pub(crate) fn duration_from_ticks(ticks: u64) -> Duration {
+    let (numer, denom) = timebase();
+    let nanos = ticks as u128 * numer / denom;
+    Duration::from_nanos(nanos.min(u64::MAX as u128) as u64)
+}
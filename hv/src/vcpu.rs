@@ -1,5 +1,34 @@
-use crate::{call, sys, Error, Vm};
-use std::sync::Arc;
+use crate::run::{dispatch, Action, Budget, ExitHandler, Meter, Slice};
+use crate::{call, sys, Error, Exit, VcpuState, Vm};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::marker::PhantomData;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+#[cfg(debug_assertions)]
+use std::thread::{self, ThreadId};
+use std::time::Duration;
+
+mod builder;
+mod cache;
+mod config;
+mod control;
+mod diagnose;
+mod group;
+mod hook;
+mod irq;
+mod policy;
+mod stats;
+pub use builder::VcpuBuilder;
+pub(crate) use cache::Field;
+pub use config::VcpuConfig;
+#[cfg(target_arch = "aarch64")]
+pub use config::{CacheType, FeatureReg};
+pub use control::VcpuController;
+pub use group::VcpuGroup;
+pub use irq::{Interrupt, IrqQueue};
+pub use policy::QosClass;
+pub use stats::{ExitStats, VcpuStats};
 
 /// The type that describes a vCPU ID on Intel.
 #[cfg(target_arch = "x86_64")]
@@ -9,29 +38,96 @@ pub type Id = sys::hv_vcpuid_t;
 #[cfg(target_arch = "aarch64")]
 pub type Id = sys::hv_vcpu_t;
 
+/// A live vCPU in [VCPUS].
+struct Registered {
+    vm: Weak<Vm>,
+    /// Tells apart vCPUs reusing the ID of a destroyed one.
+    generation: u64,
+}
+
+lazy_static::lazy_static! {
+    /// Live vCPUs of the process, for [Vcpu::lookup] and [VcpuHandle].
+    static ref VCPUS: Mutex<HashMap<Id, Registered>> = Mutex::new(HashMap::new());
+}
+
+/// Generation of the next vCPU created.
+static NEXT_GENERATION: AtomicU64 = AtomicU64::new(0);
+
+/// Registers the vCPU `id` of `vm`, returns its generation.
+fn register(id: Id, vm: &Arc<Vm>) -> u64 {
+    let generation = NEXT_GENERATION.fetch_add(1, Ordering::Relaxed);
+    let registered = Registered {
+        vm: Arc::downgrade(vm),
+        generation,
+    };
+    VCPUS.lock().unwrap().insert(id, registered);
+    generation
+}
+
 /// Represents a single virtual CPU.
 ///
 /// [Vcpu] object is not thread safe, all calls must be performed from
-/// the owning thread.
+/// the owning thread. It's `!Send`, so it can't be moved to another thread, use
+/// [Vcpu::handle] to interrupt it from elsewhere. Debug builds additionally assert that the
+/// vCPU is run and dropped on the thread that created it.
+///
+/// Being `!Send` and `!Sync` also keeps register and VMCS accessors from racing with
+/// [Vcpu::run]: no other thread can hold a reference while the owning thread is inside the
+/// guest, so the accessors need no runtime guard and never see `HV_BUSY` for that reason.
+/// Exit callbacks and hooks run on the owning thread between entries.
 pub struct Vcpu {
-    #[allow(dead_code)] // VM instance must outlive CPU in order to deallocate things properly.
+    // VM instance must outlive CPU in order to deallocate things properly.
     vm: Arc<Vm>,
     pub(crate) id: Id,
+    /// Generation of the ID in [VCPUS].
+    generation: u64,
     #[cfg(target_arch = "aarch64")]
     /// The pointer to the vCPU exit information.
     /// The function `hv_vcpu_run` updates this structure on return.
     /// Apple silicon only.
     pub(crate) exit: *const sys::hv_vcpu_exit_t,
+    /// The framework binds vCPUs to the creating thread.
+    _not_send: PhantomData<*const ()>,
+    #[cfg(debug_assertions)]
+    owner: ThreadId,
+    pub(crate) stats: RefCell<stats::Counters>,
+    control: Arc<control::Control>,
+    irqs: Arc<irq::Pending>,
+    cache: RefCell<cache::WriteCache>,
+    hook: RefCell<Option<hook::ExitHook>>,
+    /// Set by [Vcpu::destroy], so drop doesn't destroy the vCPU again.
+    destroyed: bool,
 }
 
 impl Vcpu {
     /// Creates a vCPU instance for the current thread.
     pub(crate) fn new(vm: Arc<Vm>) -> Result<Vcpu, Error> {
+        Vcpu::with_config(vm, None)
+    }
+
+    /// Creates a vCPU instance for the current thread with creation options.
+    pub(crate) fn with_config(vm: Arc<Vm>, config: Option<&VcpuConfig>) -> Result<Vcpu, Error> {
         #[cfg(target_arch = "x86_64")]
         {
+            let flags = config.map_or(0, VcpuConfig::raw);
             let mut id = 0;
-            call!(sys::hv_vcpu_create(&mut id, 0))?;
-            Ok(Vcpu { vm, id })
+            call!(sys::hv_vcpu_create(&mut id, flags))?;
+            vm.add_vcpu(id);
+            let generation = register(id, &vm);
+            Ok(Vcpu {
+                vm,
+                id,
+                generation,
+                _not_send: PhantomData,
+                #[cfg(debug_assertions)]
+                owner: thread::current().id(),
+                stats: RefCell::default(),
+                control: Arc::default(),
+                irqs: Arc::default(),
+                cache: RefCell::default(),
+                hook: RefCell::default(),
+                destroyed: false,
+            })
         }
 
         #[cfg(target_arch = "aarch64")]
@@ -41,15 +137,35 @@ impl Vcpu {
             call!(sys::hv_vcpu_create(
                 &mut id,
                 &mut exit,
-                std::ptr::null_mut()
+                config.map_or(std::ptr::null_mut(), VcpuConfig::raw)
             ))?;
-            Ok(Vcpu { vm, id, exit })
+            vm.add_vcpu(id);
+            let generation = register(id, &vm);
+            let vcpu = Vcpu {
+                vm,
+                id,
+                generation,
+                exit,
+                _not_send: PhantomData,
+                #[cfg(debug_assertions)]
+                owner: thread::current().id(),
+                stats: RefCell::default(),
+                control: Arc::default(),
+                irqs: Arc::default(),
+                cache: RefCell::default(),
+                hook: RefCell::default(),
+                destroyed: false,
+            };
+            if let Some(config) = config {
+                config.apply(&vcpu)?;
+            }
+            Ok(vcpu)
         }
     }
 
     /// Executes a vCPU.
     ///
-    /// Call blocks until the next exit of the vCPU [1].
+    /// Call blocks until the next exit of the vCPU [1] and returns the decoded exit.
     /// The owning thread must call this function.
     ///
     /// # Intel
@@ -61,8 +177,318 @@ impl Vcpu {
     /// As a result, no timer fires until the timer is unmasked with `hv_vcpu_set_vtimer_mask`.
     ///
     /// [1]: https://developer.apple.com/documentation/hypervisor/1441231-hv_vcpu_run
-    pub fn run(&self) -> Result<(), Error> {
-        call!(sys::hv_vcpu_run(self.id))
+    pub fn run(&self) -> Result<Exit, Error> {
+        self.run_inner(None)
+    }
+
+    /// Enters the vCPU, until `deadline` in mach absolute time units if given, and returns
+    /// the decoded exit. Deadlines are only supported on Intel.
+    pub(crate) fn run_inner(&self, deadline: Option<u64>) -> Result<Exit, Error> {
+        self.assert_owner();
+        self.inject_irqs()?;
+        self.flush_cache()?;
+        self.stats.borrow_mut().enter();
+        match deadline {
+            #[cfg(all(target_arch = "x86_64", feature = "hv_10_15"))]
+            Some(deadline) => call!(sys::hv_vcpu_run_until(self.id, deadline))?,
+            _ => call!(sys::hv_vcpu_run(self.id))?,
+        }
+
+        #[cfg(target_arch = "x86_64")]
+        let exit = Exit::decode(self)?;
+
+        #[cfg(target_arch = "aarch64")]
+        let exit = Exit::from(&crate::arm64::VcpuExt::exit_info(self));
+
+        self.exited(&exit)?;
+        Ok(exit)
+    }
+
+    /// Accounts an exit in the statistics and calls the exit hook.
+    pub(crate) fn exited(&self, exit: &Exit) -> Result<(), Error> {
+        self.stats.borrow_mut().exit(exit);
+
+        // Taken out while running, so the callback can replace the hook.
+        let hook = self.hook.borrow_mut().take();
+        if let Some(mut hook) = hook {
+            let result = hook.exit(self, exit);
+            let mut slot = self.hook.borrow_mut();
+            if slot.is_none() {
+                *slot = Some(hook);
+            }
+            result?;
+        }
+        Ok(())
+    }
+
+    /// Runs the vCPU, handling exits with `handler` until it returns [Action::Return] or the
+    /// `budget` is exhausted.
+    ///
+    /// Keeps the embedder's event loop responsive when the guest exits at a high rate.
+    pub fn run_slice<F>(&self, budget: Budget, mut handler: F) -> Result<Slice, Error>
+    where
+        F: FnMut(&Exit) -> Result<Action, Error>,
+    {
+        let mut meter = Meter::new(budget);
+
+        loop {
+            let exit = self.run()?;
+            if handler(&exit)? == Action::Return {
+                return Ok(Slice::Exit(exit));
+            }
+
+            if !meter.consume() {
+                return Ok(Slice::Yielded {
+                    exits: meter.exits(),
+                });
+            }
+        }
+    }
+
+    /// Runs the vCPU, dispatching exits to the typed callbacks of `handler` until one of them
+    /// returns [Action::Return].
+    ///
+    /// The loop honors [VcpuController] requests between exits: it parks while paused,
+    /// returns once stopped and applies [VcpuController::catch_up] requests.
+    ///
+    /// Returns the exit that stopped the loop.
+    pub fn run_loop<H: ExitHandler + ?Sized>(&self, handler: &mut H) -> Result<Exit, Error> {
+        if self.control.is_stopped() {
+            // Return right after entering the guest.
+            self.handle().kick()?;
+        }
+
+        loop {
+            let exit = self.run()?;
+            if !self.control.checkpoint() {
+                return Ok(exit);
+            }
+            let slept = self.control.take_slept();
+            if slept != 0 {
+                self.catch_up_clocks(slept)?;
+            }
+            if dispatch(self, &exit, handler)? == Action::Return {
+                return Ok(exit);
+            }
+        }
+    }
+
+    /// Executes exactly one guest instruction and returns the resulting exit.
+    ///
+    /// Uses the monitor trap flag on x86 (returning `Exit::MonitorTrap`) and software step
+    /// debug exceptions on arm64 (returning `Exit::SoftwareStep`). Any other exit means the
+    /// instruction trapped before completing, e.g. an MMIO access.
+    ///
+    /// # Intel
+    /// Host interrupts are retried, so a [VcpuHandle::kick] doesn't interrupt a step.
+    /// Fails with [Error::Unsupported] if the host doesn't support the monitor trap flag.
+    pub fn step(&self) -> Result<Exit, Error> {
+        self.assert_owner();
+        crate::debug::step(self)
+    }
+
+    /// Checks the vCPU state for common misconfigurations that make the guest fail to enter,
+    /// e.g. when `run` fails with [Error::Unsuccessful] or an invalid guest state exit.
+    ///
+    /// Checks segment access rights, CR0/CR4/EFER combinations and TR on x86, and the CPSR
+    /// mode and alignment on arm64. Returns human readable findings, empty if nothing
+    /// suspicious was found, which doesn't guarantee the state is valid.
+    pub fn diagnose(&self) -> Result<Vec<String>, Error> {
+        diagnose::check(self)
+    }
+
+    /// Enables or disables buffering of register writes.
+    ///
+    /// While enabled, writes to registers and VMCS fields (x86) or system registers (arm64)
+    /// are kept in the vCPU and applied right before the guest is entered, writing the same
+    /// register repeatedly results in a single framework call. Reads return buffered values.
+    /// Errors of buffered writes are reported by `run` or [Vcpu::flush_cache].
+    ///
+    /// Disabling the cache flushes it.
+    pub fn set_write_cache(&self, enabled: bool) -> Result<(), Error> {
+        self.cache.borrow_mut().set_enabled(enabled);
+        if enabled {
+            Ok(())
+        } else {
+            self.flush_cache()
+        }
+    }
+
+    /// Applies buffered register writes, e.g. before calling framework functions that
+    /// depend on them. On failure the remaining writes are discarded.
+    pub fn flush_cache(&self) -> Result<(), Error> {
+        self.cache.borrow_mut().flush(self.id)
+    }
+
+    /// Buffers a register write, returns `false` if the write cache is disabled.
+    pub(crate) fn cache_write(&self, field: Field, value: u64) -> bool {
+        self.cache.borrow_mut().write(field, value)
+    }
+
+    /// Returns the buffered value of a register, if any.
+    pub(crate) fn cached(&self, field: Field) -> Option<u64> {
+        self.cache.borrow().read(field)
+    }
+
+    /// Calls `callback` every `n` exits returned by `run`, e.g. to sample statistics or check
+    /// for a stop flag, replacing any previous hook.
+    ///
+    /// The callback runs on the vCPU thread right after the exit was decoded, an error is
+    /// returned by `run`. Fails with [Error::BadArgument] if `n` is 0.
+    pub fn set_exit_hook<F>(&self, n: u64, callback: F) -> Result<(), Error>
+    where
+        F: FnMut(&Vcpu, &Exit) -> Result<(), Error> + 'static,
+    {
+        if n == 0 {
+            return Err(Error::BadArgument);
+        }
+        *self.hook.borrow_mut() = Some(hook::ExitHook::new(n, Box::new(callback)));
+        Ok(())
+    }
+
+    /// Removes the hook installed with [Vcpu::set_exit_hook].
+    pub fn clear_exit_hook(&self) {
+        self.hook.borrow_mut().take();
+    }
+
+    /// Tunes the scheduling of the vCPU thread for low guest timer jitter, e.g. for audio or
+    /// real time guests.
+    ///
+    /// Enabling applies the user interactive QoS class and a time constraint (real time)
+    /// policy to the thread, disabling restores the default timesharing policy. Must be called
+    /// on the vCPU thread.
+    pub fn set_latency_sensitive(&self, enabled: bool) -> Result<(), Error> {
+        self.assert_owner();
+        policy::set_latency_sensitive(enabled)
+    }
+
+    /// Sets the QoS class of the vCPU thread. Must be called on the vCPU thread.
+    pub fn set_qos_class(&self, class: QosClass) -> Result<(), Error> {
+        self.assert_owner();
+        policy::set_qos_class(class)
+    }
+
+    /// Sets the affinity tag of the vCPU thread, threads with the same tag are preferably
+    /// scheduled to share a cache. Must be called on the vCPU thread.
+    ///
+    /// Affinity tags are a hint and are ignored on Apple Silicon.
+    pub fn set_affinity_tag(&self, tag: i32) -> Result<(), Error> {
+        self.assert_owner();
+        policy::set_affinity_tag(tag)
+    }
+
+    /// Returns a queue to deliver interrupts to the vCPU from other threads.
+    ///
+    /// Pending interrupts are injected by every run, including [Vcpu::run_loop] and
+    /// [Vcpu::run_slice].
+    pub fn irq_queue(&self) -> IrqQueue {
+        IrqQueue::new(Arc::clone(&self.irqs), self.handle())
+    }
+
+    /// Requests `irq` from the vCPU thread, it's injected by the next run.
+    #[cfg(target_arch = "x86_64")]
+    pub(crate) fn request_irq(&self, irq: Interrupt) {
+        self.irqs.raise(irq);
+    }
+
+    /// Injects interrupts pending in the [IrqQueue], called right before entering the guest.
+    pub(crate) fn inject_irqs(&self) -> Result<(), Error> {
+        irq::inject(self, &self.irqs)
+    }
+
+    /// Advances the guest clocks by `ticks` of host sleep, in mach absolute time units.
+    fn catch_up_clocks(&self, ticks: u64) -> Result<(), Error> {
+        #[cfg(target_arch = "x86_64")]
+        {
+            let tsc = crate::x86::GuestTsc::new()?;
+            tsc.advance(self, crate::time::duration_from_ticks(ticks))
+        }
+
+        #[cfg(target_arch = "aarch64")]
+        {
+            use crate::arm64::VcpuExt;
+            // The guest counter is the host counter minus the offset.
+            let offset = self.vtimer_offset()?;
+            self.set_vtimer_offset(offset.wrapping_sub(ticks))
+        }
+    }
+
+    /// Returns a controller to pause, resume or stop [Vcpu::run_loop] from other threads.
+    pub fn controller(&self) -> VcpuController {
+        VcpuController::new(Arc::clone(&self.control), self.handle())
+    }
+
+    /// Captures the architectural state of the vCPU: general purpose, control and system
+    /// registers, FP/SIMD state, MSRs and VMCS guest fields (x86), and timer state.
+    ///
+    /// Guest memory and devices are not included.
+    pub fn save_state(&self) -> Result<VcpuState, Error> {
+        self.assert_owner();
+
+        #[cfg(target_arch = "x86_64")]
+        {
+            crate::x86::state::save(self)
+        }
+
+        #[cfg(target_arch = "aarch64")]
+        {
+            crate::arm64::state::save(self)
+        }
+    }
+
+    /// Restores state captured with [Vcpu::save_state], possibly on another vCPU or in
+    /// another process.
+    pub fn restore_state(&self, state: &VcpuState) -> Result<(), Error> {
+        self.assert_owner();
+
+        #[cfg(target_arch = "x86_64")]
+        {
+            crate::x86::state::restore(self, state)
+        }
+
+        #[cfg(target_arch = "aarch64")]
+        {
+            crate::arm64::state::restore(self, state)
+        }
+    }
+
+    /// Returns the floating point and SIMD state of the vCPU in a buffer of
+    /// [Vcpu::fpstate_size] bytes.
+    ///
+    /// On x86 this is the XSAVE area as returned by `x86::VcpuExt::read_fpstate`. On arm64 it's Q0 - Q31 followed by
+    /// `FPCR` and `FPSR`, all little endian.
+    pub fn fpstate(&self) -> Result<Vec<u8>, Error> {
+        #[cfg(target_arch = "x86_64")]
+        {
+            crate::x86::state::fpstate(self)
+        }
+
+        #[cfg(target_arch = "aarch64")]
+        {
+            crate::arm64::state::fpstate(self)
+        }
+    }
+
+    /// Returns the size of the buffer returned by [Vcpu::fpstate].
+    pub fn fpstate_size() -> usize {
+        #[cfg(target_arch = "x86_64")]
+        {
+            crate::x86::state::fpstate_size()
+        }
+
+        #[cfg(target_arch = "aarch64")]
+        {
+            crate::arm64::state::FPSTATE_SIZE
+        }
+    }
+
+    /// Copies the architectural state of the vCPU to `other`, see [Vcpu::save_state].
+    ///
+    /// Both vCPUs must belong to the current thread. Useful to fork a vCPU mid-execution,
+    /// e.g. for fuzzing, guest memory has to be forked separately. `other` keeps its CPU
+    /// identity, see [VcpuState::without_identity].
+    pub fn clone_state_to(&self, other: &Vcpu) -> Result<(), Error> {
+        other.restore_state(&self.save_state()?.without_identity())
     }
 
     /// Returns the cumulative execution time of a vCPU in nanoseconds.
@@ -72,16 +498,154 @@ impl Vcpu {
         Ok(out)
     }
 
+    /// Returns execution statistics collected since the vCPU was created or
+    /// [Vcpu::reset_stats] was called.
+    pub fn stats(&self) -> Result<VcpuStats, Error> {
+        Ok(self.stats.borrow().snapshot(self.exec_duration()?))
+    }
+
+    /// Restarts statistics collection.
+    pub fn reset_stats(&self) -> Result<(), Error> {
+        let exec_time = self.exec_duration()?;
+        self.stats.borrow_mut().reset(exec_time);
+        Ok(())
+    }
+
+    fn exec_duration(&self) -> Result<Duration, Error> {
+        let exec_time = self.exec_time()?;
+
+        // Reported in mach absolute time units on Apple Silicon.
+        #[cfg(target_arch = "aarch64")]
+        let exec_time = crate::time::duration_from_ticks(exec_time);
+
+        #[cfg(target_arch = "x86_64")]
+        let exec_time = Duration::from_nanos(exec_time);
+
+        Ok(exec_time)
+    }
+
+    /// Returns the underlying vCPU ID.
+    #[inline]
+    pub fn id(&self) -> Id {
+        self.id
+    }
+
+    /// Returns a handle to the live vCPU with the given ID, created by any thread of the
+    /// process.
+    ///
+    /// Lets code that only knows a vCPU ID, like interrupt controller or IPI emulation, reach
+    /// the vCPU. The handle is bound to the vCPU registered under `id` at the time of the
+    /// lookup: once that vCPU is destroyed the handle fails with [Error::NoDevice], even if a
+    /// newer vCPU reuses the ID, which has to be looked up again.
+    pub fn lookup(id: Id) -> Option<VcpuHandle> {
+        let vcpus = VCPUS.lock().unwrap();
+        let registered = vcpus.get(&id)?;
+        Some(VcpuHandle {
+            vm: registered.vm.upgrade()?,
+            id,
+            generation: registered.generation,
+        })
+    }
+
+    /// Panics in debug builds if called from a thread other than the creating one, the
+    /// framework would fail with [Error::BadArgument] instead.
+    #[inline]
+    pub(crate) fn assert_owner(&self) {
+        #[cfg(debug_assertions)]
+        assert_eq!(
+            thread::current().id(),
+            self.owner,
+            "vCPU {} used from a thread other than the one that created it",
+            self.id
+        );
+    }
+
+    /// Destroys the vCPU, returning the error dropping it would ignore.
+    ///
+    /// The vCPU is unregistered from its VM either way.
+    pub fn destroy(mut self) -> Result<(), Error> {
+        self.destroyed = true;
+        self.teardown()
+    }
+
+    fn teardown(&self) -> Result<(), Error> {
+        self.assert_owner();
+        self.vm.remove_vcpu(self.id);
+        VCPUS.lock().unwrap().remove(&self.id);
+        call!(sys::hv_vcpu_destroy(self.id))
+    }
+
+    /// Returns a handle to interrupt the vCPU from other threads.
+    pub fn handle(&self) -> VcpuHandle {
+        VcpuHandle {
+            vm: Arc::clone(&self.vm),
+            id: self.id,
+            generation: self.generation,
+        }
+    }
+}
+
+/// A thread safe handle to a [Vcpu], obtained with [Vcpu::handle].
+///
+/// Unlike [Vcpu], the handle can be sent to other threads, but it only supports forcing the
+/// vCPU out of `run`. Interrupting a vCPU that has been destroyed fails with
+/// [Error::NoDevice], even if a newer vCPU reuses its ID.
+#[derive(Debug, Clone)]
+pub struct VcpuHandle {
+    #[allow(dead_code)] // Keeps the VM alive while the handle exists.
+    vm: Arc<Vm>,
+    id: Id,
+    /// Generation of the vCPU in [VCPUS] when the handle was created.
+    generation: u64,
+}
+
+impl VcpuHandle {
     /// Returns the underlying vCPU ID.
     #[inline]
     pub fn id(&self) -> Id {
         self.id
     }
+
+    /// Forces an immediate exit of the vCPU.
+    ///
+    /// If the vCPU is not running, the next `run` returns immediately. Fails with
+    /// [Error::NoDevice] if the vCPU was destroyed.
+    pub fn interrupt(&self) -> Result<(), Error> {
+        // Held during the call, the vCPU is unregistered before it's destroyed so its ID
+        // can't be reused meanwhile.
+        let vcpus = VCPUS.lock().unwrap();
+        match vcpus.get(&self.id) {
+            Some(registered) if registered.generation == self.generation => {}
+            _ => return Err(Error::NoDevice),
+        }
+        let mut id = self.id;
+
+        #[cfg(target_arch = "x86_64")]
+        {
+            call!(sys::hv_vcpu_interrupt(&mut id, 1))
+        }
+
+        #[cfg(target_arch = "aarch64")]
+        {
+            call!(sys::hv_vcpus_exit(&mut id, 1))
+        }
+    }
+
+    /// Kicks the vCPU out of `run`, so it notices state changed by another thread.
+    ///
+    /// Same as [VcpuHandle::interrupt].
+    #[inline]
+    pub fn kick(&self) -> Result<(), Error> {
+        self.interrupt()
+    }
 }
 
 /// Destroys the vCPU instance associated with the current thread.
 impl Drop for Vcpu {
+    /// Destroys the vCPU, ignoring errors, use [Vcpu::destroy] to handle them.
     fn drop(&mut self) {
-        call!(sys::hv_vcpu_destroy(self.id)).unwrap()
+        if !self.destroyed {
+            let _ = self.teardown();
+        }
     }
 }
@@ -0,0 +1,156 @@
+use std::sync::Arc;
+
+use crate::{Error, GPAddr, Vcpu, Vm};
+
+#[cfg(target_arch = "aarch64")]
+use crate::arm64::{Reg, SysReg, VcpuExt};
+#[cfg(target_arch = "x86_64")]
+use crate::x86::vmx::{self, Capability, VCpuVmxExt, Vmcs};
+#[cfg(target_arch = "x86_64")]
+use crate::x86::{Reg, VcpuExt};
+
+/// Creates a vCPU with its initial state applied.
+///
+/// ```ignore
+/// let cpu = VcpuBuilder::new()
+///     .entry(0x10000)
+///     .stack(0x20000)
+///     .reg(Reg::X1, 0x30000)
+///     .build(Arc::clone(&vm))?;
+/// ```
+///
+/// Like [Vm::create_cpu], [VcpuBuilder::build] must be called on the thread that runs the
+/// vCPU.
+#[derive(Debug, Clone, Default)]
+pub struct VcpuBuilder {
+    entry: Option<GPAddr>,
+    stack: Option<u64>,
+    regs: Vec<(Reg, u64)>,
+    #[cfg(target_arch = "x86_64")]
+    vmcs: Vec<(Vmcs, u64)>,
+    #[cfg(target_arch = "x86_64")]
+    native_msrs: Vec<u32>,
+    #[cfg(target_arch = "aarch64")]
+    sys_regs: Vec<(SysReg, u64)>,
+}
+
+impl VcpuBuilder {
+    pub fn new() -> VcpuBuilder {
+        VcpuBuilder::default()
+    }
+
+    /// Sets the address of the first instruction (RIP or PC).
+    pub fn entry(mut self, gpa: GPAddr) -> VcpuBuilder {
+        self.entry = Some(gpa);
+        self
+    }
+
+    /// Sets the stack pointer (RSP, or `SP_EL1` on arm64).
+    pub fn stack(mut self, sp: u64) -> VcpuBuilder {
+        self.stack = Some(sp);
+        self
+    }
+
+    /// Sets the initial value of a register, applied in order.
+    pub fn reg(mut self, reg: Reg, value: u64) -> VcpuBuilder {
+        self.regs.push((reg, value));
+        self
+    }
+
+    /// Sets the initial value of a VMCS field, applied in order.
+    ///
+    /// VM execution, entry and exit controls are adjusted to the capabilities of the host,
+    /// see [vmx::adjust_controls].
+    #[cfg(target_arch = "x86_64")]
+    pub fn vmcs(mut self, field: Vmcs, value: u64) -> VcpuBuilder {
+        self.vmcs.push((field, value));
+        self
+    }
+
+    /// Lets the guest access `msr` natively.
+    #[cfg(target_arch = "x86_64")]
+    pub fn native_msr(mut self, msr: u32) -> VcpuBuilder {
+        self.native_msrs.push(msr);
+        self
+    }
+
+    /// Sets the initial value of a system register, applied in order.
+    #[cfg(target_arch = "aarch64")]
+    pub fn sys_reg(mut self, reg: SysReg, value: u64) -> VcpuBuilder {
+        self.sys_regs.push((reg, value));
+        self
+    }
+
+    /// Creates the vCPU for the current thread and applies the initial state.
+    pub fn build(&self, vm: Arc<Vm>) -> Result<Vcpu, Error> {
+        let cpu = Vcpu::new(vm)?;
+        self.apply(&cpu)?;
+        Ok(cpu)
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    fn apply(&self, cpu: &Vcpu) -> Result<(), Error> {
+        /// Bit 1 of RFLAGS is reserved and must be set.
+        const RFLAGS_DEFAULT: u64 = 0x2;
+
+        let controls = [
+            (Vmcs::CTRL_PIN_BASED, Capability::PinBased),
+            (Vmcs::CTRL_CPU_BASED, Capability::ProcBased),
+            (Vmcs::CTRL_CPU_BASED2, Capability::ProcBased2),
+            (Vmcs::CTRL_VMENTRY_CONTROLS, Capability::Entry),
+            (Vmcs::CTRL_VMEXIT_CONTROLS, Capability::Exit),
+        ];
+
+        for (field, cap) in controls.iter() {
+            let requested = self
+                .vmcs
+                .iter()
+                .rev()
+                .find(|(f, _)| f == field)
+                .map_or(0, |(_, v)| *v);
+            cpu.write_vmcs(*field, vmx::adjust_controls(*cap, requested)?)?;
+        }
+
+        for (field, value) in &self.vmcs {
+            if !controls.iter().any(|(f, _)| f == field) {
+                cpu.write_vmcs(*field, *value)?;
+            }
+        }
+
+        cpu.write_register(Reg::RFLAGS, RFLAGS_DEFAULT)?;
+        cpu.write_registers(&self.regs)?;
+        if let Some(entry) = self.entry {
+            cpu.write_register(Reg::RIP, entry)?;
+        }
+        if let Some(sp) = self.stack {
+            cpu.write_register(Reg::RSP, sp)?;
+        }
+
+        for msr in &self.native_msrs {
+            cpu.enable_native_msr(*msr, true)?;
+        }
+
+        Ok(())
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    fn apply(&self, cpu: &Vcpu) -> Result<(), Error> {
+        /// EL1h with all interrupts masked.
+        const CPSR_DEFAULT: u64 = 0x3c5;
+
+        cpu.set_reg(Reg::CPSR, CPSR_DEFAULT)?;
+        for (reg, value) in &self.sys_regs {
+            cpu.set_sys_reg(*reg, *value)?;
+        }
+
+        cpu.write_registers(&self.regs)?;
+        if let Some(entry) = self.entry {
+            cpu.set_reg(Reg::PC, entry)?;
+        }
+        if let Some(sp) = self.stack {
+            cpu.set_sys_reg(SysReg::SP_EL1, sp)?;
+        }
+
+        Ok(())
+    }
+}
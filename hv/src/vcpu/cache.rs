@@ -0,0 +1,89 @@
+use crate::{call, sys, Error};
+
+use super::Id;
+
+#[cfg(target_arch = "aarch64")]
+use crate::arm64::{Reg, SysReg};
+#[cfg(target_arch = "x86_64")]
+use crate::x86::{vmx::Vmcs, Reg};
+
+/// A vCPU register whose writes can be cached.
+#[cfg(target_arch = "x86_64")]
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub(crate) enum Field {
+    Reg(Reg),
+    Vmcs(Vmcs),
+}
+
+/// A vCPU register whose writes can be cached.
+#[cfg(target_arch = "aarch64")]
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub(crate) enum Field {
+    Reg(Reg),
+    SysReg(SysReg),
+}
+
+/// Register writes buffered until the vCPU is entered, see [Vcpu::set_write_cache].
+///
+/// [Vcpu::set_write_cache]: crate::Vcpu::set_write_cache
+#[derive(Debug, Default)]
+pub(crate) struct WriteCache {
+    enabled: bool,
+    /// Latest value of every written field, in order of the first write.
+    pending: Vec<(Field, u64)>,
+}
+
+impl WriteCache {
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+    }
+
+    /// Buffers a write, returns `false` if caching is disabled.
+    pub fn write(&mut self, field: Field, value: u64) -> bool {
+        if !self.enabled {
+            return false;
+        }
+
+        match self.pending.iter_mut().find(|(f, _)| *f == field) {
+            Some((_, pending)) => *pending = value,
+            None => self.pending.push((field, value)),
+        }
+        true
+    }
+
+    /// Returns the buffered value of `field`, if it was written since the last flush.
+    pub fn read(&self, field: Field) -> Option<u64> {
+        self.pending
+            .iter()
+            .find(|(f, _)| *f == field)
+            .map(|(_, value)| *value)
+    }
+
+    /// Applies the buffered writes to the vCPU.
+    pub fn flush(&mut self, id: Id) -> Result<(), Error> {
+        for (field, value) in self.pending.drain(..) {
+            write(id, field, value)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+fn write(id: Id, field: Field, value: u64) -> Result<(), Error> {
+    match field {
+        Field::Reg(reg) => call!(sys::hv_vcpu_write_register(
+            id,
+            reg as sys::hv_x86_reg_t,
+            value
+        )),
+        Field::Vmcs(field) => call!(sys::hv_vmx_vcpu_write_vmcs(id, field as u32, value)),
+    }
+}
+
+#[cfg(target_arch = "aarch64")]
+fn write(id: Id, field: Field, value: u64) -> Result<(), Error> {
+    match field {
+        Field::Reg(reg) => call!(sys::hv_vcpu_set_reg(id, reg as _, value)),
+        Field::SysReg(reg) => call!(sys::hv_vcpu_set_sys_reg(id, reg as _, value)),
+    }
+}
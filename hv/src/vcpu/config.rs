@@ -0,0 +1,185 @@
+#[cfg(target_arch = "aarch64")]
+use crate::{call, sys, Error};
+
+/// vCPU creation options for [Vm::create_cpu_with](crate::Vm::create_cpu_with).
+///
+/// On Intel these are the flags passed to `hv_vcpu_create`.
+#[cfg(target_arch = "x86_64")]
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub struct VcpuConfig {
+    flags: u64,
+}
+
+#[cfg(target_arch = "x86_64")]
+impl VcpuConfig {
+    pub fn new() -> VcpuConfig {
+        VcpuConfig::default()
+    }
+
+    /// Sets the raw `hv_vcpu_create` flags (`HV_VCPU_DEFAULT` is 0).
+    pub fn flags(mut self, flags: u64) -> VcpuConfig {
+        self.flags = flags;
+        self
+    }
+
+    pub(crate) fn raw(&self) -> u64 {
+        self.flags
+    }
+}
+
+/// ID registers reported by a [VcpuConfig].
+#[cfg(target_arch = "aarch64")]
+#[allow(non_camel_case_types)]
+#[repr(u32)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum FeatureReg {
+    ID_AA64DFR0_EL1 = sys::hv_feature_reg_t_HV_FEATURE_REG_ID_AA64DFR0_EL1,
+    ID_AA64DFR1_EL1 = sys::hv_feature_reg_t_HV_FEATURE_REG_ID_AA64DFR1_EL1,
+    ID_AA64ISAR0_EL1 = sys::hv_feature_reg_t_HV_FEATURE_REG_ID_AA64ISAR0_EL1,
+    ID_AA64ISAR1_EL1 = sys::hv_feature_reg_t_HV_FEATURE_REG_ID_AA64ISAR1_EL1,
+    ID_AA64MMFR0_EL1 = sys::hv_feature_reg_t_HV_FEATURE_REG_ID_AA64MMFR0_EL1,
+    ID_AA64MMFR1_EL1 = sys::hv_feature_reg_t_HV_FEATURE_REG_ID_AA64MMFR1_EL1,
+    ID_AA64MMFR2_EL1 = sys::hv_feature_reg_t_HV_FEATURE_REG_ID_AA64MMFR2_EL1,
+    ID_AA64PFR0_EL1 = sys::hv_feature_reg_t_HV_FEATURE_REG_ID_AA64PFR0_EL1,
+    ID_AA64PFR1_EL1 = sys::hv_feature_reg_t_HV_FEATURE_REG_ID_AA64PFR1_EL1,
+    CTR_EL0 = sys::hv_feature_reg_t_HV_FEATURE_REG_CTR_EL0,
+    CLIDR_EL1 = sys::hv_feature_reg_t_HV_FEATURE_REG_CLIDR_EL1,
+    DCZID_EL0 = sys::hv_feature_reg_t_HV_FEATURE_REG_DCZID_EL0,
+}
+
+#[cfg(target_arch = "aarch64")]
+impl FeatureReg {
+    /// Returns the system register of the vCPU holding this feature register, `None` for the
+    /// registers the framework doesn't let a VMM write.
+    pub fn sys_reg(&self) -> Option<crate::arm64::SysReg> {
+        use crate::arm64::SysReg;
+        match self {
+            FeatureReg::ID_AA64DFR0_EL1 => Some(SysReg::ID_AA64DFR0_EL1),
+            FeatureReg::ID_AA64DFR1_EL1 => Some(SysReg::ID_AA64DFR1_EL1),
+            FeatureReg::ID_AA64ISAR0_EL1 => Some(SysReg::ID_AA64ISAR0_EL1),
+            FeatureReg::ID_AA64ISAR1_EL1 => Some(SysReg::ID_AA64ISAR1_EL1),
+            FeatureReg::ID_AA64MMFR0_EL1 => Some(SysReg::ID_AA64MMFR0_EL1),
+            FeatureReg::ID_AA64MMFR1_EL1 => Some(SysReg::ID_AA64MMFR1_EL1),
+            FeatureReg::ID_AA64MMFR2_EL1 => Some(SysReg::ID_AA64MMFR2_EL1),
+            FeatureReg::ID_AA64PFR0_EL1 => Some(SysReg::ID_AA64PFR0_EL1),
+            FeatureReg::ID_AA64PFR1_EL1 => Some(SysReg::ID_AA64PFR1_EL1),
+            FeatureReg::CTR_EL0 | FeatureReg::CLIDR_EL1 | FeatureReg::DCZID_EL0 => None,
+        }
+    }
+}
+
+/// Cache types for [VcpuConfig::ccsidr_el1_values].
+#[cfg(target_arch = "aarch64")]
+#[repr(u32)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum CacheType {
+    Data = sys::hv_cache_type_t_HV_CACHE_TYPE_DATA,
+    Instruction = sys::hv_cache_type_t_HV_CACHE_TYPE_INSTRUCTION,
+}
+
+/// vCPU creation options for [Vm::create_cpu_with](crate::Vm::create_cpu_with).
+///
+/// On Apple Silicon this wraps `hv_vcpu_config_t`, which reports the feature registers
+/// the vCPU is created with. The `ID_AA64*_EL1` registers can be overridden, see
+/// [VcpuConfig::override_feature_reg].
+///
+/// ```ignore
+/// let mut config = VcpuConfig::new()?;
+/// let pfr0 = config.feature_reg(FeatureReg::ID_AA64PFR0_EL1)?;
+/// // Hide SVE from the guest.
+/// config.override_feature_reg(FeatureReg::ID_AA64PFR0_EL1, pfr0 & !(0xf << 32))?;
+/// let cpu = vm.create_cpu_with(&config)?;
+/// ```
+#[cfg(target_arch = "aarch64")]
+#[derive(Debug)]
+pub struct VcpuConfig {
+    config: sys::hv_vcpu_config_t,
+    overrides: Vec<(FeatureReg, u64)>,
+}
+
+#[cfg(target_arch = "aarch64")]
+extern "C" {
+    fn os_release(object: *mut std::ffi::c_void);
+}
+
+#[cfg(target_arch = "aarch64")]
+impl VcpuConfig {
+    /// Creates a configuration with default settings.
+    pub fn new() -> Result<VcpuConfig, Error> {
+        let config = unsafe { sys::hv_vcpu_config_create() };
+        if config.is_null() {
+            return Err(Error::NoResources);
+        }
+        Ok(VcpuConfig {
+            config,
+            overrides: Vec::new(),
+        })
+    }
+
+    /// Returns the value of a feature register, including overrides.
+    pub fn feature_reg(&self, reg: FeatureReg) -> Result<u64, Error> {
+        if let Some(&(_, value)) = self.overrides.iter().find(|(r, _)| *r == reg) {
+            return Ok(value);
+        }
+        let mut out = 0_u64;
+        call!(sys::hv_vcpu_config_get_feature_reg(
+            self.config,
+            reg as _,
+            &mut out
+        ))?;
+        Ok(out)
+    }
+
+    /// Overrides the value of an `ID_AA64*_EL1` register for vCPUs created with this
+    /// configuration, replacing a previous override.
+    ///
+    /// The value is written to the vCPU when it's created, so it only restricts the features
+    /// the guest sees: it can't enable features the host doesn't have. Fails with
+    /// [Error::Unsupported] for `CTR_EL0`, `CLIDR_EL1` and `DCZID_EL0`.
+    pub fn override_feature_reg(&mut self, reg: FeatureReg, value: u64) -> Result<(), Error> {
+        if reg.sys_reg().is_none() {
+            return Err(Error::Unsupported);
+        }
+        self.overrides.retain(|(r, _)| *r != reg);
+        self.overrides.push((reg, value));
+        Ok(())
+    }
+
+    /// Returns the overridden registers and their values.
+    pub fn overrides(&self) -> &[(FeatureReg, u64)] {
+        &self.overrides
+    }
+
+    /// Returns the `CCSIDR_EL1` values of the caches of the given type, one per level.
+    pub fn ccsidr_el1_values(&self, cache: CacheType) -> Result<[u64; 8], Error> {
+        let mut out = [0_u64; 8];
+        call!(sys::hv_vcpu_config_get_ccsidr_el1_sys_reg_values(
+            self.config,
+            cache as _,
+            out.as_mut_ptr()
+        ))?;
+        Ok(out)
+    }
+
+    pub(crate) fn raw(&self) -> sys::hv_vcpu_config_t {
+        self.config
+    }
+
+    /// Writes the overridden registers to a newly created `vcpu`.
+    pub(crate) fn apply(&self, vcpu: &crate::Vcpu) -> Result<(), Error> {
+        use crate::arm64::VcpuExt;
+        for &(reg, value) in &self.overrides {
+            if let Some(reg) = reg.sys_reg() {
+                vcpu.set_sys_reg(reg, value)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(target_arch = "aarch64")]
+impl Drop for VcpuConfig {
+    fn drop(&mut self) {
+        unsafe { os_release(self.config as *mut _) };
+    }
+}
@@ -0,0 +1,146 @@
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Duration;
+
+use super::VcpuHandle;
+use crate::Error;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum State {
+    Running,
+    /// `parked` is set once the vCPU thread waits.
+    Paused {
+        parked: bool,
+    },
+    Stopped,
+}
+
+/// Run state shared between a vCPU and its controllers.
+#[derive(Debug)]
+pub(crate) struct Control {
+    state: Mutex<State>,
+    cvar: Condvar,
+    /// Host sleep time in mach absolute time units the guest clocks have yet to catch up on.
+    slept: Mutex<u64>,
+}
+
+impl Default for Control {
+    fn default() -> Self {
+        Control {
+            state: Mutex::new(State::Running),
+            cvar: Condvar::new(),
+            slept: Mutex::new(0),
+        }
+    }
+}
+
+impl Control {
+    /// Called by the vCPU thread between exits, parks while paused.
+    ///
+    /// Returns `false` if the vCPU is stopped.
+    pub fn checkpoint(&self) -> bool {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            match *state {
+                State::Running => return true,
+                State::Stopped => return false,
+                State::Paused { parked: false } => {
+                    *state = State::Paused { parked: true };
+                    self.cvar.notify_all();
+                }
+                State::Paused { parked: true } => {}
+            }
+            state = self.cvar.wait(state).unwrap();
+        }
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        *self.state.lock().unwrap() == State::Stopped
+    }
+
+    /// Returns and clears the sleep time requested by [VcpuController::catch_up].
+    pub fn take_slept(&self) -> u64 {
+        std::mem::take(&mut *self.slept.lock().unwrap())
+    }
+
+    fn set(&self, new: State) {
+        *self.state.lock().unwrap() = new;
+        self.cvar.notify_all();
+    }
+}
+
+/// Pauses, resumes and stops a vCPU driven by [Vcpu::run_loop](crate::Vcpu::run_loop) from
+/// other threads.
+///
+/// Obtained with [Vcpu::controller](crate::Vcpu::controller). A paused vCPU parks its thread
+/// between exits instead of re-entering the guest, a stopped one makes `run_loop` return.
+#[derive(Debug, Clone)]
+pub struct VcpuController {
+    control: Arc<Control>,
+    handle: VcpuHandle,
+}
+
+impl VcpuController {
+    pub(crate) fn new(control: Arc<Control>, handle: VcpuHandle) -> VcpuController {
+        VcpuController { control, handle }
+    }
+
+    /// Requests the vCPU to park at its next exit, which is forced immediately.
+    ///
+    /// Use [VcpuController::wait_paused] to wait for the vCPU to actually park.
+    pub fn pause(&self) -> Result<(), Error> {
+        {
+            let mut state = self.control.state.lock().unwrap();
+            if *state != State::Running {
+                return Ok(());
+            }
+            *state = State::Paused { parked: false };
+        }
+        self.handle.kick()
+    }
+
+    /// Waits until a paused vCPU has parked, returns `false` on timeout or if the vCPU isn't
+    /// paused.
+    pub fn wait_paused(&self, timeout: Duration) -> bool {
+        let state = self.control.state.lock().unwrap();
+        let (state, _) = self
+            .control
+            .cvar
+            .wait_timeout_while(state, timeout, |s| *s == State::Paused { parked: false })
+            .unwrap();
+        *state == State::Paused { parked: true }
+    }
+
+    /// Lets a paused or stopped vCPU continue.
+    pub fn resume(&self) {
+        self.control.set(State::Running);
+    }
+
+    /// Makes `run_loop` return at the next exit, which is forced immediately.
+    ///
+    /// A paused vCPU is woken up. The vCPU stays stopped until [VcpuController::resume].
+    pub fn stop(&self) -> Result<(), Error> {
+        self.control.set(State::Stopped);
+        self.handle.kick()
+    }
+
+    /// Makes the guest clocks of the vCPU (the TSC on Intel, the VTimer on Apple Silicon)
+    /// catch up on `slept_ticks` of host sleep, in mach absolute time units, see
+    /// [power::Wake](crate::power).
+    ///
+    /// The vCPU thread applies it in `run_loop` before re-entering the guest, so it can be
+    /// requested while the vCPU is paused.
+    pub fn catch_up(&self, slept_ticks: u64) {
+        let mut slept = self.control.slept.lock().unwrap();
+        *slept = slept.saturating_add(slept_ticks);
+    }
+
+    /// Returns `true` if the vCPU is paused (whether parked yet or not).
+    pub fn is_paused(&self) -> bool {
+        matches!(*self.control.state.lock().unwrap(), State::Paused { .. })
+    }
+
+    /// Returns `true` if the vCPU is stopped.
+    pub fn is_stopped(&self) -> bool {
+        self.control.is_stopped()
+    }
+}
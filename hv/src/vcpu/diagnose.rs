@@ -0,0 +1,236 @@
+use crate::{Error, Vcpu};
+
+/// Checks the vCPU state for common misconfigurations, see [Vcpu::diagnose].
+pub(crate) fn check(vcpu: &Vcpu) -> Result<Vec<String>, Error> {
+    let mut findings = Vec::new();
+    arch::check(vcpu, &mut findings)?;
+    Ok(findings)
+}
+
+#[cfg(target_arch = "x86_64")]
+mod arch {
+    use super::*;
+    use crate::x86::vmx::{VCpuVmxExt, Vmcs};
+    use crate::x86::{Reg, VcpuExt};
+
+    const CR0_PE: u64 = 1 << 0;
+    const CR0_NE: u64 = 1 << 5;
+    const CR0_PG: u64 = 1 << 31;
+    const CR4_PAE: u64 = 1 << 5;
+    const CR4_VMXE: u64 = 1 << 13;
+    const EFER_LME: u64 = 1 << 8;
+    const EFER_LMA: u64 = 1 << 10;
+
+    /// Reserved bits of RFLAGS that must be 0, bit 1 must be 1.
+    const RFLAGS_RESERVED: u64 = !0x3f_7fd7;
+
+    /// IA-32e mode guest VM-entry control.
+    const ENTRY_IA32E: u64 = 1 << 9;
+
+    /// Unrestricted guest secondary VM-execution control.
+    const CPU_BASED2_UNRESTRICTED: u64 = 1 << 7;
+
+    /// Segment access rights bits.
+    const AR_ACCESSED: u64 = 1 << 0;
+    const AR_S: u64 = 1 << 4;
+    const AR_P: u64 = 1 << 7;
+    const AR_L: u64 = 1 << 13;
+    const AR_DB: u64 = 1 << 14;
+    const AR_G: u64 = 1 << 15;
+    const AR_UNUSABLE: u64 = 1 << 16;
+
+    const SEGMENTS: [(&str, Vmcs, Vmcs); 6] = [
+        ("CS", Vmcs::GUEST_CS_AR, Vmcs::GUEST_CS_LIMIT),
+        ("SS", Vmcs::GUEST_SS_AR, Vmcs::GUEST_SS_LIMIT),
+        ("DS", Vmcs::GUEST_DS_AR, Vmcs::GUEST_DS_LIMIT),
+        ("ES", Vmcs::GUEST_ES_AR, Vmcs::GUEST_ES_LIMIT),
+        ("FS", Vmcs::GUEST_FS_AR, Vmcs::GUEST_FS_LIMIT),
+        ("GS", Vmcs::GUEST_GS_AR, Vmcs::GUEST_GS_LIMIT),
+    ];
+
+    pub fn check(vcpu: &Vcpu, findings: &mut Vec<String>) -> Result<(), Error> {
+        let cr0 = vcpu.read_vmcs(Vmcs::GUEST_CR0)?;
+        let cr4 = vcpu.read_vmcs(Vmcs::GUEST_CR4)?;
+        let efer = vcpu.read_vmcs(Vmcs::GUEST_IA32_EFER)?;
+        let entry = vcpu.read_vmcs(Vmcs::CTRL_VMENTRY_CONTROLS)?;
+        let unrestricted = vcpu.read_vmcs(Vmcs::CTRL_CPU_BASED2)? & CPU_BASED2_UNRESTRICTED != 0;
+        let long_mode = entry & ENTRY_IA32E != 0;
+
+        if cr0 & CR0_NE == 0 {
+            findings.push("CR0.NE is clear, VMX requires it to be set".into());
+        }
+        if cr0 & CR0_PG != 0 && cr0 & CR0_PE == 0 {
+            findings.push("CR0.PG is set without CR0.PE".into());
+        }
+        if cr0 & (CR0_PE | CR0_PG) != (CR0_PE | CR0_PG) && !unrestricted {
+            findings.push(
+                "CR0.PE or CR0.PG is clear, which requires the unrestricted guest control".into(),
+            );
+        }
+        if cr4 & CR4_VMXE == 0 {
+            findings.push("CR4.VMXE is clear, VMX requires it to be set".into());
+        }
+
+        if long_mode != (efer & EFER_LMA != 0) {
+            findings.push(format!(
+                "EFER.LMA is {} but the IA-32e mode guest entry control is {}",
+                efer & EFER_LMA != 0,
+                long_mode
+            ));
+        }
+        if long_mode && (cr0 & CR0_PG == 0 || cr4 & CR4_PAE == 0) {
+            findings.push("IA-32e mode requires CR0.PG and CR4.PAE".into());
+        }
+        if cr0 & CR0_PG != 0 && (efer & EFER_LMA != 0) != (efer & EFER_LME != 0) {
+            findings.push("EFER.LMA and EFER.LME differ while paging is enabled".into());
+        }
+
+        let rflags = vcpu.read_register(Reg::RFLAGS)?;
+        if rflags & RFLAGS_RESERVED != 0 || rflags & (1 << 1) == 0 {
+            findings.push(format!(
+                "RFLAGS {:#x} has reserved bits set or bit 1 clear",
+                rflags
+            ));
+        }
+
+        for (name, ar, limit) in SEGMENTS.iter() {
+            let ar = vcpu.read_vmcs(*ar)?;
+            let limit = vcpu.read_vmcs(*limit)?;
+            check_segment(name, ar, limit, long_mode, unrestricted, findings);
+        }
+
+        let tr = vcpu.read_vmcs(Vmcs::GUEST_TR_AR)?;
+        let tr_type = tr & 0xf;
+        if tr & AR_UNUSABLE != 0 || tr == 0 {
+            findings.push("TR is unusable, it must refer to a busy TSS".into());
+        } else if tr & (AR_S | AR_P) != AR_P || !(tr_type == 11 || (tr_type == 3 && !long_mode)) {
+            findings.push(format!(
+                "TR access rights {:#x} don't describe a present busy TSS",
+                tr
+            ));
+        }
+
+        let ldtr = vcpu.read_vmcs(Vmcs::GUEST_LDTR_AR)?;
+        if ldtr & AR_UNUSABLE == 0 && ldtr & (AR_S | AR_P | 0xf) != (AR_P | 2) {
+            findings.push(format!(
+                "LDTR access rights {:#x} don't describe a present LDT, mark it unusable",
+                ldtr
+            ));
+        }
+
+        Ok(())
+    }
+
+    fn check_segment(
+        name: &str,
+        ar: u64,
+        limit: u64,
+        long_mode: bool,
+        unrestricted: bool,
+        findings: &mut Vec<String>,
+    ) {
+        if ar & AR_UNUSABLE != 0 {
+            if name == "CS" {
+                findings.push("CS is unusable".into());
+            }
+            return;
+        }
+
+        let ty = ar & 0xf;
+        let code = ty & 0x8 != 0;
+        if ar & AR_S == 0 {
+            findings.push(format!("{} is a system segment (S is clear)", name));
+        }
+        if ar & AR_P == 0 {
+            findings.push(format!("{} is not present", name));
+        }
+        if ar & AR_ACCESSED == 0 {
+            findings.push(format!("{} type {:#x} is not accessed", name, ty));
+        }
+
+        match name {
+            "CS" if !(code || (unrestricted && ty == 3)) => {
+                findings.push(format!("CS type {:#x} is not a code segment", ty));
+            }
+            "CS" if long_mode && ar & AR_L != 0 && ar & AR_DB != 0 => {
+                findings.push("CS has both L and D/B set".into());
+            }
+            "SS" if ty != 3 && ty != 7 => {
+                findings.push(format!("SS type {:#x} is not a writable data segment", ty));
+            }
+            _ if name != "CS" && code && ty & 0x2 == 0 => {
+                findings.push(format!("{} is an execute-only code segment", name));
+            }
+            _ => {}
+        }
+
+        if limit & 0xfff != 0xfff && ar & AR_G != 0 {
+            findings.push(format!(
+                "{} limit {:#x} is not page granular but G is set",
+                name, limit
+            ));
+        }
+        if limit > 0xf_ffff && ar & AR_G == 0 {
+            findings.push(format!(
+                "{} limit {:#x} exceeds 1 MiB but G is clear",
+                name, limit
+            ));
+        }
+    }
+}
+
+#[cfg(target_arch = "aarch64")]
+mod arch {
+    use super::*;
+    use crate::arm64::{Reg, SysReg, VcpuExt};
+
+    /// `CPSR.M[4]`, AArch32 execution state.
+    const CPSR_AARCH32: u64 = 1 << 4;
+
+    /// `SCTLR_EL1.M`, stage 1 MMU enable.
+    const SCTLR_M: u64 = 1 << 0;
+
+    pub fn check(vcpu: &Vcpu, findings: &mut Vec<String>) -> Result<(), Error> {
+        let cpsr = vcpu.get_reg(Reg::CPSR)?;
+        if cpsr & CPSR_AARCH32 != 0 {
+            findings.push(format!(
+                "CPSR {:#x} selects AArch32, which is not supported",
+                cpsr
+            ));
+        } else {
+            match cpsr & 0xf {
+                // EL0t, EL1t, EL1h.
+                0b0000 | 0b0100 | 0b0101 => {}
+                0b1000 | 0b1001 => findings.push(format!(
+                    "CPSR {:#x} selects EL2, which requires a VM with EL2 enabled",
+                    cpsr
+                )),
+                mode => findings.push(format!("CPSR mode {:#x} is invalid", mode)),
+            }
+        }
+
+        let pc = vcpu.get_reg(Reg::PC)?;
+        if pc & 0x3 != 0 {
+            findings.push(format!("PC {:#x} is not 4 byte aligned", pc));
+        }
+
+        if cpsr & 0xf == 0b0101 {
+            let sp = vcpu.get_sys_reg(SysReg::SP_EL1)?;
+            if sp & 0xf != 0 {
+                findings.push(format!("SP_EL1 {:#x} is not 16 byte aligned", sp));
+            }
+        }
+
+        let sctlr = vcpu.get_sys_reg(SysReg::SCTLR_EL1)?;
+        if sctlr & SCTLR_M != 0 {
+            if vcpu.get_sys_reg(SysReg::TCR_EL1)? == 0 {
+                findings.push("the MMU is enabled but TCR_EL1 is 0".into());
+            }
+            if vcpu.get_sys_reg(SysReg::MAIR_EL1)? == 0 {
+                findings.push("the MMU is enabled but MAIR_EL1 is 0".into());
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,211 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Duration;
+
+use crate::{Error, GPAddr, Vcpu};
+
+#[cfg(target_arch = "aarch64")]
+use crate::arm64::{Reg, VcpuExt};
+#[cfg(target_arch = "x86_64")]
+use crate::x86::{
+    vmx::{VCpuVmxExt, Vmcs},
+    Reg, VcpuExt,
+};
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum Slot {
+    Parked,
+    Starting { entry: GPAddr, arg: u64 },
+    Started,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    slots: HashMap<u64, Slot>,
+    /// Number of secondaries that called [VcpuGroup::park].
+    arrived: usize,
+}
+
+#[derive(Debug)]
+struct Shared {
+    /// IDs of the secondaries, fixed at creation.
+    secondaries: HashSet<u64>,
+    state: Mutex<State>,
+    cvar: Condvar,
+}
+
+/// Start-up synchronization of secondary vCPUs for SMP guests.
+///
+/// Secondary vCPU threads create their vCPU and call [VcpuGroup::park], which blocks until
+/// the guest brings the CPU up, e.g. with an INIT-SIPI sequence on x86 or PSCI `CPU_ON` on
+/// arm64, and the emulation calls [VcpuGroup::start_secondary].
+///
+/// Secondaries are identified by the ID the guest uses, like the APIC ID or the MPIDR
+/// affinity, and must be known when the group is created so starting any other ID can be
+/// rejected. The group can be cloned and shared between threads.
+///
+/// ```ignore
+/// let group = VcpuGroup::new(vec![1]);
+/// let secondary = group.clone();
+/// thread::spawn(move || {
+///     let cpu = vm.create_cpu()?;
+///     secondary.park(1, &cpu)?;
+///     cpu.run_loop(&mut handler)
+/// });
+///
+/// group.wait_parked(1, Duration::from_secs(1));
+/// // Later, on CPU_ON from the boot vCPU:
+/// group.start_secondary(1, entry, context_id)?;
+/// ```
+#[derive(Debug, Clone)]
+pub struct VcpuGroup {
+    shared: Arc<Shared>,
+}
+
+impl VcpuGroup {
+    /// Creates a group of the secondaries `ids`.
+    pub fn new<I>(ids: I) -> VcpuGroup
+    where
+        I: IntoIterator<Item = u64>,
+    {
+        let shared = Shared {
+            secondaries: ids.into_iter().collect(),
+            state: Mutex::default(),
+            cvar: Condvar::new(),
+        };
+        VcpuGroup {
+            shared: Arc::new(shared),
+        }
+    }
+
+    /// Returns `true` if `id` is a secondary of the group.
+    pub fn is_secondary(&self, id: u64) -> bool {
+        self.shared.secondaries.contains(&id)
+    }
+
+    /// Parks the secondary `id` until it's started, then sets up `vcpu` to enter the guest at
+    /// the requested entry point.
+    ///
+    /// # Intel
+    /// The vCPU starts in real mode at the start address, the CS selector and base are set
+    /// like a SIPI does, other state must be reset by the caller.
+    ///
+    /// # Apple Silicon
+    /// The vCPU starts at EL1h with interrupts masked, the argument is passed in X0.
+    ///
+    /// Must be called on the vCPU thread. Fails with [Error::BadArgument] if `id` isn't a
+    /// secondary of the group, with [Error::Busy] if it's already parked or started.
+    pub fn park(&self, id: u64, vcpu: &Vcpu) -> Result<(), Error> {
+        vcpu.assert_owner();
+        if !self.is_secondary(id) {
+            return Err(Error::BadArgument);
+        }
+
+        let mut state = self.shared.state.lock().unwrap();
+        match state.slots.get(&id) {
+            Some(Slot::Parked) | Some(Slot::Started) => return Err(Error::Busy),
+            Some(Slot::Starting { .. }) => {}
+            None => {
+                state.slots.insert(id, Slot::Parked);
+            }
+        }
+        state.arrived += 1;
+        self.shared.cvar.notify_all();
+
+        let (entry, arg) = loop {
+            if let Some(Slot::Starting { entry, arg }) = state.slots.get(&id) {
+                break (*entry, *arg);
+            }
+            state = self.shared.cvar.wait(state).unwrap();
+        };
+        state.slots.insert(id, Slot::Started);
+        drop(state);
+
+        set_entry(vcpu, entry, arg)
+    }
+
+    /// Releases the parked secondary `id` to enter the guest at `entry` with `arg`.
+    ///
+    /// A secondary started before it parked doesn't block in [VcpuGroup::park]. Fails with
+    /// [Error::BadArgument] if `id` isn't a secondary of the group (PSCI `INVALID_PARAMETERS`),
+    /// with [Error::Busy] if it was already started (PSCI `ALREADY_ON`).
+    ///
+    /// # Intel
+    /// `entry` is the real mode start address of a SIPI and must be page aligned below 1 MiB,
+    /// otherwise [Error::BadArgument] is returned. `arg` is ignored.
+    pub fn start_secondary(&self, id: u64, entry: GPAddr, arg: u64) -> Result<(), Error> {
+        if !self.is_secondary(id) {
+            return Err(Error::BadArgument);
+        }
+
+        #[cfg(target_arch = "x86_64")]
+        {
+            if entry & 0xfff != 0 || entry >= 0x10_0000 {
+                return Err(Error::BadArgument);
+            }
+        }
+
+        let mut state = self.shared.state.lock().unwrap();
+        match state.slots.get(&id) {
+            Some(Slot::Starting { .. }) | Some(Slot::Started) => Err(Error::Busy),
+            Some(Slot::Parked) | None => {
+                state.slots.insert(id, Slot::Starting { entry, arg });
+                self.shared.cvar.notify_all();
+                Ok(())
+            }
+        }
+    }
+
+    /// Starts the secondary `id` as a SIPI with the given vector does.
+    #[cfg(target_arch = "x86_64")]
+    pub fn start_secondary_sipi(&self, id: u64, vector: u8) -> Result<(), Error> {
+        self.start_secondary(id, (vector as GPAddr) << 12, 0)
+    }
+
+    /// Waits until `count` secondaries called [VcpuGroup::park], returns `false` on timeout.
+    ///
+    /// Lets the boot vCPU run only once all secondaries are ready to be started.
+    pub fn wait_parked(&self, count: usize, timeout: Duration) -> bool {
+        let state = self.shared.state.lock().unwrap();
+        let (state, _) = self
+            .shared
+            .cvar
+            .wait_timeout_while(state, timeout, |state| state.arrived < count)
+            .unwrap();
+        state.arrived >= count
+    }
+
+    /// Marks the secondary `id` as stopped, e.g. after PSCI `CPU_OFF`, so its thread can park
+    /// it again to be restarted.
+    pub fn stop_secondary(&self, id: u64) {
+        let mut state = self.shared.state.lock().unwrap();
+        if state.slots.remove(&id).is_some() {
+            state.arrived = state.arrived.saturating_sub(1);
+        }
+    }
+
+    /// Returns `true` if the secondary `id` was started.
+    pub fn is_started(&self, id: u64) -> bool {
+        matches!(
+            self.shared.state.lock().unwrap().slots.get(&id),
+            Some(Slot::Starting { .. }) | Some(Slot::Started)
+        )
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+fn set_entry(vcpu: &Vcpu, entry: GPAddr, _arg: u64) -> Result<(), Error> {
+    vcpu.write_vmcs(Vmcs::GUEST_CS, entry >> 4)?;
+    vcpu.write_vmcs(Vmcs::GUEST_CS_BASE, entry)?;
+    vcpu.write_register(Reg::RIP, 0)
+}
+
+#[cfg(target_arch = "aarch64")]
+fn set_entry(vcpu: &Vcpu, entry: GPAddr, arg: u64) -> Result<(), Error> {
+    /// EL1h with all interrupts masked.
+    const CPSR_DEFAULT: u64 = 0x3c5;
+
+    vcpu.set_reg(Reg::CPSR, CPSR_DEFAULT)?;
+    vcpu.set_reg(Reg::PC, entry)?;
+    vcpu.set_reg(Reg::X0, arg)
+}
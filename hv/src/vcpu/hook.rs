@@ -0,0 +1,31 @@
+use crate::{Error, Exit, Vcpu};
+
+/// Callback of [Vcpu::set_exit_hook].
+pub(crate) type Callback = Box<dyn FnMut(&Vcpu, &Exit) -> Result<(), Error>>;
+
+/// Calls a callback every `every` exits.
+pub(crate) struct ExitHook {
+    every: u64,
+    count: u64,
+    callback: Callback,
+}
+
+impl ExitHook {
+    pub fn new(every: u64, callback: Callback) -> ExitHook {
+        ExitHook {
+            every,
+            count: 0,
+            callback,
+        }
+    }
+
+    /// Counts an exit, calls the callback if it's due.
+    pub fn exit(&mut self, vcpu: &Vcpu, exit: &Exit) -> Result<(), Error> {
+        self.count += 1;
+        if self.count < self.every {
+            return Ok(());
+        }
+        self.count = 0;
+        (self.callback)(vcpu, exit)
+    }
+}
@@ -0,0 +1,235 @@
+use std::sync::{Arc, Mutex};
+
+use super::VcpuHandle;
+use crate::{Error, Vcpu};
+
+/// An interrupt request for [IrqQueue::raise].
+#[cfg(target_arch = "x86_64")]
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Interrupt {
+    /// Non-maskable interrupt, delivered before any external interrupt.
+    Nmi,
+    /// External interrupt with the given vector, higher vectors are delivered first.
+    Vector(u8),
+}
+
+/// An interrupt line for [IrqQueue::raise] and [IrqQueue::lower].
+#[cfg(target_arch = "aarch64")]
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Interrupt {
+    Irq,
+    Fiq,
+}
+
+/// Interrupts waiting to be injected, shared between a vCPU and its queues.
+#[derive(Debug, Default)]
+pub(crate) struct Pending {
+    state: Mutex<arch::State>,
+}
+
+/// Delivers interrupts to a vCPU from any thread.
+///
+/// Obtained with [Vcpu::irq_queue]. Raising an interrupt kicks the vCPU, which injects it
+/// before re-entering the guest, once the guest can take it.
+///
+/// # Intel
+/// Interrupts are edge triggered. Requests for the same vector are coalesced until it's
+/// injected, like in the IRR of a local APIC. NMIs are delivered first, then external
+/// interrupts from the highest vector down. While the guest blocks interrupts, interrupt
+/// window exiting is enabled, the resulting [Exit::IrqWindow](crate::x86::Exit::IrqWindow)
+/// needs no handling besides resuming the guest. Blocked NMIs use NMI window exiting if
+/// virtual NMIs are enabled, see `VcpuExt::enable_virtual_nmi`.
+///
+/// # Apple Silicon
+/// Interrupts are level triggered: a raised line stays pending on every run until it's
+/// lowered, typically once the guest acknowledged the interrupt at the emulated interrupt
+/// controller.
+#[derive(Debug, Clone)]
+pub struct IrqQueue {
+    pending: Arc<Pending>,
+    handle: VcpuHandle,
+}
+
+impl IrqQueue {
+    pub(crate) fn new(pending: Arc<Pending>, handle: VcpuHandle) -> IrqQueue {
+        IrqQueue { pending, handle }
+    }
+
+    /// Requests `irq` and kicks the vCPU to inject it.
+    pub fn raise(&self, irq: Interrupt) -> Result<(), Error> {
+        self.pending.state.lock().unwrap().raise(irq);
+        self.handle.kick()
+    }
+
+    /// Deasserts the `irq` line.
+    #[cfg(target_arch = "aarch64")]
+    pub fn lower(&self, irq: Interrupt) {
+        self.pending.state.lock().unwrap().lower(irq);
+    }
+
+    /// Returns `true` if interrupts are waiting to be injected.
+    pub fn is_pending(&self) -> bool {
+        !self.pending.state.lock().unwrap().is_empty()
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+impl Pending {
+    /// Requests `irq` without kicking, for the vCPU thread itself.
+    pub fn raise(&self, irq: Interrupt) {
+        self.state.lock().unwrap().raise(irq);
+    }
+}
+
+/// Injects pending interrupts into `vcpu`, called right before entering the guest.
+pub(crate) fn inject(vcpu: &Vcpu, pending: &Pending) -> Result<(), Error> {
+    let mut state = pending.state.lock().unwrap();
+    arch::inject(vcpu, &mut state)
+}
+
+#[cfg(target_arch = "x86_64")]
+mod arch {
+    use std::collections::BTreeSet;
+
+    use super::*;
+    use crate::x86::vmx::{VCpuVmxExt, Vmcs};
+    use crate::x86::{Event, Interruptibility, Reg, VcpuExt};
+
+    /// Interrupt window exiting VM-execution control.
+    const CPU_BASED_IRQ_WND: u64 = 1 << 2;
+
+    /// NMI window exiting VM-execution control.
+    const CPU_BASED_NMI_WND: u64 = 1 << 22;
+
+    /// Virtual NMIs pin-based VM-execution control.
+    const PIN_BASED_VIRTUAL_NMI: u64 = 1 << 5;
+
+    /// `RFLAGS.IF`.
+    const RFLAGS_IF: u64 = 1 << 9;
+
+    #[derive(Debug, Default)]
+    pub struct State {
+        nmi: bool,
+        vectors: BTreeSet<u8>,
+        /// Interrupt window exiting was enabled by the queue.
+        window: bool,
+        /// NMI window exiting was enabled by the queue.
+        nmi_window: bool,
+    }
+
+    impl State {
+        pub fn raise(&mut self, irq: Interrupt) {
+            match irq {
+                Interrupt::Nmi => self.nmi = true,
+                Interrupt::Vector(vector) => {
+                    self.vectors.insert(vector);
+                }
+            }
+        }
+
+        pub fn is_empty(&self) -> bool {
+            !self.nmi && self.vectors.is_empty()
+        }
+    }
+
+    pub fn inject(vcpu: &Vcpu, state: &mut State) -> Result<(), Error> {
+        if state.is_empty() && !state.window && !state.nmi_window {
+            return Ok(());
+        }
+
+        // An event is already being injected, e.g. by the embedder.
+        if !vcpu.is_event_pending()? {
+            let blocking = vcpu.interruptibility()?;
+            let rflags = vcpu.read_register(Reg::RFLAGS)?;
+
+            let event = if state.nmi
+                && !blocking.in_shadow()
+                && !blocking.contains(Interruptibility::NMI)
+            {
+                state.nmi = false;
+                Some(Event::Nmi)
+            } else if rflags & RFLAGS_IF != 0 && !blocking.in_shadow() {
+                state.vectors.iter().next_back().copied().map(|vector| {
+                    state.vectors.remove(&vector);
+                    Event::ExternalInterrupt(vector)
+                })
+            } else {
+                None
+            };
+
+            if let Some(event) = event {
+                vcpu.inject_event(event)?;
+            }
+        }
+
+        // With virtual NMIs, blocked NMIs are retried once the NMI window opens, otherwise
+        // once the interrupt window opens.
+        let virtual_nmi =
+            state.nmi && vcpu.read_vmcs(Vmcs::CTRL_PIN_BASED)? & PIN_BASED_VIRTUAL_NMI != 0;
+        let nmi_window = state.nmi && virtual_nmi;
+        let window = !state.vectors.is_empty() || (state.nmi && !virtual_nmi);
+
+        if window != state.window || nmi_window != state.nmi_window {
+            let mut ctrl = vcpu.read_vmcs(Vmcs::CTRL_CPU_BASED)?;
+            ctrl = set_bits(ctrl, CPU_BASED_IRQ_WND, window);
+            ctrl = set_bits(ctrl, CPU_BASED_NMI_WND, nmi_window);
+            vcpu.write_vmcs(Vmcs::CTRL_CPU_BASED, ctrl)?;
+            state.window = window;
+            state.nmi_window = nmi_window;
+        }
+
+        Ok(())
+    }
+
+    fn set_bits(value: u64, bits: u64, set: bool) -> u64 {
+        if set {
+            value | bits
+        } else {
+            value & !bits
+        }
+    }
+}
+
+#[cfg(target_arch = "aarch64")]
+mod arch {
+    use super::*;
+    use crate::arm64::{InterruptType, VcpuExt};
+
+    #[derive(Debug, Default)]
+    pub struct State {
+        irq: bool,
+        fiq: bool,
+    }
+
+    impl State {
+        pub fn raise(&mut self, irq: Interrupt) {
+            self.set(irq, true);
+        }
+
+        pub fn lower(&mut self, irq: Interrupt) {
+            self.set(irq, false);
+        }
+
+        fn set(&mut self, irq: Interrupt, asserted: bool) {
+            match irq {
+                Interrupt::Irq => self.irq = asserted,
+                Interrupt::Fiq => self.fiq = asserted,
+            }
+        }
+
+        pub fn is_empty(&self) -> bool {
+            !self.irq && !self.fiq
+        }
+    }
+
+    pub fn inject(vcpu: &Vcpu, state: &mut State) -> Result<(), Error> {
+        // The framework clears pending interrupts when `hv_vcpu_run` returns.
+        if state.fiq {
+            vcpu.set_pending_interrupt(InterruptType::FIQ, true)?;
+        }
+        if state.irq {
+            vcpu.set_pending_interrupt(InterruptType::IRQ, true)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,92 @@
+use std::mem;
+use std::time::Duration;
+
+use crate::Error;
+
+/// Quality of service classes of the host scheduler, see [Vcpu::set_qos_class].
+///
+/// [Vcpu::set_qos_class]: crate::Vcpu::set_qos_class
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum QosClass {
+    UserInteractive,
+    UserInitiated,
+    Default,
+    Utility,
+    Background,
+}
+
+impl QosClass {
+    fn raw(self) -> libc::qos_class_t {
+        match self {
+            QosClass::UserInteractive => libc::QOS_CLASS_USER_INTERACTIVE,
+            QosClass::UserInitiated => libc::QOS_CLASS_USER_INITIATED,
+            QosClass::Default => libc::QOS_CLASS_DEFAULT,
+            QosClass::Utility => libc::QOS_CLASS_UTILITY,
+            QosClass::Background => libc::QOS_CLASS_BACKGROUND,
+        }
+    }
+}
+
+/// CPU time the thread needs per scheduling period when latency sensitive.
+const COMPUTATION: Duration = Duration::from_micros(500);
+
+/// Latest completion of the computation after the thread became runnable.
+const CONSTRAINT: Duration = Duration::from_millis(1);
+
+/// Maps a mach `kern_return_t`.
+fn kern_result(ret: libc::kern_return_t) -> Result<(), Error> {
+    /// `KERN_INVALID_ARGUMENT`.
+    const INVALID_ARGUMENT: libc::kern_return_t = 4;
+    /// `KERN_NOT_SUPPORTED`.
+    const NOT_SUPPORTED: libc::kern_return_t = 46;
+
+    match ret {
+        0 => Ok(()),
+        INVALID_ARGUMENT => Err(Error::BadArgument),
+        NOT_SUPPORTED => Err(Error::Unsupported),
+        _ => Err(Error::Unsuccessful),
+    }
+}
+
+/// Applies a mach thread policy to the current thread.
+fn set_policy<T>(flavor: libc::c_int, policy: &mut T) -> Result<(), Error> {
+    let count = mem::size_of::<T>() / mem::size_of::<libc::integer_t>();
+    kern_result(unsafe {
+        let thread = libc::pthread_mach_thread_np(libc::pthread_self());
+        libc::thread_policy_set(
+            thread,
+            flavor as libc::thread_policy_flavor_t,
+            policy as *mut T as libc::thread_policy_t,
+            count as libc::mach_msg_type_number_t,
+        )
+    })
+}
+
+pub(crate) fn set_qos_class(class: QosClass) -> Result<(), Error> {
+    match unsafe { libc::pthread_set_qos_class_self_np(class.raw(), 0) } {
+        0 => Ok(()),
+        _ => Err(Error::Unsuccessful),
+    }
+}
+
+pub(crate) fn set_affinity_tag(tag: i32) -> Result<(), Error> {
+    let mut policy = libc::thread_affinity_policy { affinity_tag: tag };
+    set_policy(libc::THREAD_AFFINITY_POLICY, &mut policy)
+}
+
+pub(crate) fn set_latency_sensitive(enabled: bool) -> Result<(), Error> {
+    if enabled {
+        set_qos_class(QosClass::UserInteractive)?;
+        let mut policy = libc::thread_time_constraint_policy {
+            period: 0,
+            computation: crate::time::ticks_from_duration(COMPUTATION) as u32,
+            constraint: crate::time::ticks_from_duration(CONSTRAINT) as u32,
+            preemptible: 1,
+        };
+        set_policy(libc::THREAD_TIME_CONSTRAINT_POLICY, &mut policy)
+    } else {
+        let mut policy = libc::thread_extended_policy { timeshare: 1 };
+        set_policy(libc::THREAD_EXTENDED_POLICY, &mut policy)?;
+        set_qos_class(QosClass::Default)
+    }
+}
@@ -0,0 +1,121 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use crate::Exit;
+
+/// Execution statistics of a vCPU, see [Vcpu::stats](crate::Vcpu::stats).
+#[derive(Debug, Default, Clone, Eq, PartialEq)]
+pub struct VcpuStats {
+    /// Time spent executing the guest.
+    pub exec_time: Duration,
+    /// Number of [Vcpu::run](crate::Vcpu::run) calls.
+    pub runs: u64,
+    /// Number of exits by reason.
+    pub exits: ExitStats,
+    /// Time spent in the host between an exit and the next run, i.e. handling exits.
+    pub host_time: Duration,
+}
+
+/// Exit counts by [Exit::name].
+///
+/// `Display` prints a histogram sorted by count:
+///
+/// ```text
+///  io                  9120  91.2%
+///  ept_violation        850   8.5%
+///  hlt                   30   0.3%
+/// ```
+#[derive(Debug, Default, Clone, Eq, PartialEq)]
+pub struct ExitStats {
+    counts: BTreeMap<&'static str, u64>,
+}
+
+impl ExitStats {
+    /// Returns the number of exits named `name`.
+    pub fn get(&self, name: &str) -> u64 {
+        self.counts.get(name).copied().unwrap_or(0)
+    }
+
+    /// Returns the number of exits of all kinds.
+    pub fn total(&self) -> u64 {
+        self.counts.values().sum()
+    }
+
+    /// Iterates over exit names and counts, in alphabetical order.
+    pub fn iter(&self) -> impl Iterator<Item = (&'static str, u64)> + '_ {
+        self.counts.iter().map(|(name, count)| (*name, *count))
+    }
+
+    /// Returns exit names and counts, most frequent first.
+    pub fn sorted(&self) -> Vec<(&'static str, u64)> {
+        let mut out: Vec<_> = self.iter().collect();
+        out.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+        out
+    }
+
+    fn add(&mut self, name: &'static str) {
+        *self.counts.entry(name).or_insert(0) += 1;
+    }
+}
+
+impl fmt::Display for ExitStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let total = self.total().max(1) as f64;
+        let width = self.counts.keys().map(|name| name.len()).max().unwrap_or(0);
+        for (name, count) in self.sorted() {
+            writeln!(
+                f,
+                " {:<width$} {:>10} {:>5.1}%",
+                name,
+                count,
+                count as f64 * 100.0 / total,
+                width = width
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Counters updated by the vCPU.
+#[derive(Debug, Default)]
+pub(crate) struct Counters {
+    runs: u64,
+    exits: ExitStats,
+    host_time: Duration,
+    /// Execution time at the last reset.
+    exec_base: Duration,
+    last_exit: Option<Instant>,
+}
+
+impl Counters {
+    /// Accounts entering the guest.
+    pub fn enter(&mut self) {
+        self.runs += 1;
+        if let Some(exit) = self.last_exit.take() {
+            self.host_time += exit.elapsed();
+        }
+    }
+
+    /// Accounts a decoded exit.
+    pub fn exit(&mut self, exit: &Exit) {
+        self.exits.add(exit.name());
+        self.last_exit = Some(Instant::now());
+    }
+
+    pub fn snapshot(&self, exec_time: Duration) -> VcpuStats {
+        VcpuStats {
+            exec_time: exec_time.checked_sub(self.exec_base).unwrap_or_default(),
+            runs: self.runs,
+            exits: self.exits.clone(),
+            host_time: self.host_time,
+        }
+    }
+
+    pub fn reset(&mut self, exec_time: Duration) {
+        *self = Counters {
+            exec_base: exec_time,
+            ..Counters::default()
+        };
+    }
+}
@@ -1,22 +1,62 @@
 use std::ffi::c_void;
-use std::sync::Arc;
+use std::sync::{Arc, Condvar, Mutex, PoisonError, Weak};
+use std::time::Duration;
 
-use crate::{call, sys, Addr, Error, GPAddr, Memory, Size, Vcpu};
+use crate::memory::{HostMemory, Mapping};
+use crate::vcpu::Id;
+use crate::{
+    call, sys, validate_mapping, Addr, Error, GPAddr, Memory, Size, Vcpu, VcpuConfig, VcpuHandle,
+    VcpuState,
+};
+
+mod layout;
+use layout::Layout;
+pub use layout::{MemoryMap, Region};
 
 #[cfg(target_arch = "x86_64")]
 pub type Options = crate::x86::VmOptions;
 
 #[cfg(target_arch = "aarch64")]
-pub type Options = sys::hv_vm_config_t;
+pub type Options = crate::arm64::VmConfig;
 
 /// Vm is an entry point to Hypervisor Framework.
 #[derive(Debug)]
-pub struct Vm;
+pub struct Vm {
+    /// Mappings of the default guest address space.
+    layout: Mutex<Layout>,
+    /// IDs of live vCPUs.
+    vcpus: Mutex<Vec<Id>>,
+    /// VPIDs handed out to vCPUs.
+    #[cfg(target_arch = "x86_64")]
+    pub(crate) vpids: crate::x86::VpidAllocator,
+    /// Size of the guest physical address space in bits, if the framework reports it.
+    #[cfg(target_arch = "aarch64")]
+    ipa_size: Option<u32>,
+}
+
+/// Process-wide VM bookkeeping, Hypervisor Framework allows only one VM per process.
+#[derive(Default)]
+struct Registry {
+    /// A VM object exists (it may not be shared via [Arc]).
+    alive: bool,
+    /// The VM created with [Vm::new_shared], if any.
+    shared: Option<Weak<Vm>>,
+}
+
+lazy_static::lazy_static! {
+    static ref REGISTRY: (Mutex<Registry>, Condvar) = Default::default();
+}
 
 /// Destroys the VM instance associated with the current process.
 impl Drop for Vm {
+    /// Destroys the VM, ignoring errors: panicking here could abort an unwinding thread.
     fn drop(&mut self) {
-        call!(sys::hv_vm_destroy()).unwrap()
+        let _ = call!(sys::hv_vm_destroy());
+
+        let (lock, cvar) = &*REGISTRY;
+        let mut registry = lock.lock().unwrap_or_else(PoisonError::into_inner);
+        *registry = Registry::default();
+        cvar.notify_all();
     }
 }
 
@@ -26,17 +66,82 @@ impl Vm {
     /// Creates a VM instance for the current process.
     ///
     /// Only one VM object can exists at a time.
-    /// All subsequent attempts will return an error from Hypervisor Framework.
+    /// All subsequent attempts will return [Error::VmExists] without calling the framework.
     ///
     /// In order to create child objects (`Vcpu`, `Space`, etc), this object must be wrapped
     /// with [Arc].
     ///
     pub fn new(options: Options) -> Result<Vm, Error> {
+        let (lock, _) = &*REGISTRY;
+        let mut registry = lock.lock().unwrap();
+        Vm::create(&mut registry, options)
+    }
+
+    /// Creates a VM instance for the current process and makes it available via
+    /// [Vm::try_existing].
+    pub fn new_shared(options: Options) -> Result<Arc<Vm>, Error> {
+        let (lock, _) = &*REGISTRY;
+        let mut registry = lock.lock().unwrap();
+        let vm = Arc::new(Vm::create(&mut registry, options)?);
+        registry.shared = Some(Arc::downgrade(&vm));
+        Ok(vm)
+    }
+
+    /// Creates a VM instance once the existing one is destroyed.
+    ///
+    /// The VM is destroyed when the last reference to it is dropped, which includes
+    /// all vCPUs and address spaces created from it. Returns [Error::VmExists] if that doesn't
+    /// happen within `timeout`.
+    pub fn recreate(options: Options, timeout: Duration) -> Result<Vm, Error> {
+        let (lock, cvar) = &*REGISTRY;
+        let registry = lock.lock().unwrap();
+        let (mut registry, _) = cvar
+            .wait_timeout_while(registry, timeout, |r| r.alive)
+            .unwrap();
+        Vm::create(&mut registry, options)
+    }
+
+    /// Returns the VM created with [Vm::new_shared] if it still exists.
+    ///
+    /// Allows libraries embedded into larger applications to reuse a VM created elsewhere in
+    /// the process.
+    pub fn try_existing() -> Option<Arc<Vm>> {
+        let (lock, _) = &*REGISTRY;
+        let registry = lock.lock().unwrap();
+        registry.shared.as_ref().and_then(Weak::upgrade)
+    }
+
+    /// Returns `true` if a VM exists in the current process.
+    pub fn exists() -> bool {
+        let (lock, _) = &*REGISTRY;
+        lock.lock().unwrap().alive
+    }
+
+    fn create(registry: &mut Registry, options: Options) -> Result<Vm, Error> {
+        if registry.alive {
+            return Err(Error::VmExists);
+        }
+
         #[cfg(target_arch = "x86_64")]
-        let options = options.bits();
+        let raw = options.bits();
+        #[cfg(target_arch = "aarch64")]
+        let raw = options.raw();
+        #[cfg(all(target_arch = "aarch64", feature = "hv_13_0"))]
+        let ipa_size = options.ipa_size().ok();
+        #[cfg(all(target_arch = "aarch64", not(feature = "hv_13_0")))]
+        let ipa_size = None;
+
+        call!(sys::hv_vm_create(raw))?;
+        registry.alive = true;
 
-        call!(sys::hv_vm_create(options))?;
-        Ok(Vm)
+        Ok(Vm {
+            layout: Mutex::new(Layout::default()),
+            vcpus: Mutex::new(Vec::new()),
+            #[cfg(target_arch = "x86_64")]
+            vpids: Default::default(),
+            #[cfg(target_arch = "aarch64")]
+            ipa_size,
+        })
     }
 
     /// Creates a vCPU instance for the current thread.
@@ -47,6 +152,45 @@ impl Vm {
         Vcpu::new(Arc::clone(&self))
     }
 
+    /// Creates a vCPU instance for the current thread and restores `state` into it, e.g. to
+    /// hotplug a CPU or fork one captured with [Vcpu::save_state].
+    ///
+    /// The new vCPU keeps its own CPU identity (`MPIDR_EL1` on arm64, `TSC_AUX` on Intel),
+    /// see [VcpuState::without_identity]. On Intel, VM execution controls aren't part of the
+    /// state and must be set up like for any new vCPU.
+    pub fn create_cpu_from(self: Arc<Self>, state: &VcpuState) -> Result<Vcpu, Error> {
+        let cpu = Vcpu::new(self)?;
+        cpu.restore_state(&state.clone().without_identity())?;
+        Ok(cpu)
+    }
+
+    /// Creates a vCPU instance for the current thread with creation options, e.g. to query
+    /// or override its feature registers on Apple Silicon before the first run.
+    pub fn create_cpu_with(self: Arc<Self>, config: &VcpuConfig) -> Result<Vcpu, Error> {
+        Vcpu::with_config(self, Some(config))
+    }
+
+    /// Forces an immediate exit of the given vCPUs with a single framework call.
+    pub fn kick(&self, vcpus: &[VcpuHandle]) -> Result<(), Error> {
+        let mut ids: Vec<Id> = vcpus.iter().map(VcpuHandle::id).collect();
+        exit_vcpus(&mut ids)
+    }
+
+    /// Forces an immediate exit of every vCPU of the VM, e.g. to stop the guest for a
+    /// snapshot or shutdown.
+    pub fn kick_all(&self) -> Result<(), Error> {
+        let mut ids = self.vcpus.lock().unwrap().clone();
+        exit_vcpus(&mut ids)
+    }
+
+    pub(crate) fn add_vcpu(&self, id: Id) {
+        self.vcpus.lock().unwrap().push(id);
+    }
+
+    pub(crate) fn remove_vcpu(&self, id: Id) {
+        self.vcpus.lock().unwrap().retain(|v| *v != id);
+    }
+
     /// Maps a region in the virtual address space of the current task into the guest physical
     /// address space of the VM.
     ///
@@ -59,15 +203,68 @@ impl Vm {
     /// * `size` - Size in bytes of the region to be mapped.
     /// * `flags` - READ, WRITE and EXECUTE permissions of the region
     ///
+    /// Arguments are validated before calling the framework, see [Error::InvalidMapping]. On
+    /// Apple Silicon the region must be within the configured IPA size, see [Vm::ipa_size].
+    ///
     /// [1]: https://developer.apple.com/documentation/hypervisor/1441187-hv_vm_map
     ///
     pub fn map(&self, uva: Addr, gpa: GPAddr, size: Size, flags: Memory) -> Result<(), Error> {
+        self.map_region(None, uva, gpa, size, flags)
+    }
+
+    /// Same as [Vm::map], additionally labels the region for [Vm::dump_layout].
+    pub fn map_named(
+        &self,
+        name: &str,
+        uva: Addr,
+        gpa: GPAddr,
+        size: Size,
+        flags: Memory,
+    ) -> Result<(), Error> {
+        self.map_region(Some(name.to_string()), uva, gpa, size, flags)
+    }
+
+    fn map_region(
+        &self,
+        name: Option<String>,
+        uva: Addr,
+        gpa: GPAddr,
+        size: Size,
+        flags: Memory,
+    ) -> Result<(), Error> {
+        validate_mapping(Some(uva), gpa, size, Some(flags))?;
+        #[cfg(target_arch = "aarch64")]
+        self.validate_ipa(gpa, size)?;
+
         call!(sys::hv_vm_map(
             uva as *mut c_void,
             gpa,
             size,
             flags.bits() as _
-        ))
+        ))?;
+
+        self.layout.lock().unwrap().insert(Region {
+            gpa,
+            size,
+            flags,
+            name,
+            uva: uva as usize,
+        });
+
+        Ok(())
+    }
+
+    /// Maps owned host memory into the guest physical address space of the VM.
+    ///
+    /// Unlike [Vm::map], the returned [Mapping] keeps the host memory alive for as long as
+    /// it's mapped, and unmaps the region when dropped.
+    pub fn map_memory(
+        self: Arc<Self>,
+        mem: HostMemory,
+        gpa: GPAddr,
+        flags: Memory,
+    ) -> Result<Mapping, Error> {
+        Mapping::new(self, mem, gpa, flags)
     }
 
     /// Unmaps a region in the guest physical address space of the VM
@@ -76,7 +273,10 @@ impl Vm {
     /// * `gpa` - Page aligned address in the guest physical address space.
     /// * `size` - Size in bytes of the region to be unmapped.
     pub fn unmap(&self, gpa: GPAddr, size: Size) -> Result<(), Error> {
-        call!(sys::hv_vm_unmap(gpa, size))
+        validate_mapping(None, gpa, size, None)?;
+        call!(sys::hv_vm_unmap(gpa, size))?;
+        self.layout.lock().unwrap().remove(gpa, size);
+        Ok(())
     }
 
     /// Modifies the permissions of a region in the guest physical address space of the VM.
@@ -86,6 +286,103 @@ impl Vm {
     /// * `size` - Size in bytes of the region to be modified.
     /// * `flags` - New READ, WRITE and EXECUTE permissions of the region.
     pub fn protect(&self, gpa: GPAddr, size: Size, flags: Memory) -> Result<(), Error> {
-        call!(sys::hv_vm_protect(gpa, size, flags.bits() as _))
+        validate_mapping(None, gpa, size, Some(flags))?;
+        call!(sys::hv_vm_protect(gpa, size, flags.bits() as _))?;
+        self.layout.lock().unwrap().protect(gpa, size, flags);
+        Ok(())
+    }
+
+    /// Returns the size of the guest physical address space in bits, as configured with
+    /// `VmConfig::set_ipa_size`, `None` without the `hv_13_0` feature.
+    #[cfg(target_arch = "aarch64")]
+    pub fn ipa_size(&self) -> Option<u32> {
+        self.ipa_size
+    }
+
+    /// Checks that a region ends within the guest physical address space.
+    #[cfg(target_arch = "aarch64")]
+    fn validate_ipa(&self, gpa: GPAddr, size: Size) -> Result<(), Error> {
+        match self.ipa_size {
+            Some(bits) if bits < 64 && gpa + size > 1 << bits => Err(Error::InvalidMapping {
+                reason: crate::MappingError::OutOfRange(bits),
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    /// Returns the permissions of the mapping containing `gpa`.
+    pub(crate) fn flags_at(&self, gpa: GPAddr) -> Option<Memory> {
+        self.layout.lock().unwrap().find(gpa).map(|r| r.flags)
+    }
+
+    /// Returns the current layout of the default guest physical address space.
+    ///
+    /// Regions partially unmapped or protected are reported as separate entries.
+    pub fn dump_layout(&self) -> MemoryMap {
+        MemoryMap {
+            regions: self.layout.lock().unwrap().regions().to_vec(),
+        }
+    }
+
+    /// Computes SHA-256 of `size` bytes of guest memory at `gpa`.
+    ///
+    /// The whole range must be mapped with [Vm::map] (or helpers built on it). Useful to assert
+    /// that two runs end up with identical guest memory in record/replay and differential
+    /// testing. vCPUs should be stopped, otherwise the result is racy.
+    ///
+    /// # Safety
+    /// The host memory passed to [Vm::map] for the range must still be allocated, the VM only
+    /// records its address.
+    #[cfg(feature = "memory_hash")]
+    pub unsafe fn hash_memory(&self, gpa: GPAddr, size: Size) -> Result<[u8; 32], Error> {
+        use crate::MappingError;
+        use sha2::{Digest, Sha256};
+
+        let end = gpa.checked_add(size).ok_or(Error::InvalidMapping {
+            reason: MappingError::Overflow,
+        })?;
+
+        let layout = self.layout.lock().unwrap();
+        let mut hasher = Sha256::new();
+        let mut addr = gpa;
+
+        for region in layout.overlapping(gpa, size) {
+            if region.gpa > addr {
+                break;
+            }
+
+            let chunk_end = end.min(region.gpa + region.size);
+            let offset = (addr - region.gpa) as usize;
+            let data = std::slice::from_raw_parts(
+                (region.uva + offset) as *const u8,
+                (chunk_end - addr) as usize,
+            );
+            hasher.update(data);
+            addr = chunk_end;
+        }
+
+        if addr < end {
+            return Err(Error::InvalidMapping {
+                reason: MappingError::NotMapped(addr),
+            });
+        }
+
+        Ok(hasher.finalize().into())
+    }
+}
+
+fn exit_vcpus(ids: &mut [Id]) -> Result<(), Error> {
+    if ids.is_empty() {
+        return Ok(());
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    {
+        call!(sys::hv_vcpu_interrupt(ids.as_mut_ptr(), ids.len() as u32))
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    {
+        call!(sys::hv_vcpus_exit(ids.as_mut_ptr(), ids.len() as u32))
     }
 }
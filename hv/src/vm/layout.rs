@@ -0,0 +1,254 @@
+use std::fmt;
+
+use crate::{GPAddr, Memory, Size};
+
+/// A range of the default guest physical address space mapped with [Vm::map](super::Vm::map).
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Region {
+    pub gpa: GPAddr,
+    pub size: Size,
+    pub flags: Memory,
+    /// Label given with [Vm::map_named](super::Vm::map_named).
+    pub name: Option<String>,
+    /// Host address backing the region.
+    pub(crate) uva: usize,
+}
+
+impl Region {
+    /// Returns the guest physical address right after the region.
+    pub fn end(&self) -> GPAddr {
+        self.gpa + self.size
+    }
+}
+
+/// Snapshot of the guest physical memory layout, see [Vm::dump_layout](super::Vm::dump_layout).
+///
+/// Displays as a table with one line per region, including unmapped holes between regions.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct MemoryMap {
+    pub regions: Vec<Region>,
+}
+
+impl fmt::Display for MemoryMap {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut prev_end = None;
+
+        for region in &self.regions {
+            if let Some(end) = prev_end.filter(|end| *end < region.gpa) {
+                writeln!(f, "{:#018x}-{:#018x} ---  <hole>", end, region.gpa)?;
+            }
+
+            let perm = |flag, c| if region.flags.contains(flag) { c } else { '-' };
+            writeln!(
+                f,
+                "{:#018x}-{:#018x} {}{}{}  {}",
+                region.gpa,
+                region.end(),
+                perm(Memory::READ, 'r'),
+                perm(Memory::WRITE, 'w'),
+                perm(Memory::EXEC, 'x'),
+                region.name.as_deref().unwrap_or("<unnamed>"),
+            )?;
+
+            prev_end = Some(region.end());
+        }
+
+        Ok(())
+    }
+}
+
+/// Bookkeeping of guest physical memory mappings, sorted by guest physical address.
+///
+/// Mirrors the state of the framework: regions are split on partial unmap and protect.
+#[derive(Debug, Default)]
+pub(crate) struct Layout {
+    regions: Vec<Region>,
+}
+
+impl Layout {
+    pub fn insert(&mut self, region: Region) {
+        let index = self.regions.partition_point(|r| r.gpa < region.gpa);
+        self.regions.insert(index, region);
+    }
+
+    pub fn remove(&mut self, gpa: GPAddr, size: Size) {
+        let end = gpa + size;
+        self.split_at(gpa);
+        self.split_at(end);
+        self.regions.retain(|r| r.gpa < gpa || r.gpa >= end);
+    }
+
+    pub fn protect(&mut self, gpa: GPAddr, size: Size, flags: Memory) {
+        let end = gpa + size;
+        self.split_at(gpa);
+        self.split_at(end);
+        for r in self
+            .regions
+            .iter_mut()
+            .filter(|r| r.gpa >= gpa && r.gpa < end)
+        {
+            r.flags = flags;
+        }
+    }
+
+    pub fn regions(&self) -> &[Region] {
+        &self.regions
+    }
+
+    /// Returns the region containing `gpa`.
+    pub fn find(&self, gpa: GPAddr) -> Option<&Region> {
+        self.regions.iter().find(|r| r.gpa <= gpa && r.end() > gpa)
+    }
+
+    /// Returns the regions overlapping `size` bytes at `gpa`.
+    #[cfg(feature = "memory_hash")]
+    pub fn overlapping(&self, gpa: GPAddr, size: Size) -> impl Iterator<Item = &Region> {
+        let end = gpa.saturating_add(size);
+        self.regions
+            .iter()
+            .filter(move |r| r.gpa < end && r.end() > gpa)
+    }
+
+    /// Makes sure no region crosses `addr`.
+    fn split_at(&mut self, addr: GPAddr) {
+        let index = match self
+            .regions
+            .iter()
+            .position(|r| r.gpa < addr && r.end() > addr)
+        {
+            Some(index) => index,
+            None => return,
+        };
+
+        let region = &mut self.regions[index];
+        let offset = addr - region.gpa;
+        let tail = Region {
+            gpa: addr,
+            size: region.size - offset,
+            flags: region.flags,
+            name: region.name.clone(),
+            uva: region.uva + offset as usize,
+        };
+        region.size = offset;
+
+        self.regions.insert(index + 1, tail);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RW: Memory = Memory::from_bits_truncate(Memory::READ.bits() | Memory::WRITE.bits());
+
+    fn region(gpa: GPAddr, size: Size, uva: usize) -> Region {
+        Region {
+            gpa,
+            size,
+            flags: RW,
+            name: Some("ram".into()),
+            uva,
+        }
+    }
+
+    /// Returns the regions as `(gpa, size, flags, uva)`.
+    fn dump(layout: &Layout) -> Vec<(GPAddr, Size, Memory, usize)> {
+        layout
+            .regions()
+            .iter()
+            .map(|r| (r.gpa, r.size, r.flags, r.uva))
+            .collect()
+    }
+
+    #[test]
+    fn protect_splits() {
+        let cases = [
+            // The middle of a region, split in three.
+            (
+                0x2000,
+                0x1000,
+                vec![
+                    (0x1000, 0x1000, RW, 0x10_0000),
+                    (0x2000, 0x1000, Memory::READ, 0x10_1000),
+                    (0x3000, 0x2000, RW, 0x10_2000),
+                ],
+            ),
+            // The start of a region.
+            (
+                0x1000,
+                0x1000,
+                vec![
+                    (0x1000, 0x1000, Memory::READ, 0x10_0000),
+                    (0x2000, 0x3000, RW, 0x10_1000),
+                ],
+            ),
+            // The whole region, no split.
+            (
+                0x1000,
+                0x4000,
+                vec![(0x1000, 0x4000, Memory::READ, 0x10_0000)],
+            ),
+        ];
+        for (gpa, size, expected) in cases.iter() {
+            let mut layout = Layout::default();
+            layout.insert(region(0x1000, 0x4000, 0x10_0000));
+            layout.protect(*gpa, *size, Memory::READ);
+            assert_eq!(dump(&layout), *expected, "{:#x}+{:#x}", gpa, size);
+            assert!(layout
+                .regions()
+                .iter()
+                .all(|r| r.name.as_deref() == Some("ram")));
+        }
+    }
+
+    #[test]
+    fn protect_across_regions() {
+        let mut layout = Layout::default();
+        layout.insert(region(0x4000, 0x2000, 0x20_0000));
+        layout.insert(region(0x1000, 0x2000, 0x10_0000));
+        layout.protect(0x2000, 0x3000, Memory::READ);
+        assert_eq!(
+            dump(&layout),
+            vec![
+                (0x1000, 0x1000, RW, 0x10_0000),
+                (0x2000, 0x1000, Memory::READ, 0x10_1000),
+                (0x4000, 0x1000, Memory::READ, 0x20_0000),
+                (0x5000, 0x1000, RW, 0x20_1000),
+            ]
+        );
+    }
+
+    #[test]
+    fn remove_splits() {
+        let cases = [
+            (
+                0x2000,
+                0x1000,
+                vec![
+                    (0x1000, 0x1000, RW, 0x10_0000),
+                    (0x3000, 0x2000, RW, 0x10_2000),
+                ],
+            ),
+            (0x3000, 0x2000, vec![(0x1000, 0x2000, RW, 0x10_0000)]),
+            (0x1000, 0x4000, vec![]),
+        ];
+        for (gpa, size, expected) in cases.iter() {
+            let mut layout = Layout::default();
+            layout.insert(region(0x1000, 0x4000, 0x10_0000));
+            layout.remove(*gpa, *size);
+            assert_eq!(dump(&layout), *expected, "{:#x}+{:#x}", gpa, size);
+        }
+    }
+
+    #[test]
+    fn find() {
+        let mut layout = Layout::default();
+        layout.insert(region(0x1000, 0x4000, 0x10_0000));
+        layout.protect(0x2000, 0x1000, Memory::READ);
+
+        assert_eq!(layout.find(0x0fff), None);
+        assert_eq!(layout.find(0x2fff).map(|r| r.gpa), Some(0x2000));
+        assert_eq!(layout.find(0x3000).map(|r| r.gpa), Some(0x3000));
+        assert_eq!(layout.find(0x5000), None);
+    }
+}
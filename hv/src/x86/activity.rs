@@ -0,0 +1,75 @@
+use super::vmx::{VCpuVmxExt, Vmcs};
+use super::{boot, GeneralRegs, Interruptibility, PendingDebugExceptions, VcpuExt};
+use crate::{Error, Vcpu};
+
+/// `RFLAGS` bit 1 is reserved and always set.
+const RFLAGS_DEFAULT: u64 = 1 << 1;
+
+/// Activity state of a vCPU, `GUEST_ACTIVITY_STATE`.
+#[repr(u64)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum ActivityState {
+    /// Executing instructions.
+    Active = 0,
+    /// Halted by `hlt`, resumes when an event is injected.
+    Hlt = 1,
+    /// Halted after a triple fault, only an INIT or SIPI resumes execution.
+    Shutdown = 2,
+    /// Waiting for a startup IPI, e.g. an application processor before it's started.
+    WaitForSipi = 3,
+}
+
+impl ActivityState {
+    /// Decodes a `GUEST_ACTIVITY_STATE` value, fails with [Error::BadArgument] if it's
+    /// reserved.
+    pub fn from_raw(raw: u64) -> Result<ActivityState, Error> {
+        match raw {
+            0 => Ok(ActivityState::Active),
+            1 => Ok(ActivityState::Hlt),
+            2 => Ok(ActivityState::Shutdown),
+            3 => Ok(ActivityState::WaitForSipi),
+            _ => Err(Error::BadArgument),
+        }
+    }
+}
+
+pub(crate) fn get(vcpu: &Vcpu) -> Result<ActivityState, Error> {
+    ActivityState::from_raw(vcpu.read_vmcs(Vmcs::GUEST_ACTIVITY_STATE)?)
+}
+
+pub(crate) fn set(vcpu: &Vcpu, state: ActivityState) -> Result<(), Error> {
+    vcpu.write_vmcs(Vmcs::GUEST_ACTIVITY_STATE, state as u64)
+}
+
+/// Puts `vcpu` into the state after an INIT signal: the real mode reset state, waiting for a
+/// SIPI.
+pub(crate) fn init(vcpu: &Vcpu) -> Result<(), Error> {
+    boot::setup_real_mode(vcpu, 0xf000, 0xfff0)?;
+    vcpu.set_regs(&GeneralRegs {
+        rip: 0xfff0,
+        rflags: RFLAGS_DEFAULT,
+        ..Default::default()
+    })?;
+
+    // Pending events are discarded.
+    vcpu.write_vmcs(Vmcs::CTRL_VMENTRY_IRQ_INFO, 0)?;
+    vcpu.set_interruptibility(Interruptibility::empty())?;
+    vcpu.set_pending_debug_exceptions(PendingDebugExceptions::empty())?;
+    set(vcpu, ActivityState::WaitForSipi)
+}
+
+/// Starts `vcpu` at `vector << 12` if it waits for a SIPI, returns `false` otherwise.
+pub(crate) fn sipi(vcpu: &Vcpu, vector: u8) -> Result<bool, Error> {
+    if get(vcpu)? != ActivityState::WaitForSipi {
+        return Ok(false);
+    }
+
+    let selector = u64::from(vector) << 8;
+    vcpu.write_vmcs_many(&[
+        (Vmcs::GUEST_CS, selector),
+        (Vmcs::GUEST_CS_BASE, selector << 4),
+    ])?;
+    vcpu.write_register(super::Reg::RIP, 0)?;
+    set(vcpu, ActivityState::Active)?;
+    Ok(true)
+}
@@ -0,0 +1,118 @@
+//! Local APIC support: the virtual-APIC page and, with the `apic` feature, an emulated local
+//! APIC.
+
+use std::sync::Arc;
+
+use super::vmx::{self, Capability, VCpuVmxExt, Vmcs};
+use crate::memory::{HostMemory, Mapping};
+use crate::{Error, GPAddr, Memory, Vcpu, Vm};
+
+#[cfg(feature = "apic")]
+mod lapic;
+#[cfg(feature = "apic")]
+pub use lapic::{ApicBus, LocalApic};
+
+/// Size of the virtual-APIC page.
+const APIC_PAGE_SIZE: u64 = 0x1000;
+
+/// Offset of the task priority register in the APIC page.
+const TPR_OFFSET: u64 = 0x80;
+
+/// Use TPR shadow and activate secondary controls primary VM-execution controls.
+const CPU_BASED_TPR_SHADOW: u64 = 1 << 21;
+const CPU_BASED_SECONDARY: u64 = 1 << 31;
+
+/// Virtualize APIC accesses secondary VM-execution control.
+const CPU_BASED2_APIC_ACCESSES: u64 = 1 << 0;
+
+/// The virtual-APIC page of a vCPU, which backs TPR virtualization.
+///
+/// The page is mapped into the guest physical address space and owned by this type, it's
+/// unmapped when dropped, so it must outlive the vCPUs it's attached to. With the TPR shadow
+/// enabled, guest accesses to CR8 read and write the TPR in the page and only exit once the
+/// TPR drops below the threshold.
+///
+/// ```ignore
+/// let page = ApicPage::new(vm.clone(), 0x9000)?;
+/// page.attach(&cpu)?;
+/// page.virtualize_apic_accesses(&cpu, 0xfee0_0000)?;
+/// page.set_tpr_threshold(&cpu, 2)?;
+/// ```
+#[derive(Debug)]
+pub struct ApicPage {
+    mapping: Mapping,
+}
+
+impl ApicPage {
+    /// Allocates a zeroed page and maps it at `gpa`, which must be page aligned.
+    pub fn new(vm: Arc<Vm>, gpa: GPAddr) -> Result<ApicPage, Error> {
+        let mem = HostMemory::new(APIC_PAGE_SIZE)?;
+        let mapping = Mapping::new(vm, mem, gpa, Memory::READ | Memory::WRITE)?;
+        Ok(ApicPage { mapping })
+    }
+
+    /// Returns the guest physical address of the page.
+    #[inline]
+    pub fn gpa(&self) -> GPAddr {
+        self.mapping.gpa()
+    }
+
+    /// Returns the host memory backing the page.
+    #[inline]
+    pub fn memory(&self) -> &HostMemory {
+        self.mapping.memory()
+    }
+
+    /// Returns the 32-bit APIC register at `offset`.
+    pub fn read(&self, offset: u64) -> Result<u32, Error> {
+        let mut buf = [0; 4];
+        self.memory().read(offset, &mut buf)?;
+        Ok(u32::from_le_bytes(buf))
+    }
+
+    /// Sets the 32-bit APIC register at `offset`.
+    pub fn write(&self, offset: u64, value: u32) -> Result<(), Error> {
+        self.memory().write(offset, &value.to_le_bytes())
+    }
+
+    /// Returns the virtual task priority register.
+    pub fn tpr(&self) -> Result<u8, Error> {
+        Ok(self.read(TPR_OFFSET)? as u8)
+    }
+
+    /// Makes the page the virtual-APIC page of `vcpu` and enables the TPR shadow.
+    pub fn attach(&self, vcpu: &Vcpu) -> Result<(), Error> {
+        vcpu.write_vmcs(Vmcs::CTRL_VIRTUAL_APIC, self.gpa())?;
+
+        let controls = vcpu.read_vmcs(Vmcs::CTRL_CPU_BASED)?;
+        vcpu.write_vmcs(
+            Vmcs::CTRL_CPU_BASED,
+            vmx::adjust_controls(Capability::ProcBased, controls | CPU_BASED_TPR_SHADOW)?,
+        )
+    }
+
+    /// Sets the TPR threshold, a [Reason::TPR_THRESHOLD](vmx::Reason::TPR_THRESHOLD) exit
+    /// occurs when the guest lowers bits 7:4 of the TPR below it.
+    pub fn set_tpr_threshold(&self, vcpu: &Vcpu, threshold: u8) -> Result<(), Error> {
+        vcpu.write_vmcs(Vmcs::CTRL_TPR_THRESHOLD, u64::from(threshold & 0xf))
+    }
+
+    /// Virtualizes accesses to the APIC page at `apic_base` of `vcpu`, they cause
+    /// [Reason::APIC_ACCESS](vmx::Reason::APIC_ACCESS) exits, see
+    /// [ApicAccess](super::qualification::ApicAccess). Requires [ApicPage::attach].
+    pub fn virtualize_apic_accesses(&self, vcpu: &Vcpu, apic_base: GPAddr) -> Result<(), Error> {
+        vcpu.set_apic_address(apic_base)?;
+
+        let controls = vcpu.read_vmcs(Vmcs::CTRL_CPU_BASED)?;
+        vcpu.write_vmcs(
+            Vmcs::CTRL_CPU_BASED,
+            vmx::adjust_controls(Capability::ProcBased, controls | CPU_BASED_SECONDARY)?,
+        )?;
+
+        let controls = vcpu.read_vmcs(Vmcs::CTRL_CPU_BASED2)?;
+        vcpu.write_vmcs(
+            Vmcs::CTRL_CPU_BASED2,
+            vmx::adjust_controls(Capability::ProcBased2, controls | CPU_BASED2_APIC_ACCESSES)?,
+        )
+    }
+}
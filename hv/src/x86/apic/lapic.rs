@@ -0,0 +1,663 @@
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use super::ApicPage;
+use crate::{Error, Interrupt, IrqQueue, VcpuGroup};
+
+/// Default `IA32_APIC_BASE` MSR.
+const APIC_BASE_DEFAULT: u64 = 0xfee0_0000;
+const APIC_BASE_MSR: u32 = 0x1b;
+const BASE_BSP: u64 = 1 << 8;
+const BASE_X2APIC: u64 = 1 << 10;
+const BASE_ENABLE: u64 = 1 << 11;
+
+/// x2APIC MSRs map to the xAPIC register offsets shifted right by 4.
+const X2APIC_MSRS: std::ops::RangeInclusive<u32> = 0x800..=0x8ff;
+
+/// Register offsets.
+const REG_ID: u32 = 0x20;
+const REG_VERSION: u32 = 0x30;
+const REG_TPR: u32 = 0x80;
+const REG_APR: u32 = 0x90;
+const REG_PPR: u32 = 0xa0;
+const REG_EOI: u32 = 0xb0;
+const REG_LDR: u32 = 0xd0;
+const REG_DFR: u32 = 0xe0;
+const REG_SVR: u32 = 0xf0;
+const REG_ISR: u32 = 0x100;
+const REG_TMR: u32 = 0x180;
+const REG_IRR: u32 = 0x200;
+const REG_ESR: u32 = 0x280;
+const REG_LVT_CMCI: u32 = 0x2f0;
+const REG_ICR_LOW: u32 = 0x300;
+const REG_ICR_HIGH: u32 = 0x310;
+const REG_LVT_TIMER: u32 = 0x320;
+const REG_LVT_ERROR: u32 = 0x370;
+const REG_TIMER_INITIAL: u32 = 0x380;
+const REG_TIMER_CURRENT: u32 = 0x390;
+const REG_TIMER_DIVIDE: u32 = 0x3e0;
+/// x2APIC only.
+const REG_SELF_IPI: u32 = 0x3f0;
+
+/// Version 0x14 with 7 LVT entries.
+const VERSION: u32 = 0x14 | (6 << 16);
+
+/// Software enable bit of the spurious interrupt vector register.
+const SVR_ENABLE: u32 = 1 << 8;
+
+/// LVT mask bit and timer modes.
+const LVT_MASKED: u32 = 1 << 16;
+const LVT_TIMER_MODE: u32 = 0x3 << 17;
+const LVT_TIMER_PERIODIC: u32 = 1 << 17;
+
+/// Delivery modes of the ICR.
+const DELIVERY_FIXED: u32 = 0;
+const DELIVERY_LOWEST: u32 = 1;
+const DELIVERY_NMI: u32 = 4;
+const DELIVERY_STARTUP: u32 = 6;
+
+/// Logical destination mode of the ICR.
+const ICR_LOGICAL: u32 = 1 << 11;
+
+/// Flat model in the destination format register.
+const DFR_FLAT: u32 = 0xffff_ffff;
+
+/// Timer ticks per second before division, one tick per nanosecond like KVM.
+const TIMER_HZ: u64 = 1_000_000_000;
+
+/// Destination shorthands of the ICR.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum Shorthand {
+    None,
+    SelfOnly,
+    All,
+    Others,
+}
+
+/// 256 bit vector register: IRR, ISR or TMR.
+#[derive(Debug, Default, Copy, Clone)]
+struct Bits([u32; 8]);
+
+impl Bits {
+    fn set(&mut self, vector: u8) {
+        self.0[vector as usize / 32] |= 1 << (vector % 32);
+    }
+
+    fn clear(&mut self, vector: u8) {
+        self.0[vector as usize / 32] &= !(1 << (vector % 32));
+    }
+
+    /// Returns the highest set vector.
+    fn highest(&self) -> Option<u8> {
+        self.0
+            .iter()
+            .enumerate()
+            .rev()
+            .find(|(_, w)| **w != 0)
+            .map(|(i, w)| (i * 32 + 31 - w.leading_zeros() as usize) as u8)
+    }
+}
+
+#[derive(Debug)]
+struct State {
+    id: u32,
+    base: u64,
+    tpr: u32,
+    ldr: u32,
+    dfr: u32,
+    svr: u32,
+    esr: u32,
+    isr: Bits,
+    irr: Bits,
+    tmr: Bits,
+    /// CMCI, timer, thermal, performance counter, LINT0, LINT1 and error.
+    lvt: [u32; 7],
+    icr: u64,
+    timer_initial: u32,
+    timer_divide: u32,
+    /// Start of the current period and its deadline.
+    timer: Option<(Instant, Instant)>,
+}
+
+impl State {
+    fn new(id: u32, bsp: bool) -> State {
+        let mut base = APIC_BASE_DEFAULT | BASE_ENABLE;
+        if bsp {
+            base |= BASE_BSP;
+        }
+        State {
+            id,
+            base,
+            tpr: 0,
+            ldr: 0,
+            dfr: DFR_FLAT,
+            svr: 0xff,
+            esr: 0,
+            isr: Bits::default(),
+            irr: Bits::default(),
+            tmr: Bits::default(),
+            lvt: [LVT_MASKED; 7],
+            icr: 0,
+            timer_initial: 0,
+            timer_divide: 0,
+            timer: None,
+        }
+    }
+
+    fn x2apic(&self) -> bool {
+        self.base & BASE_X2APIC != 0
+    }
+
+    fn enabled(&self) -> bool {
+        self.base & BASE_ENABLE != 0 && self.svr & SVR_ENABLE != 0
+    }
+
+    /// Processor priority: the higher of the TPR and the class of the highest in-service
+    /// vector.
+    fn ppr(&self) -> u32 {
+        let isrv = self.isr.highest().map_or(0, u32::from);
+        if self.tpr & 0xf0 >= isrv & 0xf0 {
+            self.tpr & 0xff
+        } else {
+            isrv & 0xf0
+        }
+    }
+
+    /// Returns the logical destination register as the guest sees it.
+    fn ldr(&self) -> u32 {
+        if self.x2apic() {
+            ((self.id >> 4) << 16) | (1 << (self.id & 0xf))
+        } else {
+            self.ldr
+        }
+    }
+
+    fn matches(&self, dest: u32, logical: bool) -> bool {
+        match (logical, self.x2apic()) {
+            (false, true) => dest == 0xffff_ffff || dest == self.id,
+            (false, false) => dest == 0xff || dest == self.id & 0xff,
+            (true, true) => {
+                let ldr = self.ldr();
+                dest >> 16 == ldr >> 16 && dest & ldr & 0xffff != 0
+            }
+            (true, false) if self.dfr == DFR_FLAT => dest & (self.ldr >> 24) != 0,
+            // Cluster model: the high nibble selects the cluster, the low one the CPUs.
+            (true, false) => {
+                let ldr = self.ldr >> 24;
+                (dest == 0xff || dest >> 4 == ldr >> 4) && dest & ldr & 0xf != 0
+            }
+        }
+    }
+
+    /// Ticks per timer tick from the divide configuration register.
+    fn divisor(&self) -> u64 {
+        let shift = ((self.timer_divide & 0x3) | ((self.timer_divide >> 1) & 0x4)) + 1;
+        1 << (shift & 0x7)
+    }
+
+    fn period(&self) -> Duration {
+        let ticks = u128::from(self.timer_initial) * u128::from(self.divisor());
+        Duration::from_nanos((ticks * 1_000_000_000 / u128::from(TIMER_HZ)) as u64)
+    }
+
+    fn arm_timer(&mut self, now: Instant) {
+        self.timer = if self.timer_initial == 0 {
+            None
+        } else {
+            Some((now, now + self.period()))
+        };
+    }
+
+    fn timer_current(&self, now: Instant) -> u32 {
+        match self.timer {
+            Some((_, deadline)) if deadline > now => {
+                let ticks = (deadline - now).as_nanos() * u128::from(TIMER_HZ) / 1_000_000_000;
+                (ticks / u128::from(self.divisor())) as u32
+            }
+            _ => 0,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Shared {
+    state: Mutex<State>,
+    irqs: IrqQueue,
+}
+
+impl Shared {
+    /// Accepts a fixed interrupt into the IRR and delivers it if possible.
+    fn accept(&self, vector: u8) -> Result<(), Error> {
+        let mut state = self.state.lock().unwrap();
+        // Vectors 0 to 15 are reserved, the APIC reports an illegal vector.
+        if vector < 16 {
+            state.esr |= 1 << 6;
+            return Ok(());
+        }
+        state.irr.set(vector);
+        self.deliver(&mut state)
+    }
+
+    /// Moves the highest pending vector above the processor priority to the ISR and raises
+    /// it on the vCPU's queue. Lower priority vectors wait in the IRR until EOI or a TPR
+    /// change lowers the processor priority.
+    fn deliver(&self, state: &mut State) -> Result<(), Error> {
+        if !state.enabled() {
+            return Ok(());
+        }
+        let vector = match state.irr.highest() {
+            Some(vector) => vector,
+            None => return Ok(()),
+        };
+        if u32::from(vector) & 0xf0 <= state.ppr() & 0xf0 {
+            return Ok(());
+        }
+        state.irr.clear(vector);
+        state.isr.set(vector);
+        self.irqs.raise(Interrupt::Vector(vector))
+    }
+}
+
+/// Local APICs of a VM, routes IPIs between them.
+///
+/// A SIPI starts the destination through the [VcpuGroup] given to [ApicBus::with_group],
+/// the APIC ID being the group ID. INIT IPIs are ignored.
+#[derive(Debug, Clone, Default)]
+pub struct ApicBus {
+    apics: Arc<Mutex<Vec<Arc<Shared>>>>,
+    group: Option<VcpuGroup>,
+}
+
+impl ApicBus {
+    pub fn new() -> ApicBus {
+        ApicBus::default()
+    }
+
+    /// Starts secondaries in `group` on SIPIs.
+    pub fn with_group(mut self, group: VcpuGroup) -> ApicBus {
+        self.group = Some(group);
+        self
+    }
+
+    /// Raises `vector` on all local APICs matching the destination, as an I/O APIC or MSI
+    /// does.
+    pub fn deliver(&self, dest: u32, logical: bool, vector: u8) -> Result<(), Error> {
+        for apic in self.targets(None, Shorthand::None, dest, logical) {
+            apic.accept(vector)?;
+        }
+        Ok(())
+    }
+
+    fn targets(
+        &self,
+        source: Option<&Arc<Shared>>,
+        shorthand: Shorthand,
+        dest: u32,
+        logical: bool,
+    ) -> Vec<Arc<Shared>> {
+        let is_source = |apic: &Arc<Shared>| source.map_or(false, |s| Arc::ptr_eq(s, apic));
+        self.apics
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|apic| match shorthand {
+                Shorthand::None => apic.state.lock().unwrap().matches(dest, logical),
+                Shorthand::SelfOnly => is_source(apic),
+                Shorthand::All => true,
+                Shorthand::Others => !is_source(apic),
+            })
+            .cloned()
+            .collect()
+    }
+}
+
+/// Emulated local APIC of a vCPU, in xAPIC or x2APIC mode.
+///
+/// The model keeps the IRR and ISR and delivers the highest priority vector through the
+/// vCPU's [IrqQueue] once it's above the processor priority, the next one after the guest
+/// signals EOI. The embedder forwards guest accesses:
+///
+/// * xAPIC MMIO accesses, e.g. from [Reason::APIC_ACCESS](crate::x86::vmx::Reason::APIC_ACCESS)
+///   exits, to [LocalApic::read] and [LocalApic::write] with the offset from
+///   [ApicAccess](crate::x86::qualification::ApicAccess). The data is decoded by the
+///   embedder.
+/// * `rdmsr` and `wrmsr` of `IA32_APIC_BASE` and the x2APIC MSRs to [LocalApic::rdmsr] and
+///   [LocalApic::wrmsr], e.g. from routes of an [MsrRouter](crate::run::MsrRouter).
+/// * CR8 writes, or TPR threshold exits with an [ApicPage], to [LocalApic::set_tpr] or
+///   [LocalApic::sync_tpr].
+///
+/// The timer is driven by the embedder as well: [LocalApic::timer_deadline] is the next
+/// expiry, e.g. for a [TimerWheel](crate::run::TimerWheel), and [LocalApic::poll_timer]
+/// fires it. The timer counts at 1 GHz before division, the TSC deadline mode isn't
+/// supported.
+///
+/// ```ignore
+/// let bus = ApicBus::new().with_group(group.clone());
+/// let apic = Rc::new(LocalApic::new(0, true, cpu.irq_queue(), &bus));
+/// let (r, w) = (apic.clone(), apic.clone());
+/// let router = MsrRouter::new(MsrPolicy::InjectGp).route(
+///     0x800..=0x8ff,
+///     move |_, msr| Ok(r.rdmsr(msr)),
+///     move |_, msr, value| w.wrmsr(msr, value),
+/// );
+/// ```
+#[derive(Debug)]
+pub struct LocalApic {
+    shared: Arc<Shared>,
+    bus: ApicBus,
+}
+
+impl LocalApic {
+    /// Creates the local APIC `id`, delivering to `irqs`, and attaches it to `bus`.
+    ///
+    /// The APIC starts enabled in xAPIC mode at the default base address, software disabled
+    /// with all LVT entries masked, like after reset.
+    pub fn new(id: u32, bsp: bool, irqs: IrqQueue, bus: &ApicBus) -> LocalApic {
+        let shared = Arc::new(Shared {
+            state: Mutex::new(State::new(id, bsp)),
+            irqs,
+        });
+        bus.apics.lock().unwrap().push(Arc::clone(&shared));
+        LocalApic {
+            shared,
+            bus: bus.clone(),
+        }
+    }
+
+    /// Returns the APIC ID.
+    pub fn id(&self) -> u32 {
+        self.state().id
+    }
+
+    /// Returns the `IA32_APIC_BASE` MSR.
+    pub fn base(&self) -> u64 {
+        self.state().base
+    }
+
+    /// Returns `true` in x2APIC mode.
+    pub fn is_x2apic(&self) -> bool {
+        self.state().x2apic()
+    }
+
+    /// Reads the 32-bit register at `offset` of the APIC page.
+    pub fn read(&self, offset: u32) -> u32 {
+        self.read_reg(offset & 0xff0, Instant::now())
+    }
+
+    /// Writes the 32-bit register at `offset` of the APIC page.
+    pub fn write(&self, offset: u32, value: u32) -> Result<(), Error> {
+        self.write_reg(offset & 0xff0, u64::from(value), Instant::now())
+    }
+
+    /// Emulates `rdmsr` of `IA32_APIC_BASE` or an x2APIC MSR, returns `None` for other MSRs
+    /// and x2APIC MSRs outside of x2APIC mode, which fault.
+    pub fn rdmsr(&self, msr: u32) -> Option<u64> {
+        if msr == APIC_BASE_MSR {
+            return Some(self.base());
+        }
+        if !X2APIC_MSRS.contains(&msr) || !self.is_x2apic() {
+            return None;
+        }
+        let offset = (msr - 0x800) << 4;
+        let value = match offset {
+            REG_ICR_LOW => self.state().icr,
+            REG_EOI | REG_SELF_IPI | REG_ICR_HIGH | REG_DFR => return None,
+            offset => u64::from(self.read_reg(offset, Instant::now())),
+        };
+        Some(value)
+    }
+
+    /// Emulates `wrmsr` of `IA32_APIC_BASE` or an x2APIC MSR, returns `false` for accesses
+    /// that fault.
+    pub fn wrmsr(&self, msr: u32, value: u64) -> Result<bool, Error> {
+        if msr == APIC_BASE_MSR {
+            return Ok(self.set_base(value));
+        }
+        if !X2APIC_MSRS.contains(&msr) || !self.is_x2apic() {
+            return Ok(false);
+        }
+        let offset = (msr - 0x800) << 4;
+        match offset {
+            REG_ID | REG_LDR | REG_ICR_HIGH | REG_DFR => return Ok(false),
+            REG_ICR_LOW => self.send_ipi(value)?,
+            offset => self.write_reg(offset, value, Instant::now())?,
+        }
+        Ok(true)
+    }
+
+    /// Returns the task priority register.
+    pub fn tpr(&self) -> u8 {
+        self.state().tpr as u8
+    }
+
+    /// Sets the task priority register, e.g. for a `mov` to CR8 (`cr8 << 4`).
+    pub fn set_tpr(&self, tpr: u8) -> Result<(), Error> {
+        let mut state = self.state();
+        state.tpr = u32::from(tpr);
+        self.shared.deliver(&mut state)
+    }
+
+    /// Takes the TPR from the virtual-APIC page, which the guest changed with the TPR shadow
+    /// enabled, e.g. on [Reason::TPR_THRESHOLD](crate::x86::vmx::Reason::TPR_THRESHOLD)
+    /// exits.
+    pub fn sync_tpr(&self, page: &ApicPage) -> Result<(), Error> {
+        self.set_tpr(page.tpr()?)
+    }
+
+    /// Raises the interrupt of the local vector table entry `index` (0 CMCI, 1 timer, 2
+    /// thermal, 3 performance counter, 4 LINT0, 5 LINT1, 6 error), unless it's masked.
+    pub fn raise_lvt(&self, index: usize) -> Result<(), Error> {
+        let lvt = self.state().lvt[index];
+        if lvt & LVT_MASKED != 0 {
+            return Ok(());
+        }
+        match (lvt >> 8) & 0x7 {
+            DELIVERY_NMI => self.shared.irqs.raise(Interrupt::Nmi),
+            _ => self.shared.accept(lvt as u8),
+        }
+    }
+
+    /// Returns when the timer expires next, `None` if it isn't running.
+    pub fn timer_deadline(&self) -> Option<Instant> {
+        self.state().timer.map(|(_, deadline)| deadline)
+    }
+
+    /// Fires the timer if it expired by `now`, rearming it in periodic mode. Returns `true`
+    /// if it fired.
+    pub fn poll_timer(&self, now: Instant) -> Result<bool, Error> {
+        {
+            let mut state = self.state();
+            match state.timer {
+                Some((_, deadline)) if deadline <= now => {}
+                _ => return Ok(false),
+            }
+            state.timer = if state.lvt[1] & LVT_TIMER_MODE == LVT_TIMER_PERIODIC {
+                let period = state.period();
+                state
+                    .timer
+                    .map(|(_, deadline)| (deadline, deadline + period))
+            } else {
+                None
+            };
+        }
+        self.raise_lvt(1)?;
+        Ok(true)
+    }
+
+    fn state(&self) -> std::sync::MutexGuard<'_, State> {
+        self.shared.state.lock().unwrap()
+    }
+
+    /// Updates `IA32_APIC_BASE`, returns `false` for invalid transitions.
+    fn set_base(&self, value: u64) -> bool {
+        let mut state = self.state();
+        let enable = value & BASE_ENABLE != 0;
+        let x2apic = value & BASE_X2APIC != 0;
+        // x2APIC mode requires the APIC to be enabled and can only be left by disabling it.
+        if (x2apic && !enable) || (state.x2apic() && enable && !x2apic) {
+            return false;
+        }
+        state.base = value;
+        true
+    }
+
+    fn read_reg(&self, offset: u32, now: Instant) -> u32 {
+        let state = self.state();
+        let vector_reg = |bits: &Bits, base: u32| bits.0[((offset - base) >> 4) as usize];
+        match offset {
+            REG_ID if state.x2apic() => state.id,
+            REG_ID => state.id << 24,
+            REG_VERSION => VERSION,
+            REG_TPR => state.tpr,
+            REG_APR => 0,
+            REG_PPR => state.ppr(),
+            REG_LDR => state.ldr(),
+            REG_DFR => state.dfr,
+            REG_SVR => state.svr,
+            0x100..=0x170 => vector_reg(&state.isr, REG_ISR),
+            0x180..=0x1f0 => vector_reg(&state.tmr, REG_TMR),
+            0x200..=0x270 => vector_reg(&state.irr, REG_IRR),
+            REG_ESR => state.esr,
+            REG_ICR_LOW => state.icr as u32,
+            REG_ICR_HIGH => (state.icr >> 32) as u32,
+            REG_LVT_CMCI | REG_LVT_TIMER..=REG_LVT_ERROR => state.lvt[lvt_index(offset)],
+            REG_TIMER_INITIAL => state.timer_initial,
+            REG_TIMER_CURRENT => state.timer_current(now),
+            REG_TIMER_DIVIDE => state.timer_divide,
+            _ => 0,
+        }
+    }
+
+    fn write_reg(&self, offset: u32, value: u64, now: Instant) -> Result<(), Error> {
+        let value32 = value as u32;
+        let mut state = self.state();
+        match offset {
+            REG_ID if !state.x2apic() => state.id = value32 >> 24,
+            REG_TPR => {
+                state.tpr = value32 & 0xff;
+                return self.shared.deliver(&mut state);
+            }
+            REG_EOI => {
+                if let Some(vector) = state.isr.highest() {
+                    state.isr.clear(vector);
+                    state.tmr.clear(vector);
+                }
+                return self.shared.deliver(&mut state);
+            }
+            REG_LDR => state.ldr = value32 & 0xff00_0000,
+            REG_DFR => state.dfr = value32 | 0x0fff_ffff,
+            REG_SVR => {
+                state.svr = value32 & 0x1ff;
+                if state.svr & SVR_ENABLE == 0 {
+                    for lvt in state.lvt.iter_mut() {
+                        *lvt |= LVT_MASKED;
+                    }
+                }
+                return self.shared.deliver(&mut state);
+            }
+            REG_ESR => state.esr = 0,
+            REG_LVT_CMCI | REG_LVT_TIMER..=REG_LVT_ERROR => {
+                let mut lvt = value32 & 0x7_17ff;
+                if state.svr & SVR_ENABLE == 0 {
+                    lvt |= LVT_MASKED;
+                }
+                state.lvt[lvt_index(offset)] = lvt;
+            }
+            REG_ICR_LOW => {
+                let icr = (state.icr & !0xffff_ffff) | u64::from(value32);
+                drop(state);
+                return self.send_ipi(icr);
+            }
+            REG_ICR_HIGH => state.icr = (state.icr & 0xffff_ffff) | (value << 32),
+            REG_TIMER_INITIAL => {
+                state.timer_initial = value32;
+                state.arm_timer(now);
+            }
+            REG_TIMER_DIVIDE => state.timer_divide = value32 & 0xb,
+            REG_SELF_IPI if state.x2apic() => {
+                drop(state);
+                return self.shared.accept(value as u8);
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Sends the IPI described by `icr`.
+    fn send_ipi(&self, icr: u64) -> Result<(), Error> {
+        let dest = {
+            let mut state = self.state();
+            state.icr = icr;
+            if state.x2apic() {
+                (icr >> 32) as u32
+            } else {
+                (icr >> 56) as u32
+            }
+        };
+
+        let low = icr as u32;
+        let vector = low as u8;
+        let shorthand = match (low >> 18) & 0x3 {
+            0 => Shorthand::None,
+            1 => Shorthand::SelfOnly,
+            2 => Shorthand::All,
+            _ => Shorthand::Others,
+        };
+        let logical = low & ICR_LOGICAL != 0;
+        let mut targets = self
+            .bus
+            .targets(Some(&self.shared), shorthand, dest, logical);
+
+        match (low >> 8) & 0x7 {
+            DELIVERY_FIXED => {
+                for target in targets {
+                    target.accept(vector)?;
+                }
+            }
+            // Lowest priority goes to the first destination.
+            DELIVERY_LOWEST => {
+                targets.truncate(1);
+                for target in targets {
+                    target.accept(vector)?;
+                }
+            }
+            DELIVERY_NMI => {
+                for target in targets {
+                    target.irqs.raise(Interrupt::Nmi)?;
+                }
+            }
+            DELIVERY_STARTUP => {
+                if let Some(group) = &self.bus.group {
+                    for target in targets {
+                        let id = u64::from(target.state.lock().unwrap().id);
+                        // The boot CPU and already started CPUs ignore SIPIs.
+                        if group.is_secondary(id) && !group.is_started(id) {
+                            group.start_secondary_sipi(id, vector)?;
+                        }
+                    }
+                }
+            }
+            // INIT, SMI and reserved delivery modes are ignored.
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+impl Drop for LocalApic {
+    fn drop(&mut self) {
+        self.bus
+            .apics
+            .lock()
+            .unwrap()
+            .retain(|apic| !Arc::ptr_eq(apic, &self.shared));
+    }
+}
+
+fn lvt_index(offset: u32) -> usize {
+    if offset == REG_LVT_CMCI {
+        0
+    } else {
+        ((offset - REG_LVT_TIMER) >> 4) as usize + 1
+    }
+}
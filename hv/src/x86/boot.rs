@@ -0,0 +1,291 @@
+//! Bootstrap helpers that put a vCPU into real mode, 32-bit protected mode or 64-bit long
+//! mode.
+//!
+//! VM-execution controls are left alone, they must already be set up, e.g. with
+//! [VcpuBuilder](crate::VcpuBuilder). Only the IA-32e mode and load EFER entry controls are
+//! changed.
+
+use super::cr::{CrShadow, ShadowedCr};
+use super::vmx::{self, Capability, VCpuVmxExt, Vmcs};
+use super::{Reg, Seg, SegmentDescriptor, VcpuExt};
+use crate::memory::GuestMemory;
+use crate::{Error, GPAddr, Vcpu};
+
+/// Guest physical address of the boot structures, [TABLES_SIZE] bytes are used.
+pub const TABLES_ADDR: GPAddr = 0x1000;
+
+/// Size of the boot structures: the GDT, PML4, PDPT and four page directories.
+pub const TABLES_SIZE: u64 = 0x7000;
+
+const GDT_ADDR: GPAddr = TABLES_ADDR;
+const PML4_ADDR: GPAddr = TABLES_ADDR + 0x1000;
+const PDPT_ADDR: GPAddr = TABLES_ADDR + 0x2000;
+const PD_ADDR: GPAddr = TABLES_ADDR + 0x3000;
+
+/// Identity mapped guest physical memory, with 2 MiB pages.
+const IDENTITY_MAPPED: u64 = 4 << 30;
+
+const CR0_PE: u64 = 1 << 0;
+const CR0_ET: u64 = 1 << 4;
+const CR0_NE: u64 = 1 << 5;
+const CR0_PG: u64 = 1 << 31;
+const CR4_PAE: u64 = 1 << 5;
+const CR4_VMXE: u64 = 1 << 13;
+const EFER_LME: u64 = 1 << 8;
+const EFER_LMA: u64 = 1 << 10;
+
+/// Enable EPT and unrestricted guest secondary VM-execution controls.
+const CPU_BASED2_EPT: u64 = 1 << 1;
+const CPU_BASED2_UNRESTRICTED: u64 = 1 << 7;
+
+/// IA-32e mode guest and load `IA32_EFER` VM-entry controls.
+const ENTRY_IA32E: u64 = 1 << 9;
+const ENTRY_LOAD_EFER: u64 = 1 << 15;
+
+/// Bit 1 of RFLAGS is reserved and must be set.
+const RFLAGS_DEFAULT: u64 = 0x2;
+
+/// Page table entry bits.
+const PTE_PRESENT: u64 = 1 << 0;
+const PTE_WRITABLE: u64 = 1 << 1;
+const PTE_LARGE: u64 = 1 << 7;
+
+/// Code segment selector loaded by [setup_long_mode] and [setup_protected_mode], e.g. for
+/// IDT gates.
+pub const CODE_SELECTOR: u16 = 0x8;
+const DATA_SELECTOR: u16 = 0x10;
+const TSS_SELECTOR: u16 = 0x18;
+
+/// Segment access rights.
+const AR_CODE64: u32 = 0xa09b;
+const AR_CODE32: u32 = 0xc09b;
+const AR_DATA: u32 = 0xc093;
+const AR_TSS: u32 = 0x8b;
+const AR_REAL_CODE: u32 = 0x9b;
+const AR_REAL_DATA: u32 = 0x93;
+
+const DATA_SEGMENTS: [Seg; 5] = [Seg::Ds, Seg::Es, Seg::Fs, Seg::Gs, Seg::Ss];
+
+fn set_segment(
+    vcpu: &Vcpu,
+    seg: Seg,
+    selector: u16,
+    base: u64,
+    limit: u32,
+    access_rights: u32,
+) -> Result<(), Error> {
+    let desc = SegmentDescriptor {
+        selector,
+        base,
+        limit,
+        access_rights,
+    };
+    vcpu.set_segment(seg, &desc)
+}
+
+/// Returns the GDT: null, code, data and a 16 byte TSS descriptor.
+fn gdt(code: u64) -> [u64; 5] {
+    [
+        0,
+        code,
+        0x00cf_9300_0000_ffff,
+        // Busy TSS with base 0 and limit 0x67, type 11 is valid in both modes. The upper half
+        // of the 64-bit descriptor is a null descriptor in protected mode.
+        0x0000_8b00_0000_0067,
+        0,
+    ]
+}
+
+/// Writes the GDT at [TABLES_ADDR] and loads GDTR, TR and LDTR.
+fn load_gdt(vcpu: &Vcpu, mem: &GuestMemory, code: u64) -> Result<(), Error> {
+    let bytes = to_bytes(gdt(code).iter().copied());
+    mem.write(GDT_ADDR, &bytes)?;
+
+    vcpu.write_vmcs(Vmcs::GUEST_GDTR_BASE, GDT_ADDR)?;
+    vcpu.write_vmcs(Vmcs::GUEST_GDTR_LIMIT, bytes.len() as u64 - 1)?;
+    vcpu.write_vmcs(Vmcs::GUEST_IDTR_BASE, 0)?;
+    vcpu.write_vmcs(Vmcs::GUEST_IDTR_LIMIT, 0)?;
+
+    set_segment(vcpu, Seg::Tr, TSS_SELECTOR, 0, 0x67, AR_TSS)?;
+    set_segment(vcpu, Seg::Ldtr, 0, 0, 0, SegmentDescriptor::AR_UNUSABLE)
+}
+
+/// Hides `CR4.VMXE`, which VMX requires to be set, from the guest.
+fn hide_vmxe(vcpu: &Vcpu) -> Result<(), Error> {
+    CrShadow::read(vcpu, ShadowedCr::Cr4)?
+        .trap(CR4_VMXE, 0)
+        .apply(vcpu, ShadowedCr::Cr4)
+}
+
+fn to_bytes(entries: impl Iterator<Item = u64>) -> Vec<u8> {
+    entries.flat_map(|e| e.to_le_bytes().to_vec()).collect()
+}
+
+/// Builds identity page tables for the first 4 GiB.
+fn write_page_tables(mem: &GuestMemory) -> Result<(), Error> {
+    let table = PTE_PRESENT | PTE_WRITABLE;
+
+    mem.write(PML4_ADDR, &to_bytes(std::iter::once(PDPT_ADDR | table)))?;
+
+    let pdpt = (0..IDENTITY_MAPPED >> 30).map(|i| (PD_ADDR + i * 0x1000) | table);
+    mem.write(PDPT_ADDR, &to_bytes(pdpt))?;
+
+    let pd = (0..IDENTITY_MAPPED >> 21).map(|i| (i << 21) | table | PTE_LARGE);
+    mem.write(PD_ADDR, &to_bytes(pd))
+}
+
+/// Puts `vcpu` into 64-bit long mode, starting at `entry` with the stack pointer at `stack`.
+///
+/// Writes identity page tables mapping the first 4 GiB with 2 MiB pages and a flat GDT to
+/// the [TABLES_SIZE] bytes at [TABLES_ADDR], which must be mapped in `mem`. Programs
+/// EFER, CR0, CR3 and CR4 with paging and PAE enabled, flat code and data segments, a busy
+/// TSS and an unusable LDT. `CR4.VMXE` is hidden from the guest, no IDT is loaded.
+pub fn setup_long_mode(
+    vcpu: &Vcpu,
+    mem: &GuestMemory,
+    entry: GPAddr,
+    stack: u64,
+) -> Result<(), Error> {
+    write_page_tables(mem)?;
+    load_gdt(vcpu, mem, 0x00af_9b00_0000_ffff)?;
+
+    vcpu.write_vmcs(Vmcs::GUEST_CR0, CR0_PE | CR0_ET | CR0_NE | CR0_PG)?;
+    vcpu.write_vmcs(Vmcs::GUEST_CR3, PML4_ADDR)?;
+    vcpu.write_vmcs(Vmcs::GUEST_CR4, CR4_PAE | CR4_VMXE)?;
+    vcpu.write_vmcs(Vmcs::GUEST_IA32_EFER, EFER_LME | EFER_LMA)?;
+    hide_vmxe(vcpu)?;
+
+    let controls = vcpu.read_vmcs(Vmcs::CTRL_VMENTRY_CONTROLS)?;
+    vcpu.write_vmcs(
+        Vmcs::CTRL_VMENTRY_CONTROLS,
+        vmx::adjust_controls(Capability::Entry, controls | ENTRY_IA32E | ENTRY_LOAD_EFER)?,
+    )?;
+
+    load_segments(vcpu, AR_CODE64)?;
+    start(vcpu, entry, stack)
+}
+
+/// Puts `vcpu` into 32-bit protected mode without paging, starting at `entry` with the stack
+/// pointer at `stack`.
+///
+/// Writes a flat GDT to [TABLES_ADDR], which must be mapped in `mem`. Programs CR0 with only
+/// protection enabled, flat 4 GiB code and data segments, a busy TSS and an unusable LDT.
+/// EFER is cleared, `CR4.VMXE` is hidden from the guest, no IDT is loaded.
+///
+/// Paging is disabled, which requires the unrestricted guest secondary control: it's enabled
+/// like by [setup_real_mode], fails with [Error::Unsupported] if the host doesn't support it.
+pub fn setup_protected_mode(
+    vcpu: &Vcpu,
+    mem: &GuestMemory,
+    entry: GPAddr,
+    stack: u64,
+) -> Result<(), Error> {
+    enable_unrestricted_guest(vcpu)?;
+    load_gdt(vcpu, mem, 0x00cf_9b00_0000_ffff)?;
+
+    vcpu.write_vmcs(Vmcs::GUEST_CR0, CR0_PE | CR0_ET | CR0_NE)?;
+    vcpu.write_vmcs(Vmcs::GUEST_CR3, 0)?;
+    vcpu.write_vmcs(Vmcs::GUEST_CR4, CR4_VMXE)?;
+    vcpu.write_vmcs(Vmcs::GUEST_IA32_EFER, 0)?;
+    hide_vmxe(vcpu)?;
+
+    let controls = vcpu.read_vmcs(Vmcs::CTRL_VMENTRY_CONTROLS)?;
+    vcpu.write_vmcs(
+        Vmcs::CTRL_VMENTRY_CONTROLS,
+        vmx::adjust_controls(
+            Capability::Entry,
+            (controls & !ENTRY_IA32E) | ENTRY_LOAD_EFER,
+        )?,
+    )?;
+
+    load_segments(vcpu, AR_CODE32)?;
+    start(vcpu, entry, stack)
+}
+
+/// Loads flat 4 GiB segments, CS with the access rights `code`.
+fn load_segments(vcpu: &Vcpu, code: u32) -> Result<(), Error> {
+    set_segment(vcpu, Seg::Cs, CODE_SELECTOR, 0, 0xffff_ffff, code)?;
+    for segment in DATA_SEGMENTS.iter() {
+        set_segment(vcpu, *segment, DATA_SELECTOR, 0, 0xffff_ffff, AR_DATA)?;
+    }
+    Ok(())
+}
+
+fn start(vcpu: &Vcpu, entry: GPAddr, stack: u64) -> Result<(), Error> {
+    vcpu.write_register(Reg::RFLAGS, RFLAGS_DEFAULT)?;
+    vcpu.write_register(Reg::RIP, entry)?;
+    vcpu.write_register(Reg::RSP, stack)
+}
+
+/// Returns `true` if the host supports the unrestricted guest control, which real mode and
+/// protected mode without paging require.
+pub fn unrestricted_guest_supported() -> Result<bool, Error> {
+    let allowed = vmx::read_capability(Capability::ProcBased2)? >> 32;
+    Ok(allowed & CPU_BASED2_UNRESTRICTED != 0)
+}
+
+/// Enables the EPT and unrestricted guest controls, fails with [Error::Unsupported] if the
+/// host doesn't support them.
+fn enable_unrestricted_guest(vcpu: &Vcpu) -> Result<(), Error> {
+    if !unrestricted_guest_supported()? {
+        return Err(Error::Unsupported);
+    }
+
+    let controls = vcpu.read_vmcs(Vmcs::CTRL_CPU_BASED2)?;
+    vcpu.write_vmcs(
+        Vmcs::CTRL_CPU_BASED2,
+        vmx::adjust_controls(
+            Capability::ProcBased2,
+            controls | CPU_BASED2_EPT | CPU_BASED2_UNRESTRICTED,
+        )?,
+    )
+}
+
+/// Puts `vcpu` into real mode, starting at `cs:ip` with all other segments and the stack at 0.
+///
+/// Enables the unrestricted guest control, fails with [Error::Unsupported] if the host doesn't
+/// support it. Segment bases are consistent with the selectors, limits are 64 KiB and the IDT
+/// is the interrupt vector table at 0. CR0 has protection and paging disabled, EFER is
+/// cleared and `CR4.VMXE` is hidden from the guest.
+///
+/// The reset state of the processor, for firmware, is `cs` `0xf000` and `ip` `0xfff0`, note
+/// that the base of `CS` is `0xf_0000` here, not `0xffff_0000`.
+pub fn setup_real_mode(vcpu: &Vcpu, cs: u16, ip: u16) -> Result<(), Error> {
+    enable_unrestricted_guest(vcpu)?;
+
+    vcpu.write_vmcs(Vmcs::GUEST_CR0, CR0_ET | CR0_NE)?;
+    vcpu.write_vmcs(Vmcs::GUEST_CR3, 0)?;
+    vcpu.write_vmcs(Vmcs::GUEST_CR4, CR4_VMXE)?;
+    vcpu.write_vmcs(Vmcs::GUEST_IA32_EFER, 0)?;
+    hide_vmxe(vcpu)?;
+
+    let controls = vcpu.read_vmcs(Vmcs::CTRL_VMENTRY_CONTROLS)?;
+    vcpu.write_vmcs(
+        Vmcs::CTRL_VMENTRY_CONTROLS,
+        vmx::adjust_controls(
+            Capability::Entry,
+            (controls & !ENTRY_IA32E) | ENTRY_LOAD_EFER,
+        )?,
+    )?;
+
+    vcpu.write_vmcs(Vmcs::GUEST_GDTR_BASE, 0)?;
+    vcpu.write_vmcs(Vmcs::GUEST_GDTR_LIMIT, 0xffff)?;
+    vcpu.write_vmcs(Vmcs::GUEST_IDTR_BASE, 0)?;
+    vcpu.write_vmcs(Vmcs::GUEST_IDTR_LIMIT, 0x3ff)?;
+
+    set_segment(vcpu, Seg::Cs, cs, u64::from(cs) << 4, 0xffff, AR_REAL_CODE)?;
+    for segment in DATA_SEGMENTS.iter() {
+        set_segment(vcpu, *segment, 0, 0, 0xffff, AR_REAL_DATA)?;
+    }
+    set_segment(vcpu, Seg::Tr, 0, 0, 0xffff, AR_TSS)?;
+    set_segment(
+        vcpu,
+        Seg::Ldtr,
+        0,
+        0,
+        0xffff,
+        SegmentDescriptor::AR_UNUSABLE,
+    )?;
+
+    start(vcpu, ip as u64, 0)
+}
@@ -0,0 +1,117 @@
+use super::qualification::{CrAccess, CrAccessType};
+use super::vmx::{Reason, VCpuVmxExt, Vmcs};
+use super::VcpuExt;
+use crate::{Error, Vcpu};
+
+impl CrAccess {
+    /// Decodes the last exit of `vcpu`, fails with [Error::BadArgument] if it isn't a control
+    /// register access.
+    pub fn read(vcpu: &Vcpu) -> Result<CrAccess, Error> {
+        if vcpu.exit_reason()?.reason() != Some(Reason::MOV_CR) {
+            return Err(Error::BadArgument);
+        }
+        Ok(CrAccess::from(vcpu.read_vmcs(Vmcs::RO_EXIT_QUALIFIC)?))
+    }
+
+    /// Returns the value the guest writes: the source register of `mov` to the control
+    /// register, or the `lmsw` source data. `None` for `mov` from the control register and
+    /// `clts`.
+    ///
+    /// The `lmsw` source only contains the low 4 bits of CR0 (PE, MP, EM and TS).
+    pub fn value(&self, vcpu: &Vcpu) -> Result<Option<u64>, Error> {
+        Ok(match self.access {
+            CrAccessType::MovTo => Some(vcpu.read_register(self.reg)?),
+            CrAccessType::Lmsw => Some(u64::from(self.lmsw_data) & 0xf),
+            CrAccessType::MovFrom | CrAccessType::Clts => None,
+        })
+    }
+}
+
+/// Control registers with a guest/host mask and read shadow.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum ShadowedCr {
+    Cr0,
+    Cr4,
+}
+
+impl ShadowedCr {
+    fn fields(self) -> (Vmcs, Vmcs) {
+        match self {
+            ShadowedCr::Cr0 => (Vmcs::CTRL_CR0_MASK, Vmcs::CTRL_CR0_SHADOW),
+            ShadowedCr::Cr4 => (Vmcs::CTRL_CR4_MASK, Vmcs::CTRL_CR4_SHADOW),
+        }
+    }
+}
+
+/// Selects the bits of CR0 or CR4 owned by the host.
+///
+/// Guest writes that change an owned bit to a value other than its shadow cause a MOV CR
+/// exit, guest reads of owned bits return the shadow. Other bits are read and written by the
+/// guest directly.
+///
+/// ```ignore
+/// // Trap enabling paging, report it disabled until the VMM has set up EPT.
+/// CrShadow::new().trap(CR0_PG, 0).apply(&cpu, ShadowedCr::Cr0)?;
+/// ```
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub struct CrShadow {
+    mask: u64,
+    shadow: u64,
+}
+
+impl CrShadow {
+    /// Creates a shadow that doesn't trap any bits.
+    pub fn new() -> CrShadow {
+        CrShadow::default()
+    }
+
+    /// Reads the mask and shadow of `cr` from the VMCS.
+    pub fn read(vcpu: &Vcpu, cr: ShadowedCr) -> Result<CrShadow, Error> {
+        let (mask, shadow) = cr.fields();
+        Ok(CrShadow {
+            mask: vcpu.read_vmcs(mask)?,
+            shadow: vcpu.read_vmcs(shadow)?,
+        })
+    }
+
+    /// Makes the host own `bits`, the guest reads them as in `value`.
+    pub fn trap(mut self, bits: u64, value: u64) -> CrShadow {
+        self.mask |= bits;
+        self.shadow = (self.shadow & !bits) | (value & bits);
+        self
+    }
+
+    /// Gives `bits` back to the guest.
+    pub fn release(mut self, bits: u64) -> CrShadow {
+        self.mask &= !bits;
+        self.shadow &= !bits;
+        self
+    }
+
+    /// Returns the bits owned by the host.
+    pub fn mask(&self) -> u64 {
+        self.mask
+    }
+
+    /// Returns the value the guest reads for owned bits.
+    pub fn shadow(&self) -> u64 {
+        self.shadow
+    }
+
+    /// Returns `true` if writing `value` to the register causes an exit.
+    pub fn traps(&self, value: u64) -> bool {
+        (value ^ self.shadow) & self.mask != 0
+    }
+
+    /// Returns the value the guest reads when the register holds `actual`.
+    pub fn guest_view(&self, actual: u64) -> u64 {
+        (actual & !self.mask) | (self.shadow & self.mask)
+    }
+
+    /// Writes the mask and shadow of `cr` to the VMCS.
+    pub fn apply(&self, vcpu: &Vcpu, cr: ShadowedCr) -> Result<(), Error> {
+        let (mask, shadow) = cr.fields();
+        vcpu.write_vmcs(mask, self.mask)?;
+        vcpu.write_vmcs(shadow, self.shadow)
+    }
+}
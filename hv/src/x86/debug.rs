@@ -0,0 +1,285 @@
+//! Hardware breakpoints of a single vCPU, encoded into `DR0`-`DR3` and `DR7`.
+//!
+//! [Breakpoints] is a plain description of the debug registers, programmed into a vCPU with
+//! [Breakpoints::apply], which also intercepts `#DB` (and optionally `#BP`) with the exception
+//! bitmap. [DebugStatus] decodes `DR6`, or the exit qualification of intercepted `#DB`
+//! exceptions, to find the breakpoint that triggered.
+//!
+//! For a VM-wide set of breakpoints shared by all vCPUs, see [crate::debug::Breakpoints].
+//!
+//! ```ignore
+//! let mut bps = Breakpoints::new();
+//! bps.set(0, HwBreakpoint::exec(0x10_0000))?;
+//! bps.set(1, HwBreakpoint::new(0x20_0000, BreakKind::Write, BreakLen::Eight))?;
+//! bps.trap_int3(true);
+//! bps.apply(&cpu)?;
+//!
+//! if let Some(status) = DebugStatus::from_exit(&cpu, &exit)? {
+//!     println!("hit slots {:?}", status.slots().collect::<Vec<_>>());
+//!     resume_past_breakpoint(&cpu)?;
+//! }
+//! ```
+
+use super::vmx::{VCpuVmxExt, Vmcs};
+use super::{Reg, VcpuExt};
+use crate::{Error, Exit, Vcpu};
+
+/// Number of debug address registers.
+pub const SLOTS: usize = 4;
+
+/// `#DB` exception vector.
+pub const VECTOR_DB: u8 = 1;
+
+/// `#BP` exception vector, raised by `int3`.
+pub const VECTOR_BP: u8 = 3;
+
+const ADDR_REGS: [Reg; SLOTS] = [Reg::DR0, Reg::DR1, Reg::DR2, Reg::DR3];
+
+/// `DR7` bit 10 is reserved and always set.
+const DR7_RESERVED: u64 = 1 << 10;
+
+/// Resume flag in `RFLAGS`.
+const RFLAGS_RF: u64 = 1 << 16;
+
+/// Accesses that trigger a breakpoint, the R/W field of `DR7`.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum BreakKind {
+    /// Instruction execution.
+    Exec,
+    /// Data writes.
+    Write,
+    /// Data reads and writes.
+    ReadWrite,
+}
+
+impl BreakKind {
+    fn bits(self) -> u64 {
+        match self {
+            BreakKind::Exec => 0b00,
+            BreakKind::Write => 0b01,
+            BreakKind::ReadWrite => 0b11,
+        }
+    }
+}
+
+/// Size of the range watched by a breakpoint, the LEN field of `DR7`.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum BreakLen {
+    One,
+    Two,
+    Four,
+    Eight,
+}
+
+impl BreakLen {
+    /// Returns the length for a size in bytes, `None` unless it's 1, 2, 4 or 8.
+    pub fn from_bytes(bytes: u8) -> Option<BreakLen> {
+        match bytes {
+            1 => Some(BreakLen::One),
+            2 => Some(BreakLen::Two),
+            4 => Some(BreakLen::Four),
+            8 => Some(BreakLen::Eight),
+            _ => None,
+        }
+    }
+
+    /// Returns the size in bytes.
+    pub fn bytes(self) -> u8 {
+        match self {
+            BreakLen::One => 1,
+            BreakLen::Two => 2,
+            BreakLen::Four => 4,
+            BreakLen::Eight => 8,
+        }
+    }
+
+    fn bits(self) -> u64 {
+        match self {
+            BreakLen::One => 0b00,
+            BreakLen::Two => 0b01,
+            BreakLen::Eight => 0b10,
+            BreakLen::Four => 0b11,
+        }
+    }
+}
+
+/// A hardware breakpoint on a guest linear address.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct HwBreakpoint {
+    pub addr: u64,
+    pub kind: BreakKind,
+    pub len: BreakLen,
+}
+
+impl HwBreakpoint {
+    pub fn new(addr: u64, kind: BreakKind, len: BreakLen) -> HwBreakpoint {
+        HwBreakpoint { addr, kind, len }
+    }
+
+    /// Returns an instruction breakpoint at `addr`.
+    pub fn exec(addr: u64) -> HwBreakpoint {
+        HwBreakpoint::new(addr, BreakKind::Exec, BreakLen::One)
+    }
+
+    /// Checks that the breakpoint can be encoded: instruction breakpoints have a length of
+    /// one byte and data breakpoints are aligned to their length.
+    fn validate(&self) -> Result<(), Error> {
+        let valid = match self.kind {
+            BreakKind::Exec => self.len == BreakLen::One,
+            _ => self.addr % u64::from(self.len.bytes()) == 0,
+        };
+        if valid {
+            Ok(())
+        } else {
+            Err(Error::BadArgument)
+        }
+    }
+}
+
+/// The hardware breakpoints of a vCPU.
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub struct Breakpoints {
+    slots: [Option<HwBreakpoint>; SLOTS],
+    trap_int3: bool,
+}
+
+impl Breakpoints {
+    pub fn new() -> Breakpoints {
+        Breakpoints::default()
+    }
+
+    /// Sets the breakpoint in `slot`, replacing the previous one.
+    ///
+    /// Fails with [Error::BadArgument] if `slot` is out of range or the breakpoint is
+    /// misaligned.
+    pub fn set(&mut self, slot: usize, breakpoint: HwBreakpoint) -> Result<(), Error> {
+        breakpoint.validate()?;
+        let entry = self.slots.get_mut(slot).ok_or(Error::BadArgument)?;
+        *entry = Some(breakpoint);
+        Ok(())
+    }
+
+    /// Removes the breakpoint in `slot` and returns it.
+    pub fn clear(&mut self, slot: usize) -> Option<HwBreakpoint> {
+        self.slots.get_mut(slot).and_then(Option::take)
+    }
+
+    /// Returns the breakpoint in `slot`.
+    pub fn get(&self, slot: usize) -> Option<HwBreakpoint> {
+        self.slots.get(slot).copied().flatten()
+    }
+
+    /// Returns the breakpoints by slot.
+    pub fn slots(&self) -> &[Option<HwBreakpoint>; SLOTS] {
+        &self.slots
+    }
+
+    /// Intercepts `#BP` exceptions, so software breakpoints (`int3`) exit with
+    /// [Exit::Exception] instead of being delivered to the guest.
+    pub fn trap_int3(&mut self, trap: bool) {
+        self.trap_int3 = trap;
+    }
+
+    /// Returns the `DR7` value enabling the breakpoints as local breakpoints.
+    pub fn dr7(&self) -> u64 {
+        self.slots
+            .iter()
+            .enumerate()
+            .fold(DR7_RESERVED, |dr7, (i, slot)| match slot {
+                Some(bp) => {
+                    dr7 | (1 << (i * 2))
+                        | (bp.kind.bits() << (16 + i * 4))
+                        | (bp.len.bits() << (18 + i * 4))
+                }
+                None => dr7,
+            })
+    }
+
+    /// Programs the debug registers of `vcpu` and intercepts `#DB` while any breakpoint is
+    /// set, and `#BP` if enabled with [Breakpoints::trap_int3].
+    pub fn apply(&self, vcpu: &Vcpu) -> Result<(), Error> {
+        self.write_registers(vcpu)?;
+        let any = self.slots.iter().any(Option::is_some);
+        trap_exception(vcpu, VECTOR_DB, any)?;
+        trap_exception(vcpu, VECTOR_BP, self.trap_int3)
+    }
+
+    /// Writes `DR0`-`DR3` and `DR7` of `vcpu`, leaving the exception bitmap unchanged.
+    pub fn write_registers(&self, vcpu: &Vcpu) -> Result<(), Error> {
+        for (reg, slot) in ADDR_REGS.iter().zip(self.slots.iter()) {
+            vcpu.write_register(*reg, slot.map_or(0, |bp| bp.addr))?;
+        }
+        vcpu.write_register(Reg::DR7, self.dr7())
+    }
+}
+
+/// Sets or clears the bit of `vector` in the exception bitmap of `vcpu`, exceptions with a set
+/// bit cause [Exit::Exception] exits.
+pub fn trap_exception(vcpu: &Vcpu, vector: u8, trap: bool) -> Result<(), Error> {
+    if vector >= 32 {
+        return Err(Error::BadArgument);
+    }
+    let bitmap = vcpu.read_vmcs(Vmcs::CTRL_EXC_BITMAP)?;
+    let bitmap = if trap {
+        bitmap | (1 << vector)
+    } else {
+        bitmap & !(1 << vector)
+    };
+    vcpu.write_vmcs(Vmcs::CTRL_EXC_BITMAP, bitmap)
+}
+
+/// Sets the resume flag of `vcpu`, so the instruction breakpoint that caused the last `#DB`
+/// doesn't trigger again when the guest is resumed.
+pub fn resume_past_breakpoint(vcpu: &Vcpu) -> Result<(), Error> {
+    let rflags = vcpu.read_register(Reg::RFLAGS)?;
+    vcpu.write_register(Reg::RFLAGS, rflags | RFLAGS_RF)
+}
+
+/// Causes of a debug exception, decoded from `DR6` or the exit qualification of an intercepted
+/// `#DB`, which uses the same layout.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct DebugStatus {
+    /// Slots whose breakpoint conditions were met (B0-B3), one bit per slot. Conditions may be
+    /// reported for disabled breakpoints too.
+    pub hits: u8,
+    /// The next instruction accesses a debug register, with `DR7.GD` set (BD).
+    pub debug_register_access: bool,
+    /// Single-step trap with `RFLAGS.TF` (BS).
+    pub single_step: bool,
+}
+
+impl DebugStatus {
+    /// Decodes the debug status of a `#DB` exit of `vcpu`, returns `None` for other exits.
+    pub fn from_exit(vcpu: &Vcpu, exit: &Exit) -> Result<Option<DebugStatus>, Error> {
+        match *exit {
+            Exit::Exception {
+                vector: VECTOR_DB, ..
+            } => {
+                let qualification = vcpu.read_vmcs(Vmcs::RO_EXIT_QUALIFIC)?;
+                Ok(Some(DebugStatus::from(qualification)))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// Returns `true` if the breakpoint in `slot` triggered.
+    pub fn hit(&self, slot: usize) -> bool {
+        slot < SLOTS && self.hits & (1 << slot) != 0
+    }
+
+    /// Returns the slots of the breakpoints that triggered.
+    pub fn slots(&self) -> impl Iterator<Item = usize> {
+        let hits = self.hits;
+        (0..SLOTS).filter(move |i| hits & (1 << i) != 0)
+    }
+}
+
+impl From<u64> for DebugStatus {
+    fn from(dr6: u64) -> Self {
+        DebugStatus {
+            hits: (dr6 & 0xf) as u8,
+            debug_register_access: dr6 & (1 << 13) != 0,
+            single_step: dr6 & (1 << 14) != 0,
+        }
+    }
+}
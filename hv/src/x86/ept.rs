@@ -0,0 +1,81 @@
+use super::vmx::{Reason, VCpuVmxExt, Vmcs};
+use crate::{Error, GPAddr, Memory, Vcpu};
+
+/// An EPT violation exit, decoded from the exit qualification and the VMCS.
+///
+/// Carried by [Exit::EptViolation](super::Exit::EptViolation), or decoded with
+/// [EptViolation::read]:
+///
+/// ```ignore
+/// let ept = EptViolation::read(&cpu)?;
+/// if let Some(device) = bus.find(ept.gpa) {
+///     emulate(&cpu, device, ept.gpa, ept.write)?;
+/// }
+/// ```
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct EptViolation {
+    /// The guest physical address of the access.
+    pub gpa: GPAddr,
+    /// The guest linear address of the access, valid if `gla_valid` is set.
+    pub gla: u64,
+    pub read: bool,
+    pub write: bool,
+    pub exec: bool,
+    /// The guest physical address was readable.
+    pub readable: bool,
+    /// The guest physical address was writable.
+    pub writable: bool,
+    /// The guest physical address was executable.
+    pub executable: bool,
+    pub gla_valid: bool,
+    /// The access was to the translated linear address, not to a paging structure during the
+    /// page walk. Only meaningful if `gla_valid` is set.
+    pub translated: bool,
+    /// The violation occurred during an `iret` that unblocked NMIs.
+    pub nmi_unblocking: bool,
+}
+
+impl EptViolation {
+    /// Decodes the last exit of `vcpu`, fails with [Error::BadArgument] if it isn't an EPT
+    /// violation.
+    pub fn read(vcpu: &Vcpu) -> Result<EptViolation, Error> {
+        if vcpu.exit_reason()?.reason() != Some(Reason::EPT_VIOLATION) {
+            return Err(Error::BadArgument);
+        }
+        EptViolation::decode(vcpu, vcpu.read_vmcs(Vmcs::RO_EXIT_QUALIFIC)?)
+    }
+
+    /// Decodes an EPT violation with the exit qualification `q`.
+    pub(super) fn decode(vcpu: &Vcpu, q: u64) -> Result<EptViolation, Error> {
+        let bit = |n: u32| q & (1 << n) != 0;
+        let gla_valid = bit(7);
+        let gla = if gla_valid {
+            vcpu.read_vmcs(Vmcs::RO_GUEST_LIN_ADDR)?
+        } else {
+            0
+        };
+
+        Ok(EptViolation {
+            gpa: vcpu.read_vmcs(Vmcs::GUEST_PHYSICAL_ADDRESS)?,
+            gla,
+            read: bit(0),
+            write: bit(1),
+            exec: bit(2),
+            readable: bit(3),
+            writable: bit(4),
+            executable: bit(5),
+            gla_valid,
+            translated: bit(8),
+            nmi_unblocking: bit(12),
+        })
+    }
+
+    /// Returns the kind of access as memory permissions.
+    pub fn access(&self) -> Memory {
+        let mut access = Memory::empty();
+        access.set(Memory::READ, self.read);
+        access.set(Memory::WRITE, self.write);
+        access.set(Memory::EXEC, self.exec);
+        access
+    }
+}
@@ -0,0 +1,96 @@
+use super::activity::{self, ActivityState};
+use super::vmx::{IrqInfo, VCpuVmxExt, Vmcs};
+use crate::{Error, Vcpu};
+
+/// An event injected at the next VM entry, see `VcpuExt::inject_event`.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Event {
+    /// An external interrupt with the given vector.
+    ExternalInterrupt(u8),
+    Nmi,
+    /// A hardware exception.
+    ///
+    /// The error code is delivered only for vectors that push one (#DF, #TS, #NP, #SS, #GP,
+    /// #PF, #AC and #CP), 0 is used if it's missing.
+    Exception {
+        vector: u8,
+        error_code: Option<u32>,
+    },
+    /// A software interrupt (`int n`).
+    SoftwareInterrupt(u8),
+    /// A software exception (`int3` or `into`).
+    SoftwareException(u8),
+    /// A privileged software exception (`int1` / `icebp`).
+    PrivilegedSoftwareException,
+}
+
+/// Returns `true` if the exception pushes an error code.
+fn has_error_code(vector: u8) -> bool {
+    matches!(vector, 8 | 10 | 11 | 12 | 13 | 14 | 17 | 21)
+}
+
+impl Event {
+    /// Returns the `CTRL_VMENTRY_IRQ_INFO` value and error code of the event, without the
+    /// valid bit.
+    fn encode(&self) -> (u64, Option<u32>) {
+        match *self {
+            Event::ExternalInterrupt(vector) => (IrqInfo::EXT_IRQ as u64 | vector as u64, None),
+            Event::Nmi => (IrqInfo::NMI as u64 | 2, None),
+            Event::Exception { vector, error_code } => {
+                let info = IrqInfo::HARD_EXC as u64 | vector as u64;
+                if has_error_code(vector) {
+                    (info, Some(error_code.unwrap_or(0)))
+                } else {
+                    (info, None)
+                }
+            }
+            Event::SoftwareInterrupt(vector) => (IrqInfo::SOFT_IRQ as u64 | vector as u64, None),
+            Event::SoftwareException(vector) => (IrqInfo::SOFT_EXC as u64 | vector as u64, None),
+            Event::PrivilegedSoftwareException => (IrqInfo::PRIV_SOFT_EXC as u64 | 1, None),
+        }
+    }
+
+    /// Returns `true` for events caused by an instruction, which need its length.
+    fn is_software(&self) -> bool {
+        matches!(
+            self,
+            Event::SoftwareInterrupt(_)
+                | Event::SoftwareException(_)
+                | Event::PrivilegedSoftwareException
+        )
+    }
+}
+
+/// Injects `event` at the next entry, replacing any pending injection. A halted vCPU is
+/// woken up.
+pub(crate) fn inject(vcpu: &Vcpu, event: Event) -> Result<(), Error> {
+    let (info, error_code) = event.encode();
+
+    if activity::get(vcpu)? == ActivityState::Hlt {
+        activity::set(vcpu, ActivityState::Active)?;
+    }
+
+    if let Some(error_code) = error_code {
+        vcpu.write_vmcs(Vmcs::CTRL_VMENTRY_EXC_ERROR, error_code as u64)?;
+    }
+    if event.is_software() {
+        // The instruction that trapped, execution resumes after it.
+        let len = vcpu.read_vmcs(Vmcs::RO_VMEXIT_INSTR_LEN)?;
+        vcpu.write_vmcs(Vmcs::CTRL_VMENTRY_INSTR_LEN, len)?;
+    }
+
+    let error_valid = if error_code.is_some() {
+        IrqInfo::ERROR_VALID as u64
+    } else {
+        0
+    };
+    vcpu.write_vmcs(
+        Vmcs::CTRL_VMENTRY_IRQ_INFO,
+        IrqInfo::VALID as u64 | error_valid | info,
+    )
+}
+
+/// Returns `true` if an event is pending injection at the next entry.
+pub(crate) fn is_pending(vcpu: &Vcpu) -> Result<bool, Error> {
+    Ok(vcpu.read_vmcs(Vmcs::CTRL_VMENTRY_IRQ_INFO)? & IrqInfo::VALID as u64 != 0)
+}
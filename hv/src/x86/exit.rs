@@ -0,0 +1,187 @@
+use super::ept::EptViolation;
+use super::io::IoExit;
+use super::vmx::{IrqInfo, Reason, VCpuVmxExt, Vmcs};
+use super::{Reg, VcpuExt};
+use crate::{Error, Vcpu};
+
+/// Decoded VM exit, returned by [Vcpu::run].
+///
+/// Exits without a dedicated variant are reported as [Exit::Other] with the raw basic exit
+/// reason, so the VMCS can be inspected manually.
+#[non_exhaustive]
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Exit {
+    /// External interrupt, including host interrupts and [VcpuExt::interrupt].
+    /// The guest can be resumed.
+    Irq,
+    /// The guest is ready to accept interrupts (interrupt window exiting).
+    IrqWindow,
+    /// The guest is ready to accept NMIs (NMI window exiting, with virtual NMIs).
+    NmiWindow,
+    /// The guest executed `hlt`.
+    Hlt,
+    /// The guest executed `cpuid`, the leaf is in `RAX` / `RCX`.
+    Cpuid,
+    /// The guest executed `vmcall`.
+    Vmcall,
+    /// Port I/O.
+    Io(IoExit),
+    /// The guest executed `rdmsr`.
+    Rdmsr { msr: u32 },
+    /// The guest executed `wrmsr`.
+    Wrmsr { msr: u32, value: u64 },
+    /// Control register access, decode the qualification with
+    /// [CrAccess](super::qualification::CrAccess).
+    MovCr { qualification: u64 },
+    /// Access to unmapped or protected guest physical memory.
+    EptViolation(EptViolation),
+    /// A guest exception or NMI intercepted by the exception bitmap.
+    Exception { vector: u8, error_code: Option<u32> },
+    /// The guest triple faulted, i.e. it entered the shutdown state, see
+    /// [ActivityState::Shutdown](super::ActivityState::Shutdown).
+    TripleFault,
+    /// The vCPU received an INIT signal, see `VcpuExt::deliver_init`.
+    Init,
+    /// The vCPU received a startup IPI while waiting for one, see `VcpuExt::deliver_sipi`.
+    Sipi { vector: u8 },
+    /// The VMX preemption timer expired.
+    PreemptionTimer,
+    /// Monitor trap flag, the guest executed a single instruction.
+    MonitorTrap,
+    /// The guest executed `pause`, with pause exiting, or spun in a `pause` loop for longer
+    /// than the window of pause-loop exiting, see `VcpuExt::enable_pause_loop_exiting`.
+    Pause,
+    /// A deadline of a [TimerWheel](crate::run::TimerWheel) passed, never returned by
+    /// [Vcpu::run](crate::Vcpu::run).
+    TimerExpired,
+    /// VM entry failed, e.g. because of invalid guest state, with the basic exit reason.
+    /// [Vcpu::diagnose](crate::Vcpu::diagnose) can help finding the cause.
+    EntryFailed { reason: u32, qualification: u64 },
+    /// Any other exit.
+    Other { reason: u32, qualification: u64 },
+}
+
+impl Exit {
+    /// Returns the name of the exit kind, e.g. for statistics.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Exit::Irq => "irq",
+            Exit::IrqWindow => "irq_window",
+            Exit::NmiWindow => "nmi_window",
+            Exit::Hlt => "hlt",
+            Exit::Cpuid => "cpuid",
+            Exit::Vmcall => "vmcall",
+            Exit::Io(_) => "io",
+            Exit::Rdmsr { .. } => "rdmsr",
+            Exit::Wrmsr { .. } => "wrmsr",
+            Exit::MovCr { .. } => "mov_cr",
+            Exit::EptViolation(_) => "ept_violation",
+            Exit::Exception { .. } => "exception",
+            Exit::TripleFault => "triple_fault",
+            Exit::Init => "init",
+            Exit::Sipi { .. } => "sipi",
+            Exit::PreemptionTimer => "preemption_timer",
+            Exit::MonitorTrap => "monitor_trap",
+            Exit::Pause => "pause",
+            Exit::TimerExpired => "timer_expired",
+            Exit::EntryFailed { .. } => "entry_failed",
+            Exit::Other { .. } => "other",
+        }
+    }
+
+    /// Returns the VMX exit reason of the exit, `None` for [Exit::TimerExpired] and unknown
+    /// reasons.
+    pub fn reason(&self) -> Option<Reason> {
+        match self {
+            Exit::Irq => Some(Reason::IRQ),
+            Exit::IrqWindow => Some(Reason::IRQ_WND),
+            Exit::NmiWindow => Some(Reason::VIRTUAL_NMI_WND),
+            Exit::Hlt => Some(Reason::HLT),
+            Exit::Cpuid => Some(Reason::CPUID),
+            Exit::Vmcall => Some(Reason::VMCALL),
+            Exit::Io(_) => Some(Reason::IO),
+            Exit::Rdmsr { .. } => Some(Reason::RDMSR),
+            Exit::Wrmsr { .. } => Some(Reason::WRMSR),
+            Exit::MovCr { .. } => Some(Reason::MOV_CR),
+            Exit::EptViolation(_) => Some(Reason::EPT_VIOLATION),
+            Exit::Exception { .. } => Some(Reason::EXC_NMI),
+            Exit::TripleFault => Some(Reason::TRIPLE_FAULT),
+            Exit::Init => Some(Reason::INIT),
+            Exit::Sipi { .. } => Some(Reason::SIPI),
+            Exit::PreemptionTimer => Some(Reason::VMX_TIMER_EXPIRED),
+            Exit::MonitorTrap => Some(Reason::MTF),
+            Exit::Pause => Some(Reason::PAUSE),
+            Exit::TimerExpired => None,
+            Exit::EntryFailed { reason, .. } | Exit::Other { reason, .. } => {
+                Reason::from_raw(*reason)
+            }
+        }
+    }
+
+    /// Decodes the last exit of `vcpu`.
+    pub fn decode(vcpu: &Vcpu) -> Result<Exit, Error> {
+        let exit_reason = vcpu.exit_reason()?;
+        let reason = exit_reason.basic();
+        let qualification = || vcpu.read_vmcs(Vmcs::RO_EXIT_QUALIFIC);
+
+        if exit_reason.is_entry_failure() {
+            return Ok(Exit::EntryFailed {
+                reason,
+                qualification: qualification()?,
+            });
+        }
+
+        let exit = match reason {
+            r if r == Reason::IRQ as u32 => Exit::Irq,
+            r if r == Reason::IRQ_WND as u32 => Exit::IrqWindow,
+            r if r == Reason::VIRTUAL_NMI_WND as u32 => Exit::NmiWindow,
+            r if r == Reason::HLT as u32 => Exit::Hlt,
+            r if r == Reason::CPUID as u32 => Exit::Cpuid,
+            r if r == Reason::VMCALL as u32 => Exit::Vmcall,
+            r if r == Reason::TRIPLE_FAULT as u32 => Exit::TripleFault,
+            r if r == Reason::VMX_TIMER_EXPIRED as u32 => Exit::PreemptionTimer,
+            r if r == Reason::INIT as u32 => Exit::Init,
+            r if r == Reason::SIPI as u32 => Exit::Sipi {
+                vector: qualification()? as u8,
+            },
+            r if r == Reason::MTF as u32 => Exit::MonitorTrap,
+            r if r == Reason::PAUSE as u32 => Exit::Pause,
+            r if r == Reason::IO as u32 => Exit::Io(IoExit::decode(vcpu, qualification()?)?),
+            r if r == Reason::RDMSR as u32 => Exit::Rdmsr {
+                msr: vcpu.read_register(Reg::RCX)? as u32,
+            },
+            r if r == Reason::WRMSR as u32 => {
+                let lo = vcpu.read_register(Reg::RAX)? & 0xffff_ffff;
+                let hi = vcpu.read_register(Reg::RDX)? & 0xffff_ffff;
+                Exit::Wrmsr {
+                    msr: vcpu.read_register(Reg::RCX)? as u32,
+                    value: (hi << 32) | lo,
+                }
+            }
+            r if r == Reason::MOV_CR as u32 => Exit::MovCr {
+                qualification: qualification()?,
+            },
+            r if r == Reason::EPT_VIOLATION as u32 => {
+                Exit::EptViolation(EptViolation::decode(vcpu, qualification()?)?)
+            }
+            r if r == Reason::EXC_NMI as u32 => {
+                let info = vcpu.read_vmcs(Vmcs::RO_VMEXIT_IRQ_INFO)?;
+                let error_code = if info & IrqInfo::ERROR_VALID as u64 != 0 {
+                    Some(vcpu.read_vmcs(Vmcs::RO_VMEXIT_IRQ_ERROR)? as u32)
+                } else {
+                    None
+                };
+                Exit::Exception {
+                    vector: info as u8,
+                    error_code,
+                }
+            }
+            reason => Exit::Other {
+                reason,
+                qualification: qualification()?,
+            },
+        };
+
+        Ok(exit)
+    }
+}
@@ -0,0 +1,235 @@
+use super::vmx::{VCpuVmxExt, Vmcs};
+use crate::{Error, Vcpu};
+
+/// Guest exceptions that can be intercepted with the exception bitmap.
+#[repr(u8)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Exception {
+    /// `#DE`
+    DivideError = 0,
+    /// `#DB`
+    Debug = 1,
+    /// `#BP`, raised by `int3`.
+    Breakpoint = 3,
+    /// `#OF`
+    Overflow = 4,
+    /// `#BR`
+    BoundRange = 5,
+    /// `#UD`
+    InvalidOpcode = 6,
+    /// `#NM`
+    DeviceNotAvailable = 7,
+    /// `#DF`
+    DoubleFault = 8,
+    /// `#TS`
+    InvalidTss = 10,
+    /// `#NP`
+    SegmentNotPresent = 11,
+    /// `#SS`
+    StackFault = 12,
+    /// `#GP`
+    GeneralProtection = 13,
+    /// `#PF`, filtered by error code with [PfFilter].
+    PageFault = 14,
+    /// `#MF`
+    FloatingPoint = 16,
+    /// `#AC`
+    AlignmentCheck = 17,
+    /// `#MC`
+    MachineCheck = 18,
+    /// `#XM`
+    Simd = 19,
+    /// `#VE`
+    Virtualization = 20,
+    /// `#CP`
+    ControlProtection = 21,
+}
+
+impl Exception {
+    const ALL: [Exception; 19] = [
+        Exception::DivideError,
+        Exception::Debug,
+        Exception::Breakpoint,
+        Exception::Overflow,
+        Exception::BoundRange,
+        Exception::InvalidOpcode,
+        Exception::DeviceNotAvailable,
+        Exception::DoubleFault,
+        Exception::InvalidTss,
+        Exception::SegmentNotPresent,
+        Exception::StackFault,
+        Exception::GeneralProtection,
+        Exception::PageFault,
+        Exception::FloatingPoint,
+        Exception::AlignmentCheck,
+        Exception::MachineCheck,
+        Exception::Simd,
+        Exception::Virtualization,
+        Exception::ControlProtection,
+    ];
+
+    /// Returns the exception with `vector`, e.g. of an [Exit::Exception](super::Exit).
+    pub fn from_vector(vector: u8) -> Option<Exception> {
+        Exception::ALL.iter().copied().find(|e| *e as u8 == vector)
+    }
+
+    /// Returns the exception vector.
+    #[inline]
+    pub fn vector(self) -> u8 {
+        self as u8
+    }
+}
+
+bitflags::bitflags! {
+    /// Bits of the `#PF` error code.
+    pub struct PfErrorCode: u32 {
+        /// The fault was caused by a protection violation, not a non-present page.
+        const PRESENT = 1 << 0;
+        const WRITE = 1 << 1;
+        /// The access was made in user mode.
+        const USER = 1 << 2;
+        /// A reserved bit was set in a paging structure.
+        const RESERVED = 1 << 3;
+        const FETCH = 1 << 4;
+        /// Protection key violation.
+        const PROTECTION_KEY = 1 << 5;
+        /// Shadow stack access.
+        const SHADOW_STACK = 1 << 6;
+        const SGX = 1 << 15;
+    }
+}
+
+/// The page faults that exit, by error code.
+///
+/// A page fault with error code `ec` matches if `ec & mask == value`.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum PfFilter {
+    /// All page faults exit.
+    All,
+    /// Page faults matching `mask` and `value` exit, e.g. only writes to present pages.
+    Matching {
+        mask: PfErrorCode,
+        value: PfErrorCode,
+    },
+    /// Page faults not matching `mask` and `value` exit.
+    NotMatching {
+        mask: PfErrorCode,
+        value: PfErrorCode,
+    },
+}
+
+/// The guest exceptions that cause [Exit::Exception](super::Exit::Exception) exits instead of
+/// being delivered to the guest, programmed into `CTRL_EXC_BITMAP`, `CTRL_PF_ERROR_MASK` and
+/// `CTRL_PF_ERROR_MATCH`.
+///
+/// ```ignore
+/// ExceptionIntercepts::new()
+///     .intercept(Exception::InvalidOpcode)
+///     .page_faults(PfFilter::Matching {
+///         mask: PfErrorCode::PRESENT | PfErrorCode::WRITE,
+///         value: PfErrorCode::PRESENT | PfErrorCode::WRITE,
+///     })
+///     .apply(&cpu)?;
+/// ```
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub struct ExceptionIntercepts {
+    bitmap: u32,
+    pf_mask: u32,
+    pf_match: u32,
+}
+
+impl ExceptionIntercepts {
+    /// Returns intercepts that let the guest handle all exceptions.
+    pub fn new() -> ExceptionIntercepts {
+        ExceptionIntercepts::default()
+    }
+
+    /// Returns the current intercepts of `vcpu`.
+    pub fn read(vcpu: &Vcpu) -> Result<ExceptionIntercepts, Error> {
+        let v = vcpu.read_vmcs_many(&[
+            Vmcs::CTRL_EXC_BITMAP,
+            Vmcs::CTRL_PF_ERROR_MASK,
+            Vmcs::CTRL_PF_ERROR_MATCH,
+        ])?;
+        Ok(ExceptionIntercepts {
+            bitmap: v[0] as u32,
+            pf_mask: v[1] as u32,
+            pf_match: v[2] as u32,
+        })
+    }
+
+    /// Intercepts `exception`, all page faults for [Exception::PageFault].
+    pub fn intercept(self, exception: Exception) -> ExceptionIntercepts {
+        match exception {
+            Exception::PageFault => self.page_faults(PfFilter::All),
+            _ => ExceptionIntercepts {
+                bitmap: self.bitmap | (1 << exception.vector()),
+                ..self
+            },
+        }
+    }
+
+    /// Lets the guest handle `exception`.
+    pub fn release(self, exception: Exception) -> ExceptionIntercepts {
+        let bitmap = self.bitmap & !(1 << exception.vector());
+        match exception {
+            Exception::PageFault => ExceptionIntercepts {
+                bitmap,
+                pf_mask: 0,
+                pf_match: 0,
+            },
+            _ => ExceptionIntercepts { bitmap, ..self },
+        }
+    }
+
+    /// Intercepts the page faults selected by `filter`.
+    pub fn page_faults(self, filter: PfFilter) -> ExceptionIntercepts {
+        // With the #PF bit set, faults with `ec & mask == match` exit, with it clear the other
+        // ones do.
+        let pf = 1 << Exception::PageFault.vector();
+        let (bitmap, mask, value) = match filter {
+            PfFilter::All => (self.bitmap | pf, 0, 0),
+            PfFilter::Matching { mask, value } => (self.bitmap | pf, mask.bits, value.bits),
+            PfFilter::NotMatching { mask, value } => (self.bitmap & !pf, mask.bits, value.bits),
+        };
+        ExceptionIntercepts {
+            bitmap,
+            pf_mask: mask,
+            pf_match: value & mask,
+        }
+    }
+
+    /// Returns `true` if `exception` is intercepted, page faults may be filtered.
+    pub fn is_intercepted(&self, exception: Exception) -> bool {
+        match exception {
+            Exception::PageFault => self.page_fault_filter().is_some(),
+            _ => self.bitmap & (1 << exception.vector()) != 0,
+        }
+    }
+
+    /// Returns the filter of intercepted page faults, `None` if they aren't intercepted.
+    pub fn page_fault_filter(&self) -> Option<PfFilter> {
+        let intercepted = self.bitmap & (1 << Exception::PageFault.vector()) != 0;
+        let mask = PfErrorCode::from_bits_truncate(self.pf_mask);
+        let value = PfErrorCode::from_bits_truncate(self.pf_match);
+        if self.pf_mask == 0 {
+            // All page faults match an empty mask if the match value is 0, none otherwise.
+            let all = intercepted == (self.pf_match == 0);
+            return if all { Some(PfFilter::All) } else { None };
+        }
+        if intercepted {
+            Some(PfFilter::Matching { mask, value })
+        } else {
+            Some(PfFilter::NotMatching { mask, value })
+        }
+    }
+
+    /// Programs the intercepts into `vcpu`.
+    pub fn apply(&self, vcpu: &Vcpu) -> Result<(), Error> {
+        vcpu.write_vmcs_many(&[
+            (Vmcs::CTRL_EXC_BITMAP, u64::from(self.bitmap)),
+            (Vmcs::CTRL_PF_ERROR_MASK, u64::from(self.pf_mask)),
+            (Vmcs::CTRL_PF_ERROR_MATCH, u64::from(self.pf_match)),
+        ])
+    }
+}
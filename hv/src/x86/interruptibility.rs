@@ -0,0 +1,44 @@
+bitflags::bitflags! {
+    /// Interruptibility state of a vCPU, `GUEST_IGNORE_IRQ`.
+    pub struct Interruptibility: u64 {
+        /// Blocking by `sti`: interrupts are blocked until after the next instruction.
+        const STI = 1 << 0;
+        /// Blocking by `mov ss` or `pop ss`: interrupts, NMIs and debug exceptions are
+        /// blocked until after the next instruction.
+        const MOV_SS = 1 << 1;
+        /// Blocking by SMI.
+        const SMI = 1 << 2;
+        /// Blocking by NMI: an NMI is being handled.
+        const NMI = 1 << 3;
+        /// The exit happened in an SGX enclave.
+        const ENCLAVE = 1 << 4;
+    }
+}
+
+impl Interruptibility {
+    /// Returns `true` if the guest is in an interrupt shadow, i.e. external interrupts are
+    /// blocked regardless of `RFLAGS.IF`.
+    pub fn in_shadow(&self) -> bool {
+        self.intersects(Interruptibility::STI | Interruptibility::MOV_SS)
+    }
+}
+
+bitflags::bitflags! {
+    /// Debug exceptions pending at the next entry, `GUEST_DEBUG_EXC`, in the layout of `DR6`.
+    ///
+    /// Entering the guest in an `sti` or `mov ss` shadow with `RFLAGS.TF` set requires
+    /// [PendingDebugExceptions::BS], otherwise the entry fails.
+    pub struct PendingDebugExceptions: u64 {
+        /// Breakpoint conditions of `DR0` to `DR3` were met.
+        const B0 = 1 << 0;
+        const B1 = 1 << 1;
+        const B2 = 1 << 2;
+        const B3 = 1 << 3;
+        /// At least one of the met breakpoint conditions is enabled in `DR7`.
+        const ENABLED_BREAKPOINT = 1 << 12;
+        /// A single-step trap is pending.
+        const BS = 1 << 14;
+        /// The debug exception happened in an RTM region.
+        const RTM = 1 << 16;
+    }
+}
@@ -0,0 +1,122 @@
+use super::vmx::{Reason, VCpuVmxExt, Vmcs};
+use super::{Reg, VcpuExt};
+use crate::{Error, Vcpu};
+
+/// Direction of a port I/O access.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum IoDirection {
+    /// `in`, the guest reads the port.
+    In,
+    /// `out`, the guest writes the port.
+    Out,
+}
+
+/// Encoding of the port operand.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum IoOperand {
+    Dx,
+    Immediate,
+}
+
+/// A port I/O exit, decoded from the exit qualification and the VMCS.
+///
+/// Carried by [Exit::Io](super::Exit::Io), or decoded with [IoExit::read]:
+///
+/// ```ignore
+/// let io = IoExit::read(&cpu)?;
+/// let data = match io.direction {
+///     IoDirection::In => device.read(io.port, io.size),
+///     IoDirection::Out => device.write(io.port, io.size, io.out_data(&cpu)?),
+/// };
+/// io.complete(&cpu, data)?;
+/// ```
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct IoExit {
+    pub port: u16,
+    /// Access size in bytes (1, 2 or 4).
+    pub size: u8,
+    pub direction: IoDirection,
+    /// String instruction (`ins` / `outs`).
+    pub string: bool,
+    /// Has a `rep` prefix.
+    pub rep: bool,
+    pub operand: IoOperand,
+    /// Guest linear address of the memory operand of string instructions, 0 otherwise.
+    ///
+    /// The `RO_IO_*` VMCS fields are only saved for SMIs following I/O instructions, use
+    /// this and the guest's `RCX`, `RSI` and `RDI` to emulate string instructions.
+    pub linear_address: u64,
+    /// Length of the instruction, to advance `RIP`.
+    pub instruction_len: u64,
+}
+
+impl IoExit {
+    /// Decodes the last exit of `vcpu`, fails with [Error::BadArgument] if it isn't a port I/O
+    /// exit.
+    pub fn read(vcpu: &Vcpu) -> Result<IoExit, Error> {
+        if vcpu.exit_reason()?.reason() != Some(Reason::IO) {
+            return Err(Error::BadArgument);
+        }
+        IoExit::decode(vcpu, vcpu.read_vmcs(Vmcs::RO_EXIT_QUALIFIC)?)
+    }
+
+    /// Decodes a port I/O exit with the exit qualification `q`.
+    pub(super) fn decode(vcpu: &Vcpu, q: u64) -> Result<IoExit, Error> {
+        let bit = |n: u32| q & (1 << n) != 0;
+        let string = bit(4);
+        let linear_address = if string {
+            vcpu.read_vmcs(Vmcs::RO_GUEST_LIN_ADDR)?
+        } else {
+            0
+        };
+
+        Ok(IoExit {
+            port: (q >> 16) as u16,
+            size: ((q & 0x7) + 1) as u8,
+            direction: if bit(3) {
+                IoDirection::In
+            } else {
+                IoDirection::Out
+            },
+            string,
+            rep: bit(5),
+            operand: if bit(6) {
+                IoOperand::Immediate
+            } else {
+                IoOperand::Dx
+            },
+            linear_address,
+            instruction_len: vcpu.read_vmcs(Vmcs::RO_VMEXIT_INSTR_LEN)?,
+        })
+    }
+
+    /// Returns the mask of the access size.
+    pub fn mask(&self) -> u64 {
+        (1_u64 << (self.size * 8)) - 1
+    }
+
+    /// Returns the value written by `out`, from `AL`, `AX` or `EAX`.
+    pub fn out_data(&self, vcpu: &Vcpu) -> Result<u64, Error> {
+        Ok(vcpu.read_register(Reg::RAX)? & self.mask())
+    }
+
+    /// Completes a non-string access: stores `data` in `AL`, `AX` or `EAX` for `in` and moves
+    /// `RIP` past the instruction. `data` is ignored for `out`.
+    ///
+    /// 32-bit results zero extend into `RAX`, narrower ones are merged as the processor does.
+    pub fn complete(&self, vcpu: &Vcpu, data: u64) -> Result<(), Error> {
+        if self.direction == IoDirection::In {
+            let mask = self.mask();
+            let rax = vcpu.read_register(Reg::RAX)?;
+            let keep = if self.size == 4 { 0 } else { rax & !mask };
+            vcpu.write_register(Reg::RAX, keep | (data & mask))?;
+        }
+        self.skip(vcpu)
+    }
+
+    /// Moves `RIP` past the instruction, e.g. after emulating a string instruction.
+    pub fn skip(&self, vcpu: &Vcpu) -> Result<(), Error> {
+        let rip = vcpu.read_register(Reg::RIP)?;
+        vcpu.write_register(Reg::RIP, rip + self.instruction_len)
+    }
+}
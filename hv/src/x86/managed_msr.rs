@@ -0,0 +1,41 @@
+use super::VcpuExt;
+use crate::{sys, Error, Vcpu};
+
+bitflags::bitflags! {
+    /// Guest accesses to a managed MSR that don't exit.
+    pub struct MsrAccess: u32 {
+        const NONE = sys::HV_MSR_NONE;
+        const READ = sys::HV_MSR_READ;
+        const WRITE = sys::HV_MSR_WRITE;
+    }
+}
+
+/// An MSR whose guest value is kept by the framework, with a per-access policy.
+///
+/// Unlike [VcpuExt::enable_native_msr], which passes both reads and writes through, accesses
+/// not allowed by `access` exit with `rdmsr` or `wrmsr`, e.g. to audit writes while reads
+/// run natively.
+///
+/// ```ignore
+/// cpu.manage_msrs(&[
+///     ManagedMsr::new(IA32_TSC_AUX, MsrAccess::READ | MsrAccess::WRITE),
+///     ManagedMsr::new(IA32_SPEC_CTRL, MsrAccess::READ),
+/// ])?;
+/// ```
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct ManagedMsr {
+    pub msr: u32,
+    pub access: MsrAccess,
+}
+
+impl ManagedMsr {
+    pub fn new(msr: u32, access: MsrAccess) -> ManagedMsr {
+        ManagedMsr { msr, access }
+    }
+
+    /// Enables management of the MSR on `vcpu` and applies the access policy.
+    pub fn apply(&self, vcpu: &Vcpu) -> Result<(), Error> {
+        vcpu.enable_managed_msr(self.msr, true)?;
+        vcpu.set_msr_access(self.msr, self.access)
+    }
+}
@@ -3,10 +3,65 @@
 use std::ffi::c_void;
 use std::mem;
 use std::sync::Arc;
+use std::time::Duration;
+#[cfg(feature = "hv_10_15")]
+use std::time::Instant;
 
-use crate::{call, sys, Addr, Error, GPAddr, Memory, Size, Vcpu, Vm};
+use crate::vcpu::Field;
+use crate::{call, sys, validate_mapping, Addr, Error, GPAddr, Memory, Size, Vcpu, Vm};
+use vmx::VCpuVmxExt;
 
+mod activity;
+pub mod apic;
+pub mod boot;
+mod cr;
+pub mod debug;
+mod ept;
+mod event;
+mod exit;
+mod intercept;
+mod interruptibility;
+mod io;
+#[cfg(feature = "hv_12_0")]
+mod managed_msr;
+mod msr_area;
+pub mod qualification;
+mod segment;
+pub(crate) mod state;
+mod tsc;
+mod vmcs_dump;
 pub mod vmx;
+mod vpid;
+mod xsave;
+
+pub use activity::ActivityState;
+pub use apic::ApicPage;
+pub use cr::{CrShadow, ShadowedCr};
+pub use ept::EptViolation;
+pub use event::Event;
+pub use exit::Exit;
+pub use intercept::{Exception, ExceptionIntercepts, PfErrorCode, PfFilter};
+pub use interruptibility::{Interruptibility, PendingDebugExceptions};
+pub use io::{IoDirection, IoExit, IoOperand};
+#[cfg(feature = "hv_12_0")]
+pub use managed_msr::{ManagedMsr, MsrAccess};
+pub use msr_area::MsrSwapArea;
+pub use segment::{Seg, SegmentDescriptor};
+pub use state::VcpuState;
+pub(crate) use tsc::guest_tsc;
+pub use tsc::GuestTsc;
+pub use vmcs_dump::{VmcsDump, VmcsGroup};
+pub use vpid::{TlbChange, VpidAllocator};
+pub use xsave::{XFeatures, XSaveArea};
+
+#[cfg(feature = "hv_10_15")]
+mod nested;
+#[cfg(feature = "hv_10_15")]
+mod shared;
+#[cfg(feature = "hv_10_15")]
+pub use nested::NestedVmcs;
+#[cfg(feature = "hv_10_15")]
+pub use shared::{SharedMapping, SharedRegion};
 
 pub type UVAddr = Addr;
 
@@ -15,6 +70,19 @@ pub type SpaceId = sys::hv_vm_space_t;
 
 pub const VM_SPACE_DEFAULT: SpaceId = sys::HV_VM_SPACE_DEFAULT;
 
+/// Activate VMX preemption timer pin-based VM-execution control.
+const PIN_BASED_PREEMPTION_TIMER: u64 = 1 << 6;
+
+/// `rdtsc` exiting, monitor trap flag and activate secondary controls primary VM-execution
+/// controls.
+const CPU_BASED_RDTSC: u64 = 1 << 12;
+pub(crate) const CPU_BASED_MTF: u64 = 1 << 27;
+const CPU_BASED_SECONDARY: u64 = 1 << 31;
+
+/// Enable VPID and pause-loop exiting secondary VM-execution controls.
+const CPU_BASED2_VPID: u64 = 1 << 5;
+const CPU_BASED2_PAUSE_LOOP: u64 = 1 << 10;
+
 /// The type of system capabilities.
 #[repr(u32)]
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
@@ -73,6 +141,8 @@ impl Space {
     /// * `size` - Size in bytes of the region to be mapped.
     /// * `flags` - READ, WRITE and EXECUTE permissions of the region.
     pub fn map(&self, uva: UVAddr, gpa: GPAddr, size: u64, flags: Memory) -> Result<(), Error> {
+        validate_mapping(Some(uva), gpa, size, Some(flags))?;
+
         call!(sys::hv_vm_map_space(
             self.id,
             uva as *const c_void,
@@ -88,6 +158,7 @@ impl Space {
     /// * `gpa` - Page aligned address in the guest physical address space.
     /// * `size` - Size in bytes of the region to be unmapped.
     pub fn unmap(&self, gpa: GPAddr, size: Size) -> Result<(), Error> {
+        validate_mapping(None, gpa, size, None)?;
         call!(sys::hv_vm_unmap_space(self.id, gpa, size))
     }
 
@@ -98,6 +169,7 @@ impl Space {
     /// * `size` - Size in bytes of the region to be modified.
     /// * `flags` - New READ, WRITE and EXECUTE permissions of the region.
     pub fn protect(&self, gpa: GPAddr, size: Size, flags: Memory) -> Result<(), Error> {
+        validate_mapping(None, gpa, size, Some(flags))?;
         call!(sys::hv_vm_protect_space(
             self.id,
             gpa,
@@ -124,13 +196,24 @@ pub trait VmExt {
 
     /// Synchronizes guest TSC across all vCPUs.
     fn sync_tsc(tcs: u64) -> Result<(), Error>;
+
+    /// Returns the allocator of VPIDs for the vCPUs of the VM.
+    fn vpids(&self) -> &VpidAllocator;
 }
 
 /// x86 specific routines for vCPU.
 pub trait VcpuExt {
     /// Executes a vCPU until the given deadline.
     #[cfg(feature = "hv_10_15")]
-    fn run_until(&self, deadline: u64) -> Result<(), Error>;
+    fn run_until(&self, deadline: u64) -> Result<Exit, Error>;
+
+    /// Executes a vCPU for at most `duration`.
+    #[cfg(feature = "hv_10_15")]
+    fn run_for(&self, duration: Duration) -> Result<Exit, Error>;
+
+    /// Executes a vCPU until `deadline` at the latest.
+    #[cfg(feature = "hv_10_15")]
+    fn run_until_instant(&self, deadline: Instant) -> Result<Exit, Error>;
 
     /// Forces flushing of cached vCPU state.
     fn flush(&self) -> Result<(), Error>;
@@ -142,12 +225,45 @@ pub trait VcpuExt {
     #[cfg(feature = "hv_10_15")]
     fn set_space(&self, space: &Space) -> Result<(), Error>;
 
+    /// Moves the vCPU to `space`, invalidating its TLB only if required, see [TlbChange].
+    #[cfg(feature = "hv_10_15")]
+    fn switch_space(&self, space: &Space) -> Result<(), Error>;
+
+    /// Tags the TLB entries of the vCPU with `vpid` and enables VPIDs, 0 disables them. See
+    /// [VmExt::vpids] to allocate unique identifiers.
+    ///
+    /// Fails with [Error::Unsupported] if the host doesn't support VPIDs.
+    fn set_vpid(&self, vpid: u16) -> Result<(), Error>;
+
+    /// Returns the VPID of the vCPU, `None` if VPIDs are disabled.
+    fn vpid(&self) -> Result<Option<u16>, Error>;
+
+    /// Reads every VMCS field, e.g. to analyze an entry failure after the fact.
+    fn dump_vmcs(&self) -> Result<VmcsDump, Error>;
+
+    /// Invalidates the TLB of the vCPU if `change` may have left stale translations in it.
+    /// Returns `true` if it was invalidated.
+    fn sync_tlb(&self, change: TlbChange) -> Result<bool, Error>;
+
     /// Forces an immediate VMEXIT of the vCPU.
     fn interrupt(&self) -> Result<(), Error>;
 
     /// Enables an MSR to be used natively by the VM.
     fn enable_native_msr(&self, msr: u32, enable: bool) -> Result<(), Error>;
 
+    /// Enables or disables management of an MSR by the framework, which keeps its guest
+    /// value. Guest accesses exit unless allowed with [VcpuExt::set_msr_access].
+    #[cfg(feature = "hv_12_0")]
+    fn enable_managed_msr(&self, msr: u32, enable: bool) -> Result<(), Error>;
+
+    /// Sets the guest accesses to a managed MSR that don't exit.
+    #[cfg(feature = "hv_12_0")]
+    fn set_msr_access(&self, msr: u32, access: MsrAccess) -> Result<(), Error>;
+
+    /// Applies the policies of several managed MSRs, in order.
+    #[cfg(feature = "hv_12_0")]
+    fn manage_msrs(&self, msrs: &[ManagedMsr]) -> Result<(), Error>;
+
     /// Returns the current value of an MSR of a vCPU.
     fn read_msr(&self, msr: u32) -> Result<u64, Error>;
 
@@ -160,12 +276,165 @@ pub trait VcpuExt {
     /// Set the value of an architectural x86 register of a vCPU.
     fn write_register(&self, reg: Reg, value: u64) -> Result<(), Error>;
 
+    /// Returns the values of several architectural x86 registers, in order.
+    ///
+    /// A convenience loop over [VcpuExt::read_register], it doesn't batch: every register
+    /// that isn't in the write cache costs one framework call.
+    fn read_registers(&self, regs: &[Reg]) -> Result<Vec<u64>, Error>;
+
+    /// Sets the values of several architectural x86 registers, in order.
+    ///
+    /// A convenience loop over [VcpuExt::write_register], it doesn't batch by itself: the
+    /// writes are only buffered until the next entry with the write cache enabled, see
+    /// [Vcpu::set_write_cache]. Stops at the first failing register.
+    fn write_registers(&self, regs: &[(Reg, u64)]) -> Result<(), Error>;
+
+    /// Returns the general purpose registers of a vCPU.
+    fn get_regs(&self) -> Result<GeneralRegs, Error>;
+
+    /// Sets the general purpose registers of a vCPU.
+    fn set_regs(&self, regs: &GeneralRegs) -> Result<(), Error>;
+
+    /// Returns a segment register, read from the VMCS.
+    fn get_segment(&self, seg: Seg) -> Result<SegmentDescriptor, Error>;
+
+    /// Sets a segment register in the VMCS.
+    ///
+    /// Fails with [Error::BadArgument] if the access rights are invalid for `seg`, see
+    /// [SegmentDescriptor::validate]. The unrestricted guest control of the vCPU is taken
+    /// into account, so set it first.
+    fn set_segment(&self, seg: Seg, desc: &SegmentDescriptor) -> Result<(), Error>;
+
     /// Returns the current architectural x86 floating point and SIMD state of a vCPU.
-    /// Structure and size are defined by the XSAVE feature set of the host processor.
+    /// Structure and size are defined by the XSAVE feature set of the host processor, see
+    /// [Vcpu::fpstate_size].
     fn read_fpstate(&self, buffer: &mut [u8]) -> Result<(), Error>;
 
     /// Sets the architectural x86 floating point and SIMD state of a vCPU.
     fn write_fpstate(&self, buffer: &[u8]) -> Result<(), Error>;
+
+    /// Returns the floating point and SIMD state of a vCPU, decoded from the XSAVE area.
+    fn read_xsave(&self) -> Result<XSaveArea, Error>;
+
+    /// Sets the floating point and SIMD state of a vCPU from a decoded XSAVE area.
+    fn write_xsave(&self, area: &XSaveArea) -> Result<(), Error>;
+
+    /// Sets `XCR0` of a vCPU after validating it with [XFeatures::validate], failing with
+    /// [Error::InvalidXcr0] rather than with an invalid guest state at the next run.
+    fn set_xcr0(&self, features: XFeatures) -> Result<(), Error>;
+
+    /// Injects an event at the next entry, replacing any pending injection.
+    ///
+    /// Sets the error code valid bit and the error code for exceptions that push one. Software
+    /// interrupts and exceptions use the length of the instruction that caused the last exit,
+    /// so the guest resumes after it.
+    fn inject_event(&self, event: Event) -> Result<(), Error>;
+
+    /// Returns `true` if an event is pending injection at the next entry.
+    fn is_event_pending(&self) -> Result<bool, Error>;
+
+    /// Returns the activity state of the vCPU.
+    fn activity_state(&self) -> Result<ActivityState, Error>;
+
+    /// Sets the activity state of the vCPU, e.g. [ActivityState::Hlt] to let a halted guest
+    /// wait in the processor until an event is injected.
+    fn set_activity_state(&self, state: ActivityState) -> Result<(), Error>;
+
+    /// Emulates an INIT signal: resets the vCPU to the real mode reset state and makes it
+    /// wait for a SIPI, see [VcpuExt::deliver_sipi]. Requires the unrestricted guest control,
+    /// see [boot::setup_real_mode].
+    ///
+    /// [Vcpu::run_loop](crate::Vcpu::run_loop) calls it for [Exit::Init].
+    fn deliver_init(&self) -> Result<(), Error>;
+
+    /// Emulates a startup IPI: a vCPU waiting for a SIPI starts in real mode at
+    /// `vector << 12`. Returns `false`, ignoring the SIPI, if the vCPU isn't waiting for one.
+    ///
+    /// [Vcpu::run_loop](crate::Vcpu::run_loop) calls it for [Exit::Sipi].
+    fn deliver_sipi(&self, vector: u8) -> Result<bool, Error>;
+
+    /// Returns the interruptibility state of the vCPU.
+    fn interruptibility(&self) -> Result<Interruptibility, Error>;
+
+    /// Sets the interruptibility state of the vCPU, e.g. to clear an interrupt shadow after
+    /// emulating the instruction following `sti`.
+    fn set_interruptibility(&self, state: Interruptibility) -> Result<(), Error>;
+
+    /// Returns the debug exceptions pending at the next entry.
+    fn pending_debug_exceptions(&self) -> Result<PendingDebugExceptions, Error>;
+
+    /// Sets the debug exceptions pending at the next entry.
+    fn set_pending_debug_exceptions(&self, pending: PendingDebugExceptions) -> Result<(), Error>;
+
+    /// Requests an external interrupt with `vector`, e.g. from an exit handler.
+    ///
+    /// Unlike [VcpuExt::inject_event], the interrupt is only injected once the guest can take
+    /// it: while `RFLAGS.IF` is clear or in an interrupt shadow, interrupt window exiting is
+    /// armed and the next run after the window opens injects it. Requests share the queue of
+    /// [Vcpu::irq_queue](crate::Vcpu::irq_queue).
+    fn request_interrupt(&self, vector: u8);
+
+    /// Requests an NMI, e.g. for a watchdog or performance counter overflow.
+    ///
+    /// The NMI is injected by the next run unless the guest blocks NMIs, i.e. it's handling an
+    /// NMI or in a `mov ss` shadow. Blocked NMIs are retried when the NMI window opens if
+    /// virtual NMIs are enabled, see [VcpuExt::enable_virtual_nmi], or else when the interrupt
+    /// window opens.
+    fn inject_nmi(&self);
+
+    /// Enables virtual NMIs and NMI exiting if the host supports them, which makes the
+    /// processor track NMI blocking of the guest. Returns `false` if unsupported.
+    fn enable_virtual_nmi(&self) -> Result<bool, Error>;
+
+    /// Arms the VMX preemption timer, the guest exits with [Exit::PreemptionTimer] once it
+    /// ran for `duration`.
+    ///
+    /// The timer counts at the rate reported by the `PreemptionTimer` VMX capability and
+    /// restarts from `duration` on every entry, so it bounds the time of each run rather
+    /// than the total. Durations beyond the 32-bit timer range are clamped. Fails with
+    /// [Error::Unsupported] if the host doesn't support the timer.
+    fn arm_preemption_timer(&self, duration: Duration) -> Result<(), Error>;
+
+    /// Disables the VMX preemption timer.
+    fn disarm_preemption_timer(&self) -> Result<(), Error>;
+
+    /// Enables or disables the monitor trap flag. While it's enabled, the vCPU exits with
+    /// [Exit::MonitorTrap](crate::Exit::MonitorTrap) after every guest instruction, which the run loop passes to
+    /// [ExitHandler::on_step](crate::run::ExitHandler::on_step).
+    ///
+    /// Fails with [Error::Unsupported] if the host doesn't support the monitor trap flag.
+    fn enable_mtf(&self, enable: bool) -> Result<(), Error>;
+
+    /// Enables or disables `rdtsc` exiting. While it's enabled, `rdtsc` and `rdtscp` exit
+    /// with [Exit::Other] and the `RDTSC` or `RDTSCP` reason, e.g. for a
+    /// [Recorder](crate::run::Recorder) to log the values the guest reads.
+    fn enable_rdtsc_exiting(&self, enable: bool) -> Result<(), Error>;
+
+    /// Enables pause-loop exiting: the guest exits with [Exit::Pause] once it spins in a
+    /// `pause` loop for longer than `window`, where executions of `pause` at most `gap` apart
+    /// belong to the same loop. Both are in TSC ticks.
+    ///
+    /// Lets the host deschedule vCPUs spinning on a lock held by a preempted vCPU. Fails with
+    /// [Error::Unsupported] if the host doesn't support pause-loop exiting.
+    fn enable_pause_loop_exiting(&self, gap: u32, window: u32) -> Result<(), Error>;
+
+    /// Disables pause-loop exiting.
+    fn disable_pause_loop_exiting(&self) -> Result<(), Error>;
+
+    /// Swaps the guest values of `msrs` automatically on every entry and exit, using a
+    /// [MsrSwapArea] mapped at `gpa`. The area must be kept alive while the vCPU runs.
+    fn auto_save_msrs(&self, vm: Arc<Vm>, gpa: GPAddr, msrs: &[u32]) -> Result<MsrSwapArea, Error>;
+
+    /// Returns the TSC offset added to the host TSC for the guest.
+    fn tsc_offset(&self) -> Result<i64, Error>;
+
+    /// Sets the TSC offset and enables TSC offsetting, see [GuestTsc] to keep vCPUs
+    /// consistent.
+    fn set_tsc_offset(&self, offset: i64) -> Result<(), Error>;
+
+    /// Sets the TSC multiplier, a fixed point number with 48 fractional bits, and enables TSC
+    /// scaling. Fails with [Error::Unsupported] if the host doesn't support scaling.
+    fn set_tsc_multiplier(&self, multiplier: u64) -> Result<(), Error>;
 }
 
 impl VmExt for Vm {
@@ -186,13 +455,31 @@ impl VmExt for Vm {
     fn sync_tsc(tcs: u64) -> Result<(), Error> {
         call!(sys::hv_vm_sync_tsc(tcs))
     }
+
+    /// Returns the allocator of VPIDs for the vCPUs of the VM.
+    fn vpids(&self) -> &VpidAllocator {
+        &self.vpids
+    }
 }
 
 impl VcpuExt for Vcpu {
     /// Executes a vCPU until the given deadline.
     #[cfg(feature = "hv_10_15")]
-    fn run_until(&self, deadline: u64) -> Result<(), Error> {
-        call!(sys::hv_vcpu_run_until(self.id, deadline))
+    fn run_until(&self, deadline: u64) -> Result<Exit, Error> {
+        self.run_inner(Some(deadline))
+    }
+
+    /// Executes a vCPU for at most `duration`.
+    #[cfg(feature = "hv_10_15")]
+    fn run_for(&self, duration: Duration) -> Result<Exit, Error> {
+        let ticks = crate::time::ticks_from_duration(duration);
+        self.run_until(crate::time::now().saturating_add(ticks))
+    }
+
+    /// Executes a vCPU until `deadline` at the latest.
+    #[cfg(feature = "hv_10_15")]
+    fn run_until_instant(&self, deadline: Instant) -> Result<Exit, Error> {
+        self.run_for(deadline.saturating_duration_since(Instant::now()))
     }
 
     /// Forces flushing of cached vCPU state.
@@ -211,6 +498,57 @@ impl VcpuExt for Vcpu {
         call!(sys::hv_vcpu_set_space(self.id, space.id()))
     }
 
+    /// Moves the vCPU to `space`, invalidating its TLB only if required.
+    #[cfg(feature = "hv_10_15")]
+    fn switch_space(&self, space: &Space) -> Result<(), Error> {
+        self.set_space(space)?;
+        self.sync_tlb(TlbChange::SpaceSwitched)?;
+        Ok(())
+    }
+
+    /// Tags the TLB entries of the vCPU with `vpid` and enables VPIDs, 0 disables them.
+    fn set_vpid(&self, vpid: u16) -> Result<(), Error> {
+        let ctrl = self.read_vmcs(vmx::Vmcs::CTRL_CPU_BASED2)?;
+        if vpid == 0 {
+            return self.write_vmcs(vmx::Vmcs::CTRL_CPU_BASED2, ctrl & !CPU_BASED2_VPID);
+        }
+
+        let allowed = vmx::read_capability(vmx::Capability::ProcBased2)? >> 32;
+        if allowed & CPU_BASED2_VPID == 0 {
+            return Err(Error::Unsupported);
+        }
+
+        let primary = self.read_vmcs(vmx::Vmcs::CTRL_CPU_BASED)?;
+        self.write_vmcs(
+            vmx::Vmcs::CTRL_CPU_BASED,
+            vmx::adjust_controls(vmx::Capability::ProcBased, primary | CPU_BASED_SECONDARY)?,
+        )?;
+        self.write_vmcs(vmx::Vmcs::VPID, u64::from(vpid))?;
+        self.write_vmcs(vmx::Vmcs::CTRL_CPU_BASED2, ctrl | CPU_BASED2_VPID)
+    }
+
+    /// Returns the VPID of the vCPU, `None` if VPIDs are disabled.
+    fn vpid(&self) -> Result<Option<u16>, Error> {
+        if self.read_vmcs(vmx::Vmcs::CTRL_CPU_BASED2)? & CPU_BASED2_VPID == 0 {
+            return Ok(None);
+        }
+        Ok(Some(self.read_vmcs(vmx::Vmcs::VPID)? as u16))
+    }
+
+    /// Reads every VMCS field.
+    fn dump_vmcs(&self) -> Result<VmcsDump, Error> {
+        Ok(VmcsDump::read(self))
+    }
+
+    /// Invalidates the TLB of the vCPU if `change` may have left stale translations in it.
+    fn sync_tlb(&self, change: TlbChange) -> Result<bool, Error> {
+        if !change.needs_invalidation() {
+            return Ok(false);
+        }
+        self.invalidate_tlb()?;
+        Ok(true)
+    }
+
     /// Forces an immediate VMEXIT of the vCPU.
     fn interrupt(&self) -> Result<(), Error> {
         call!(sys::hv_vcpu_interrupt(mem::transmute(&self.id), 1))
@@ -221,6 +559,24 @@ impl VcpuExt for Vcpu {
         call!(sys::hv_vcpu_enable_native_msr(self.id, msr, enable))
     }
 
+    /// Enables or disables management of an MSR by the framework.
+    #[cfg(feature = "hv_12_0")]
+    fn enable_managed_msr(&self, msr: u32, enable: bool) -> Result<(), Error> {
+        call!(sys::hv_vcpu_enable_managed_msr(self.id, msr, enable))
+    }
+
+    /// Sets the guest accesses to a managed MSR that don't exit.
+    #[cfg(feature = "hv_12_0")]
+    fn set_msr_access(&self, msr: u32, access: MsrAccess) -> Result<(), Error> {
+        call!(sys::hv_vcpu_set_msr_access(self.id, msr, access.bits()))
+    }
+
+    /// Applies the policies of several managed MSRs, in order.
+    #[cfg(feature = "hv_12_0")]
+    fn manage_msrs(&self, msrs: &[ManagedMsr]) -> Result<(), Error> {
+        msrs.iter().try_for_each(|msr| msr.apply(self))
+    }
+
     /// Returns the current value of an MSR of a vCPU.
     fn read_msr(&self, msr: u32) -> Result<u64, Error> {
         let mut value = 0_u64;
@@ -235,6 +591,9 @@ impl VcpuExt for Vcpu {
 
     /// Returns the current value of an architectural x86 register of a vCPU.
     fn read_register(&self, reg: Reg) -> Result<u64, Error> {
+        if let Some(value) = self.cached(Field::Reg(reg)) {
+            return Ok(value);
+        }
         let mut value = 0_u64;
         call!(sys::hv_vcpu_read_register(
             self.id,
@@ -246,6 +605,9 @@ impl VcpuExt for Vcpu {
 
     /// Set the value of an architectural x86 register of a vCPU.
     fn write_register(&self, reg: Reg, value: u64) -> Result<(), Error> {
+        if self.cache_write(Field::Reg(reg), value) {
+            return Ok(());
+        }
         call!(sys::hv_vcpu_write_register(
             self.id,
             reg as sys::hv_x86_reg_t,
@@ -253,8 +615,85 @@ impl VcpuExt for Vcpu {
         ))
     }
 
+    /// Returns the values of several architectural x86 registers, in order.
+    fn read_registers(&self, regs: &[Reg]) -> Result<Vec<u64>, Error> {
+        regs.iter().map(|reg| self.read_register(*reg)).collect()
+    }
+
+    /// Sets the values of several architectural x86 registers, in order.
+    fn write_registers(&self, regs: &[(Reg, u64)]) -> Result<(), Error> {
+        regs.iter()
+            .try_for_each(|(reg, value)| self.write_register(*reg, *value))
+    }
+
+    /// Returns the general purpose registers of a vCPU.
+    fn get_regs(&self) -> Result<GeneralRegs, Error> {
+        let v = self.read_registers(&GeneralRegs::REGS)?;
+        Ok(GeneralRegs {
+            rax: v[0],
+            rbx: v[1],
+            rcx: v[2],
+            rdx: v[3],
+            rsi: v[4],
+            rdi: v[5],
+            rsp: v[6],
+            rbp: v[7],
+            r8: v[8],
+            r9: v[9],
+            r10: v[10],
+            r11: v[11],
+            r12: v[12],
+            r13: v[13],
+            r14: v[14],
+            r15: v[15],
+            rip: v[16],
+            rflags: v[17],
+        })
+    }
+
+    /// Sets the general purpose registers of a vCPU.
+    fn set_regs(&self, regs: &GeneralRegs) -> Result<(), Error> {
+        let values = [
+            regs.rax,
+            regs.rbx,
+            regs.rcx,
+            regs.rdx,
+            regs.rsi,
+            regs.rdi,
+            regs.rsp,
+            regs.rbp,
+            regs.r8,
+            regs.r9,
+            regs.r10,
+            regs.r11,
+            regs.r12,
+            regs.r13,
+            regs.r14,
+            regs.r15,
+            regs.rip,
+            regs.rflags,
+        ];
+        let pairs: Vec<(Reg, u64)> = GeneralRegs::REGS
+            .iter()
+            .copied()
+            .zip(values.iter().copied())
+            .collect();
+        self.write_registers(&pairs)
+    }
+
+    /// Returns a segment register, read from the VMCS.
+    fn get_segment(&self, seg: Seg) -> Result<SegmentDescriptor, Error> {
+        segment::get(self, seg)
+    }
+
+    /// Sets a segment register in the VMCS.
+    fn set_segment(&self, seg: Seg, desc: &SegmentDescriptor) -> Result<(), Error> {
+        segment::set(self, seg, desc)
+    }
+
     /// Returns the current architectural x86 floating point and SIMD state of a vCPU.
-    /// Structure and size are defined by the XSAVE feature set of the host processor.
+    /// Structure and size are defined by the XSAVE feature set of the host processor, see
+    /// [Vcpu::fpstate_size].
     fn read_fpstate(&self, buffer: &mut [u8]) -> Result<(), Error> {
         call!(sys::hv_vcpu_read_fpstate(
             self.id,
@@ -271,6 +710,229 @@ impl VcpuExt for Vcpu {
             buffer.len() as u64
         ))
     }
+
+    /// Returns the floating point and SIMD state of a vCPU, decoded from the XSAVE area.
+    fn read_xsave(&self) -> Result<XSaveArea, Error> {
+        XSaveArea::from_bytes(&state::fpstate(self)?)
+    }
+
+    /// Sets the floating point and SIMD state of a vCPU from a decoded XSAVE area.
+    fn write_xsave(&self, area: &XSaveArea) -> Result<(), Error> {
+        self.write_fpstate(&area.to_bytes())
+    }
+
+    /// Sets `XCR0` of a vCPU after validating it.
+    fn set_xcr0(&self, features: XFeatures) -> Result<(), Error> {
+        features.validate()?;
+        self.write_register(Reg::XCR0, features.bits())
+    }
+
+    /// Injects an event at the next entry, replacing any pending injection.
+    fn inject_event(&self, event: Event) -> Result<(), Error> {
+        event::inject(self, event)
+    }
+
+    /// Returns `true` if an event is pending injection at the next entry.
+    fn is_event_pending(&self) -> Result<bool, Error> {
+        event::is_pending(self)
+    }
+
+    /// Returns the activity state of the vCPU.
+    fn activity_state(&self) -> Result<ActivityState, Error> {
+        activity::get(self)
+    }
+
+    /// Sets the activity state of the vCPU.
+    fn set_activity_state(&self, state: ActivityState) -> Result<(), Error> {
+        activity::set(self, state)
+    }
+
+    /// Emulates an INIT signal.
+    fn deliver_init(&self) -> Result<(), Error> {
+        activity::init(self)
+    }
+
+    /// Emulates a startup IPI.
+    fn deliver_sipi(&self, vector: u8) -> Result<bool, Error> {
+        activity::sipi(self, vector)
+    }
+
+    /// Returns the interruptibility state of the vCPU.
+    fn interruptibility(&self) -> Result<Interruptibility, Error> {
+        let raw = self.read_vmcs(vmx::Vmcs::GUEST_IGNORE_IRQ)?;
+        Ok(Interruptibility::from_bits_truncate(raw))
+    }
+
+    /// Sets the interruptibility state of the vCPU.
+    fn set_interruptibility(&self, state: Interruptibility) -> Result<(), Error> {
+        self.write_vmcs(vmx::Vmcs::GUEST_IGNORE_IRQ, state.bits())
+    }
+
+    /// Returns the debug exceptions pending at the next entry.
+    fn pending_debug_exceptions(&self) -> Result<PendingDebugExceptions, Error> {
+        let raw = self.read_vmcs(vmx::Vmcs::GUEST_DEBUG_EXC)?;
+        Ok(PendingDebugExceptions::from_bits_truncate(raw))
+    }
+
+    /// Sets the debug exceptions pending at the next entry.
+    fn set_pending_debug_exceptions(&self, pending: PendingDebugExceptions) -> Result<(), Error> {
+        self.write_vmcs(vmx::Vmcs::GUEST_DEBUG_EXC, pending.bits())
+    }
+
+    /// Requests an external interrupt with `vector`, e.g. from an exit handler.
+    fn request_interrupt(&self, vector: u8) {
+        self.request_irq(crate::Interrupt::Vector(vector));
+    }
+
+    /// Requests an NMI, e.g. for a watchdog or performance counter overflow.
+    fn inject_nmi(&self) {
+        self.request_irq(crate::Interrupt::Nmi);
+    }
+
+    /// Enables virtual NMIs and NMI exiting if the host supports them.
+    fn enable_virtual_nmi(&self) -> Result<bool, Error> {
+        /// NMI exiting, required by virtual NMIs.
+        const NMI_EXITING: u64 = 1 << 3;
+        const VIRTUAL_NMI: u64 = 1 << 5;
+
+        let allowed = vmx::read_capability(vmx::Capability::PinBased)? >> 32;
+        if allowed & (NMI_EXITING | VIRTUAL_NMI) != NMI_EXITING | VIRTUAL_NMI {
+            return Ok(false);
+        }
+
+        let ctrl = self.read_vmcs(vmx::Vmcs::CTRL_PIN_BASED)?;
+        self.write_vmcs(vmx::Vmcs::CTRL_PIN_BASED, ctrl | NMI_EXITING | VIRTUAL_NMI)?;
+        Ok(true)
+    }
+
+    /// Arms the VMX preemption timer.
+    fn arm_preemption_timer(&self, duration: Duration) -> Result<(), Error> {
+        let allowed = vmx::read_capability(vmx::Capability::PinBased)? >> 32;
+        let frequency = vmx::read_capability(vmx::Capability::PreemptionTimer)?;
+        if allowed & PIN_BASED_PREEMPTION_TIMER == 0 || frequency == 0 {
+            return Err(Error::Unsupported);
+        }
+
+        let ticks = duration.as_nanos() * u128::from(frequency) / 1_000_000_000;
+        let value = ticks.min(u128::from(u32::MAX)) as u64;
+        self.write_vmcs(vmx::Vmcs::GUEST_VMX_TIMER_VALUE, value)?;
+
+        let ctrl = self.read_vmcs(vmx::Vmcs::CTRL_PIN_BASED)?;
+        self.write_vmcs(vmx::Vmcs::CTRL_PIN_BASED, ctrl | PIN_BASED_PREEMPTION_TIMER)
+    }
+
+    /// Disables the VMX preemption timer.
+    fn disarm_preemption_timer(&self) -> Result<(), Error> {
+        let ctrl = self.read_vmcs(vmx::Vmcs::CTRL_PIN_BASED)?;
+        self.write_vmcs(
+            vmx::Vmcs::CTRL_PIN_BASED,
+            ctrl & !PIN_BASED_PREEMPTION_TIMER,
+        )
+    }
+
+    /// Enables or disables the monitor trap flag.
+    fn enable_mtf(&self, enable: bool) -> Result<(), Error> {
+        let allowed = vmx::read_capability(vmx::Capability::ProcBased)? >> 32;
+        if allowed & CPU_BASED_MTF == 0 {
+            return Err(Error::Unsupported);
+        }
+
+        let ctrl = self.read_vmcs(vmx::Vmcs::CTRL_CPU_BASED)?;
+        let ctrl = if enable {
+            ctrl | CPU_BASED_MTF
+        } else {
+            ctrl & !CPU_BASED_MTF
+        };
+        self.write_vmcs(vmx::Vmcs::CTRL_CPU_BASED, ctrl)
+    }
+
+    /// Enables or disables `rdtsc` exiting.
+    fn enable_rdtsc_exiting(&self, enable: bool) -> Result<(), Error> {
+        let ctrl = self.read_vmcs(vmx::Vmcs::CTRL_CPU_BASED)?;
+        let ctrl = if enable {
+            ctrl | CPU_BASED_RDTSC
+        } else {
+            ctrl & !CPU_BASED_RDTSC
+        };
+        self.write_vmcs(
+            vmx::Vmcs::CTRL_CPU_BASED,
+            vmx::adjust_controls(vmx::Capability::ProcBased, ctrl)?,
+        )
+    }
+
+    /// Enables pause-loop exiting.
+    fn enable_pause_loop_exiting(&self, gap: u32, window: u32) -> Result<(), Error> {
+        let allowed = vmx::read_capability(vmx::Capability::ProcBased2)? >> 32;
+        if allowed & CPU_BASED2_PAUSE_LOOP == 0 {
+            return Err(Error::Unsupported);
+        }
+
+        self.write_vmcs_many(&[
+            (vmx::Vmcs::CTRL_PLE_GAP, u64::from(gap)),
+            (vmx::Vmcs::CTRL_PLE_WINDOW, u64::from(window)),
+        ])?;
+        let primary = self.read_vmcs(vmx::Vmcs::CTRL_CPU_BASED)?;
+        self.write_vmcs(
+            vmx::Vmcs::CTRL_CPU_BASED,
+            vmx::adjust_controls(vmx::Capability::ProcBased, primary | CPU_BASED_SECONDARY)?,
+        )?;
+        let ctrl = self.read_vmcs(vmx::Vmcs::CTRL_CPU_BASED2)?;
+        self.write_vmcs(vmx::Vmcs::CTRL_CPU_BASED2, ctrl | CPU_BASED2_PAUSE_LOOP)
+    }
+
+    /// Disables pause-loop exiting.
+    fn disable_pause_loop_exiting(&self) -> Result<(), Error> {
+        let ctrl = self.read_vmcs(vmx::Vmcs::CTRL_CPU_BASED2)?;
+        self.write_vmcs(vmx::Vmcs::CTRL_CPU_BASED2, ctrl & !CPU_BASED2_PAUSE_LOOP)
+    }
+
+    /// Swaps the guest values of `msrs` automatically on every entry and exit.
+    fn auto_save_msrs(&self, vm: Arc<Vm>, gpa: GPAddr, msrs: &[u32]) -> Result<MsrSwapArea, Error> {
+        let area = MsrSwapArea::new(vm, gpa, msrs)?;
+        area.attach(self)?;
+        Ok(area)
+    }
+
+    /// Returns the TSC offset added to the host TSC for the guest.
+    fn tsc_offset(&self) -> Result<i64, Error> {
+        Ok(self.read_vmcs(vmx::Vmcs::CTRL_TSC_OFFSET)? as i64)
+    }
+
+    /// Sets the TSC offset and enables TSC offsetting.
+    fn set_tsc_offset(&self, offset: i64) -> Result<(), Error> {
+        /// Use TSC offsetting primary VM-execution control.
+        const CPU_BASED_TSC_OFFSET: u64 = 1 << 3;
+
+        self.write_vmcs(vmx::Vmcs::CTRL_TSC_OFFSET, offset as u64)?;
+        let ctrl = self.read_vmcs(vmx::Vmcs::CTRL_CPU_BASED)?;
+        self.write_vmcs(
+            vmx::Vmcs::CTRL_CPU_BASED,
+            vmx::adjust_controls(vmx::Capability::ProcBased, ctrl | CPU_BASED_TSC_OFFSET)?,
+        )
+    }
+
+    /// Sets the TSC multiplier and enables TSC scaling.
+    fn set_tsc_multiplier(&self, multiplier: u64) -> Result<(), Error> {
+        /// Use TSC scaling secondary VM-execution control.
+        const CPU_BASED2_TSC_SCALING: u64 = 1 << 25;
+
+        let allowed = vmx::read_capability(vmx::Capability::ProcBased2)? >> 32;
+        if allowed & CPU_BASED2_TSC_SCALING == 0 {
+            return Err(Error::Unsupported);
+        }
+
+        self.write_vmcs(vmx::Vmcs::CTRL_TSC_MULTIPLIER, multiplier)?;
+        let primary = self.read_vmcs(vmx::Vmcs::CTRL_CPU_BASED)?;
+        self.write_vmcs(
+            vmx::Vmcs::CTRL_CPU_BASED,
+            vmx::adjust_controls(vmx::Capability::ProcBased, primary | CPU_BASED_SECONDARY)?,
+        )?;
+        let ctrl = self.read_vmcs(vmx::Vmcs::CTRL_CPU_BASED2)?;
+        self.write_vmcs(
+            vmx::Vmcs::CTRL_CPU_BASED2,
+            vmx::adjust_controls(vmx::Capability::ProcBased2, ctrl | CPU_BASED2_TSC_SCALING)?,
+        )
+    }
 }
 
 /// x86 architecture register IDs.
@@ -332,3 +994,50 @@ pub enum Reg {
     XCR0 = sys::hv_x86_reg_t_HV_X86_XCR0,
     MAX = sys::hv_x86_reg_t_HV_X86_REGISTERS_MAX,
 }
+
+/// General purpose registers of a vCPU, laid out like `kvm_regs`.
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub struct GeneralRegs {
+    pub rax: u64,
+    pub rbx: u64,
+    pub rcx: u64,
+    pub rdx: u64,
+    pub rsi: u64,
+    pub rdi: u64,
+    pub rsp: u64,
+    pub rbp: u64,
+    pub r8: u64,
+    pub r9: u64,
+    pub r10: u64,
+    pub r11: u64,
+    pub r12: u64,
+    pub r13: u64,
+    pub r14: u64,
+    pub r15: u64,
+    pub rip: u64,
+    pub rflags: u64,
+}
+
+impl GeneralRegs {
+    /// Registers in field order.
+    pub(crate) const REGS: [Reg; 18] = [
+        Reg::RAX,
+        Reg::RBX,
+        Reg::RCX,
+        Reg::RDX,
+        Reg::RSI,
+        Reg::RDI,
+        Reg::RSP,
+        Reg::RBP,
+        Reg::R8,
+        Reg::R9,
+        Reg::R10,
+        Reg::R11,
+        Reg::R12,
+        Reg::R13,
+        Reg::R14,
+        Reg::R15,
+        Reg::RIP,
+        Reg::RFLAGS,
+    ];
+}
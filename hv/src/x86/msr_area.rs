@@ -0,0 +1,103 @@
+use std::sync::Arc;
+
+use super::vmx::{VCpuVmxExt, Vmcs};
+use crate::memory::{HostMemory, Mapping};
+use crate::{Error, GPAddr, Memory, Vcpu, Vm};
+
+/// Size of the area, one page.
+const AREA_SIZE: u64 = 0x1000;
+
+/// Size of an entry: the MSR index, 4 reserved bytes and the value.
+const ENTRY_SIZE: u64 = 16;
+
+/// Guest MSRs swapped automatically on VM entry and exit.
+///
+/// The area is used as the VM-exit MSR-store and the VM-entry MSR-load area of a vCPU, so the
+/// processor saves the guest values of the MSRs on every exit and restores them on the next
+/// entry, e.g. for `STAR`, `LSTAR` and `KERNEL_GS_BASE` which the framework doesn't switch.
+/// Host values are restored by the framework.
+///
+/// The page is mapped into the guest physical address space and owned by this type, it's
+/// unmapped when dropped, so it must outlive the vCPU it's attached to.
+///
+/// ```ignore
+/// let msrs = cpu.auto_save_msrs(vm.clone(), 0xa000, &[IA32_STAR, IA32_LSTAR])?;
+/// msrs.set_guest_value(IA32_LSTAR, syscall_entry)?;
+/// ```
+#[derive(Debug)]
+pub struct MsrSwapArea {
+    mapping: Mapping,
+    msrs: Vec<u32>,
+}
+
+impl MsrSwapArea {
+    /// Allocates an area for `msrs` at `gpa`, which must be page aligned. Guest values start
+    /// at 0. Fails with [Error::BadArgument] if the MSRs don't fit in a page (256 entries).
+    pub fn new(vm: Arc<Vm>, gpa: GPAddr, msrs: &[u32]) -> Result<MsrSwapArea, Error> {
+        if msrs.len() as u64 * ENTRY_SIZE > AREA_SIZE {
+            return Err(Error::BadArgument);
+        }
+
+        let mem = HostMemory::new(AREA_SIZE)?;
+        for (i, msr) in msrs.iter().enumerate() {
+            mem.write(i as u64 * ENTRY_SIZE, &msr.to_le_bytes())?;
+        }
+        let mapping = Mapping::new(vm, mem, gpa, Memory::READ | Memory::WRITE)?;
+
+        Ok(MsrSwapArea {
+            mapping,
+            msrs: msrs.to_vec(),
+        })
+    }
+
+    /// Returns the guest physical address of the area.
+    #[inline]
+    pub fn gpa(&self) -> GPAddr {
+        self.mapping.gpa()
+    }
+
+    /// Returns the swapped MSRs.
+    pub fn msrs(&self) -> &[u32] {
+        &self.msrs
+    }
+
+    /// Uses the area as the exit MSR-store and entry MSR-load area of `vcpu`.
+    pub fn attach(&self, vcpu: &Vcpu) -> Result<(), Error> {
+        let count = self.msrs.len() as u64;
+        vcpu.write_vmcs(Vmcs::CTRL_VMEXIT_MSR_STORE_ADDR, self.gpa())?;
+        vcpu.write_vmcs(Vmcs::CTRL_VMEXIT_MSR_STORE_COUNT, count)?;
+        vcpu.write_vmcs(Vmcs::CTRL_VMENTRY_MSR_LOAD_ADDR, self.gpa())?;
+        vcpu.write_vmcs(Vmcs::CTRL_VMENTRY_MSR_LOAD_COUNT, count)
+    }
+
+    /// Stops swapping MSRs of `vcpu`.
+    pub fn detach(&self, vcpu: &Vcpu) -> Result<(), Error> {
+        vcpu.write_vmcs(Vmcs::CTRL_VMEXIT_MSR_STORE_COUNT, 0)?;
+        vcpu.write_vmcs(Vmcs::CTRL_VMENTRY_MSR_LOAD_COUNT, 0)
+    }
+
+    /// Returns the guest value of `msr` saved on the last exit, `None` if it isn't swapped.
+    pub fn guest_value(&self, msr: u32) -> Result<Option<u64>, Error> {
+        let offset = match self.value_offset(msr) {
+            Some(offset) => offset,
+            None => return Ok(None),
+        };
+        let mut buf = [0; 8];
+        self.mapping.memory().read(offset, &mut buf)?;
+        Ok(Some(u64::from_le_bytes(buf)))
+    }
+
+    /// Sets the guest value of `msr` loaded on the next entry, fails with
+    /// [Error::BadArgument] if it isn't swapped.
+    pub fn set_guest_value(&self, msr: u32, value: u64) -> Result<(), Error> {
+        let offset = self.value_offset(msr).ok_or(Error::BadArgument)?;
+        self.mapping.memory().write(offset, &value.to_le_bytes())
+    }
+
+    fn value_offset(&self, msr: u32) -> Option<u64> {
+        self.msrs
+            .iter()
+            .position(|&m| m == msr)
+            .map(|i| i as u64 * ENTRY_SIZE + 8)
+    }
+}
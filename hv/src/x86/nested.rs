@@ -0,0 +1,260 @@
+use std::collections::HashMap;
+
+use super::qualification::GPRS;
+use super::vmx::{self, Capability, Reason, ShadowFlags, VCpuVmxExt, Vmcs};
+use super::{Reg, VcpuExt};
+use crate::{Error, GPAddr, Vcpu};
+
+/// Activate secondary controls primary VM-execution control.
+const CPU_BASED_SECONDARY: u64 = 1 << 31;
+
+/// VMCS shadowing secondary VM-execution control.
+const CPU_BASED2_VMCS_SHADOWING: u64 = 1 << 14;
+
+/// Arithmetic flags of `RFLAGS` reporting the result of VMX instructions: CF, PF, AF, ZF, SF
+/// and OF.
+const RFLAGS_VMX_RESULT: u64 = 0x8d5;
+const RFLAGS_CF: u64 = 1 << 0;
+const RFLAGS_ZF: u64 = 1 << 6;
+
+/// VM-instruction error numbers.
+const ERROR_UNSUPPORTED_FIELD: u64 = 12;
+const ERROR_READ_ONLY_FIELD: u64 = 13;
+
+/// The VMCS regions of an L1 guest hypervisor, backed by the shadow VMCS of its vCPU.
+///
+/// The guest loads a VMCS with `vmptrld`, the embedder decodes the address and calls
+/// [NestedVmcs::load], which makes the region current and copies its fields into the shadow
+/// VMCS. The guest then accesses fields allowed with [NestedVmcs::allow] directly with
+/// `vmread` and `vmwrite`, accesses to other fields cause
+/// [Reason::VMREAD] and [Reason::VMWRITE] exits that [NestedVmcs::emulate] completes. Field
+/// values are mirrored by this type, the layout of the guest memory region isn't used.
+///
+/// Requires a host with VMCS shadowing, see [NestedVmcs::enable].
+///
+/// ```ignore
+/// let mut nested = NestedVmcs::new();
+/// nested.enable(&cpu)?;
+/// nested.allow(&cpu, Vmcs::GUEST_RIP, ShadowFlags::READ | ShadowFlags::WRITE)?;
+/// nested.allow(&cpu, Vmcs::RO_EXIT_REASON, ShadowFlags::READ)?;
+///
+/// match reason {
+///     Reason::VMPTRLD => nested.load(&cpu, vmcs_gpa)?,
+///     Reason::VMREAD | Reason::VMWRITE => nested.emulate(&cpu, reason)?,
+///     _ => {}
+/// }
+/// ```
+#[derive(Debug, Default)]
+pub struct NestedVmcs {
+    /// Fields the guest may access, by encoding.
+    fields: HashMap<u32, (Vmcs, ShadowFlags)>,
+    /// Field values of the regions, by guest physical address.
+    regions: HashMap<GPAddr, HashMap<u32, u64>>,
+    current: Option<GPAddr>,
+}
+
+impl NestedVmcs {
+    pub fn new() -> NestedVmcs {
+        NestedVmcs::default()
+    }
+
+    /// Enables VMCS shadowing for `vcpu`, fails with [Error::Unsupported] if the host doesn't
+    /// support it.
+    pub fn enable(&self, vcpu: &Vcpu) -> Result<(), Error> {
+        let allowed = vmx::read_capability(Capability::ProcBased2)? >> 32;
+        if allowed & CPU_BASED2_VMCS_SHADOWING == 0 {
+            return Err(Error::Unsupported);
+        }
+
+        let ctrl = vcpu.read_vmcs(Vmcs::CTRL_CPU_BASED)?;
+        vcpu.write_vmcs(
+            Vmcs::CTRL_CPU_BASED,
+            vmx::adjust_controls(Capability::ProcBased, ctrl | CPU_BASED_SECONDARY)?,
+        )?;
+        let ctrl = vcpu.read_vmcs(Vmcs::CTRL_CPU_BASED2)?;
+        vcpu.write_vmcs(Vmcs::CTRL_CPU_BASED2, ctrl | CPU_BASED2_VMCS_SHADOWING)
+    }
+
+    /// Lets the guest access `field` without exits as allowed by `flags`. Accesses that aren't
+    /// allowed exit and fail in [NestedVmcs::emulate], [ShadowFlags::NONE] revokes access.
+    pub fn allow(&mut self, vcpu: &Vcpu, field: Vmcs, flags: ShadowFlags) -> Result<(), Error> {
+        if self.current.is_some() {
+            // Keep the value of the current region when the field becomes (un)shadowed.
+            let value = self.read(vcpu, field)?;
+            self.fields.insert(field as u32, (field, flags));
+            self.write(vcpu, field, value)?;
+        } else {
+            self.fields.insert(field as u32, (field, flags));
+        }
+        vcpu.set_shadow_access(field, flags)
+    }
+
+    /// Returns the access the guest has to `field`.
+    pub fn access(&self, field: Vmcs) -> ShadowFlags {
+        self.fields
+            .get(&(field as u32))
+            .map_or(ShadowFlags::NONE, |(_, flags)| *flags)
+    }
+
+    /// Returns the address of the current VMCS region.
+    pub fn current(&self) -> Option<GPAddr> {
+        self.current
+    }
+
+    /// Makes the region at `gpa` current, as `vmptrld`. Fields of new regions are 0.
+    pub fn load(&mut self, vcpu: &Vcpu, gpa: GPAddr) -> Result<(), Error> {
+        self.save(vcpu)?;
+        let values = self.regions.entry(gpa).or_default();
+        for (encoding, (field, flags)) in self.fields.iter() {
+            if !flags.is_empty() {
+                let value = values.get(encoding).copied().unwrap_or(0);
+                vcpu.write_shadow_vmcs(*field, value)?;
+            }
+        }
+        self.current = Some(gpa);
+        Ok(())
+    }
+
+    /// Clears the region at `gpa`, as `vmclear`, which is no longer current afterwards.
+    pub fn clear(&mut self, vcpu: &Vcpu, gpa: GPAddr) -> Result<(), Error> {
+        if self.current == Some(gpa) {
+            self.save(vcpu)?;
+            self.current = None;
+        }
+        Ok(())
+    }
+
+    /// Returns the value of `field` in the current region, fails with [Error::BadArgument]
+    /// without a current region.
+    pub fn read(&self, vcpu: &Vcpu, field: Vmcs) -> Result<u64, Error> {
+        let current = self.current.ok_or(Error::BadArgument)?;
+        if self.is_shadowed(field as u32) {
+            return vcpu.read_shadow_vmcs(field);
+        }
+        Ok(self.regions[&current]
+            .get(&(field as u32))
+            .copied()
+            .unwrap_or(0))
+    }
+
+    /// Sets the value of `field` in the current region, fails with [Error::BadArgument]
+    /// without a current region.
+    pub fn write(&mut self, vcpu: &Vcpu, field: Vmcs, value: u64) -> Result<(), Error> {
+        self.write_raw(vcpu, field as u32, value)
+    }
+
+    /// Emulates a `vmread` or `vmwrite` exit of the guest with a register operand and moves
+    /// `RIP` past the instruction.
+    ///
+    /// Accesses that aren't allowed fail in the guest (VMfailValid), as do accesses without a
+    /// current region (VMfailInvalid). Fails with [Error::Unsupported] for memory operands and
+    /// [Error::BadArgument] for other exits.
+    pub fn emulate(&mut self, vcpu: &Vcpu, reason: Reason) -> Result<(), Error> {
+        let info = vcpu.read_vmcs(Vmcs::RO_VMX_INSTR_INFO)?;
+        // Bit 10 is set for register operands, Reg1 is in bits 6:3 and Reg2 in bits 31:28.
+        if info & (1 << 10) == 0 {
+            return Err(Error::Unsupported);
+        }
+        let operand = GPRS[((info >> 3) & 0xf) as usize];
+        let encoding = vcpu.read_register(GPRS[((info >> 28) & 0xf) as usize])? as u32;
+
+        let result = match reason {
+            Reason::VMREAD => self.emulate_read(vcpu, encoding, operand)?,
+            Reason::VMWRITE => {
+                let value = vcpu.read_register(operand)?;
+                self.emulate_write(vcpu, encoding, value)?
+            }
+            _ => return Err(Error::BadArgument),
+        };
+
+        let rflags = vcpu.read_register(Reg::RFLAGS)? & !RFLAGS_VMX_RESULT;
+        let rflags = match result {
+            Ok(()) => rflags,
+            Err(None) => rflags | RFLAGS_CF,
+            Err(Some(error)) => {
+                self.write_raw(vcpu, Vmcs::RO_INSTR_ERROR as u32, error)?;
+                rflags | RFLAGS_ZF
+            }
+        };
+        vcpu.write_register(Reg::RFLAGS, rflags)?;
+
+        let len = vcpu.read_vmcs(Vmcs::RO_VMEXIT_INSTR_LEN)?;
+        let rip = vcpu.read_register(Reg::RIP)?;
+        vcpu.write_register(Reg::RIP, rip + len)
+    }
+
+    /// Returns the VMX instruction result: `Err(None)` for VMfailInvalid and `Err(Some(n))`
+    /// for VMfailValid with error number `n`.
+    fn emulate_read(
+        &self,
+        vcpu: &Vcpu,
+        encoding: u32,
+        operand: Reg,
+    ) -> Result<Result<(), Option<u64>>, Error> {
+        if self.current.is_none() {
+            return Ok(Err(None));
+        }
+        let field = match self.fields.get(&encoding) {
+            Some((field, flags)) if flags.contains(ShadowFlags::READ) => *field,
+            _ => return Ok(Err(Some(ERROR_UNSUPPORTED_FIELD))),
+        };
+        vcpu.write_register(operand, self.read(vcpu, field)?)?;
+        Ok(Ok(()))
+    }
+
+    fn emulate_write(
+        &mut self,
+        vcpu: &Vcpu,
+        encoding: u32,
+        value: u64,
+    ) -> Result<Result<(), Option<u64>>, Error> {
+        if self.current.is_none() {
+            return Ok(Err(None));
+        }
+        let field = match self.fields.get(&encoding) {
+            Some((field, flags)) if flags.contains(ShadowFlags::WRITE) => *field,
+            Some(_) => return Ok(Err(Some(ERROR_READ_ONLY_FIELD))),
+            None => return Ok(Err(Some(ERROR_UNSUPPORTED_FIELD))),
+        };
+        self.write(vcpu, field, value)?;
+        Ok(Ok(()))
+    }
+
+    fn is_shadowed(&self, encoding: u32) -> bool {
+        self.fields
+            .get(&encoding)
+            .map_or(false, |(_, flags)| !flags.is_empty())
+    }
+
+    /// Sets a field of the current region in the mirror, and in the shadow VMCS if it's
+    /// shadowed.
+    fn write_raw(&mut self, vcpu: &Vcpu, encoding: u32, value: u64) -> Result<(), Error> {
+        let current = self.current.ok_or(Error::BadArgument)?;
+        match self.fields.get(&encoding) {
+            Some((field, flags)) if !flags.is_empty() => {
+                return vcpu.write_shadow_vmcs(*field, value);
+            }
+            _ => {}
+        }
+        self.regions
+            .entry(current)
+            .or_default()
+            .insert(encoding, value);
+        Ok(())
+    }
+
+    /// Copies the shadowed fields of the current region from the shadow VMCS to the mirror.
+    fn save(&mut self, vcpu: &Vcpu) -> Result<(), Error> {
+        let current = match self.current {
+            Some(current) => current,
+            None => return Ok(()),
+        };
+        let values = self.regions.entry(current).or_default();
+        for (encoding, (field, flags)) in self.fields.iter() {
+            if !flags.is_empty() {
+                values.insert(*encoding, vcpu.read_shadow_vmcs(*field)?);
+            }
+        }
+        Ok(())
+    }
+}
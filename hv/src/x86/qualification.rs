@@ -0,0 +1,259 @@
+//! Decoders of the exit qualification (`RO_EXIT_QUALIFIC`) of VMX exits.
+//!
+//! Bit layouts follow the Intel SDM, Vol. 3C, section 27.2.1.
+
+use super::Reg;
+
+/// General purpose registers in the order of their encoding in exit qualifications.
+pub(super) const GPRS: [Reg; 16] = [
+    Reg::RAX,
+    Reg::RCX,
+    Reg::RDX,
+    Reg::RBX,
+    Reg::RSP,
+    Reg::RBP,
+    Reg::RSI,
+    Reg::RDI,
+    Reg::R8,
+    Reg::R9,
+    Reg::R10,
+    Reg::R11,
+    Reg::R12,
+    Reg::R13,
+    Reg::R14,
+    Reg::R15,
+];
+
+fn gpr(q: u64) -> Reg {
+    GPRS[((q >> 8) & 0xf) as usize]
+}
+
+/// Kinds of control register accesses.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum CrAccessType {
+    /// `mov` to a control register.
+    MovTo,
+    /// `mov` from a control register.
+    MovFrom,
+    Clts,
+    Lmsw,
+}
+
+/// Qualification of [Reason::MOV_CR](super::vmx::Reason::MOV_CR) exits.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct CrAccess {
+    /// The control register number.
+    pub cr: u8,
+    pub access: CrAccessType,
+    /// The source or destination of `mov`.
+    pub reg: Reg,
+    /// `true` if the operand of `lmsw` is in memory.
+    pub lmsw_memory: bool,
+    /// The source data of `lmsw`.
+    pub lmsw_data: u16,
+}
+
+impl From<u64> for CrAccess {
+    fn from(q: u64) -> Self {
+        let access = match (q >> 4) & 0x3 {
+            0 => CrAccessType::MovTo,
+            1 => CrAccessType::MovFrom,
+            2 => CrAccessType::Clts,
+            _ => CrAccessType::Lmsw,
+        };
+        CrAccess {
+            cr: (q & 0xf) as u8,
+            access,
+            reg: gpr(q),
+            lmsw_memory: q & (1 << 6) != 0,
+            lmsw_data: (q >> 16) as u16,
+        }
+    }
+}
+
+/// Qualification of [Reason::MOV_DR](super::vmx::Reason::MOV_DR) exits.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct DrAccess {
+    /// The debug register number.
+    pub dr: u8,
+    /// `true` for `mov` from the debug register.
+    pub read: bool,
+    pub reg: Reg,
+}
+
+impl From<u64> for DrAccess {
+    fn from(q: u64) -> Self {
+        DrAccess {
+            dr: (q & 0x7) as u8,
+            read: q & (1 << 4) != 0,
+            reg: gpr(q),
+        }
+    }
+}
+
+/// Sources of task switches.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum TaskSwitchSource {
+    Call,
+    Iret,
+    Jmp,
+    /// A task gate in the IDT.
+    TaskGate,
+}
+
+/// Qualification of [Reason::TASK](super::vmx::Reason::TASK) exits.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct TaskSwitch {
+    /// Selector of the new task's TSS.
+    pub selector: u16,
+    pub source: TaskSwitchSource,
+}
+
+impl From<u64> for TaskSwitch {
+    fn from(q: u64) -> Self {
+        let source = match (q >> 30) & 0x3 {
+            0 => TaskSwitchSource::Call,
+            1 => TaskSwitchSource::Iret,
+            2 => TaskSwitchSource::Jmp,
+            _ => TaskSwitchSource::TaskGate,
+        };
+        TaskSwitch {
+            selector: q as u16,
+            source,
+        }
+    }
+}
+
+/// Kinds of APIC accesses.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum ApicAccessType {
+    LinearRead,
+    LinearWrite,
+    LinearFetch,
+    /// Linear access during event delivery.
+    LinearEvent,
+    /// Guest physical access during event delivery.
+    PhysicalEvent,
+    /// Guest physical access for an instruction fetch or during instruction execution.
+    PhysicalFetch,
+    Other(u8),
+}
+
+/// Qualification of [Reason::APIC_ACCESS](super::vmx::Reason::APIC_ACCESS) exits.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct ApicAccess {
+    /// Offset of the access into the APIC page, for linear accesses.
+    pub offset: u16,
+    pub access: ApicAccessType,
+}
+
+impl From<u64> for ApicAccess {
+    fn from(q: u64) -> Self {
+        let access = match (q >> 12) & 0xf {
+            0 => ApicAccessType::LinearRead,
+            1 => ApicAccessType::LinearWrite,
+            2 => ApicAccessType::LinearFetch,
+            3 => ApicAccessType::LinearEvent,
+            10 => ApicAccessType::PhysicalEvent,
+            15 => ApicAccessType::PhysicalFetch,
+            other => ApicAccessType::Other(other as u8),
+        };
+        ApicAccess {
+            offset: (q & 0xfff) as u16,
+            access,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cr_access() {
+        let mov = |cr, access, reg| CrAccess {
+            cr,
+            access,
+            reg,
+            lmsw_memory: false,
+            lmsw_data: 0,
+        };
+        let cases = [
+            // mov cr3, rbx
+            (0x303, mov(3, CrAccessType::MovTo, Reg::RBX)),
+            // mov cr4, r15
+            (0xf04, mov(4, CrAccessType::MovTo, Reg::R15)),
+            // mov rax, cr8
+            (0x18, mov(8, CrAccessType::MovFrom, Reg::RAX)),
+            // clts
+            (0x20, mov(0, CrAccessType::Clts, Reg::RAX)),
+            // lmsw [mem] storing 0x1234
+            (
+                0x1234_0070,
+                CrAccess {
+                    lmsw_memory: true,
+                    lmsw_data: 0x1234,
+                    ..mov(0, CrAccessType::Lmsw, Reg::RAX)
+                },
+            ),
+        ];
+        for (q, expected) in cases.iter() {
+            assert_eq!(CrAccess::from(*q), *expected, "{:#x}", q);
+        }
+    }
+
+    #[test]
+    fn dr_access() {
+        let cases = [
+            // mov rdx, dr7
+            (0x217, 7, true, Reg::RDX),
+            // mov dr0, r9
+            (0x900, 0, false, Reg::R9),
+        ];
+        for (q, dr, read, reg) in cases.iter() {
+            let expected = DrAccess {
+                dr: *dr,
+                read: *read,
+                reg: *reg,
+            };
+            assert_eq!(DrAccess::from(*q), expected, "{:#x}", q);
+        }
+    }
+
+    #[test]
+    fn task_switch() {
+        let cases = [
+            (0x0028, 0x28, TaskSwitchSource::Call),
+            (0x4000_0030, 0x30, TaskSwitchSource::Iret),
+            (0x8000_0040, 0x40, TaskSwitchSource::Jmp),
+            (0xc000_0050, 0x50, TaskSwitchSource::TaskGate),
+        ];
+        for (q, selector, source) in cases.iter() {
+            let expected = TaskSwitch {
+                selector: *selector,
+                source: *source,
+            };
+            assert_eq!(TaskSwitch::from(*q), expected, "{:#x}", q);
+        }
+    }
+
+    #[test]
+    fn apic_access() {
+        let cases = [
+            (0x0080, 0x80, ApicAccessType::LinearRead),
+            (0x10b0, 0xb0, ApicAccessType::LinearWrite),
+            (0x2000, 0, ApicAccessType::LinearFetch),
+            (0x3300, 0x300, ApicAccessType::LinearEvent),
+            (0x4000, 0, ApicAccessType::Other(4)),
+            (0xa000, 0, ApicAccessType::PhysicalEvent),
+            (0xf000, 0, ApicAccessType::PhysicalFetch),
+        ];
+        for (q, offset, access) in cases.iter() {
+            let expected = ApicAccess {
+                offset: *offset,
+                access: *access,
+            };
+            assert_eq!(ApicAccess::from(*q), expected, "{:#x}", q);
+        }
+    }
+}
@@ -0,0 +1,199 @@
+use super::vmx::{VCpuVmxExt, Vmcs};
+use crate::{Error, Vcpu};
+
+/// Unrestricted guest secondary VM-execution control.
+const CPU_BASED2_UNRESTRICTED: u64 = 1 << 7;
+
+/// Segment registers.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Seg {
+    Cs,
+    Ss,
+    Ds,
+    Es,
+    Fs,
+    Gs,
+    Tr,
+    Ldtr,
+}
+
+impl Seg {
+    /// Returns the selector, base, limit and access rights VMCS fields.
+    fn fields(self) -> (Vmcs, Vmcs, Vmcs, Vmcs) {
+        match self {
+            Seg::Cs => (
+                Vmcs::GUEST_CS,
+                Vmcs::GUEST_CS_BASE,
+                Vmcs::GUEST_CS_LIMIT,
+                Vmcs::GUEST_CS_AR,
+            ),
+            Seg::Ss => (
+                Vmcs::GUEST_SS,
+                Vmcs::GUEST_SS_BASE,
+                Vmcs::GUEST_SS_LIMIT,
+                Vmcs::GUEST_SS_AR,
+            ),
+            Seg::Ds => (
+                Vmcs::GUEST_DS,
+                Vmcs::GUEST_DS_BASE,
+                Vmcs::GUEST_DS_LIMIT,
+                Vmcs::GUEST_DS_AR,
+            ),
+            Seg::Es => (
+                Vmcs::GUEST_ES,
+                Vmcs::GUEST_ES_BASE,
+                Vmcs::GUEST_ES_LIMIT,
+                Vmcs::GUEST_ES_AR,
+            ),
+            Seg::Fs => (
+                Vmcs::GUEST_FS,
+                Vmcs::GUEST_FS_BASE,
+                Vmcs::GUEST_FS_LIMIT,
+                Vmcs::GUEST_FS_AR,
+            ),
+            Seg::Gs => (
+                Vmcs::GUEST_GS,
+                Vmcs::GUEST_GS_BASE,
+                Vmcs::GUEST_GS_LIMIT,
+                Vmcs::GUEST_GS_AR,
+            ),
+            Seg::Tr => (
+                Vmcs::GUEST_TR,
+                Vmcs::GUEST_TR_BASE,
+                Vmcs::GUEST_TR_LIMIT,
+                Vmcs::GUEST_TR_AR,
+            ),
+            Seg::Ldtr => (
+                Vmcs::GUEST_LDTR,
+                Vmcs::GUEST_LDTR_BASE,
+                Vmcs::GUEST_LDTR_LIMIT,
+                Vmcs::GUEST_LDTR_AR,
+            ),
+        }
+    }
+}
+
+/// A segment register, as held in the guest-state area of the VMCS.
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub struct SegmentDescriptor {
+    pub selector: u16,
+    pub base: u64,
+    pub limit: u32,
+    /// Access rights in the VMCS format: type, S, DPL and P in bits 0 to 7, AVL, L, D/B and G
+    /// in bits 12 to 15 and the unusable bit 16.
+    pub access_rights: u32,
+}
+
+impl SegmentDescriptor {
+    /// Access rights bits.
+    pub const AR_ACCESSED: u32 = 1 << 0;
+    pub const AR_S: u32 = 1 << 4;
+    pub const AR_P: u32 = 1 << 7;
+    pub const AR_L: u32 = 1 << 13;
+    pub const AR_DB: u32 = 1 << 14;
+    pub const AR_G: u32 = 1 << 15;
+    pub const AR_UNUSABLE: u32 = 1 << 16;
+
+    /// Bits of the access rights that must be 0.
+    const AR_RESERVED: u32 = 0xf00 | !0x1_ffff;
+
+    /// Returns the segment type, bits 0 to 3 of the access rights.
+    pub fn segment_type(&self) -> u8 {
+        (self.access_rights & 0xf) as u8
+    }
+
+    /// Returns the descriptor privilege level.
+    pub fn dpl(&self) -> u8 {
+        ((self.access_rights >> 5) & 0x3) as u8
+    }
+
+    /// Returns `true` if the segment is marked unusable, i.e. loaded with a null selector.
+    pub fn is_unusable(&self) -> bool {
+        self.access_rights & Self::AR_UNUSABLE != 0
+    }
+
+    /// Checks the access rights of a usable segment loaded into `seg`: reserved bits, the
+    /// present and descriptor type bits, the segment type and, unless `unrestricted` (the
+    /// unrestricted guest control is set), the DPL against the selector's RPL.
+    ///
+    /// Only checks of a single segment are done, the VM entry checks across segments, e.g.
+    /// `SS.DPL` matching `CS.DPL`, aren't.
+    pub fn validate(&self, seg: Seg, unrestricted: bool) -> Result<(), Error> {
+        if self.access_rights & Self::AR_RESERVED != 0 {
+            return Err(Error::BadArgument);
+        }
+        // TR can't be unusable, the other segments are otherwise unchecked.
+        if self.is_unusable() {
+            return if seg == Seg::Tr {
+                Err(Error::BadArgument)
+            } else {
+                Ok(())
+            };
+        }
+        if self.access_rights & Self::AR_P == 0 {
+            return Err(Error::BadArgument);
+        }
+
+        let system = matches!(seg, Seg::Tr | Seg::Ldtr);
+        if (self.access_rights & Self::AR_S == 0) != system {
+            return Err(Error::BadArgument);
+        }
+
+        let ty = self.segment_type();
+        let code = ty & 0x8 != 0;
+        let rpl = (self.selector & 0x3) as u8;
+        let valid = match seg {
+            // Accessed code, or accessed read/write data in real mode.
+            Seg::Cs => ty & 0x1 != 0 && (code || (unrestricted && ty == 3)),
+            // Accessed read/write data.
+            Seg::Ss => ty == 3 || ty == 7,
+            // Accessed data or readable code.
+            Seg::Ds | Seg::Es | Seg::Fs | Seg::Gs => ty & 0x1 != 0 && (!code || ty & 0x2 != 0),
+            // Busy 16-bit or 32/64-bit TSS.
+            Seg::Tr => ty == 3 || ty == 11,
+            Seg::Ldtr => ty == 2,
+        };
+        if !valid {
+            return Err(Error::BadArgument);
+        }
+
+        if !unrestricted {
+            let privilege_ok = match seg {
+                Seg::Ss => self.dpl() == rpl,
+                // Data and non-conforming code segments.
+                Seg::Ds | Seg::Es | Seg::Fs | Seg::Gs if ty < 12 => self.dpl() >= rpl,
+                _ => true,
+            };
+            if !privilege_ok {
+                return Err(Error::BadArgument);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Reads `seg` from the VMCS.
+pub(crate) fn get(vcpu: &Vcpu, seg: Seg) -> Result<SegmentDescriptor, Error> {
+    let (selector, base, limit, ar) = seg.fields();
+    let v = vcpu.read_vmcs_many(&[selector, base, limit, ar])?;
+    Ok(SegmentDescriptor {
+        selector: v[0] as u16,
+        base: v[1],
+        limit: v[2] as u32,
+        access_rights: v[3] as u32,
+    })
+}
+
+/// Validates `desc` and writes it to `seg`.
+pub(crate) fn set(vcpu: &Vcpu, seg: Seg, desc: &SegmentDescriptor) -> Result<(), Error> {
+    let unrestricted = vcpu.read_vmcs(Vmcs::CTRL_CPU_BASED2)? & CPU_BASED2_UNRESTRICTED != 0;
+    desc.validate(seg, unrestricted)?;
+
+    let (selector, base, limit, ar) = seg.fields();
+    vcpu.write_vmcs_many(&[
+        (selector, desc.selector as u64),
+        (base, desc.base),
+        (limit, desc.limit as u64),
+        (ar, desc.access_rights as u64),
+    ])
+}
@@ -0,0 +1,117 @@
+use std::sync::Arc;
+
+use crate::memory::HostMemory;
+use crate::{Error, GPAddr, Memory, Size, Vm};
+
+use super::Space;
+
+/// Host memory that can be mapped into several guest address spaces at once
+/// (e.g. to model SMM or secure world memory visible from multiple contexts).
+///
+/// The host memory is reference counted: it's released once the region and all of its
+/// mappings are dropped.
+#[derive(Debug, Clone)]
+pub struct SharedRegion {
+    mem: Arc<HostMemory>,
+}
+
+impl SharedRegion {
+    /// Allocates a new shared region of `size` bytes.
+    pub fn new(size: Size) -> Result<SharedRegion, Error> {
+        Ok(SharedRegion::from(HostMemory::new(size)?))
+    }
+
+    /// Returns the host memory backing the region.
+    #[inline]
+    pub fn memory(&self) -> &HostMemory {
+        &self.mem
+    }
+
+    /// Maps the region into an additional guest address space.
+    pub fn map_into<'a>(
+        &self,
+        space: &'a Space,
+        gpa: GPAddr,
+        flags: Memory,
+    ) -> Result<SharedMapping<'a>, Error> {
+        space.map(self.mem.as_ptr(), gpa, self.mem.size(), flags)?;
+        Ok(SharedMapping {
+            target: Target::Space(space),
+            mem: Arc::clone(&self.mem),
+            gpa,
+        })
+    }
+
+    /// Maps the region into the default guest address space of the VM.
+    pub fn map_into_default<'a>(
+        &self,
+        vm: &'a Vm,
+        gpa: GPAddr,
+        flags: Memory,
+    ) -> Result<SharedMapping<'a>, Error> {
+        vm.map(self.mem.as_ptr(), gpa, self.mem.size(), flags)?;
+        Ok(SharedMapping {
+            target: Target::Default(vm),
+            mem: Arc::clone(&self.mem),
+            gpa,
+        })
+    }
+
+    /// Returns the number of live mappings of the region.
+    pub fn mappings(&self) -> usize {
+        Arc::strong_count(&self.mem) - 1
+    }
+}
+
+impl From<HostMemory> for SharedRegion {
+    fn from(mem: HostMemory) -> Self {
+        SharedRegion { mem: Arc::new(mem) }
+    }
+}
+
+#[derive(Debug)]
+enum Target<'a> {
+    Default(&'a Vm),
+    Space(&'a Space),
+}
+
+/// A mapping of a [SharedRegion] into a guest address space.
+///
+/// Unmaps the region from the address space when dropped, the address space must outlive
+/// the mapping.
+#[derive(Debug)]
+pub struct SharedMapping<'a> {
+    target: Target<'a>,
+    mem: Arc<HostMemory>,
+    gpa: GPAddr,
+}
+
+impl SharedMapping<'_> {
+    /// Returns the guest physical address of the mapping.
+    #[inline]
+    pub fn gpa(&self) -> GPAddr {
+        self.gpa
+    }
+
+    /// Modifies the access permissions of the mapping.
+    pub fn protect(&self, flags: Memory) -> Result<(), Error> {
+        match self.target {
+            Target::Default(vm) => vm.protect(self.gpa, self.mem.size(), flags),
+            Target::Space(space) => space.protect(self.gpa, self.mem.size(), flags),
+        }
+    }
+}
+
+impl Drop for SharedMapping<'_> {
+    fn drop(&mut self) {
+        let result = match self.target {
+            Target::Default(vm) => vm.unmap(self.gpa, self.mem.size()),
+            Target::Space(space) => space.unmap(self.gpa, self.mem.size()),
+        };
+
+        if result.is_err() {
+            // Still mapped, keep the host memory alive forever.
+            std::mem::forget(Arc::clone(&self.mem));
+        }
+    }
+}
@@ -0,0 +1,170 @@
+use crate::x86::vmx::{VCpuVmxExt, Vmcs};
+use crate::x86::{GeneralRegs, Reg, VcpuExt};
+use crate::{Error, Vcpu};
+
+/// Guest state fields of the VMCS, besides RIP, RSP and RFLAGS.
+const VMCS_FIELDS: &[Vmcs] = &[
+    Vmcs::GUEST_CR0,
+    Vmcs::GUEST_CR3,
+    Vmcs::GUEST_CR4,
+    Vmcs::GUEST_DR7,
+    Vmcs::GUEST_ES,
+    Vmcs::GUEST_ES_BASE,
+    Vmcs::GUEST_ES_LIMIT,
+    Vmcs::GUEST_ES_AR,
+    Vmcs::GUEST_CS,
+    Vmcs::GUEST_CS_BASE,
+    Vmcs::GUEST_CS_LIMIT,
+    Vmcs::GUEST_CS_AR,
+    Vmcs::GUEST_SS,
+    Vmcs::GUEST_SS_BASE,
+    Vmcs::GUEST_SS_LIMIT,
+    Vmcs::GUEST_SS_AR,
+    Vmcs::GUEST_DS,
+    Vmcs::GUEST_DS_BASE,
+    Vmcs::GUEST_DS_LIMIT,
+    Vmcs::GUEST_DS_AR,
+    Vmcs::GUEST_FS,
+    Vmcs::GUEST_FS_BASE,
+    Vmcs::GUEST_FS_LIMIT,
+    Vmcs::GUEST_FS_AR,
+    Vmcs::GUEST_GS,
+    Vmcs::GUEST_GS_BASE,
+    Vmcs::GUEST_GS_LIMIT,
+    Vmcs::GUEST_GS_AR,
+    Vmcs::GUEST_LDTR,
+    Vmcs::GUEST_LDTR_BASE,
+    Vmcs::GUEST_LDTR_LIMIT,
+    Vmcs::GUEST_LDTR_AR,
+    Vmcs::GUEST_TR,
+    Vmcs::GUEST_TR_BASE,
+    Vmcs::GUEST_TR_LIMIT,
+    Vmcs::GUEST_TR_AR,
+    Vmcs::GUEST_GDTR_BASE,
+    Vmcs::GUEST_GDTR_LIMIT,
+    Vmcs::GUEST_IDTR_BASE,
+    Vmcs::GUEST_IDTR_LIMIT,
+    Vmcs::GUEST_IA32_EFER,
+    Vmcs::GUEST_IA32_PAT,
+    Vmcs::GUEST_IA32_DEBUGCTL,
+    Vmcs::GUEST_IA32_SYSENTER_CS,
+    Vmcs::GUEST_SYSENTER_ESP,
+    Vmcs::GUEST_SYSENTER_EIP,
+    Vmcs::GUEST_PDPTE0,
+    Vmcs::GUEST_PDPTE1,
+    Vmcs::GUEST_PDPTE2,
+    Vmcs::GUEST_PDPTE3,
+    Vmcs::GUEST_IGNORE_IRQ,
+    Vmcs::GUEST_ACTIVITY_STATE,
+    Vmcs::GUEST_DEBUG_EXC,
+    Vmcs::GUEST_VMX_TIMER_VALUE,
+    Vmcs::CTRL_VMENTRY_IRQ_INFO,
+    Vmcs::CTRL_VMENTRY_EXC_ERROR,
+    Vmcs::CTRL_VMENTRY_INSTR_LEN,
+];
+
+/// Registers not covered by [GeneralRegs] or the VMCS.
+const REGISTERS: &[Reg] = &[
+    Reg::CR2,
+    Reg::DR0,
+    Reg::DR1,
+    Reg::DR2,
+    Reg::DR3,
+    Reg::DR6,
+    Reg::XCR0,
+    Reg::TPR,
+];
+
+/// MSRs not covered by the VMCS.
+const MSRS: &[u32] = &[
+    0x0000_0010, // TSC
+    0xc000_0081, // STAR
+    0xc000_0082, // LSTAR
+    0xc000_0083, // CSTAR
+    0xc000_0084, // SFMASK
+    0xc000_0102, // KERNEL_GS_BASE
+    TSC_AUX,
+];
+
+/// Architectural state of a vCPU, see [Vcpu::save_state].
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct VcpuState {
+    pub regs: GeneralRegs,
+    /// Guest state fields of the VMCS: control registers, segments, descriptor tables,
+    /// interruptibility, pending event injection and the preemption timer.
+    pub vmcs: Vec<(Vmcs, u64)>,
+    /// Control, debug and extended control registers.
+    pub registers: Vec<(Reg, u64)>,
+    /// MSRs, including the TSC.
+    pub msrs: Vec<(u32, u64)>,
+    /// XSAVE area.
+    pub fpstate: Vec<u8>,
+}
+
+/// `IA32_TSC_AUX`, identifies the CPU to `rdtscp` and `rdpid`.
+pub(crate) const TSC_AUX: u32 = 0xc000_0103;
+
+impl VcpuState {
+    /// Drops the registers identifying the CPU, `TSC_AUX`, so restoring the state into
+    /// another vCPU keeps its identity, e.g. for CPU hotplug.
+    pub fn without_identity(mut self) -> VcpuState {
+        self.msrs.retain(|(msr, _)| *msr != TSC_AUX);
+        self
+    }
+}
+
+/// Size of the legacy `FXSAVE` area, used if the host lacks XSAVE.
+const FXSAVE_SIZE: usize = 512;
+
+/// Returns the size of the XSAVE area for the features enabled in the host `XCR0`.
+pub(crate) fn fpstate_size() -> usize {
+    use std::arch::x86_64::__cpuid_count;
+
+    /// `CPUID.01H:ECX.XSAVE`.
+    const XSAVE: u32 = 1 << 26;
+
+    unsafe {
+        if __cpuid_count(1, 0).ecx & XSAVE == 0 {
+            return FXSAVE_SIZE;
+        }
+        // EBX of leaf 0Dh, sub-leaf 0 reflects the features enabled in XCR0.
+        __cpuid_count(0xd, 0).ebx as usize
+    }
+}
+
+pub(crate) fn fpstate(cpu: &Vcpu) -> Result<Vec<u8>, Error> {
+    let mut out = vec![0_u8; fpstate_size()];
+    cpu.read_fpstate(&mut out)?;
+    Ok(out)
+}
+
+pub(crate) fn save(cpu: &Vcpu) -> Result<VcpuState, Error> {
+    let values = cpu.read_vmcs_many(VMCS_FIELDS)?;
+    let vmcs = VMCS_FIELDS.iter().copied().zip(values).collect();
+
+    let values = cpu.read_registers(REGISTERS)?;
+    let registers = REGISTERS.iter().copied().zip(values).collect();
+
+    let msrs = MSRS
+        .iter()
+        .map(|msr| Ok((*msr, cpu.read_msr(*msr)?)))
+        .collect::<Result<_, Error>>()?;
+
+    Ok(VcpuState {
+        regs: cpu.get_regs()?,
+        vmcs,
+        registers,
+        msrs,
+        fpstate: fpstate(cpu)?,
+    })
+}
+
+pub(crate) fn restore(cpu: &Vcpu, state: &VcpuState) -> Result<(), Error> {
+    cpu.write_vmcs_many(&state.vmcs)?;
+    cpu.write_registers(&state.registers)?;
+    for (msr, value) in &state.msrs {
+        cpu.write_msr(*msr, *value)?;
+    }
+    cpu.write_fpstate(&state.fpstate)?;
+    cpu.set_regs(&state.regs)
+}
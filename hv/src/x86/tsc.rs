@@ -0,0 +1,101 @@
+use std::arch::x86_64::_rdtsc;
+use std::os::raw::c_char;
+use std::time::Duration;
+
+use super::vmx::{VCpuVmxExt, Vmcs};
+use super::{VcpuExt, VmExt};
+use crate::{Error, Vcpu, Vm};
+
+/// The guest TSC of a VM, kept consistent across its vCPUs.
+///
+/// [GuestTsc::sync] sets the TSC of all vCPUs to the one of a vCPU, e.g. after its offset
+/// was changed with [VcpuExt::set_tsc_offset](super::VcpuExt::set_tsc_offset). While the
+/// host sleeps, the host TSC and thus the guest TSC stop, [GuestTsc::catch_up] advances the
+/// guest TSC by the time slept, e.g. from `power::Wake::slept`.
+///
+/// ```ignore
+/// let tsc = GuestTsc::new()?;
+/// // On the vCPU thread, after the host woke up:
+/// tsc.catch_up(&cpu, wake.slept)?;
+/// ```
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct GuestTsc {
+    frequency: u64,
+}
+
+impl GuestTsc {
+    /// Queries the TSC frequency of the host, fails with [Error::Unsupported] if it isn't
+    /// known.
+    pub fn new() -> Result<GuestTsc, Error> {
+        let frequency = tsc_frequency().ok_or(Error::Unsupported)?;
+        Ok(GuestTsc { frequency })
+    }
+
+    /// Returns the TSC frequency in Hz.
+    pub fn frequency(&self) -> u64 {
+        self.frequency
+    }
+
+    /// Returns the number of TSC ticks in `duration`.
+    pub fn ticks(&self, duration: Duration) -> u64 {
+        let ticks = duration.as_nanos() * u128::from(self.frequency) / 1_000_000_000;
+        ticks.min(u128::from(u64::MAX)) as u64
+    }
+
+    /// Returns the current guest TSC of `vcpu`, ignoring TSC scaling.
+    pub fn read(&self, vcpu: &Vcpu) -> Result<u64, Error> {
+        guest_tsc(vcpu)
+    }
+
+    /// Sets the TSC of all vCPUs to the current guest TSC of `vcpu`.
+    pub fn sync(&self, vcpu: &Vcpu) -> Result<(), Error> {
+        Vm::sync_tsc(self.read(vcpu)?)
+    }
+
+    /// Advances the TSC of all vCPUs by `slept`, to make up for a host sleep.
+    pub fn catch_up(&self, vcpu: &Vcpu, slept: Duration) -> Result<(), Error> {
+        let tsc = self.read(vcpu)?.wrapping_add(self.ticks(slept));
+        Vm::sync_tsc(tsc)
+    }
+
+    /// Advances the TSC of `vcpu` alone by `slept`, through its TSC offset. Used when each
+    /// vCPU thread catches up on its own, see
+    /// [VcpuController::catch_up](crate::VcpuController::catch_up).
+    pub fn advance(&self, vcpu: &Vcpu, slept: Duration) -> Result<(), Error> {
+        let offset = vcpu.tsc_offset()?.wrapping_add(self.ticks(slept) as i64);
+        vcpu.set_tsc_offset(offset)
+    }
+}
+
+/// Returns the current guest TSC of `vcpu`, as read by `rdtsc` without TSC scaling.
+pub(crate) fn guest_tsc(vcpu: &Vcpu) -> Result<u64, Error> {
+    let offset = vcpu.read_vmcs(Vmcs::CTRL_TSC_OFFSET)?;
+    Ok(host_tsc().wrapping_add(offset))
+}
+
+fn host_tsc() -> u64 {
+    unsafe { _rdtsc() }
+}
+
+/// Queries the `machdep.tsc.frequency` sysctl.
+fn tsc_frequency() -> Option<u64> {
+    let mut value: u64 = 0;
+    let mut size = std::mem::size_of::<u64>();
+    let name = b"machdep.tsc.frequency\0";
+
+    let ret = unsafe {
+        libc::sysctlbyname(
+            name.as_ptr() as *const c_char,
+            &mut value as *mut _ as *mut libc::c_void,
+            &mut size,
+            std::ptr::null_mut(),
+            0,
+        )
+    };
+
+    if ret == 0 && value != 0 {
+        Some(value)
+    } else {
+        None
+    }
+}
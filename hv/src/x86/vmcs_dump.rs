@@ -0,0 +1,261 @@
+use std::fmt;
+
+use super::vmx::{VCpuVmxExt, Vmcs};
+use crate::Vcpu;
+
+/// All VMCS fields known to the framework.
+const FIELDS: &[Vmcs] = &[
+    Vmcs::VPID,
+    Vmcs::CTRL_POSTED_INT_N_VECTOR,
+    Vmcs::CTRL_EPTP_INDEX,
+    Vmcs::GUEST_ES,
+    Vmcs::GUEST_CS,
+    Vmcs::GUEST_SS,
+    Vmcs::GUEST_DS,
+    Vmcs::GUEST_FS,
+    Vmcs::GUEST_GS,
+    Vmcs::GUEST_LDTR,
+    Vmcs::GUEST_TR,
+    Vmcs::GUEST_INT_STATUS,
+    Vmcs::GUESTPML_INDEX,
+    Vmcs::HOST_ES,
+    Vmcs::HOST_CS,
+    Vmcs::HOST_SS,
+    Vmcs::HOST_DS,
+    Vmcs::HOST_FS,
+    Vmcs::HOST_GS,
+    Vmcs::HOST_TR,
+    Vmcs::CTRL_IO_BITMAP_A,
+    Vmcs::CTRL_IO_BITMAP_B,
+    Vmcs::CTRL_MSR_BITMAPS,
+    Vmcs::CTRL_VMEXIT_MSR_STORE_ADDR,
+    Vmcs::CTRL_VMEXIT_MSR_LOAD_ADDR,
+    Vmcs::CTRL_VMENTRY_MSR_LOAD_ADDR,
+    Vmcs::CTRL_EXECUTIVE_VMCS_PTR,
+    Vmcs::CTRL_PML_ADDR,
+    Vmcs::CTRL_TSC_OFFSET,
+    Vmcs::CTRL_VIRTUAL_APIC,
+    Vmcs::CTRL_APIC_ACCESS,
+    Vmcs::CTRL_POSTED_INT_DESC_ADDR,
+    Vmcs::CTRL_VMFUNC_CTRL,
+    Vmcs::CTRL_EPTP,
+    Vmcs::CTRL_EOI_EXIT_BITMAP_0,
+    Vmcs::CTRL_EOI_EXIT_BITMAP_1,
+    Vmcs::CTRL_EOI_EXIT_BITMAP_2,
+    Vmcs::CTRL_EOI_EXIT_BITMAP_3,
+    Vmcs::CTRL_EPTP_LIST_ADDR,
+    Vmcs::CTRL_VMREAD_BITMAP_ADDR,
+    Vmcs::CTRL_VMWRITE_BITMAP_ADDR,
+    Vmcs::CTRL_VIRT_EXC_INFO_ADDR,
+    Vmcs::CTRL_XSS_EXITING_BITMAP,
+    Vmcs::CTRL_ENCLS_EXITING_BITMAP,
+    Vmcs::CTRL_TSC_MULTIPLIER,
+    Vmcs::GUEST_PHYSICAL_ADDRESS,
+    Vmcs::GUEST_LINK_POINTER,
+    Vmcs::GUEST_IA32_DEBUGCTL,
+    Vmcs::GUEST_IA32_PAT,
+    Vmcs::GUEST_IA32_EFER,
+    Vmcs::GUEST_IA32_PERF_GLOBAL_CTRL,
+    Vmcs::GUEST_PDPTE0,
+    Vmcs::GUEST_PDPTE1,
+    Vmcs::GUEST_PDPTE2,
+    Vmcs::GUEST_PDPTE3,
+    Vmcs::GUEST_IA32_BNDCFGS,
+    Vmcs::HOST_IA32_PAT,
+    Vmcs::HOST_IA32_EFER,
+    Vmcs::HOST_IA32_PERF_GLOBAL_CTRL,
+    Vmcs::CTRL_PIN_BASED,
+    Vmcs::CTRL_CPU_BASED,
+    Vmcs::CTRL_EXC_BITMAP,
+    Vmcs::CTRL_PF_ERROR_MASK,
+    Vmcs::CTRL_PF_ERROR_MATCH,
+    Vmcs::CTRL_CR3_COUNT,
+    Vmcs::CTRL_VMEXIT_CONTROLS,
+    Vmcs::CTRL_VMEXIT_MSR_STORE_COUNT,
+    Vmcs::CTRL_VMEXIT_MSR_LOAD_COUNT,
+    Vmcs::CTRL_VMENTRY_CONTROLS,
+    Vmcs::CTRL_VMENTRY_MSR_LOAD_COUNT,
+    Vmcs::CTRL_VMENTRY_IRQ_INFO,
+    Vmcs::CTRL_VMENTRY_EXC_ERROR,
+    Vmcs::CTRL_VMENTRY_INSTR_LEN,
+    Vmcs::CTRL_TPR_THRESHOLD,
+    Vmcs::CTRL_CPU_BASED2,
+    Vmcs::CTRL_PLE_GAP,
+    Vmcs::CTRL_PLE_WINDOW,
+    Vmcs::RO_INSTR_ERROR,
+    Vmcs::RO_EXIT_REASON,
+    Vmcs::RO_VMEXIT_IRQ_INFO,
+    Vmcs::RO_VMEXIT_IRQ_ERROR,
+    Vmcs::RO_IDT_VECTOR_INFO,
+    Vmcs::RO_IDT_VECTOR_ERROR,
+    Vmcs::RO_VMEXIT_INSTR_LEN,
+    Vmcs::RO_VMX_INSTR_INFO,
+    Vmcs::GUEST_ES_LIMIT,
+    Vmcs::GUEST_CS_LIMIT,
+    Vmcs::GUEST_SS_LIMIT,
+    Vmcs::GUEST_DS_LIMIT,
+    Vmcs::GUEST_FS_LIMIT,
+    Vmcs::GUEST_GS_LIMIT,
+    Vmcs::GUEST_LDTR_LIMIT,
+    Vmcs::GUEST_TR_LIMIT,
+    Vmcs::GUEST_GDTR_LIMIT,
+    Vmcs::GUEST_IDTR_LIMIT,
+    Vmcs::GUEST_ES_AR,
+    Vmcs::GUEST_CS_AR,
+    Vmcs::GUEST_SS_AR,
+    Vmcs::GUEST_DS_AR,
+    Vmcs::GUEST_FS_AR,
+    Vmcs::GUEST_GS_AR,
+    Vmcs::GUEST_LDTR_AR,
+    Vmcs::GUEST_TR_AR,
+    Vmcs::GUEST_IGNORE_IRQ,
+    Vmcs::GUEST_ACTIVITY_STATE,
+    Vmcs::GUEST_SMBASE,
+    Vmcs::GUEST_IA32_SYSENTER_CS,
+    Vmcs::GUEST_VMX_TIMER_VALUE,
+    Vmcs::HOST_IA32_SYSENTER_CS,
+    Vmcs::CTRL_CR0_MASK,
+    Vmcs::CTRL_CR4_MASK,
+    Vmcs::CTRL_CR0_SHADOW,
+    Vmcs::CTRL_CR4_SHADOW,
+    Vmcs::CTRL_CR3_VALUE0,
+    Vmcs::CTRL_CR3_VALUE1,
+    Vmcs::CTRL_CR3_VALUE2,
+    Vmcs::CTRL_CR3_VALUE3,
+    Vmcs::RO_EXIT_QUALIFIC,
+    Vmcs::RO_IO_RCX,
+    Vmcs::RO_IO_RSI,
+    Vmcs::RO_IO_RDI,
+    Vmcs::RO_IO_RIP,
+    Vmcs::RO_GUEST_LIN_ADDR,
+    Vmcs::GUEST_CR0,
+    Vmcs::GUEST_CR3,
+    Vmcs::GUEST_CR4,
+    Vmcs::GUEST_ES_BASE,
+    Vmcs::GUEST_CS_BASE,
+    Vmcs::GUEST_SS_BASE,
+    Vmcs::GUEST_DS_BASE,
+    Vmcs::GUEST_FS_BASE,
+    Vmcs::GUEST_GS_BASE,
+    Vmcs::GUEST_LDTR_BASE,
+    Vmcs::GUEST_TR_BASE,
+    Vmcs::GUEST_GDTR_BASE,
+    Vmcs::GUEST_IDTR_BASE,
+    Vmcs::GUEST_DR7,
+    Vmcs::GUEST_RSP,
+    Vmcs::GUEST_RIP,
+    Vmcs::GUEST_RFLAGS,
+    Vmcs::GUEST_DEBUG_EXC,
+    Vmcs::GUEST_SYSENTER_ESP,
+    Vmcs::GUEST_SYSENTER_EIP,
+    Vmcs::HOST_CR0,
+    Vmcs::HOST_CR3,
+    Vmcs::HOST_CR4,
+    Vmcs::HOST_FS_BASE,
+    Vmcs::HOST_GS_BASE,
+    Vmcs::HOST_TR_BASE,
+    Vmcs::HOST_GDTR_BASE,
+    Vmcs::HOST_IDTR_BASE,
+    Vmcs::HOST_IA32_SYSENTER_ESP,
+    Vmcs::HOST_IA32_SYSENTER_EIP,
+    Vmcs::HOST_RSP,
+    Vmcs::HOST_RIP,
+];
+
+/// Groups of VMCS fields in a [VmcsDump].
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum VmcsGroup {
+    /// VM-execution, exit and entry controls.
+    Control,
+    /// Guest state.
+    Guest,
+    /// Host state.
+    Host,
+    /// Read-only exit information.
+    ExitInfo,
+}
+
+impl VmcsGroup {
+    const ALL: [VmcsGroup; 4] = [
+        VmcsGroup::Control,
+        VmcsGroup::Guest,
+        VmcsGroup::Host,
+        VmcsGroup::ExitInfo,
+    ];
+
+    /// Returns the group of `field`.
+    pub fn of(field: Vmcs) -> VmcsGroup {
+        let name = format!("{:?}", field);
+        if name.starts_with("GUEST") {
+            VmcsGroup::Guest
+        } else if name.starts_with("HOST") {
+            VmcsGroup::Host
+        } else if name.starts_with("RO_") {
+            VmcsGroup::ExitInfo
+        } else {
+            VmcsGroup::Control
+        }
+    }
+}
+
+impl fmt::Display for VmcsGroup {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            VmcsGroup::Control => "control",
+            VmcsGroup::Guest => "guest state",
+            VmcsGroup::Host => "host state",
+            VmcsGroup::ExitInfo => "exit information",
+        };
+        f.write_str(name)
+    }
+}
+
+/// Snapshot of every VMCS field of a vCPU, see `VcpuExt::dump_vmcs`.
+///
+/// Fields the framework refuses to read, e.g. of features the host doesn't support, have no
+/// value. Displays as one labeled line per field, grouped by [VmcsGroup].
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct VmcsDump {
+    pub fields: Vec<(Vmcs, Option<u64>)>,
+}
+
+impl VmcsDump {
+    pub(crate) fn read(vcpu: &Vcpu) -> VmcsDump {
+        let fields = FIELDS
+            .iter()
+            .map(|field| (*field, vcpu.read_vmcs(*field).ok()))
+            .collect();
+        VmcsDump { fields }
+    }
+
+    /// Returns the value of `field`, `None` if it couldn't be read.
+    pub fn get(&self, field: Vmcs) -> Option<u64> {
+        self.fields
+            .iter()
+            .find(|(f, _)| *f == field)
+            .and_then(|(_, value)| *value)
+    }
+
+    /// Returns the fields of `group`.
+    pub fn group(&self, group: VmcsGroup) -> impl Iterator<Item = &(Vmcs, Option<u64>)> {
+        self.fields
+            .iter()
+            .filter(move |(field, _)| VmcsGroup::of(*field) == group)
+    }
+}
+
+impl fmt::Display for VmcsDump {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for group in VmcsGroup::ALL.iter() {
+            writeln!(f, "{}:", group)?;
+            for (field, value) in self.group(*group) {
+                let name = format!("{:?}", field);
+                match value {
+                    Some(value) => writeln!(f, "  {:<28} {:#018x}", name, value)?,
+                    None => writeln!(f, "  {:<28} <unavailable>", name)?,
+                }
+            }
+        }
+        Ok(())
+    }
+}
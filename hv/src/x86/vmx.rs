@@ -1,6 +1,7 @@
 //! VMX extensions.
 
-use crate::{call, sys, Error, Vcpu};
+use crate::vcpu::Field;
+use crate::{call, sys, Error, GPAddr, Vcpu};
 
 /// Enum type of VMX cabability fields
 #[repr(u32)]
@@ -28,6 +29,12 @@ pub fn read_capability(field: Capability) -> Result<u64, Error> {
     Ok(out)
 }
 
+/// Adjusts the requested VMX controls to the allowed 0 and 1 settings of the host processor.
+pub fn adjust_controls(cap: Capability, ctrl: u64) -> Result<u64, Error> {
+    let cap = read_capability(cap)?;
+    Ok((ctrl | (cap & 0xffff_ffff)) & (cap >> 32))
+}
+
 bitflags::bitflags! {
     #[cfg(feature = "hv_10_15")]
     pub struct ShadowFlags: u32 {
@@ -44,6 +51,26 @@ pub trait VCpuVmxExt {
     /// Set the value of a VMCS field of a vCPU.
     fn write_vmcs(&self, field: Vmcs, value: u64) -> Result<(), Error>;
 
+    /// Returns the values of several VMCS fields of a vCPU, in order.
+    ///
+    /// A convenience loop over [VCpuVmxExt::read_vmcs], it doesn't batch: the framework has no
+    /// batched accessor, so every field that isn't in the write cache costs one call.
+    fn read_vmcs_many(&self, fields: &[Vmcs]) -> Result<Vec<u64>, Error>;
+
+    /// Sets the values of several VMCS fields of a vCPU, in order.
+    ///
+    /// A convenience loop over [VCpuVmxExt::write_vmcs], it doesn't batch by itself: the
+    /// writes are only buffered until the next entry with the write cache enabled, see
+    /// [Vcpu::set_write_cache]. Stops at the first failing field.
+    fn write_vmcs_many(&self, writes: &[(Vmcs, u64)]) -> Result<(), Error>;
+
+    /// Returns the reason of the last exit, decoded from `RO_EXIT_REASON`.
+    fn exit_reason(&self) -> Result<ExitReason, Error>;
+
+    /// Sets the guest physical address of the APIC-access page of a vCPU, accesses to it
+    /// cause [Reason::APIC_ACCESS] exits once APIC accesses are virtualized.
+    fn set_apic_address(&self, gpa: GPAddr) -> Result<(), Error>;
+
     /// Returns the current value of a shadow VMCS field of a vCPU.
     #[cfg(feature = "hv_10_15")]
     fn read_shadow_vmcs(&self, field: Vmcs) -> Result<u64, Error>;
@@ -60,6 +87,9 @@ pub trait VCpuVmxExt {
 impl VCpuVmxExt for Vcpu {
     /// Returns the current value of a VMCS field of a vCPU.
     fn read_vmcs(&self, field: Vmcs) -> Result<u64, Error> {
+        if let Some(value) = self.cached(Field::Vmcs(field)) {
+            return Ok(value);
+        }
         let mut out = 0_u64;
         call!(sys::hv_vmx_vcpu_read_vmcs(self.id, field as u32, &mut out))?;
         Ok(out)
@@ -67,9 +97,36 @@ impl VCpuVmxExt for Vcpu {
 
     /// Set the value of a VMCS field of a vCPU.
     fn write_vmcs(&self, field: Vmcs, value: u64) -> Result<(), Error> {
+        if self.cache_write(Field::Vmcs(field), value) {
+            return Ok(());
+        }
         call!(sys::hv_vmx_vcpu_write_vmcs(self.id, field as u32, value))
     }
 
+    /// Returns the values of several VMCS fields of a vCPU, in order.
+    fn read_vmcs_many(&self, fields: &[Vmcs]) -> Result<Vec<u64>, Error> {
+        fields.iter().map(|field| self.read_vmcs(*field)).collect()
+    }
+
+    /// Sets the values of several VMCS fields of a vCPU, in order.
+    fn write_vmcs_many(&self, writes: &[(Vmcs, u64)]) -> Result<(), Error> {
+        writes
+            .iter()
+            .try_for_each(|(field, value)| self.write_vmcs(*field, *value))
+    }
+
+    /// Returns the reason of the last exit, decoded from `RO_EXIT_REASON`.
+    fn exit_reason(&self) -> Result<ExitReason, Error> {
+        Ok(ExitReason::from_raw(
+            self.read_vmcs(Vmcs::RO_EXIT_REASON)? as u32
+        ))
+    }
+
+    /// Sets the guest physical address of the APIC-access page of a vCPU.
+    fn set_apic_address(&self, gpa: GPAddr) -> Result<(), Error> {
+        call!(sys::hv_vmx_vcpu_set_apic_address(self.id, gpa))
+    }
+
     /// Returns the current value of a shadow VMCS field of a vCPU.
     #[cfg(feature = "hv_10_15")]
     fn read_shadow_vmcs(&self, field: Vmcs) -> Result<u64, Error> {
@@ -335,6 +392,107 @@ pub enum Reason {
     XRSTORS = sys::VMX_REASON_XRSTORS,
 }
 
+impl Reason {
+    /// Returns the reason for a basic exit reason (bits 15:0 of `RO_EXIT_REASON`), `None` if
+    /// it's unknown.
+    pub fn from_raw(raw: u32) -> Option<Reason> {
+        const ALL: &[Reason] = &[
+            Reason::EXC_NMI,
+            Reason::IRQ,
+            Reason::TRIPLE_FAULT,
+            Reason::INIT,
+            Reason::SIPI,
+            Reason::IO_SMI,
+            Reason::OTHER_SMI,
+            Reason::IRQ_WND,
+            Reason::VIRTUAL_NMI_WND,
+            Reason::TASK,
+            Reason::CPUID,
+            Reason::GETSEC,
+            Reason::HLT,
+            Reason::INVD,
+            Reason::INVLPG,
+            Reason::RDPMC,
+            Reason::RDTSC,
+            Reason::RSM,
+            Reason::VMCALL,
+            Reason::VMCLEAR,
+            Reason::VMLAUNCH,
+            Reason::VMPTRLD,
+            Reason::VMPTRST,
+            Reason::VMREAD,
+            Reason::VMRESUME,
+            Reason::VMWRITE,
+            Reason::VMOFF,
+            Reason::VMON,
+            Reason::MOV_CR,
+            Reason::MOV_DR,
+            Reason::IO,
+            Reason::RDMSR,
+            Reason::WRMSR,
+            Reason::VMENTRY_GUEST,
+            Reason::VMENTRY_MSR,
+            Reason::MWAIT,
+            Reason::MTF,
+            Reason::MONITOR,
+            Reason::PAUSE,
+            Reason::VMENTRY_MC,
+            Reason::TPR_THRESHOLD,
+            Reason::APIC_ACCESS,
+            Reason::VIRTUALIZED_EOI,
+            Reason::GDTR_IDTR,
+            Reason::LDTR_TR,
+            Reason::EPT_VIOLATION,
+            Reason::EPT_MISCONFIG,
+            Reason::EPT_INVEPT,
+            Reason::RDTSCP,
+            Reason::VMX_TIMER_EXPIRED,
+            Reason::INVVPID,
+            Reason::WBINVD,
+            Reason::XSETBV,
+            Reason::APIC_WRITE,
+            Reason::RDRAND,
+            Reason::INVPCID,
+            Reason::VMFUNC,
+            Reason::RDSEED,
+            Reason::XSAVES,
+            Reason::XRSTORS,
+        ];
+        ALL.iter().copied().find(|reason| *reason as u32 == raw)
+    }
+}
+
+/// The `RO_EXIT_REASON` field, see [VCpuVmxExt::exit_reason].
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct ExitReason(u32);
+
+impl ExitReason {
+    pub fn from_raw(raw: u32) -> ExitReason {
+        ExitReason(raw)
+    }
+
+    /// Returns the whole field, including flags.
+    pub fn raw(&self) -> u32 {
+        self.0
+    }
+
+    /// Returns the basic exit reason.
+    pub fn basic(&self) -> u32 {
+        self.0 & 0xffff
+    }
+
+    /// Returns the basic exit reason, `None` if it's unknown.
+    pub fn reason(&self) -> Option<Reason> {
+        Reason::from_raw(self.basic())
+    }
+
+    /// Returns `true` if the exit was caused by a failed VM entry, e.g. because of invalid
+    /// guest state.
+    pub fn is_entry_failure(&self) -> bool {
+        self.0 & (1 << 31) != 0
+    }
+}
+
 #[allow(non_camel_case_types)]
 #[non_exhaustive]
 #[repr(u32)]
@@ -0,0 +1,80 @@
+use std::sync::Mutex;
+
+use crate::Error;
+
+/// Hands out virtual-processor identifiers, which tag the TLB entries of a vCPU so they
+/// survive VM entries and exits and switches between vCPUs.
+///
+/// Every VM has one, see [VmExt::vpids](super::VmExt::vpids). VPID 0 is reserved for the host,
+/// identifiers go from 1 to `u16::MAX`.
+///
+/// ```ignore
+/// let vpid = vm.vpids().alloc()?;
+/// cpu.set_vpid(vpid)?;
+/// // When the vCPU is destroyed:
+/// vm.vpids().release(vpid);
+/// ```
+#[derive(Debug, Default)]
+pub struct VpidAllocator {
+    inner: Mutex<Inner>,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    /// Highest identifier handed out so far.
+    last: u16,
+    /// Released identifiers, reused first.
+    free: Vec<u16>,
+}
+
+impl VpidAllocator {
+    /// Allocates an unused VPID, fails with [Error::NoResources] if all are in use.
+    pub fn alloc(&self) -> Result<u16, Error> {
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(vpid) = inner.free.pop() {
+            return Ok(vpid);
+        }
+        if inner.last == u16::MAX {
+            return Err(Error::NoResources);
+        }
+        inner.last += 1;
+        Ok(inner.last)
+    }
+
+    /// Returns `vpid` to the allocator.
+    pub fn release(&self, vpid: u16) {
+        let mut inner = self.inner.lock().unwrap();
+        if vpid != 0 && vpid <= inner.last && !inner.free.contains(&vpid) {
+            inner.free.push(vpid);
+        }
+    }
+}
+
+/// Changes that may leave stale guest translations in the TLB of a vCPU, see
+/// [VcpuExt::sync_tlb](super::VcpuExt::sync_tlb).
+///
+/// Only removing or restricting guest physical mappings requires an invalidation, as cached
+/// translations of the changed range may still be used.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum TlbChange {
+    /// Guest physical memory was mapped. Missing translations aren't cached, so this never
+    /// requires an invalidation.
+    Mapped,
+    /// Guest physical memory was unmapped.
+    Unmapped,
+    /// Permissions of guest physical memory were changed.
+    Protected,
+    /// The vCPU was moved to another address space. Translations are tagged with the address
+    /// space, so this doesn't require an invalidation either.
+    SpaceSwitched,
+}
+
+impl TlbChange {
+    /// Returns `true` if the TLB of a vCPU must be invalidated after the change.
+    pub fn needs_invalidation(self) -> bool {
+        match self {
+            TlbChange::Mapped | TlbChange::SpaceSwitched => false,
+            TlbChange::Unmapped | TlbChange::Protected => true,
+        }
+    }
+}
@@ -0,0 +1,251 @@
+use std::arch::x86_64::{__cpuid_count, _xgetbv};
+use std::convert::TryInto;
+
+use crate::{Error, Xcr0Error};
+
+bitflags::bitflags! {
+    /// State components of `XCR0`.
+    pub struct XFeatures: u64 {
+        const X87 = 1 << 0;
+        const SSE = 1 << 1;
+        const AVX = 1 << 2;
+        const BNDREGS = 1 << 3;
+        const BNDCSR = 1 << 4;
+        const OPMASK = 1 << 5;
+        const ZMM_HI256 = 1 << 6;
+        const HI16_ZMM = 1 << 7;
+        const PKRU = 1 << 9;
+        const TILECFG = 1 << 17;
+        const TILEDATA = 1 << 18;
+
+        const MPX = Self::BNDREGS.bits | Self::BNDCSR.bits;
+        const AVX512 = Self::OPMASK.bits | Self::ZMM_HI256.bits | Self::HI16_ZMM.bits;
+        const AMX = Self::TILECFG.bits | Self::TILEDATA.bits;
+    }
+}
+
+impl XFeatures {
+    /// Returns the features a guest can enable: supported by the processor (`CPUID.0DH`)
+    /// and enabled in the host `XCR0`, which the framework restricts guests to.
+    pub fn host() -> XFeatures {
+        /// `CPUID.01H:ECX.XSAVE` and `CPUID.01H:ECX.OSXSAVE`.
+        const XSAVE: u32 = 1 << 26;
+        const OSXSAVE: u32 = 1 << 27;
+
+        unsafe {
+            let ecx = __cpuid_count(1, 0).ecx;
+            if ecx & (XSAVE | OSXSAVE) != XSAVE | OSXSAVE {
+                return XFeatures::X87 | XFeatures::SSE;
+            }
+            let leaf = __cpuid_count(0xd, 0);
+            let supported = u64::from(leaf.eax) | (u64::from(leaf.edx) << 32);
+            XFeatures::from_bits_truncate(supported & _xgetbv(0))
+        }
+    }
+
+    /// Checks that `self` is a valid `XCR0` for guests on this host: x87 is enabled, all
+    /// features are available (see [XFeatures::host]) and features come with the ones they
+    /// depend on.
+    pub fn validate(self) -> Result<(), Error> {
+        let invalid = |reason| Err(Error::InvalidXcr0 { reason });
+
+        if !self.contains(XFeatures::X87) {
+            return invalid(Xcr0Error::MissingX87);
+        }
+        let unsupported = self - XFeatures::host();
+        if !unsupported.is_empty() {
+            return invalid(Xcr0Error::Unsupported(unsupported.bits));
+        }
+
+        // Features that must be enabled together, and what they require.
+        let dependencies = [
+            (XFeatures::AVX, XFeatures::SSE),
+            (XFeatures::AVX512, XFeatures::AVX512 | XFeatures::AVX),
+            (XFeatures::MPX, XFeatures::MPX),
+            (XFeatures::AMX, XFeatures::AMX),
+        ];
+        for (features, requires) in dependencies.iter() {
+            let enabled = self & *features;
+            if !enabled.is_empty() && !self.contains(*requires) {
+                return invalid(Xcr0Error::MissingDependency {
+                    features: enabled.bits,
+                    requires: (*requires - self).bits,
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Size of the legacy `FXSAVE` region.
+const LEGACY_SIZE: usize = 512;
+
+/// Size of the legacy region and the XSAVE header.
+const HEADER_END: usize = LEGACY_SIZE + 64;
+
+/// State components in `XSTATE_BV`.
+const XSTATE_X87: u64 = 1 << 0;
+const XSTATE_SSE: u64 = 1 << 1;
+const XSTATE_AVX: u64 = 1 << 2;
+
+/// Compacted format bit of `XCOMP_BV`.
+const XCOMP_COMPACTED: u64 = 1 << 63;
+
+/// Offsets in the legacy region.
+const ST_OFFSET: usize = 32;
+const XMM_OFFSET: usize = 160;
+
+/// The XSAVE area of a vCPU, as read by `VcpuExt::read_xsave`.
+///
+/// The legacy `FXSAVE` region, the XSAVE header and the upper halves of the YMM registers
+/// are decoded, other state components are kept as they are and written back unchanged.
+/// The area has the size reported by the host CPUID, see
+/// [Vcpu::fpstate_size](crate::Vcpu::fpstate_size).
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct XSaveArea {
+    /// x87 control word.
+    pub fcw: u16,
+    /// x87 status word.
+    pub fsw: u16,
+    /// Abridged x87 tag word, one bit per register, set if it's valid.
+    pub ftw: u8,
+    /// Last x87 opcode.
+    pub fop: u16,
+    /// Last x87 instruction pointer.
+    pub fip: u64,
+    /// Last x87 data pointer.
+    pub fdp: u64,
+    pub mxcsr: u32,
+    pub mxcsr_mask: u32,
+    /// ST0 to ST7 (or MM0 to MM7), 80 bits each.
+    pub st: [[u8; 10]; 8],
+    pub xmm: [u128; 16],
+    /// Components in the area, components with a clear bit are in their initial state.
+    pub xstate_bv: u64,
+    /// Components of the compacted format, 0 for the standard format.
+    pub xcomp_bv: u64,
+    /// Upper 128 bits of YMM0 to YMM15, `None` if AVX isn't supported by the host or the area
+    /// uses the compacted format.
+    pub ymm_hi: Option<[u128; 16]>,
+    /// The whole area, for components that aren't decoded.
+    raw: Vec<u8>,
+}
+
+impl XSaveArea {
+    /// Decodes an XSAVE area, fails with [Error::BadArgument] if it's shorter than the legacy
+    /// region. Areas without the XSAVE header, as saved by `FXSAVE`, have an empty header.
+    pub fn from_bytes(bytes: &[u8]) -> Result<XSaveArea, Error> {
+        if bytes.len() < LEGACY_SIZE {
+            return Err(Error::BadArgument);
+        }
+
+        let u16_at = |o: usize| u16::from_le_bytes(bytes[o..o + 2].try_into().unwrap());
+        let u32_at = |o: usize| u32::from_le_bytes(bytes[o..o + 4].try_into().unwrap());
+        let u64_at = |o: usize| u64::from_le_bytes(bytes[o..o + 8].try_into().unwrap());
+        let u128_at = |o: usize| u128::from_le_bytes(bytes[o..o + 16].try_into().unwrap());
+
+        let mut st = [[0; 10]; 8];
+        for (i, reg) in st.iter_mut().enumerate() {
+            let o = ST_OFFSET + i * 16;
+            reg.copy_from_slice(&bytes[o..o + 10]);
+        }
+
+        let mut xmm = [0; 16];
+        for (i, reg) in xmm.iter_mut().enumerate() {
+            *reg = u128_at(XMM_OFFSET + i * 16);
+        }
+
+        let (xstate_bv, xcomp_bv) = if bytes.len() >= HEADER_END {
+            (u64_at(LEGACY_SIZE), u64_at(LEGACY_SIZE + 8))
+        } else {
+            (0, 0)
+        };
+
+        let ymm_hi = match avx_offset() {
+            Some(offset) if xcomp_bv & XCOMP_COMPACTED == 0 && bytes.len() >= offset + 256 => {
+                let mut ymm_hi = [0; 16];
+                // Components not in XSTATE_BV are in their initial state, all zeros.
+                if xstate_bv & XSTATE_AVX != 0 {
+                    for (i, reg) in ymm_hi.iter_mut().enumerate() {
+                        *reg = u128_at(offset + i * 16);
+                    }
+                }
+                Some(ymm_hi)
+            }
+            _ => None,
+        };
+
+        Ok(XSaveArea {
+            fcw: u16_at(0),
+            fsw: u16_at(2),
+            ftw: bytes[4],
+            fop: u16_at(6),
+            fip: u64_at(8),
+            fdp: u64_at(16),
+            mxcsr: u32_at(24),
+            mxcsr_mask: u32_at(28),
+            st,
+            xmm,
+            xstate_bv,
+            xcomp_bv,
+            ymm_hi,
+            raw: bytes.to_vec(),
+        })
+    }
+
+    /// Encodes the area for `VcpuExt::write_fpstate`.
+    ///
+    /// The x87 and SSE components are always marked present in `XSTATE_BV`, so they're
+    /// loaded rather than reset, and so is AVX if `ymm_hi` is set.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = self.raw.clone();
+
+        bytes[0..2].copy_from_slice(&self.fcw.to_le_bytes());
+        bytes[2..4].copy_from_slice(&self.fsw.to_le_bytes());
+        bytes[4] = self.ftw;
+        bytes[6..8].copy_from_slice(&self.fop.to_le_bytes());
+        bytes[8..16].copy_from_slice(&self.fip.to_le_bytes());
+        bytes[16..24].copy_from_slice(&self.fdp.to_le_bytes());
+        bytes[24..28].copy_from_slice(&self.mxcsr.to_le_bytes());
+        bytes[28..32].copy_from_slice(&self.mxcsr_mask.to_le_bytes());
+        for (i, reg) in self.st.iter().enumerate() {
+            let o = ST_OFFSET + i * 16;
+            bytes[o..o + 10].copy_from_slice(reg);
+        }
+        for (i, reg) in self.xmm.iter().enumerate() {
+            let o = XMM_OFFSET + i * 16;
+            bytes[o..o + 16].copy_from_slice(&reg.to_le_bytes());
+        }
+
+        if bytes.len() >= HEADER_END {
+            let mut xstate_bv = self.xstate_bv | XSTATE_X87 | XSTATE_SSE;
+            let avx = avx_offset().filter(|offset| bytes.len() >= offset + 256);
+            if let (Some(ymm_hi), Some(offset)) = (&self.ymm_hi, avx) {
+                for (i, reg) in ymm_hi.iter().enumerate() {
+                    let o = offset + i * 16;
+                    bytes[o..o + 16].copy_from_slice(&reg.to_le_bytes());
+                }
+                xstate_bv |= XSTATE_AVX;
+            }
+            bytes[LEGACY_SIZE..LEGACY_SIZE + 8].copy_from_slice(&xstate_bv.to_le_bytes());
+            bytes[LEGACY_SIZE + 8..LEGACY_SIZE + 16].copy_from_slice(&self.xcomp_bv.to_le_bytes());
+        }
+
+        bytes
+    }
+
+    /// Returns the raw area as it was read.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.raw
+    }
+}
+
+/// Returns the offset of the AVX state in the standard format, `None` without AVX support.
+fn avx_offset() -> Option<usize> {
+    let leaf = unsafe { __cpuid_count(0xd, 2) };
+    if leaf.eax == 0 {
+        None
+    } else {
+        Some(leaf.ebx as usize)
+    }
+}